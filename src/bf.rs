@@ -1,11 +1,13 @@
-use crate::{CpuConfig, TrackKind};
+use crate::{CpuConfig, TrackId, TrackKind};
 use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
 use std::cell;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::{Read, Write};
+use std::time::Instant;
+use wasm_bindgen::prelude::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum BfOp {
     Left,
     Right,
@@ -15,11 +17,16 @@ pub enum BfOp {
     Out,
     Loop(Vec<BfOp>),
     Clr,
+    Set(u8),
     Shift(i16),
     Add(u8),
     MoveAdd(i16),
+    MoveSub(i16),
     MoveAdd2(i16, i16),
+    MoveMul(i16, u8),
     MoveAddMul(Vec<ShiftAdd>),
+    ScanRight,
+    ScanLeft,
     Comment(String),
     DebugMessage(String),
     Crash(String),
@@ -30,8 +37,20 @@ pub enum BfOp {
 
 #[derive(Debug, Copy, Clone)]
 pub struct TextPos {
-    line_num: usize,
-    col: usize,
+    pub line_num: usize,
+    pub col: usize,
+}
+
+impl TextPos {
+    /// Converts a byte offset into `src` into a 1-based line and column
+    pub fn from_offset(src: &str, offset: usize) -> TextPos {
+        let before = &src[..offset];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        TextPos {
+            line_num: before.matches('\n').count() + 1,
+            col: before[line_start..].chars().count() + 1,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -50,6 +69,25 @@ pub enum ParseBfProgError {
     UnbalancedCloseBracket(UnbalancedCloseBracket),
 }
 
+impl std::fmt::Display for TextPos {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line_num, self.col)
+    }
+}
+
+impl std::fmt::Display for ParseBfProgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseBfProgError::UnbalancedOpenBracket(e) => {
+                write!(f, "Unmatched '[' at {}", e.pos)
+            }
+            ParseBfProgError::UnbalancedCloseBracket(e) => {
+                write!(f, "Unmatched ']' at {}", e.pos)
+            }
+        }
+    }
+}
+
 pub fn parse_bf(s: &str) -> Result<Vec<BfOp>, ParseBfProgError> {
     struct StackFrame {
         open_bracket_pos: TextPos,
@@ -76,10 +114,7 @@ pub fn parse_bf(s: &str) -> Result<Vec<BfOp>, ParseBfProgError> {
                         .last_mut()
                         .unwrap()
                         .ops
-                        .push(BfOp::Comment(std::mem::replace(
-                            &mut comment,
-                            String::new(),
-                        )));
+                        .push(BfOp::Comment(std::mem::take(&mut comment)));
                 }
             } else {
                 comment.push(c);
@@ -128,24 +163,18 @@ pub fn parse_bf(s: &str) -> Result<Vec<BfOp>, ParseBfProgError> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ShiftAdd {
     pub shift: i16,
     pub add: u8,
 }
 
+/// The adds are summed exactly and only returned if each fits in a signed byte, so
+/// the resulting ops also behave like the loop on cells wider than 8 bits
 fn get_loop_as_shiftadds(ops: &Vec<BfOp>) -> Option<HashMap<i16, u8>> {
-    let mut shift_adds: HashMap<i16, u8> = HashMap::new();
+    let mut shift_adds: HashMap<i16, i32> = HashMap::new();
     let mut cur_shift = 0;
-    let mut encounter_add = |x: u8, shift: i16| match shift_adds.entry(shift) {
-        Entry::Occupied(e) => {
-            let new_add = e.get().wrapping_add(x);
-            *e.into_mut() = new_add;
-        }
-        Entry::Vacant(e) => {
-            e.insert(x);
-        }
-    };
+    let mut encounter_add = |x: i32, shift: i16| *shift_adds.entry(shift).or_insert(0) += x;
     for op in ops {
         match op {
             BfOp::Left => {
@@ -158,34 +187,77 @@ fn get_loop_as_shiftadds(ops: &Vec<BfOp>) -> Option<HashMap<i16, u8>> {
                 encounter_add(1, cur_shift);
             }
             BfOp::Dec => {
-                encounter_add(255, cur_shift);
+                encounter_add(-1, cur_shift);
             }
             BfOp::Shift(shift) => {
                 cur_shift += *shift;
             }
             BfOp::Add(val) => {
-                encounter_add(*val, cur_shift);
+                encounter_add(*val as i8 as i32, cur_shift);
             }
             _ => {
                 return None;
             }
         }
     }
-    if cur_shift == 0 {
-        Some(shift_adds)
-    } else {
-        None
+    if cur_shift != 0 {
+        return None;
+    }
+    shift_adds
+        .into_iter()
+        .filter(|&(_, add)| add != 0)
+        .map(|(shift, add)| i8::try_from(add).ok().map(|add| (shift, add as u8)))
+        .collect()
+}
+
+/// Cells that a clearing or moving op adds to, besides clearing the current cell
+fn move_op_targets(op: &BfOp) -> Option<Vec<i16>> {
+    match op {
+        BfOp::Clr => Some(Vec::new()),
+        BfOp::MoveAdd(s) | BfOp::MoveSub(s) | BfOp::MoveMul(s, _) => Some(vec![*s]),
+        BfOp::MoveAdd2(s1, s2) => Some(vec![*s1, *s2]),
+        BfOp::MoveAddMul(v) => Some(v.iter().map(|sa| sa.shift).collect()),
+        _ => None,
     }
 }
 
 pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
     fn get_optimized_bf_ops_rec(ops: &Vec<BfOp>, first_cell_is_empty: bool) -> Vec<BfOp> {
         let mut result = Vec::new();
-        let mut cur_cell_is_empty = first_cell_is_empty;
+        /// Cells known to be zero, relative to where the last opaque loop or scan ended
+        struct EmptyCells {
+            pos: i32,
+            cells: HashSet<i32>,
+        }
+        impl EmptyCells {
+            fn cur(&self) -> bool {
+                self.cells.contains(&self.pos)
+            }
+
+            fn set(&mut self, shift: i16, empty: bool) {
+                let cell = self.pos + shift as i32;
+                if empty {
+                    self.cells.insert(cell);
+                } else {
+                    self.cells.remove(&cell);
+                }
+            }
+
+            fn forget_all(&mut self) {
+                self.pos = 0;
+                self.cells.clear();
+            }
+        }
+        let mut empty = EmptyCells {
+            pos: 0,
+            cells: HashSet::new(),
+        };
+        empty.set(0, first_cell_is_empty);
         let log_useless_ops = false;
         struct BufferState {
             cur_shift: i16,
-            cur_add: u8,
+            /// The exact sum, so that runs can be split into adds that fit in a signed byte
+            cur_add: i32,
         }
         impl BufferState {
             fn flush_shift(&mut self, result: &mut Vec<BfOp>) {
@@ -199,15 +271,30 @@ pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
                 self.cur_shift = 0;
             }
 
+            fn add_shift(&mut self, shift: i16, result: &mut Vec<BfOp>) {
+                // long moves don't fit in a single Shift op, so split them up
+                if self.cur_shift.checked_add(shift).is_none() {
+                    self.flush_shift(result);
+                }
+                self.cur_shift += shift;
+            }
+
             fn flush_add(&mut self, result: &mut Vec<BfOp>) {
-                if self.cur_add == 1 {
-                    result.push(BfOp::Inc);
-                } else if self.cur_add == 255 {
-                    result.push(BfOp::Dec);
-                } else if self.cur_add != 0 {
-                    result.push(BfOp::Add(self.cur_add));
-                }
-                self.cur_add = 0;
+                while self.cur_add != 0 {
+                    let add = self.cur_add.clamp(i8::MIN as i32, i8::MAX as i32);
+                    self.cur_add -= add;
+                    let add = add as i8 as u8;
+                    if let Some(BfOp::Clr) = result.last() {
+                        // adding right after clearing is just setting
+                        *result.last_mut().unwrap() = BfOp::Set(add);
+                    } else if add == 1 {
+                        result.push(BfOp::Inc);
+                    } else if add == 255 {
+                        result.push(BfOp::Dec);
+                    } else {
+                        result.push(BfOp::Add(add));
+                    }
+                }
             }
 
             fn flush_all(&mut self, result: &mut Vec<BfOp>) {
@@ -224,43 +311,52 @@ pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
             match op {
                 BfOp::Left => {
                     buffer.flush_add(&mut result);
-                    buffer.cur_shift -= 1;
-                    cur_cell_is_empty = false;
+                    buffer.add_shift(-1, &mut result);
+                    empty.pos -= 1;
                 }
                 BfOp::Right => {
                     buffer.flush_add(&mut result);
-                    buffer.cur_shift += 1;
-                    cur_cell_is_empty = false;
+                    buffer.add_shift(1, &mut result);
+                    empty.pos += 1;
                 }
                 BfOp::Inc => {
                     buffer.flush_shift(&mut result);
-                    buffer.cur_add = buffer.cur_add.wrapping_add(1);
-                    cur_cell_is_empty = false;
+                    buffer.cur_add += 1;
+                    empty.set(0, false);
                 }
                 BfOp::Dec => {
                     buffer.flush_shift(&mut result);
-                    buffer.cur_add = buffer.cur_add.wrapping_sub(1);
-                    cur_cell_is_empty = false;
+                    buffer.cur_add -= 1;
+                    empty.set(0, false);
                 }
                 BfOp::Shift(shift) => {
                     buffer.flush_add(&mut result);
-                    buffer.cur_shift += *shift;
-                    cur_cell_is_empty = false;
+                    buffer.add_shift(*shift, &mut result);
+                    empty.pos += *shift as i32;
                 }
                 BfOp::Add(val) => {
                     buffer.flush_shift(&mut result);
-                    buffer.cur_add = buffer.cur_add.wrapping_add(*val);
-                    cur_cell_is_empty = false;
+                    buffer.cur_add += *val as i8 as i32;
+                    empty.set(0, false);
                 }
                 BfOp::Loop(ops) => {
-                    if cur_cell_is_empty {
+                    // a loop on a cell known to be zero never runs, whatever its body is.
+                    // Otherwise even an empty or no-op body has to stay: `[]` on a nonzero
+                    // cell spins forever, and removing it would change what the program does
+                    if empty.cur() {
                         if log_useless_ops {
                             crate::console_log!("useless loop: {:?}", ops);
                         }
                     } else {
                         buffer.flush_all(&mut result);
                         let mut created_output = false;
-                        if let Some(shift_adds) = get_loop_as_shiftadds(ops) {
+                        if let [BfOp::Right] = ops.as_slice() {
+                            result.push(BfOp::ScanRight);
+                            created_output = true;
+                        } else if let [BfOp::Left] = ops.as_slice() {
+                            result.push(BfOp::ScanLeft);
+                            created_output = true;
+                        } else if let Some(shift_adds) = get_loop_as_shiftadds(ops) {
                             if let Some(255) = shift_adds.get(&0) {
                                 if shift_adds.len() == 1 {
                                     result.push(BfOp::Clr);
@@ -268,11 +364,15 @@ pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
                                 } else if shift_adds.len() == 2 {
                                     for (&shift, &add) in &shift_adds {
                                         if shift != 0 {
+                                            assert!(!created_output);
                                             if add == 1 {
-                                                assert!(!created_output);
                                                 result.push(BfOp::MoveAdd(shift));
-                                                created_output = true;
+                                            } else if add == 255 {
+                                                result.push(BfOp::MoveSub(shift));
+                                            } else {
+                                                result.push(BfOp::MoveMul(shift, add));
                                             }
+                                            created_output = true;
                                         }
                                     }
                                 } else if shift_adds.len() == 3 {
@@ -304,7 +404,7 @@ pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
                                             shift_adds_vec.push(ShiftAdd { shift, add });
                                         }
                                     }
-                                    shift_adds_vec.sort_by(|a, b| a.shift.cmp(&b.shift));
+                                    shift_adds_vec.sort_by_key(|a| a.shift);
                                     result.push(BfOp::MoveAddMul(shift_adds_vec));
                                     created_output = true;
                                 }
@@ -313,56 +413,47 @@ pub fn get_optimized_bf_ops(ops: &Vec<BfOp>) -> Vec<BfOp> {
                         if !created_output {
                             result.push(BfOp::Loop(get_optimized_bf_ops_rec(ops, false)));
                         }
-                    }
-                    cur_cell_is_empty = true;
-                }
-                BfOp::Clr => {
-                    if !cur_cell_is_empty {
-                        buffer.flush_all(&mut result);
-                        result.push(BfOp::Clr);
-                    }
-                    cur_cell_is_empty = true;
-                }
-                BfOp::MoveAdd(s) => {
-                    if cur_cell_is_empty {
-                        if log_useless_ops {
-                            crate::console_log!("useless MoveAdd: {:?}", s);
+                        match move_op_targets(result.last().unwrap()) {
+                            Some(targets) => {
+                                for target in targets {
+                                    empty.set(target, false);
+                                }
+                            }
+                            None => empty.forget_all(),
                         }
                     }
-                    if !cur_cell_is_empty {
-                        buffer.flush_all(&mut result);
-                        result.push(BfOp::MoveAdd(*s));
-                    }
-                    cur_cell_is_empty = true;
+                    empty.set(0, true);
                 }
-                BfOp::MoveAdd2(s1, s2) => {
-                    if cur_cell_is_empty {
+                BfOp::Clr
+                | BfOp::MoveAdd(_)
+                | BfOp::MoveSub(_)
+                | BfOp::MoveAdd2(..)
+                | BfOp::MoveMul(..)
+                | BfOp::MoveAddMul(_) => {
+                    // on a cell that is already zero these don't do anything
+                    if empty.cur() {
                         if log_useless_ops {
-                            crate::console_log!("useless MoveAdd2: {:?} {:?}", s1, s2);
+                            crate::console_log!("useless op: {:?}", op);
                         }
-                    }
-                    if !cur_cell_is_empty {
+                    } else {
                         buffer.flush_all(&mut result);
-                        result.push(BfOp::MoveAdd2(*s1, *s2));
-                    }
-                    cur_cell_is_empty = true;
-                }
-                BfOp::MoveAddMul(v) => {
-                    if cur_cell_is_empty {
-                        if log_useless_ops {
-                            crate::console_log!("useless MoveAddMul: {:?}", v);
+                        result.push(op.clone());
+                        for target in move_op_targets(op).unwrap() {
+                            empty.set(target, false);
                         }
                     }
-                    if !cur_cell_is_empty {
-                        buffer.flush_all(&mut result);
-                        result.push(BfOp::MoveAddMul(v.clone()));
-                    }
-                    cur_cell_is_empty = true;
+                    empty.set(0, true);
+                }
+                BfOp::ScanRight | BfOp::ScanLeft => {
+                    buffer.flush_all(&mut result);
+                    result.push(op.clone());
+                    empty.forget_all();
+                    empty.set(0, true);
                 }
                 other => {
                     buffer.flush_all(&mut result);
                     result.push(other.clone());
-                    cur_cell_is_empty = false;
+                    empty.set(0, false);
                 }
             }
         }
@@ -378,24 +469,195 @@ pub enum RunOpError {
     ReaderErr(std::io::Error),
     WriterErr(std::io::Error),
     Crashed,
+    StepLimitExceeded,
+    TimedOut,
     Other(String),
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CellWidth {
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl CellWidth {
+    fn mask(&self) -> u32 {
+        match self {
+            CellWidth::Bits8 => 0xFF,
+            CellWidth::Bits16 => 0xFFFF,
+            CellWidth::Bits32 => 0xFFFFFFFF,
+        }
+    }
+}
+
+/// The payloads of `Add`, `Set` and the move ops are signed bytes. The optimizer splits
+/// longer runs into several ops, so they also give the same results on wider cells.
+fn sign_extend(x: u8) -> u32 {
+    x as i8 as i32 as u32
+}
+
+/// Reads one byte of input, ignoring \r
+fn read_char_in(reader: &mut impl Read) -> Result<Option<u8>, RunOpError> {
+    let mut buf: [u8; 1] = [0; 1];
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {
+                let c = buf[0];
+                if c != 13 {
+                    return Ok(Some(c));
+                }
+            }
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(None);
+                }
+                _ => {
+                    return Err(RunOpError::ReaderErr(e));
+                }
+            },
+        }
+    }
+}
+
+/// What `In` does to the current cell once the input is exhausted
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EofBehavior {
+    Zero,
+    NegativeOne,
+    Unchanged,
+}
+
+/// A copy of the tape of a BfState, see `BfState::snapshot`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BfSnapshot {
+    pub cells: Vec<u32>,
+    pub cell_ptr: usize,
+}
+
+/// Optional extras for running ops, see `BfState::run_ops`
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// Used to print and check the tracks on debug ops
+    pub cpu_config: Option<&'a CpuConfig>,
+    /// Collects how often every loop ran
+    pub loop_count: Option<&'a mut LoopCount>,
+    /// Fail with `RunOpError::StepLimitExceeded` after this many steps
+    pub max_steps: Option<u64>,
+    /// Called on every `Breakpoint` op instead of printing the state
+    pub on_breakpoint: Option<&'a mut dyn FnMut(&BfState)>,
+}
+
 pub struct BfState {
-    cells: Vec<u8>,
+    cells: Vec<u32>,
     cell_ptr: usize,
+    max_cell_ptr: usize,
+    cell_width: CellWidth,
+    eof_behavior: EofBehavior,
+    allow_negative_tape: bool,
+    steps_executed: u64,
+    deadline: Option<Instant>,
 }
 
+/// How many steps to run between checking the deadline, reading the clock is slow
+const DEADLINE_CHECK_INTERVAL: u64 = 1 << 16;
+
 impl BfState {
     pub fn new() -> BfState {
+        BfState::with_cell_width(CellWidth::Bits8)
+    }
+
+    pub fn with_cell_width(cell_width: CellWidth) -> BfState {
         BfState {
             cells: vec![0; 1],
             cell_ptr: 0,
+            max_cell_ptr: 0,
+            cell_width,
+            eof_behavior: EofBehavior::Zero,
+            allow_negative_tape: false,
+            steps_executed: 0,
+            deadline: None,
+        }
+    }
+
+    pub fn with_tape(initial: Vec<u8>) -> BfState {
+        BfState::with_tape_and_ptr(initial, 0)
+    }
+
+    pub fn with_tape_and_ptr(initial: Vec<u8>, ptr: usize) -> BfState {
+        let mut state = BfState::new();
+        state.cells = initial.into_iter().map(|c| c as u32).collect();
+        if state.cells.len() <= ptr {
+            state.cells.resize(ptr + 1, 0);
         }
+        state.cell_ptr = ptr;
+        state.max_cell_ptr = ptr;
+        state
+    }
+
+    /// When set, moving left of the first cell grows the tape leftward instead of
+    /// failing with PtrOutOfBounds. Cell indices then no longer line up with the
+    /// tracks of a CpuConfig, so only use this for plain BF programs.
+    pub fn set_allow_negative_tape(&mut self, allow_negative_tape: bool) {
+        self.allow_negative_tape = allow_negative_tape;
+    }
+
+    fn grow_left(&mut self, min_growth: usize) {
+        let growth = min_growth.max(self.cells.len());
+        self.cells.splice(0..0, std::iter::repeat_n(0, growth));
+        self.cell_ptr += growth;
+        self.max_cell_ptr += growth;
+    }
+
+    /// Number of cells the tape has grown to so far
+    pub fn tape_len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Highest cell index the pointer has reached so far, a high-water mark for the tape used
+    pub fn max_cell_ptr(&self) -> usize {
+        self.max_cell_ptr
+    }
+
+    pub fn snapshot(&self) -> BfSnapshot {
+        BfSnapshot {
+            cells: self.cells.clone(),
+            cell_ptr: self.cell_ptr,
+        }
+    }
+
+    pub fn restore(&mut self, snap: &BfSnapshot) {
+        self.cells = snap.cells.clone();
+        self.cell_ptr = snap.cell_ptr;
+        self.max_cell_ptr = self.max_cell_ptr.max(self.cell_ptr);
+    }
+
+    pub fn set_eof_behavior(&mut self, eof_behavior: EofBehavior) {
+        self.eof_behavior = eof_behavior;
+    }
+
+    /// Makes running fail with `RunOpError::TimedOut` once `deadline` has passed
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Number of (optimized) ops run by the last call to `run_ops` and friends
+    pub fn steps_executed(&self) -> u64 {
+        self.steps_executed
+    }
+
+    fn wrap(&self, val: u32) -> u32 {
+        val & self.cell_width.mask()
     }
 
     fn get_valid_ptr(&mut self, shift: i16) -> Result<usize, RunOpError> {
-        let new_ptr = self.cell_ptr as isize + shift as isize;
+        let mut new_ptr = self.cell_ptr as isize + shift as isize;
+        if new_ptr < 0 && self.allow_negative_tape {
+            let old_ptr = self.cell_ptr;
+            self.grow_left((-new_ptr) as usize);
+            new_ptr += (self.cell_ptr - old_ptr) as isize;
+        }
         if new_ptr < 0 {
             Err(RunOpError::PtrOutOfBounds)
         } else {
@@ -407,117 +669,202 @@ impl BfState {
         }
     }
 
+    fn move_left(&mut self) -> Result<(), RunOpError> {
+        if self.cell_ptr == 0 && self.allow_negative_tape {
+            self.grow_left(1);
+        }
+        if self.cell_ptr == 0 {
+            Err(RunOpError::PtrOutOfBounds)
+        } else {
+            self.cell_ptr -= 1;
+            Ok(())
+        }
+    }
+
+    fn move_right(&mut self) {
+        self.cell_ptr += 1;
+        if self.cell_ptr >= self.cells.len() {
+            self.cells.push(0);
+        }
+        self.max_cell_ptr = self.max_cell_ptr.max(self.cell_ptr);
+    }
+
+    /// Every loop iteration also counts as a step, so that `[]` can't spin forever.
+    fn count_step(&mut self, max_steps: Option<u64>) -> Result<(), RunOpError> {
+        self.steps_executed += 1;
+        match max_steps {
+            Some(max_steps) if self.steps_executed > max_steps => {
+                return Err(RunOpError::StepLimitExceeded);
+            }
+            _ => {}
+        }
+        match self.deadline {
+            Some(deadline)
+                if self.steps_executed.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+                    && Instant::now() >= deadline =>
+            {
+                Err(RunOpError::TimedOut)
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn run_op_f(
         &mut self,
         op: &BfOp,
-        get_char_in: &mut impl FnMut() -> Result<u8, RunOpError>,
+        get_char_in: &mut impl FnMut() -> Result<Option<u8>, RunOpError>,
         write_char_out: &mut impl FnMut(u8) -> Result<(), RunOpError>,
-        cpu_config: Option<&CpuConfig>,
-        mut loop_count: Option<&mut LoopCount>,
+        options: &mut RunOptions<'_>,
     ) -> Result<(), RunOpError> {
-        if let Some(loop_count) = &mut loop_count {
-            match op {
-                BfOp::Comment(_) => {}
-                BfOp::Breakpoint => {}
-                BfOp::DebugMessage(_) => {}
-                BfOp::CheckScratchIsEmptyFromHere(_) => {}
-                BfOp::PrintRegisters => {}
-                _ => {
+        let cpu_config = options.cpu_config;
+        let max_steps = options.max_steps;
+        match op {
+            BfOp::Comment(_) => {}
+            BfOp::Breakpoint => {}
+            BfOp::DebugMessage(_) => {}
+            BfOp::CheckScratchIsEmptyFromHere(_) => {}
+            BfOp::PrintRegisters => {}
+            _ => {
+                if let Some(loop_count) = &mut options.loop_count {
                     loop_count.self_instrs_executed += 1;
                     loop_count.tot_instrs_executed += 1;
                 }
+                self.count_step(max_steps)?;
             }
         }
         match op {
             BfOp::Left => {
-                if self.cell_ptr == 0 {
-                    return Err(RunOpError::PtrOutOfBounds);
-                } else {
-                    self.cell_ptr -= 1;
-                }
+                self.move_left()?;
             }
             BfOp::Right => {
-                self.cell_ptr += 1;
-                if self.cell_ptr >= self.cells.len() {
-                    self.cells.push(0);
-                }
+                self.move_right();
             }
             BfOp::Inc => {
-                self.cells[self.cell_ptr] = self.cells[self.cell_ptr].wrapping_add(1);
+                self.cells[self.cell_ptr] = self.wrap(self.cells[self.cell_ptr].wrapping_add(1));
             }
             BfOp::Dec => {
-                self.cells[self.cell_ptr] = self.cells[self.cell_ptr].wrapping_sub(1);
-            }
-            BfOp::In => {
-                self.cells[self.cell_ptr] = get_char_in()?;
+                self.cells[self.cell_ptr] = self.wrap(self.cells[self.cell_ptr].wrapping_sub(1));
             }
+            BfOp::In => match get_char_in()? {
+                Some(c) => {
+                    self.cells[self.cell_ptr] = c as u32;
+                }
+                None => match self.eof_behavior {
+                    EofBehavior::Zero => {
+                        self.cells[self.cell_ptr] = 0;
+                    }
+                    EofBehavior::NegativeOne => {
+                        self.cells[self.cell_ptr] = self.cell_width.mask();
+                    }
+                    EofBehavior::Unchanged => {}
+                },
+            },
             BfOp::Out => {
-                let byte = self.cells[self.cell_ptr];
+                let byte = self.cells[self.cell_ptr] as u8;
                 write_char_out(byte)?;
             }
             BfOp::Loop(ops) => {
-                if let Some(loop_count) = loop_count {
+                if let Some(loop_count) = options.loop_count.as_deref_mut() {
+                    let on_breakpoint = &mut options.on_breakpoint;
                     loop_count.tot_instrs_executed += loop_count.goto_next_loop(|loop_count| {
                         let at_begin = loop_count.tot_instrs_executed;
                         while self.cells[self.cell_ptr] != 0 {
+                            self.count_step(max_steps)?;
                             loop_count.num_times_loop_run += 1;
                             loop_count.next_loop = 0;
-                            self.run_ops_f(
-                                ops,
-                                &mut *get_char_in,
-                                &mut *write_char_out,
+                            let mut options = RunOptions {
                                 cpu_config,
-                                Some(&mut *loop_count),
-                            )?;
+                                loop_count: Some(&mut *loop_count),
+                                max_steps,
+                                on_breakpoint: on_breakpoint
+                                    .as_deref_mut()
+                                    .map(|f| f as &mut dyn FnMut(&BfState)),
+                            };
+                            for op in ops {
+                                self.run_op_f(
+                                    op,
+                                    &mut *get_char_in,
+                                    &mut *write_char_out,
+                                    &mut options,
+                                )?;
+                            }
                         }
                         assert!(loop_count.tot_instrs_executed >= at_begin);
                         Ok(loop_count.tot_instrs_executed - at_begin)
                     })?;
                 } else {
                     while self.cells[self.cell_ptr] != 0 {
-                        self.run_ops_f(
-                            ops,
-                            &mut *get_char_in,
-                            &mut *write_char_out,
-                            cpu_config,
-                            None,
-                        )?;
+                        self.count_step(max_steps)?;
+                        for op in ops {
+                            self.run_op_f(op, &mut *get_char_in, &mut *write_char_out, options)?;
+                        }
                     }
                 }
             }
             BfOp::Clr => {
                 self.cells[self.cell_ptr] = 0;
             }
+            BfOp::Set(val) => {
+                self.cells[self.cell_ptr] = self.wrap(sign_extend(*val));
+            }
             BfOp::Shift(shift) => {
                 self.cell_ptr = self.get_valid_ptr(*shift)?;
+                self.max_cell_ptr = self.max_cell_ptr.max(self.cell_ptr);
             }
             BfOp::Add(val) => {
-                self.cells[self.cell_ptr] = self.cells[self.cell_ptr].wrapping_add(*val);
+                self.cells[self.cell_ptr] =
+                    self.wrap(self.cells[self.cell_ptr].wrapping_add(sign_extend(*val)));
             }
             BfOp::MoveAdd(shift) => {
                 let other_ptr = self.get_valid_ptr(*shift)?;
                 self.cells[other_ptr] =
-                    self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]);
+                    self.wrap(self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]));
+                self.cells[self.cell_ptr] = 0;
+            }
+            BfOp::MoveSub(shift) => {
+                let other_ptr = self.get_valid_ptr(*shift)?;
+                self.cells[other_ptr] =
+                    self.wrap(self.cells[other_ptr].wrapping_sub(self.cells[self.cell_ptr]));
                 self.cells[self.cell_ptr] = 0;
             }
             BfOp::MoveAdd2(shift1, shift2) => {
                 let other_ptr = self.get_valid_ptr(*shift1)?;
                 self.cells[other_ptr] =
-                    self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]);
+                    self.wrap(self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]));
                 let other_ptr = self.get_valid_ptr(*shift2)?;
                 self.cells[other_ptr] =
-                    self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]);
+                    self.wrap(self.cells[other_ptr].wrapping_add(self.cells[self.cell_ptr]));
+                self.cells[self.cell_ptr] = 0;
+            }
+            BfOp::MoveMul(shift, factor) => {
+                let other_ptr = self.get_valid_ptr(*shift)?;
+                self.cells[other_ptr] = self
+                    .wrap(self.cells[other_ptr].wrapping_add(
+                        self.cells[self.cell_ptr].wrapping_mul(sign_extend(*factor)),
+                    ));
                 self.cells[self.cell_ptr] = 0;
             }
             BfOp::MoveAddMul(vec) => {
                 let base_val = self.cells[self.cell_ptr];
                 for shift_add in vec {
                     let other_ptr = self.get_valid_ptr(shift_add.shift)?;
-                    self.cells[other_ptr] =
-                        self.cells[other_ptr].wrapping_add(base_val.wrapping_mul(shift_add.add));
+                    self.cells[other_ptr] = self.wrap(
+                        self.cells[other_ptr]
+                            .wrapping_add(base_val.wrapping_mul(sign_extend(shift_add.add))),
+                    );
                 }
                 self.cells[self.cell_ptr] = 0;
             }
+            BfOp::ScanRight => {
+                while self.cells[self.cell_ptr] != 0 {
+                    self.move_right();
+                }
+            }
+            BfOp::ScanLeft => {
+                while self.cells[self.cell_ptr] != 0 {
+                    self.move_left()?;
+                }
+            }
             BfOp::Comment(_) => {}
             BfOp::DebugMessage(msg) => {
                 println!("{}", msg);
@@ -527,7 +874,9 @@ impl BfState {
                 return Err(RunOpError::Crashed);
             }
             BfOp::Breakpoint => {
-                if let Some(cfg) = cpu_config {
+                if let Some(on_breakpoint) = &mut options.on_breakpoint {
+                    on_breakpoint(self);
+                } else if let Some(cfg) = cpu_config {
                     self.print_state(cfg);
                 }
             }
@@ -562,33 +911,11 @@ impl BfState {
         ops: &[BfOp],
         reader: &mut impl Read,
         writer: &mut impl Write,
-        cpu_config: Option<&CpuConfig>,
-        loop_count: Option<&mut LoopCount>,
+        options: RunOptions<'_>,
     ) -> Result<(), RunOpError> {
         self.run_ops_f(
             ops,
-            &mut move || {
-                let mut buf: [u8; 1] = [0; 1];
-                loop {
-                    match reader.read_exact(&mut buf) {
-                        Ok(()) => {
-                            // simply ignore \r
-                            let c = buf[0];
-                            if c != 13 {
-                                return Ok(c);
-                            }
-                        }
-                        Err(e) => match e.kind() {
-                            std::io::ErrorKind::UnexpectedEof => {
-                                return Ok(0);
-                            }
-                            _ => {
-                                return Err(RunOpError::ReaderErr(e));
-                            }
-                        },
-                    }
-                }
-            },
+            &mut move || read_char_in(reader),
             &mut move |byte| {
                 let buf: [u8; 1] = [byte];
                 match writer.write_all(&buf) {
@@ -599,32 +926,42 @@ impl BfState {
                 }
                 match writer.flush() {
                     Ok(()) => Ok(()),
-                    Err(e) => {
-                        return Err(RunOpError::WriterErr(e));
-                    }
+                    Err(e) => Err(RunOpError::WriterErr(e)),
                 }
             },
-            cpu_config,
-            loop_count,
+            options,
+        )
+    }
+
+    /// Like `run_ops`, but hands every output byte to `on_output` as soon as it is produced
+    pub fn run_ops_streaming(
+        &mut self,
+        ops: &[BfOp],
+        reader: &mut impl Read,
+        on_output: &mut impl FnMut(u8),
+        options: RunOptions<'_>,
+    ) -> Result<(), RunOpError> {
+        self.run_ops_f(
+            ops,
+            &mut move || read_char_in(reader),
+            &mut move |byte| {
+                on_output(byte);
+                Ok(())
+            },
+            options,
         )
     }
 
     pub fn run_ops_f(
         &mut self,
         ops: &[BfOp],
-        get_char_in: &mut impl FnMut() -> Result<u8, RunOpError>,
+        get_char_in: &mut impl FnMut() -> Result<Option<u8>, RunOpError>,
         write_char_out: &mut impl FnMut(u8) -> Result<(), RunOpError>,
-        cpu_config: Option<&CpuConfig>,
-        mut loop_count: Option<&mut LoopCount>,
+        mut options: RunOptions<'_>,
     ) -> Result<(), RunOpError> {
+        self.steps_executed = 0;
         for op in ops {
-            self.run_op_f(
-                op,
-                &mut *get_char_in,
-                &mut *write_char_out,
-                cpu_config,
-                loop_count.as_deref_mut(),
-            )?;
+            self.run_op_f(op, &mut *get_char_in, &mut *write_char_out, &mut options)?;
         }
         Ok(())
     }
@@ -636,7 +973,7 @@ impl BfState {
     }
 
     pub fn print_state(&self, cpu: &CpuConfig) {
-        let num_digits = |x: u8| x.to_string().chars().count();
+        let num_digits = |x: u32| x.to_string().chars().count();
         println!("CPU STATE:");
         let tracks = cpu.get_tracks();
         let num_tracks = tracks.len();
@@ -660,7 +997,7 @@ impl BfState {
                         println!("{}^", " ".repeat(print_caret_at));
                     }
                 }
-                TrackKind::Scratch(track) => {
+                TrackKind::Scratch(track, _) => {
                     let mut i = track.track.track_num as usize;
                     let mut caret_i = 0;
                     let mut print_caret_at = None;
@@ -701,52 +1038,89 @@ impl BfState {
         }
     }
 
+    /// Splits the tape into the cells of each track, ordered by track number.
+    /// Compiled code only stores bytes, so wider cells are cut to their low byte.
+    pub fn dump_tracks(&self, cpu: &CpuConfig) -> Vec<(TrackId, Vec<u8>)> {
+        let tracks = cpu.get_tracks();
+        let num_tracks = tracks.len();
+        let mut result: Vec<_> = tracks
+            .iter()
+            .map(|(id, track)| {
+                let cells = self
+                    .cells
+                    .iter()
+                    .skip(track.track_num() as usize)
+                    .step_by(num_tracks)
+                    .map(|&cell| cell as u8)
+                    .collect();
+                (track.track_num(), id.clone(), cells)
+            })
+            .collect();
+        result.sort_by_key(|(track_num, _, _)| *track_num);
+        result
+            .into_iter()
+            .map(|(_, id, cells)| (id, cells))
+            .collect()
+    }
+
     pub fn print_registers(&self, cpu: &CpuConfig) {
+        let tracks = cpu.get_tracks();
+        let cur_track_num = self.cell_ptr % tracks.len();
+        for (id, track) in tracks {
+            if cur_track_num as isize != track.track_num() {
+                continue;
+            }
+            let names = match track {
+                TrackKind::MultipleRegisters(_, register_map, binregister_map) => register_map
+                    .keys()
+                    .chain(binregister_map.keys())
+                    .collect::<Vec<_>>(),
+                TrackKind::Scratch(_, register_map) => register_map.keys().collect(),
+                _ => continue,
+            };
+            for name in names {
+                println!(
+                    "{}: {}",
+                    name,
+                    self.read_named_register(cpu, id.clone(), name).unwrap()
+                );
+            }
+        }
+    }
+
+    /// Reads the value of a named register in the current frame, most significant cell first
+    pub fn read_named_register(&self, cpu: &CpuConfig, track: TrackId, name: &str) -> Option<u64> {
         let tracks = cpu.get_tracks();
         let num_tracks = tracks.len();
-        let cur_track_num = self.cell_ptr % num_tracks;
-        let offset = self.cell_ptr / num_tracks;
-        for (_id, track) in tracks {
-            match track {
-                TrackKind::MultipleRegisters(track_num, register_map, binregister_map) => {
-                    if cur_track_num as isize != *track_num {
-                        continue;
-                    }
-                    for (name, register) in register_map {
-                        let mut val_str = String::new();
-                        let mut val = 0u32;
-                        for i in 0..register.size {
-                            let cell_val = self.cells[cur_track_num
-                                + (offset + i as usize + register.offset as usize) * num_tracks];
-                            val *= 256;
-                            val += cell_val as u32;
-                            val_str += &format!("{}, ", cell_val);
-                        }
-                        println!("{}: {}", name, val);
-                    }
-                    for (name, register) in binregister_map {
-                        let mut val_str = String::new();
-                        let mut val = 0u32;
-                        for i in 0..register.size {
-                            let cell_val = self.cells[cur_track_num
-                                + (offset + i as usize + register.offset as usize) * num_tracks];
-                            val *= 2;
-                            val += cell_val as u32;
-                            val_str += &format!("{}, ", cell_val);
-                        }
-                        println!("{}: {}", name, val);
-                    }
+        let frame = self.cell_ptr / num_tracks;
+        let (track_num, offset, size, base) = match tracks.get(&track)? {
+            TrackKind::MultipleRegisters(track_num, register_map, binregister_map) => {
+                if let Some(register) = register_map.get(name) {
+                    (*track_num, register.offset, register.size, 256)
+                } else {
+                    let register = binregister_map.get(name)?;
+                    (*track_num, register.offset, register.size, 2)
                 }
-                _ => {}
             }
+            TrackKind::Scratch(scratch, register_map) => {
+                let register = register_map.get(name)?;
+                (scratch.track.track_num, register.offset, register.size, 256)
+            }
+            _ => return None,
+        };
+        let mut val = 0u64;
+        for i in 0..size {
+            let index = track_num as usize + (frame + (offset + i) as usize) * num_tracks;
+            val = val * base + *self.cells.get(index).unwrap_or(&0) as u64;
         }
+        Some(val)
     }
 
     pub fn check_scratch_is_empty(&self, cpu: &CpuConfig) {
         let tracks = cpu.get_tracks();
         let num_tracks = tracks.len();
         for (id, track) in tracks {
-            if let TrackKind::Scratch(track) = track {
+            if let TrackKind::Scratch(track, _) = track {
                 let mut i = track.track.track_num as usize;
                 while i < self.cells.len() {
                     if self.cells[i] != 0 {
@@ -762,12 +1136,66 @@ impl BfState {
     }
 }
 
+/// Runs ops on a fresh tape and returns everything they wrote, byte for byte
+pub fn run_and_capture(ops: &[BfOp], input: &[u8]) -> Result<Vec<u8>, RunOpError> {
+    let mut r = input;
+    let mut w = Vec::new();
+    BfState::new().run_ops(ops, &mut r, &mut w, RunOptions::default())?;
+    Ok(w)
+}
+
+/// Counts each of the 8 BF instructions, expanding optimized ops back into the primitives they stand for
+pub fn bf_instruction_histogram(ops: &[BfOp]) -> HashMap<char, usize> {
+    let mut result = HashMap::new();
+    for c in ops2str(&ops.to_vec(), BfFormatOptions::clean()).chars() {
+        if "+-<>[].,".contains(c) {
+            *result.entry(c).or_insert(0) += 1;
+        }
+    }
+    result
+}
+
+/// Each comment in ops, with the character offset in the clean output where it would be
+pub fn bf_comment_offsets(ops: &[BfOp]) -> Vec<(usize, String)> {
+    fn rec(ops: &[BfOp], offset: &mut usize, result: &mut Vec<(usize, String)>) {
+        for op in ops {
+            match op {
+                BfOp::Comment(msg) => result.push((*offset, msg.clone())),
+                BfOp::Loop(inner) => {
+                    *offset += 1;
+                    rec(inner, offset, result);
+                    *offset += 1;
+                }
+                op => *offset += ops2str(&vec![op.clone()], BfFormatOptions::clean()).len(),
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    rec(ops, &mut 0, &mut result);
+    result
+}
+
+/// One `offset: comment` line per comment, see `bf_comment_offsets`
+pub fn bf_comment_listing(ops: &[BfOp]) -> String {
+    let mut result = String::new();
+    for (offset, msg) in bf_comment_offsets(ops) {
+        result += &format!("{:8}: {}\n", offset, msg);
+    }
+    result
+}
+
+#[derive(Clone, Copy)]
 pub struct BfFormatOptions<'a> {
     pub print_optimizations: bool,
     pub clean_output: bool,
     pub indented: bool,
     pub only_loops_and_comments: bool,
     pub loop_count: Option<&'a LoopCount>,
+    /// Break lines longer than this many characters, repeating their indentation
+    pub wrap_columns: Option<usize>,
+    /// Only print the comments this returns true for
+    pub comment_filter: Option<fn(&str) -> bool>,
 }
 
 impl<'a> BfFormatOptions<'a> {
@@ -778,6 +1206,8 @@ impl<'a> BfFormatOptions<'a> {
             indented: false,
             only_loops_and_comments: false,
             loop_count: None,
+            wrap_columns: None,
+            comment_filter: None,
         }
     }
 
@@ -788,6 +1218,8 @@ impl<'a> BfFormatOptions<'a> {
             indented: false,
             only_loops_and_comments: false,
             loop_count: None,
+            wrap_columns: None,
+            comment_filter: None,
         }
     }
 
@@ -798,26 +1230,32 @@ impl<'a> BfFormatOptions<'a> {
             indented: false,
             only_loops_and_comments: false,
             loop_count: None,
+            wrap_columns: None,
+            comment_filter: None,
         }
     }
 
-    pub fn perf_clean(loop_count: &'a LoopCount) -> BfFormatOptions {
+    pub fn perf_clean(loop_count: &'a LoopCount) -> BfFormatOptions<'a> {
         BfFormatOptions {
             print_optimizations: false,
             clean_output: false,
             indented: true,
             only_loops_and_comments: true,
             loop_count: Some(loop_count),
+            wrap_columns: None,
+            comment_filter: None,
         }
     }
 
-    pub fn perf_verbose(loop_count: &'a LoopCount) -> BfFormatOptions {
+    pub fn perf_verbose(loop_count: &'a LoopCount) -> BfFormatOptions<'a> {
         BfFormatOptions {
             print_optimizations: true,
             clean_output: false,
             indented: true,
             only_loops_and_comments: false,
             loop_count: Some(loop_count),
+            wrap_columns: None,
+            comment_filter: None,
         }
     }
 
@@ -828,12 +1266,13 @@ impl<'a> BfFormatOptions<'a> {
 
 pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
     fn write_add(result: &mut String, val: u8) {
-        if val <= 128 {
+        let val = val as i8;
+        if val >= 0 {
             for _ in 0..val {
                 *result += "+";
             }
         } else {
-            for _ in val..=255 {
+            for _ in val..0 {
                 *result += "-";
             }
         }
@@ -893,15 +1332,12 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                 BfOp::Loop(ops) => {
                     *result += "[";
                     if let Some(loop_count) = &mut loop_count {
-                        *result += &format!(
-                            "{}",
-                            loop_count
-                                .0
-                                .children_counts
-                                .get(loop_count.1)
-                                .map(|l| l.tot_instrs_executed.to_formatted_string(&Locale::en))
-                                .unwrap_or("0".to_string())
-                        );
+                        *result += &loop_count
+                            .0
+                            .children_counts
+                            .get(loop_count.1)
+                            .map(|l| l.tot_instrs_executed.to_formatted_string(&Locale::en))
+                            .unwrap_or("0".to_string());
                     }
                     if let Some(cur_indent_level) = cur_indent_level {
                         *result += "\n";
@@ -910,11 +1346,10 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                         }
                     }
                     let rec_loop_count = if let Some((loop_count, i)) = &loop_count {
-                        if let Some(child_loop_count) = loop_count.children_counts.get(*i) {
-                            Some((child_loop_count, 0))
-                        } else {
-                            None
-                        }
+                        loop_count
+                            .children_counts
+                            .get(*i)
+                            .map(|child_loop_count| (child_loop_count, 0))
                     } else {
                         None
                     };
@@ -945,6 +1380,16 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                         }
                     }
                 }
+                BfOp::Set(val) => {
+                    if !format_opts.only_loops_and_comments {
+                        if format_opts.should_print_optimizations() {
+                            *result += &format!("Set({})", val);
+                        } else {
+                            *result += "[-]";
+                            write_add(result, *val);
+                        }
+                    }
+                }
                 BfOp::Shift(shift) => {
                     if !format_opts.only_loops_and_comments {
                         if format_opts.should_print_optimizations() {
@@ -976,6 +1421,19 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                         }
                     }
                 }
+                BfOp::MoveSub(shift) => {
+                    if !format_opts.only_loops_and_comments {
+                        if format_opts.should_print_optimizations() {
+                            *result += &format!("MoveSub({})", shift);
+                        } else {
+                            *result += "[-";
+                            write_shift(result, *shift);
+                            *result += "-";
+                            write_shift(result, -*shift);
+                            *result += "]";
+                        }
+                    }
+                }
                 BfOp::MoveAdd2(shift1, shift2) => {
                     if !format_opts.only_loops_and_comments {
                         if format_opts.should_print_optimizations() {
@@ -991,6 +1449,19 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                         }
                     }
                 }
+                BfOp::MoveMul(shift, factor) => {
+                    if !format_opts.only_loops_and_comments {
+                        if format_opts.should_print_optimizations() {
+                            *result += &format!("MoveMul({}, {})", shift, factor);
+                        } else {
+                            *result += "[-";
+                            write_shift(result, *shift);
+                            write_add(result, *factor);
+                            write_shift(result, -*shift);
+                            *result += "]";
+                        }
+                    }
+                }
                 BfOp::MoveAddMul(vec) => {
                     if !format_opts.only_loops_and_comments {
                         if format_opts.should_print_optimizations() {
@@ -999,7 +1470,7 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                                 vec.iter()
                                     .map(|x| format!("({}, {})", x.shift, x.add))
                                     .fold(String::new(), |a, b| {
-                                        let comma = if &a == "" { "" } else { ", " };
+                                        let comma = if a.is_empty() { "" } else { ", " };
                                         a + comma + &b
                                     })
                             );
@@ -1016,9 +1487,29 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
                         }
                     }
                 }
+                BfOp::ScanRight => {
+                    if !format_opts.only_loops_and_comments {
+                        if format_opts.should_print_optimizations() {
+                            *result += "ScanRight";
+                        } else {
+                            *result += "[>]";
+                        }
+                    }
+                }
+                BfOp::ScanLeft => {
+                    if !format_opts.only_loops_and_comments {
+                        if format_opts.should_print_optimizations() {
+                            *result += "ScanLeft";
+                        } else {
+                            *result += "[<]";
+                        }
+                    }
+                }
                 BfOp::Comment(msg) => {
                     if format_opts.clean_output {
                         // no output
+                    } else if format_opts.comment_filter.is_some_and(|keep| !keep(msg)) {
+                        // filtered out
                     } else if format_opts.should_print_optimizations() {
                         *result += &format!("Comment({})", msg);
                     } else {
@@ -1083,9 +1574,223 @@ pub fn ops2str(ops: &Vec<BfOp>, format_opts: BfFormatOptions) -> String {
         cur_indent_level,
         format_opts.loop_count.map(|l| (l, 0)),
     );
+    if let Some(wrap_columns) = format_opts.wrap_columns {
+        result = wrap_lines(&result, wrap_columns);
+    }
+    result
+}
+
+fn wrap_lines(s: &str, wrap_columns: usize) -> String {
+    let mut result = String::new();
+    for (i, line) in s.split('\n').enumerate() {
+        if i > 0 {
+            result.push('\n');
+        }
+        let content = line.trim_start();
+        let indent = &line[..line.len() - content.len()];
+        let width = wrap_columns.saturating_sub(indent.chars().count()).max(1);
+        let chars: Vec<char> = content.chars().collect();
+        if chars.is_empty() {
+            result += line;
+        }
+        for (j, chunk) in chars.chunks(width).enumerate() {
+            if j > 0 {
+                result.push('\n');
+            }
+            result += indent;
+            result.extend(chunk);
+        }
+    }
     result
 }
 
+/// Translates a bf program into a standalone C program with a 30000-cell tape
+pub fn ops2c(ops: &[BfOp]) -> String {
+    fn c_str(s: &str) -> String {
+        let mut result = String::from("\"");
+        for b in s.bytes() {
+            match b {
+                b'"' | b'\\' => {
+                    result.push('\\');
+                    result.push(b as char);
+                }
+                0x20..=0x7e => result.push(b as char),
+                _ => result += &format!("\\{:03o}", b),
+            }
+        }
+        result.push('"');
+        result
+    }
+    fn reads_input(ops: &[BfOp]) -> bool {
+        ops.iter().any(|op| match op {
+            BfOp::In => true,
+            BfOp::Loop(ops) => reads_input(ops),
+            _ => false,
+        })
+    }
+    fn rec(ops: &[BfOp], result: &mut String, indent: usize) {
+        for op in ops {
+            let line = match op {
+                BfOp::Left => "p--;".to_owned(),
+                BfOp::Right => "p++;".to_owned(),
+                BfOp::Inc => "++*p;".to_owned(),
+                BfOp::Dec => "--*p;".to_owned(),
+                BfOp::In => "fflush(stdout);c=getchar();*p=c==EOF?0:c;".to_owned(),
+                BfOp::Out => "putchar(*p);".to_owned(),
+                BfOp::Loop(ops) => {
+                    *result += &format!("{:indent$}while(*p){{\n", "", indent = indent);
+                    rec(ops, result, indent + 4);
+                    "}".to_owned()
+                }
+                BfOp::Clr => "*p=0;".to_owned(),
+                BfOp::Set(val) => format!("*p={};", val),
+                BfOp::Shift(shift) => format!("p+={};", shift),
+                BfOp::Add(val) => format!("*p+={};", val),
+                BfOp::MoveAdd(shift) => format!("p[{}]+=*p;*p=0;", shift),
+                BfOp::MoveSub(shift) => format!("p[{}]-=*p;*p=0;", shift),
+                BfOp::MoveAdd2(shift1, shift2) => {
+                    format!("p[{}]+=*p;p[{}]+=*p;*p=0;", shift1, shift2)
+                }
+                BfOp::MoveMul(shift, factor) => format!("p[{}]+=*p*{};*p=0;", shift, factor),
+                BfOp::MoveAddMul(vec) => {
+                    let mut line = String::new();
+                    for shift_add in vec {
+                        line += &format!("p[{}]+=*p*{};", shift_add.shift, shift_add.add);
+                    }
+                    line + "*p=0;"
+                }
+                BfOp::ScanRight => "while(*p)p++;".to_owned(),
+                BfOp::ScanLeft => "while(*p)p--;".to_owned(),
+                BfOp::Comment(msg) => format!("/* {} */", msg.replace("*/", "* /")),
+                BfOp::Crash(msg) => format!("fputs({},stderr);exit(1);", c_str(msg)),
+                BfOp::DebugMessage(_)
+                | BfOp::Breakpoint
+                | BfOp::PrintRegisters
+                | BfOp::CheckScratchIsEmptyFromHere(_) => continue,
+            };
+            *result += &format!("{:indent$}{}\n", "", line, indent = indent);
+        }
+    }
+    let mut result = String::new();
+    result += "#include <stdio.h>\n";
+    result += "#include <stdlib.h>\n\n";
+    result += "unsigned char tape[30000];\n\n";
+    result += "int main(void) {\n";
+    result += "    unsigned char *p = tape;\n";
+    if reads_input(ops) {
+        result += "    int c;\n";
+    }
+    rec(ops, &mut result, 4);
+    result += "    return 0;\n";
+    result += "}\n";
+    result
+}
+
+/// Translates a bf program into a standalone Python 3 script with a 30000-cell tape
+pub fn ops2python(ops: &[BfOp]) -> String {
+    fn py_str(s: &str) -> String {
+        let mut result = String::from("\"");
+        for c in s.chars() {
+            match c {
+                '"' | '\\' => {
+                    result.push('\\');
+                    result.push(c);
+                }
+                ' '..='~' => result.push(c),
+                _ => result += &format!("\\U{:08x}", c as u32),
+            }
+        }
+        result.push('"');
+        result
+    }
+    fn rec(ops: &[BfOp], result: &mut String, indent: usize) {
+        let mut wrote_anything = false;
+        for op in ops {
+            let line = match op {
+                BfOp::Left => "p-=1".to_owned(),
+                BfOp::Right => "p+=1".to_owned(),
+                BfOp::Inc => "tape[p]=(tape[p]+1)%256".to_owned(),
+                BfOp::Dec => "tape[p]=(tape[p]-1)%256".to_owned(),
+                BfOp::In => {
+                    "sys.stdout.buffer.flush();c=sys.stdin.buffer.read(1);tape[p]=c[0] if c else 0"
+                        .to_owned()
+                }
+                BfOp::Out => "sys.stdout.buffer.write(tape[p:p+1])".to_owned(),
+                BfOp::Loop(ops) => {
+                    *result += &format!("{:indent$}while tape[p]:\n", "", indent = indent);
+                    rec(ops, result, indent + 4);
+                    wrote_anything = true;
+                    continue;
+                }
+                BfOp::Clr => "tape[p]=0".to_owned(),
+                BfOp::Set(val) => format!("tape[p]={}", val),
+                BfOp::Shift(shift) => format!("p+={}", shift),
+                BfOp::Add(val) => format!("tape[p]=(tape[p]+{})%256", val),
+                BfOp::MoveAdd(shift) => {
+                    format!("tape[p+{0}]=(tape[p+{0}]+tape[p])%256;tape[p]=0", shift)
+                }
+                BfOp::MoveSub(shift) => {
+                    format!("tape[p+{0}]=(tape[p+{0}]-tape[p])%256;tape[p]=0", shift)
+                }
+                BfOp::MoveAdd2(shift1, shift2) => format!(
+                    "tape[p+{0}]=(tape[p+{0}]+tape[p])%256;tape[p+{1}]=(tape[p+{1}]+tape[p])%256;tape[p]=0",
+                    shift1, shift2
+                ),
+                BfOp::MoveMul(shift, factor) => format!(
+                    "tape[p+{0}]=(tape[p+{0}]+tape[p]*{1})%256;tape[p]=0",
+                    shift, factor
+                ),
+                BfOp::MoveAddMul(vec) => {
+                    let mut line = String::new();
+                    for shift_add in vec {
+                        line += &format!(
+                            "tape[p+{0}]=(tape[p+{0}]+tape[p]*{1})%256;",
+                            shift_add.shift, shift_add.add
+                        );
+                    }
+                    line + "tape[p]=0"
+                }
+                BfOp::ScanRight => "while tape[p]:p+=1".to_owned(),
+                BfOp::ScanLeft => "while tape[p]:p-=1".to_owned(),
+                BfOp::Comment(msg) => {
+                    for line in msg.lines() {
+                        *result += &format!("{:indent$}# {}\n", "", line, indent = indent);
+                    }
+                    continue;
+                }
+                BfOp::Crash(msg) => format!("sys.exit({})", py_str(msg)),
+                BfOp::DebugMessage(_)
+                | BfOp::Breakpoint
+                | BfOp::PrintRegisters
+                | BfOp::CheckScratchIsEmptyFromHere(_) => continue,
+            };
+            *result += &format!("{:indent$}{}\n", "", line, indent = indent);
+            wrote_anything = true;
+        }
+        if !wrote_anything {
+            *result += &format!("{:indent$}pass\n", "", indent = indent);
+        }
+    }
+    let mut result = String::new();
+    result += "import sys\n\n";
+    result += "tape = bytearray(30000)\n";
+    result += "p = 0\n";
+    rec(ops, &mut result, 0);
+    result += "sys.stdout.buffer.flush()\n";
+    result
+}
+
+pub fn bf_ops_to_json(ops: &[BfOp]) -> String {
+    serde_json::to_string(ops).expect("bf ops always serialize")
+}
+
+pub fn bf_ops_from_json(s: &str) -> Result<Vec<BfOp>, serde_json::Error> {
+    serde_json::from_str(s)
+}
+
+/// Identifies a loop by its index among its sibling loops at every nesting level
+pub type LoopId = Vec<usize>;
+
 #[derive(Debug)]
 pub struct LoopCount {
     self_instrs_executed: u64,
@@ -1129,4 +1834,23 @@ impl LoopCount {
     pub fn get_instrs_executed(&self) -> u64 {
         self.tot_instrs_executed
     }
+
+    /// The top_n loops that executed the most instructions (including those of nested loops),
+    /// most expensive first
+    pub fn hotspots(&self, top_n: usize) -> Vec<(LoopId, u64)> {
+        fn collect(loop_count: &LoopCount, id: &mut LoopId, result: &mut Vec<(LoopId, u64)>) {
+            for (i, child) in loop_count.children_counts.iter().enumerate() {
+                id.push(i);
+                result.push((id.clone(), child.tot_instrs_executed));
+                collect(child, id, result);
+                id.pop();
+            }
+        }
+
+        let mut result = Vec::new();
+        collect(self, &mut Vec::new(), &mut result);
+        result.sort_by(|(_, a), (_, b)| b.cmp(a));
+        result.truncate(top_n);
+        result
+    }
 }
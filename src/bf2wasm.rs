@@ -19,7 +19,7 @@ fn asyncify(ops: Vec<BfOp>) -> Vec<AsyncifiedOp> {
             if !sync_ops.is_empty() {
                 result.push(AsyncifiedOp {
                     counter: 0,
-                    kind: AsyncifiedOpKind::SyncBlock(std::mem::replace(sync_ops, Vec::new())),
+                    kind: AsyncifiedOpKind::SyncBlock(std::mem::take(sync_ops)),
                 });
             }
         }
@@ -104,7 +104,27 @@ fn asyncify(ops: Vec<BfOp>) -> Vec<AsyncifiedOp> {
     async_ops
 }
 
-pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>> {
+/// The minimum tape size of generated modules, in 64KiB wasm pages
+pub const DEFAULT_TAPE_PAGES: u32 = 1;
+
+/// Emitted at the start of every loop iteration when there is a limit on them:
+/// counts down `$steps_left_global` and returns 2 from `run_bf` once it runs out.
+fn count_loop_iteration(bf_wat: &mut String, limit_loop_iterations: bool) {
+    if !limit_loop_iterations {
+        return;
+    }
+    *bf_wat += "(global.set $steps_left_global (i64.sub (global.get $steps_left_global) (i64.const 1)))\n";
+    *bf_wat += "(if (i64.eqz (global.get $steps_left_global)) (then\n";
+    *bf_wat += "(global.set $cell_ptr_global (local.get $cell_ptr))\n";
+    *bf_wat += "(global.set $async_start_block_global (i32.const 2147483647))\n";
+    *bf_wat += "(return (i32.const 2))))\n";
+}
+
+/// The generated module imports `read_input_byte`, which should return 0 if there is no input yet
+/// (the module then returns 1 from `run_bf` so it can be resumed later), or -1 at the end of the input.
+/// With `max_loop_iterations`, `run_bf` gives up and returns 2 once that many loop iterations have run,
+/// so that an infinite loop can't keep the page busy forever.
+pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool, tape_pages: u32, eof_behavior: EofBehavior, max_loop_iterations: Option<u64>) -> wat::Result<Vec<u8>> {
     /*let mut _opt_bf_ops = None;
     let bf_ops = if optimize_first {
         _opt_bf_ops = Some(get_optimized_bf_ops(bf_ops));
@@ -121,6 +141,7 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
         bf_ops: &Vec<BfOp>,
         bf_wat: &mut String,
         global_loop_counter: &mut usize,
+        limit_loop_iterations: bool,
     ) {
         let mut cur_shift = 0;
         let assure_nonnegative_offsets = |bf_wat: &mut String, cur_shift: &mut i16, added_shifts: &[i16]| {
@@ -172,7 +193,8 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                         "(br_if ${} (i32.eqz (i32.load8_u (local.get $cell_ptr))))\n",
                         cur_block_id
                     );
-                    process_sync_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter);
+                    count_loop_iteration(bf_wat, limit_loop_iterations);
+                    process_sync_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter, limit_loop_iterations);
                     *bf_wat += &format!("(br ${})\n", cur_loop_id);
                     *bf_wat += ")\n";
                     *bf_wat += ")\n";
@@ -181,6 +203,10 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                     assure_nonnegative_offsets(bf_wat, &mut cur_shift, &[0]);
                     *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
                 }
+                BfOp::Set(val) => {
+                    assure_nonnegative_offsets(bf_wat, &mut cur_shift, &[0]);
+                    *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const {}))\n", cur_shift, val);
+                }
                 BfOp::Shift(shift) => {
                     cur_shift += shift;
                 }
@@ -196,6 +222,14 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                     // set cell to 0:
                     *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
                 }
+                BfOp::MoveSub(shift) => {
+                    assert_ne!(*shift, 0);
+                    // subtract from new cell:
+                    assure_nonnegative_offsets(bf_wat, &mut cur_shift, &[0, *shift]);
+                    *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.sub (i32.load8_u offset={} (local.get $cell_ptr)) (i32.load8_u offset={} (local.get $cell_ptr))))\n", cur_shift+shift, cur_shift+shift, cur_shift);
+                    // set cell to 0:
+                    *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
+                }
                 BfOp::MoveAdd2(shift1, shift2) => {
                     assert_ne!(*shift1, 0);
                     assert_ne!(*shift2, 0);
@@ -209,6 +243,14 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                     // set cell to 0:
                     *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
                 }
+                BfOp::MoveMul(shift, factor) => {
+                    assert_ne!(*shift, 0);
+                    // add multiple to new cell:
+                    assure_nonnegative_offsets(bf_wat, &mut cur_shift, &[0, *shift]);
+                    *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.add (i32.load8_u offset={} (local.get $cell_ptr)) (i32.mul (i32.load8_u offset={} (local.get $cell_ptr)) (i32.const {}))))\n", cur_shift+shift, cur_shift+shift, cur_shift, factor);
+                    // set cell to 0:
+                    *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
+                }
                 BfOp::MoveAddMul(shift_adds) => {
                     let mut all_shifts_vec = vec![0];
                     all_shifts_vec.extend(shift_adds.iter().map(|sa| sa.shift));
@@ -230,6 +272,26 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                     // set cell to 0:
                     *bf_wat += &format!("(i32.store8 offset={} (local.get $cell_ptr) (i32.const 0))\n", cur_shift);
                 }
+                BfOp::ScanRight | BfOp::ScanLeft => {
+                    if cur_shift != 0 {
+                        *bf_wat += &format!("(local.set $cell_ptr (i32.add (local.get $cell_ptr) (i32.const {})))", cur_shift);
+                        cur_shift = 0;
+                    }
+                    let step = if let BfOp::ScanRight = op { 1 } else { -1 };
+                    let cur_loop_id = format!("bf_loop_{}", global_loop_counter);
+                    let cur_block_id = format!("bf_loop_block_{}", global_loop_counter);
+                    *global_loop_counter += 1;
+                    *bf_wat += &format!("(loop ${}\n", cur_loop_id);
+                    *bf_wat += &format!("(block ${}\n", cur_block_id);
+                    *bf_wat += &format!(
+                        "(br_if ${} (i32.eqz (i32.load8_u (local.get $cell_ptr))))\n",
+                        cur_block_id
+                    );
+                    *bf_wat += &format!("(local.set $cell_ptr (i32.add (local.get $cell_ptr) (i32.const {})))\n", step);
+                    *bf_wat += &format!("(br ${})\n", cur_loop_id);
+                    *bf_wat += ")\n";
+                    *bf_wat += ")\n";
+                }
                 BfOp::Comment(_) => {}
                 BfOp::DebugMessage(_) => {}
                 BfOp::Crash(_) => {}
@@ -246,6 +308,8 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
         ops: &Vec<AsyncifiedOp>,
         bf_wat: &mut String,
         global_loop_counter: &mut usize,
+        eof_behavior: EofBehavior,
+        limit_loop_iterations: bool,
     ) {
         for op in ops {
             let cur_async_block_counter = op.counter;
@@ -258,7 +322,7 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
             );
             match &op.kind {
                 AsyncifiedOpKind::SyncBlock(ops) => {
-                    process_sync_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter);
+                    process_sync_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter, limit_loop_iterations);
                 }
                 AsyncifiedOpKind::In => {
                     let inner_block_id = format!("{}_inner", cur_async_block_id);
@@ -285,7 +349,28 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                     *bf_wat += "(return (i32.const 1))\n";
                     *bf_wat += ")\n";
 
-                    // We have input, set $async_start_block to 0 so we just execute everthing from now on
+                    // We have input or reached EOF. On EOF, replace $tmp1 by whatever should end up in the cell.
+                    let eof_block_id = format!("{}_eof", cur_async_block_id);
+                    *bf_wat += &format!("(block ${}\n", eof_block_id);
+                    *bf_wat += &format!(
+                        "(br_if ${} (i32.ne (i32.const -1) (local.get $tmp1)))\n",
+                        eof_block_id
+                    );
+                    match eof_behavior {
+                        EofBehavior::Zero => {
+                            *bf_wat += "(local.set $tmp1 (i32.const 0))\n";
+                        }
+                        EofBehavior::NegativeOne => {
+                            *bf_wat += "(local.set $tmp1 (i32.const 255))\n";
+                        }
+                        EofBehavior::Unchanged => {
+                            // if we are rewinding, the cell was overwritten and its old value is in $tmp2
+                            *bf_wat += "(local.set $tmp1 (select (local.get $tmp2) (i32.load8_u (local.get $cell_ptr)) (local.get $async_start_block)))\n";
+                        }
+                    }
+                    *bf_wat += ")\n";
+
+                    // Set $async_start_block to 0 so we just execute everthing from now on
                     *bf_wat += "(i32.store8 (local.get $cell_ptr) (local.get $tmp1))\n";
                     *bf_wat += "(local.set $async_start_block (i32.const 0))\n";
                 }
@@ -299,7 +384,8 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
                         "(br_if ${} (i32.eqz (i32.load8_u (local.get $cell_ptr))))\n",
                         cur_block_id
                     );
-                    process_async_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter);
+                    count_loop_iteration(bf_wat, limit_loop_iterations);
+                    process_async_ops_rec(ops, &mut *bf_wat, &mut *global_loop_counter, eof_behavior, limit_loop_iterations);
                     *bf_wat += &format!("(br ${})\n", cur_loop_id);
                     *bf_wat += ")\n";
                     *bf_wat += ")\n";
@@ -310,19 +396,27 @@ pub fn bf2wasm(bf_ops: Vec<BfOp>, optimize_first: bool) -> wat::Result<Vec<u8>>
     }
     let mut bf_wat = String::new();
     let mut global_loop_counter = 0;
-    process_async_ops_rec(&async_ops, &mut bf_wat, &mut global_loop_counter);
+    process_async_ops_rec(&async_ops, &mut bf_wat, &mut global_loop_counter, eof_behavior, max_loop_iterations.is_some());
 
     let mut wat = r#"
         (module
             (import "imports" "read_input_byte" (func $read_input_byte (result i32)))
-            (import "imports" "write_output_byte" (func $write_output_byte (param i32)))
-            (import "imports" "tape" (memory 1))
+            (import "imports" "write_output_byte" (func $write_output_byte (param i32)))"#.to_owned();
+    wat += &format!(r#"
+            (import "imports" "tape" (memory {}))"#, tape_pages);
+    wat += r#"
             (global $cell_ptr_global (mut i32) (i32.const 0))
-            (global $async_start_block_global (mut i32) (i32.const 0))
+            (global $async_start_block_global (mut i32) (i32.const 0))"#;
+    if let Some(max_loop_iterations) = max_loop_iterations {
+        // run_bf stops once this hits 0, so it starts one higher to allow exactly max_loop_iterations
+        wat += &format!(r#"
+            (global $steps_left_global (mut i64) (i64.const {}))"#, max_loop_iterations.saturating_add(1));
+    }
+    wat += r#"
             (export "cell_ptr" (global $cell_ptr_global))
             (func $run_bf (result i32) (local $cell_ptr i32) (local $async_start_block i32)  (local $tmp1 i32) (local $tmp2 i32)
                 (local.set $cell_ptr (global.get $cell_ptr_global))
-                (local.set $async_start_block (global.get $async_start_block_global))"#.to_owned();
+                (local.set $async_start_block (global.get $async_start_block_global))"#;
     // This is a bit cursed, but if we're rewinding from a request for more input,
     // then we set the current cell to 1 so that all the loops are executed
     // until we encounter the "," instruction that caused the interruption.
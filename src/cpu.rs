@@ -1,6 +1,7 @@
 use num::BigUint;
 use num::Integer;
 use num::Zero;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::result;
 
@@ -276,6 +277,120 @@ impl ScratchTrack {
     }
 }
 
+/// Statically finds writes to scratch cells that aren't cleared again by the end of `ops`,
+/// without running them. Returns the indices of the last write to each leaked cell, counting
+/// ops in pre-order (a loop comes right before the ops in its body). A loop that moves the
+/// pointer by whole frames forgets the cells written before it; any other unbalanced loop
+/// stops the analysis.
+pub fn find_scratch_leaks(ops: &[Lir], cfg: &CpuConfig) -> Vec<usize> {
+    struct State {
+        frame_size: isize,
+        scratch_tracks: Vec<isize>,
+        ptr: isize,
+        // cells that may be nonzero, with their value if it's known, and the op that last wrote them
+        dirty: HashMap<isize, (Option<u8>, usize)>,
+        next_index: usize,
+        lost_track: bool,
+    }
+
+    impl State {
+        fn write(&mut self, index: usize, f: impl FnOnce(u8) -> Option<u8>) {
+            if !self
+                .scratch_tracks
+                .contains(&self.ptr.rem_euclid(self.frame_size))
+            {
+                return;
+            }
+            let old = self.dirty.get(&self.ptr).map_or(Some(0), |(val, _)| *val);
+            match old.and_then(f) {
+                Some(0) => {
+                    self.dirty.remove(&self.ptr);
+                }
+                new => {
+                    self.dirty.insert(self.ptr, (new, index));
+                }
+            }
+        }
+
+        fn visit(&mut self, ops: &[Lir]) {
+            for op in ops {
+                if self.lost_track {
+                    return;
+                }
+                let index = self.next_index;
+                self.next_index += 1;
+                match op {
+                    Lir::Left => self.ptr -= 1,
+                    Lir::Right => self.ptr += 1,
+                    Lir::Inc => self.write(index, |val| Some(val.wrapping_add(1))),
+                    Lir::Dec => self.write(index, |val| Some(val.wrapping_sub(1))),
+                    Lir::In => self.write(index, |_| None),
+                    Lir::Loop(body) => {
+                        let start_ptr = self.ptr;
+                        let before = self.dirty.clone();
+                        self.visit(body);
+                        if self.ptr != start_ptr {
+                            if (self.ptr - start_ptr) % self.frame_size != 0 {
+                                self.lost_track = true;
+                                return;
+                            }
+                            self.dirty.clear();
+                        } else {
+                            // the body may have run any number of times, including zero
+                            let after = std::mem::take(&mut self.dirty);
+                            let val_of = |entry: Option<&(Option<u8>, usize)>| {
+                                entry.map_or(Some(0), |(val, _)| *val)
+                            };
+                            for &cell in before.keys().chain(after.keys()) {
+                                let (b, a) = (before.get(&cell), after.get(&cell));
+                                let val = if val_of(b) == val_of(a) {
+                                    val_of(a)
+                                } else {
+                                    None
+                                };
+                                let last_write = a.or(b).unwrap().1;
+                                self.dirty.insert(cell, (val, last_write));
+                            }
+                        }
+                        // loops only exit on a zero cell
+                        self.dirty.remove(&self.ptr);
+                    }
+                    Lir::Out
+                    | Lir::Comment(_)
+                    | Lir::DebugMessage(_)
+                    | Lir::Crash(_)
+                    | Lir::Breakpoint
+                    | Lir::PrintRegisters
+                    | Lir::CheckScratchIsEmptyFromHere(_) => {}
+                }
+            }
+        }
+    }
+
+    let mut state = State {
+        frame_size: cfg.frame_size(),
+        scratch_tracks: cfg
+            .get_tracks()
+            .values()
+            .filter(|kind| matches!(kind, TrackKind::Scratch(..)))
+            .map(|kind| kind.track_num())
+            .collect(),
+        ptr: 0,
+        dirty: HashMap::new(),
+        next_index: 0,
+        lost_track: false,
+    };
+    state.visit(ops);
+    let mut result = state
+        .dirty
+        .values()
+        .map(|(_, last_write)| *last_write)
+        .collect::<Vec<_>>();
+    result.sort_unstable();
+    result.dedup();
+    result
+}
+
 fn all_different<T: PartialEq>(elements: &[T]) -> bool {
     for i in 0..elements.len() {
         for j in i + 1..elements.len() {
@@ -287,17 +402,44 @@ fn all_different<T: PartialEq>(elements: &[T]) -> bool {
     true
 }
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug)]
-pub enum TrackId {
-    Stack,
-    Heap,
-    Scratch1,
-    Scratch2,
-    Scratch3,
-    CurDataPtr,
-    Register1,
+/// Names a track in a `CpuConfig`. Any name can be used; the constants are the ones the compiler itself uses
+#[derive(Hash, Eq, PartialEq, Clone)]
+pub struct TrackId(Cow<'static, str>);
+
+#[allow(non_upper_case_globals)]
+impl TrackId {
+    pub const Stack: TrackId = TrackId(Cow::Borrowed("Stack"));
+    pub const Heap: TrackId = TrackId(Cow::Borrowed("Heap"));
+    pub const Scratch1: TrackId = TrackId(Cow::Borrowed("Scratch1"));
+    pub const Scratch2: TrackId = TrackId(Cow::Borrowed("Scratch2"));
+    pub const Scratch3: TrackId = TrackId(Cow::Borrowed("Scratch3"));
+    pub const CurDataPtr: TrackId = TrackId(Cow::Borrowed("CurDataPtr"));
+    pub const Register1: TrackId = TrackId(Cow::Borrowed("Register1"));
+
+    pub fn new(name: impl Into<String>) -> TrackId {
+        TrackId(Cow::Owned(name.into()))
+    }
+
+    /// Made by `CpuConfig::add_anonymous_scratch_track`, numbered by when it was added
+    pub fn anonymous_scratch(n: usize) -> TrackId {
+        TrackId::new(format!("AnonymousScratch({})", n))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for TrackId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
 }
 
+/// A value that doesn't fit in the register it's being stored in
+#[derive(Debug)]
+pub struct RegisterOverflow;
+
 #[derive(Clone)]
 pub struct CpuConfig {
     pub tracks: HashMap<TrackId, TrackKind>,
@@ -335,11 +477,19 @@ impl CpuConfig {
             offset: 0,
             dont_go_left_of: None,
         };
-        let old = self.tracks.insert(id, TrackKind::Scratch(track));
+        let old = self
+            .tracks
+            .insert(id, TrackKind::Scratch(track, HashMap::new()));
         assert!(old.is_none());
         track
     }
 
+    /// Adds a scratch track under a fresh id, for helpers that don't know which named tracks are free
+    pub fn add_anonymous_scratch_track(&mut self) -> ScratchTrack {
+        let id = TrackId::anonymous_scratch(self.tracks.len());
+        self.add_scratch_track(id)
+    }
+
     pub fn add_register_track(&mut self, id: TrackId, size: isize) -> Register {
         let track = Register {
             size,
@@ -353,6 +503,17 @@ impl CpuConfig {
         track
     }
 
+    /// Names a register on a scratch or multiple-registers track, so it can be looked up after a run
+    pub fn name_register(&mut self, id: TrackId, name: &str, register: Register) {
+        match self.tracks.get_mut(&id) {
+            Some(TrackKind::MultipleRegisters(_, register_map, _))
+            | Some(TrackKind::Scratch(_, register_map)) => {
+                register_map.insert(name.to_owned(), register);
+            }
+            _ => panic!("Track {:?} can't hold named registers", id),
+        }
+    }
+
     pub fn build_register_track(&mut self, id: TrackId) -> RegisterTrackBuilder {
         let track_num = self.tracks.len() as isize;
         let old = self.tracks.insert(
@@ -377,7 +538,20 @@ pub enum TrackKind {
         HashMap<String, BinRegister>,
     ),
     BinRegister(BinRegister),
-    Scratch(ScratchTrack),
+    /// Registers split off a scratch track can be named too
+    Scratch(ScratchTrack, HashMap<String, Register>),
+}
+
+impl TrackKind {
+    pub fn track_num(&self) -> isize {
+        match self {
+            TrackKind::Data(track) => track.track_num,
+            TrackKind::Register(register) => register.track.track_num,
+            TrackKind::MultipleRegisters(track_num, _, _) => *track_num,
+            TrackKind::BinRegister(register) => register.track.track_num,
+            TrackKind::Scratch(track, _) => track.track.track_num,
+        }
+    }
 }
 
 pub struct RegisterTrackBuilder {
@@ -637,6 +811,14 @@ impl<'c> Cpu<'c> {
         self.lir.push(Lir::Loop(cpu.lir));
     }
 
+    /// Runs `f` as many times as the value at `count`, which ends up cleared
+    pub fn repeat(&mut self, count: Pos, f: impl for<'a> FnOnce(&'a mut Cpu)) {
+        self.loop_while(count, |cpu| {
+            cpu.dec_at(count);
+            f(cpu);
+        });
+    }
+
     pub fn raw_loop(&mut self, f: impl for<'a> FnOnce(&'a mut Cpu)) {
         let mut cpu = Cpu {
             cfg: self.cfg,
@@ -774,6 +956,33 @@ impl<'c> Cpu<'c> {
         }
     }
 
+    /// Sets len consecutive cells starting at start to val, using a counter loop
+    /// rather than unrolling, so large slices don't blow up the program size
+    pub fn fill_slice(&mut self, start: Pos, len: isize, val: u8, scratch_track: ScratchTrack) {
+        assert!(len >= 0);
+        let mut chunk_start = 0;
+        while chunk_start < len {
+            let chunk_size = (len - chunk_start).min(255);
+            let chunk = Register {
+                track: Track {
+                    track_num: start.track,
+                },
+                size: chunk_size,
+                offset: start.frame + chunk_start,
+            };
+            self.foreach_pos_of_register(
+                chunk,
+                scratch_track,
+                None::<fn(&mut Cpu, ScratchTrack)>,
+                |cpu, pos, _| {
+                    cpu.set_byte(pos, val);
+                },
+                None::<fn(&mut Cpu, ScratchTrack)>,
+            );
+            chunk_start += chunk_size;
+        }
+    }
+
     pub fn zero_register(&mut self, register: Register) {
         self.zero_slice(register.at(0), register.size);
     }
@@ -1057,6 +1266,55 @@ impl<'c> Cpu<'c> {
         }
     }
 
+    /// Copies len cells starting at from to the cells starting at to, walking over the
+    /// region in a loop rather than unrolling it. Overlapping regions are handled.
+    pub fn copy_region(&mut self, from: Pos, to: Pos, len: isize, scratch_track: ScratchTrack) {
+        assert!(len >= 0);
+        if from == to {
+            return;
+        }
+        let shift = to.frame - from.frame;
+        let copy_backwards = from.track == to.track && shift > 0;
+        let mut chunk_starts: Vec<isize> = (0..len).step_by(255).collect();
+        if copy_backwards {
+            chunk_starts.reverse();
+        }
+        for chunk_start in chunk_starts {
+            let chunk = Register {
+                track: Track {
+                    track_num: from.track,
+                },
+                size: (len - chunk_start).min(255),
+                offset: from.frame + chunk_start,
+            };
+            let f = |cpu: &mut Cpu, pos: Pos, scratch_track: ScratchTrack| {
+                let to_pos = Pos {
+                    frame: pos.frame + shift,
+                    track: to.track,
+                };
+                cpu.clr_at(to_pos);
+                cpu.copy_byte_autoscratch(pos, to_pos, scratch_track);
+            };
+            if copy_backwards {
+                self.foreach_pos_of_register_rev(
+                    chunk,
+                    scratch_track,
+                    None::<fn(&mut Cpu, ScratchTrack)>,
+                    f,
+                    None::<fn(&mut Cpu, ScratchTrack)>,
+                );
+            } else {
+                self.foreach_pos_of_register(
+                    chunk,
+                    scratch_track,
+                    None::<fn(&mut Cpu, ScratchTrack)>,
+                    f,
+                    None::<fn(&mut Cpu, ScratchTrack)>,
+                );
+            }
+        }
+    }
+
     pub fn copy_register(
         &mut self,
         from: Register,
@@ -1071,6 +1329,14 @@ impl<'c> Cpu<'c> {
         self.copy_slice(from.at(0), from.size, to.at(0), scratch_track);
     }
 
+    pub fn swap_registers(&mut self, a: Register, b: Register, scratch_track: ScratchTrack) {
+        assert_eq!(a.size, b.size);
+        let (tmp, _) = scratch_track.split_register(a.size);
+        self.move_onto_zero_register(a, tmp);
+        self.move_onto_zero_register(b, a);
+        self.move_onto_zero_register(tmp, b);
+    }
+
     // carry must be 0
     pub fn inc_byte_with_carry(&mut self, x: Pos, carry: Pos, scratch_track: ScratchTrack) {
         let (x_cpy, _) = scratch_track.split_1();
@@ -1133,11 +1399,36 @@ impl<'c> Cpu<'c> {
         assert!(a != b);
         assert!(b != carry);
         assert!(a != carry);
-        // slow, but unfortunately I don't know a much faster alternative...
-        self.loop_while(a, |cpu| {
+        // b + a overflows iff b != 0 and a >= 256 - b, so instead of incrementing b one step
+        // at a time (and checking for overflow at every step), race a against 256 - b,
+        // which takes min(a, 256 - b) steps, and then add a to b in one go.
+        let ([a_cpy, steps_to_overflow, b_cpy], scratch_track) = scratch_track.split_3();
+        self.copy_byte_autoscratch(a, a_cpy, scratch_track);
+        self.moveadd_byte(b, b_cpy);
+        self.loop_while(b_cpy, |cpu| {
+            cpu.dec();
+            cpu.inc_at(b);
+            cpu.dec_at(steps_to_overflow);
+        });
+        self.moveadd_byte(a, b);
+        self.if_nonzero(steps_to_overflow, scratch_track, |cpu, _| {
+            cpu.inc_at(carry);
+        });
+        self.loop_while(steps_to_overflow, |cpu| {
             cpu.dec();
-            cpu.inc_byte_with_carry(b, carry, scratch_track);
+            cpu.if_nonzero_else(
+                a_cpy,
+                scratch_track,
+                |cpu, _| {
+                    cpu.dec_at(a_cpy);
+                },
+                |cpu, _| {
+                    cpu.dec_at(carry);
+                    cpu.clr_at(steps_to_overflow);
+                },
+            );
         });
+        self.clr_at(a_cpy);
     }
 
     pub fn moveadd_registers(&mut self, a: Register, b: Register, scratch_track: ScratchTrack) {
@@ -1237,35 +1528,37 @@ impl<'c> Cpu<'c> {
     }
 
     pub fn moveprint_hex_digit(&mut self, pos: Pos, scratch_track: ScratchTrack) {
+        self.add_digit_char_offset(pos, b'A', scratch_track);
+        self.goto(pos);
+        self.out();
+        self.clr();
+    }
+
+    /// Turns the digit value (0-15) at pos into its ASCII char, with 10 and up mapped to
+    /// letters starting at first_letter
+    pub fn add_digit_char_offset(
+        &mut self,
+        pos: Pos,
+        first_letter: u8,
+        scratch_track: ScratchTrack,
+    ) {
         let ([byte_cpy, zero], scratch_track) = scratch_track.split_2();
         self.copy_byte(pos, byte_cpy, zero);
-        self.add_const_to_byte(pos, 48);
+        self.add_const_to_byte(pos, b'0');
 
         self.add_const_to_byte(zero, 9);
         self.movesub_byte_clamped(zero, byte_cpy, scratch_track);
 
-        self.if_nonzero_else(
-            byte_cpy,
-            scratch_track,
-            |cpu, _| {
-                cpu.goto(pos);
-                cpu.inc_by(7);
-                cpu.out();
-                cpu.clr();
-            },
-            |cpu, _| {
-                cpu.goto(pos);
-                cpu.out();
-                cpu.clr();
-            },
-        );
+        self.if_nonzero(byte_cpy, scratch_track, |cpu, _| {
+            cpu.add_const_to_byte(pos, first_letter - b'0' - 10);
+        });
         self.clr_at(byte_cpy);
-        self.clr_at(pos);
     }
 
     pub fn print_char(&mut self, c: char, scratch: Pos) {
         let x = c as u32;
-        if x <= 8 || x >= 127 {
+        let is_control = x < 32 && !matches!(c, '\n' | '\t' | '\r');
+        if is_control || x >= 127 {
             panic!("Printing unprintable char");
         }
         let x = x as u8;
@@ -1274,19 +1567,89 @@ impl<'c> Cpu<'c> {
         self.clr();
     }
 
-    pub fn print_text(&mut self, s: &str, scratch_track: ScratchTrack) {
+    /// Prints raw bytes, so multi-byte UTF-8 sequences are emitted as is
+    pub fn print_bytes(&mut self, bytes: &[u8], scratch_track: ScratchTrack) {
         let (pos, _) = scratch_track.split_1();
-        for c in s.chars() {
-            self.print_char(c, pos);
+        for &b in bytes {
+            self.add_const_to_byte(pos, b);
+            self.out();
+            self.clr();
+        }
+    }
+
+    pub fn print_text(&mut self, s: &str, scratch_track: ScratchTrack) {
+        self.print_bytes(s.as_bytes(), scratch_track);
+    }
+
+    /// Reads input into `dest` and the `max_len` - 1 frames after it until a newline or EOF,
+    /// and null-terminates it in place of the newline. A longer line is cut off after
+    /// `max_len` bytes, leaving the rest unread, so `dest` needs room for `max_len` + 1 bytes.
+    pub fn read_line_into(&mut self, dest: Pos, max_len: isize, scratch_track: ScratchTrack) {
+        let (keep_going, scratch_track) = scratch_track.split_1();
+        self.inc_at(keep_going);
+        for i in 0..max_len {
+            let pos = dest.get_shifted(i);
+            self.if_nonzero(keep_going, scratch_track, |cpu, scratch_track| {
+                cpu.goto(pos);
+                cpu.read_stdin();
+                cpu.if_nonzero_else(
+                    pos,
+                    scratch_track,
+                    |cpu, scratch_track| {
+                        let (is_newline, scratch_track) = scratch_track.split_1();
+                        cpu.copy_byte_autoscratch(pos, is_newline, scratch_track);
+                        cpu.sub_const_from_byte(is_newline, b'\n');
+                        cpu.not(is_newline, scratch_track);
+                        cpu.if_nonzero(is_newline, scratch_track, |cpu, _| {
+                            cpu.clr_at(pos);
+                            cpu.dec_at(keep_going);
+                            cpu.dec_at(is_newline);
+                        });
+                    },
+                    |cpu, _| {
+                        cpu.dec_at(keep_going);
+                    },
+                );
+            });
         }
+        self.clr_at(keep_going);
+        self.clr_at(dest.get_shifted(max_len));
     }
 
     pub fn moveprint_byte(&mut self, pos: Pos, scratch_track: ScratchTrack) {
-        let ([singles, temp, tens, hundreds], div_scratch_track) = scratch_track.split_4();
-        self.movediv_byte_onto_zeros(pos, 10, temp, singles, div_scratch_track);
-        self.movediv_byte_onto_zeros(temp, 10, hundreds, tens, div_scratch_track);
-        self.moveprint_digit_if_nonzero(hundreds);
-        self.moveprint_digit_if_nonzero(tens);
+        self.moveprint_byte_padded(pos, 0, ' ', scratch_track);
+    }
+
+    /// Like moveprint_byte, but left-pads the number with `pad` to at least `width` chars
+    pub fn moveprint_byte_padded(
+        &mut self,
+        pos: Pos,
+        width: u8,
+        pad: char,
+        scratch_track: ScratchTrack,
+    ) {
+        let ([singles, temp, tens, hundreds, has_digit], scratch_track) = scratch_track.split_5();
+        self.movediv_byte_onto_zeros(pos, 10, temp, singles, scratch_track);
+        self.movediv_byte_onto_zeros(temp, 10, hundreds, tens, scratch_track);
+        let (pad_pos, _) = scratch_track.split_1();
+        for _ in 3..width {
+            self.print_char(pad, pad_pos);
+        }
+        // has_digit becomes nonzero at the first nonzero digit, from which on zeros are printed
+        for (digit, digit_width) in [(hundreds, 3), (tens, 2)] {
+            self.if_nonzero(digit, scratch_track, |cpu, _| {
+                cpu.inc_at(has_digit);
+            });
+            if width >= digit_width {
+                self.if_zero(has_digit, scratch_track, |cpu, _| {
+                    cpu.print_char(pad, pad_pos);
+                });
+            }
+            self.if_nonzero(has_digit, scratch_track, |cpu, _| {
+                cpu.moveprint_digit(digit);
+            });
+        }
+        self.clr_at(has_digit);
         self.moveprint_digit(singles);
     }
 
@@ -1300,19 +1663,47 @@ impl<'c> Cpu<'c> {
         }
     }
 
+    /// Prints the register in binary, clearing it
+    pub fn moveprint_register_in_binary(
+        &mut self,
+        register: Register,
+        scratch_track: ScratchTrack,
+    ) {
+        self.print_text("0b", scratch_track);
+        let (bits, scratch_track) = scratch_track.split_binregister(8);
+        for i in 0..register.size {
+            self.move_unpack_byte_onto_zeros(register.at(i), bits.at(0), scratch_track);
+            for j in 0..8 {
+                self.add_const_to_byte(bits.at(j), b'0');
+                self.out();
+                self.clr();
+            }
+        }
+    }
+
     pub fn print_newline(&mut self, scratch_track: ScratchTrack) {
         self.print_char('\n', scratch_track.split_1().0);
     }
 
     pub fn set_register(&mut self, register: Register, val: impl Into<BigUint>) {
+        self.try_set_register(register, val)
+            .unwrap_or_else(|_| panic!("Value too big to fit in register"));
+    }
+
+    /// Like set_register, but returns an error instead of panicking if `val` doesn't fit
+    pub fn try_set_register(
+        &mut self,
+        register: Register,
+        val: impl Into<BigUint>,
+    ) -> Result<(), RegisterOverflow> {
         let two_fifty_six = BigUint::from(256u64);
         let zero = BigUint::zero();
         let mut div: BigUint = val.into();
+        if div.bits() > 8 * register.size as u64 {
+            return Err(RegisterOverflow);
+        }
         let mut i = 0;
         while div != zero {
-            if i >= register.size {
-                panic!("Value too big to fit in register");
-            }
             let (new_div, rem) = div.div_rem(&two_fifty_six);
             div = new_div;
             let rem_bytes = rem.to_bytes_be();
@@ -1321,6 +1712,29 @@ impl<'c> Cpu<'c> {
             self.set_byte(register.at(register.size - i - 1), *rem);
             i += 1;
         }
+        Ok(())
+    }
+
+    /// Sets every byte of the register to the two's complement encoding of `val`, sign-extended
+    /// to the register's size. Panics unless `val` fits as either a signed or an unsigned number.
+    pub fn set_register_signed(&mut self, register: Register, val: i64) {
+        let bits = 8 * register.size;
+        if bits < 64 {
+            assert!(
+                val >= -(1i64 << (bits - 1)) && val < (1i64 << bits),
+                "Value too big to fit in register"
+            );
+        }
+        for i in 0..register.size {
+            let byte = if i < 8 {
+                (val >> (8 * i)) as u8
+            } else if val < 0 {
+                0xFF
+            } else {
+                0
+            };
+            self.set_byte(register.at(register.size - i - 1), byte);
+        }
     }
 
     pub fn add_const_to_register(
@@ -1742,6 +2156,16 @@ impl<'c> Cpu<'c> {
         );
     }
 
+    /// Prints `0x` followed by the value in base 16, see `print_binregister_in_base`
+    pub fn print_binregister_in_hex(
+        &mut self,
+        binregister: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        self.print_text("0x", scratch_track);
+        self.print_binregister_in_base(binregister, 16, scratch_track);
+    }
+
     pub fn if_binregister_nonzero_else(
         &mut self,
         register: BinRegister,
@@ -1749,6 +2173,16 @@ impl<'c> Cpu<'c> {
         if_nonzero: impl for<'a> FnOnce(&'a mut Cpu, ScratchTrack),
         if_zero: impl for<'a> FnOnce(&'a mut Cpu, ScratchTrack),
     ) {
+        // for small registers, unrolling is cheaper than walking sentinels across the register
+        if register.size <= 8 {
+            self.if_register_nonzero_else(
+                register.as_register(),
+                scratch_track,
+                if_nonzero,
+                if_zero,
+            );
+            return;
+        }
         let ([acc, sentinel1], scratch_track) = scratch_track.split_2();
         self.inc_at(sentinel1);
         let scratch_track = scratch_track.get_split_scratch(register.size - 1);
@@ -1830,6 +2264,25 @@ impl<'c> Cpu<'c> {
         );
     }
 
+    /// Adds a constant, using repeated increments when it is small enough to be cheaper
+    pub fn add_const_to_binregister(
+        &mut self,
+        register: BinRegister,
+        val: u64,
+        scratch_track: ScratchTrack,
+    ) {
+        if val <= 4 {
+            for _ in 0..val {
+                self.inc_binregister(register, scratch_track);
+            }
+            return;
+        }
+        let (val_register, scratch_track) = scratch_track.split_binregister(register.size);
+        self.set_binregister(val_register, val, scratch_track);
+        self.add_binregister_to_binregister(val_register, register, scratch_track);
+        self.clr_binregister(val_register, scratch_track);
+    }
+
     pub fn sub_binregister_from_binregister(
         &mut self,
         reg1: BinRegister,
@@ -1889,77 +2342,319 @@ impl<'c> Cpu<'c> {
         );
     }
 
-    /// shift the register, going out of bounds of the register
-    pub fn shift_register_left_oob_by(
+    /// out = a & b. out may alias a or b
+    pub fn and_binregisters(
         &mut self,
-        register: Register,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
         scratch_track: ScratchTrack,
-        by: isize,
     ) {
-        assert!(by > 0);
-        self.foreach_pos_of_register(
-            register,
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.size, out.size);
+        self.foreach_pos_of_binregister(
+            a,
             scratch_track,
             None::<fn(&mut Cpu, ScratchTrack)>,
-            |cpu, pos, _| {
-                cpu.moveadd_byte(pos, pos.get_shifted(-by));
+            |cpu, pos, scratch_track| {
+                let (result, scratch_track) = scratch_track.split_1();
+                cpu.if_nonzero(pos, scratch_track, |cpu, scratch_track| {
+                    cpu.if_nonzero(b.at(0), scratch_track, |cpu, _| {
+                        cpu.inc_at(result);
+                    });
+                });
+                cpu.clr_at(out.at(0));
+                cpu.moveadd_byte(result, out.at(0));
             },
             None::<fn(&mut Cpu, ScratchTrack)>,
         );
     }
 
-    /// shift the register, going out of bounds of the register
-    pub fn shift_register_right_oob_by(
+    /// out = a | b. out may alias a or b
+    pub fn or_binregisters(
         &mut self,
-        register: Register,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
         scratch_track: ScratchTrack,
-        by: isize,
     ) {
-        assert!(by > 0);
-        self.foreach_pos_of_register_rev(
-            register,
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.size, out.size);
+        self.foreach_pos_of_binregister(
+            a,
             scratch_track,
             None::<fn(&mut Cpu, ScratchTrack)>,
-            |cpu, pos, _| {
-                cpu.moveadd_byte(pos, pos.get_shifted(by));
+            |cpu, pos, scratch_track| {
+                let (result, scratch_track) = scratch_track.split_1();
+                cpu.if_nonzero_else(
+                    pos,
+                    scratch_track,
+                    |cpu, _| {
+                        cpu.inc_at(result);
+                    },
+                    |cpu, scratch_track| {
+                        cpu.if_nonzero(b.at(0), scratch_track, |cpu, _| {
+                            cpu.inc_at(result);
+                        });
+                    },
+                );
+                cpu.clr_at(out.at(0));
+                cpu.moveadd_byte(result, out.at(0));
             },
             None::<fn(&mut Cpu, ScratchTrack)>,
         );
     }
 
-    pub fn shift_register_left(&mut self, register: Register, scratch_track: ScratchTrack) {
-        self.clr_at(register.at(0));
-        self.foreach_pos_of_register(
-            register.subview(1, register.size - 1),
-            scratch_track,
-            None::<fn(&mut Cpu, ScratchTrack)>,
-            |cpu, pos, _| {
-                cpu.moveadd_byte(pos, pos.get_shifted(-1));
-            },
-            None::<fn(&mut Cpu, ScratchTrack)>,
-        );
-    }
-    pub fn shift_register_right(&mut self, register: Register, scratch_track: ScratchTrack) {
-        self.clr_at(register.at(register.size - 1));
-        self.foreach_pos_of_register_rev(
-            register.subview(0, register.size - 1),
+    /// out = a ^ b. out may alias a or b
+    pub fn xor_binregisters(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(a.size, out.size);
+        self.foreach_pos_of_binregister(
+            a,
             scratch_track,
             None::<fn(&mut Cpu, ScratchTrack)>,
-            |cpu, pos, _| {
-                cpu.moveadd_byte(pos, pos.get_shifted(1));
+            |cpu, pos, scratch_track| {
+                let (result, scratch_track) = scratch_track.split_1();
+                cpu.copy_byte_autoscratch(pos, result, scratch_track);
+                cpu.if_nonzero(b.at(0), scratch_track, |cpu, scratch_track| {
+                    cpu.not(result, scratch_track);
+                });
+                cpu.clr_at(out.at(0));
+                cpu.moveadd_byte(result, out.at(0));
             },
             None::<fn(&mut Cpu, ScratchTrack)>,
         );
     }
 
-    pub fn shift_binregister_left(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
-        self.shift_register_left(register.as_register(), scratch_track);
-    }
-
+    /// Flips every bit of the register in place
+    pub fn not_binregister(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
+        if register.size <= 8 {
+            for i in 0..register.size {
+                self.not(register.at(i), scratch_track);
+            }
+        } else {
+            self.foreach_pos_of_binregister(
+                register,
+                scratch_track,
+                None::<fn(&mut Cpu, ScratchTrack)>,
+                |cpu, pos, scratch_track| {
+                    cpu.not(pos, scratch_track);
+                },
+                None::<fn(&mut Cpu, ScratchTrack)>,
+            );
+        }
+    }
+
+    /// Two's complement negation in place
+    pub fn neg_binregister(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
+        self.not_binregister(register, scratch_track);
+        self.inc_binregister(register, scratch_track);
+    }
+
+    /// Replaces a two's complement signed value by its absolute value.
+    /// The most negative value stays as is, which reads correctly as unsigned
+    pub fn abs_binregister(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
+        let (is_neg, scratch_track) = scratch_track.split_1();
+        self.copy_byte_autoscratch(register.at(0), is_neg, scratch_track);
+        self.if_nonzero(is_neg, scratch_track, |cpu, scratch_track| {
+            cpu.neg_binregister(register, scratch_track);
+        });
+        self.clr_at(is_neg);
+    }
+
+    /// shift the register, going out of bounds of the register
+    pub fn shift_register_left_oob_by(
+        &mut self,
+        register: Register,
+        scratch_track: ScratchTrack,
+        by: isize,
+    ) {
+        assert!(by > 0);
+        self.foreach_pos_of_register(
+            register,
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(-by));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+
+    /// shift the register, going out of bounds of the register
+    pub fn shift_register_right_oob_by(
+        &mut self,
+        register: Register,
+        scratch_track: ScratchTrack,
+        by: isize,
+    ) {
+        assert!(by > 0);
+        self.foreach_pos_of_register_rev(
+            register,
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(by));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+
+    pub fn shift_register_left(&mut self, register: Register, scratch_track: ScratchTrack) {
+        self.clr_at(register.at(0));
+        self.foreach_pos_of_register(
+            register.subview(1, register.size - 1),
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(-1));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+    pub fn shift_register_right(&mut self, register: Register, scratch_track: ScratchTrack) {
+        self.clr_at(register.at(register.size - 1));
+        self.foreach_pos_of_register_rev(
+            register.subview(0, register.size - 1),
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(1));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+
+    pub fn shift_binregister_left(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
+        self.shift_register_left(register.as_register(), scratch_track);
+    }
+
     pub fn shift_binregister_right(&mut self, register: BinRegister, scratch_track: ScratchTrack) {
         self.shift_register_right(register.as_register(), scratch_track);
     }
 
+    /// Shifts right by one, replicating the sign bit into the vacated top position
+    pub fn arith_shift_binregister_right(
+        &mut self,
+        register: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        if register.size == 1 {
+            return;
+        }
+        self.shift_binregister_right(register, scratch_track);
+        self.copy_byte_autoscratch(register.at(1), register.at(0), scratch_track);
+    }
+
+    /// Shifts left by a constant number of positions, filling with zeros.
+    /// Shifting by at least the size of the register clears it
+    pub fn shift_register_left_by(
+        &mut self,
+        register: Register,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        assert!(by >= 0);
+        if by == 0 {
+            return;
+        }
+        if by >= register.size {
+            self.clr_register(register, scratch_track);
+            return;
+        }
+        self.clr_register(register.subview(0, by), scratch_track);
+        self.foreach_pos_of_register(
+            register.subview(by, register.size - by),
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(-by));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+
+    /// Shifts right by a constant number of positions, filling with zeros.
+    /// Shifting by at least the size of the register clears it
+    pub fn shift_register_right_by(
+        &mut self,
+        register: Register,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        assert!(by >= 0);
+        if by == 0 {
+            return;
+        }
+        if by >= register.size {
+            self.clr_register(register, scratch_track);
+            return;
+        }
+        self.clr_register(register.subview(register.size - by, by), scratch_track);
+        self.foreach_pos_of_register_rev(
+            register.subview(0, register.size - by),
+            scratch_track,
+            None::<fn(&mut Cpu, ScratchTrack)>,
+            |cpu, pos, _| {
+                cpu.moveadd_byte(pos, pos.get_shifted(by));
+            },
+            None::<fn(&mut Cpu, ScratchTrack)>,
+        );
+    }
+
+    pub fn shift_binregister_left_by(
+        &mut self,
+        register: BinRegister,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        self.shift_register_left_by(register.as_register(), by, scratch_track);
+    }
+
+    pub fn shift_binregister_right_by(
+        &mut self,
+        register: BinRegister,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        self.shift_register_right_by(register.as_register(), by, scratch_track);
+    }
+
+    /// Rotates left by a constant number of positions, wrapping the high bits around
+    pub fn rotate_binregister_left(
+        &mut self,
+        register: BinRegister,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        let by = by.rem_euclid(register.size);
+        if by == 0 {
+            return;
+        }
+        let (high_bits, scratch_track) = scratch_track.split_binregister(by);
+        self.copy_binregister(register.subview(0, by), high_bits, scratch_track, false);
+        self.shift_binregister_left_by(register, by, scratch_track);
+        self.copy_binregister(high_bits, register.subview_tail(by), scratch_track, false);
+        self.clr_binregister(high_bits, scratch_track);
+    }
+
+    /// Rotates right by a constant number of positions, wrapping the low bits around
+    pub fn rotate_binregister_right(
+        &mut self,
+        register: BinRegister,
+        by: isize,
+        scratch_track: ScratchTrack,
+    ) {
+        let by = by.rem_euclid(register.size);
+        self.rotate_binregister_left(register, register.size - by, scratch_track);
+    }
+
     pub fn copy_binregister(
         &mut self,
         from: BinRegister,
@@ -2064,25 +2759,14 @@ impl<'c> Cpu<'c> {
                     b.at(0),
                     scratch_track,
                     |cpu, scratch_track| {
-                        // both negative
+                        // both negative, which in two's complement compare like the
+                        // remaining bits do
                         cpu.cmp_2_uint_binregisters(
                             a.subview(1, a.size - 1),
                             b.subview(1, b.size - 1),
                             cmp_result,
                             scratch_track,
                         );
-                        // invert cmp_result
-                        cpu.match_cmp_result(
-                            cmp_result,
-                            scratch_track,
-                            |cpu, _| {
-                                cpu.add_const_to_byte(cmp_result, 2);
-                            },
-                            |_, _| {},
-                            |cpu, _| {
-                                cpu.sub_const_from_byte(cmp_result, 2);
-                            },
-                        );
                     },
                     |cpu, _| {
                         cpu.dec_at(cmp_result);
@@ -2164,6 +2848,105 @@ impl<'c> Cpu<'c> {
         }
     }
 
+    /// Sets `out` (initially 0) to 1 if the unsigned comparison of a and b comes out as one of
+    /// the orderings flagged in `[lt, eq, gt]`, and leaves it at 0 otherwise
+    fn cmp_2_uint_binregisters_to_bool(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        [lt, eq, gt]: [bool; 3],
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        let (cmp_result, scratch_track) = scratch_track.split_1();
+        self.cmp_2_uint_binregisters(a, b, cmp_result, scratch_track);
+        self.move_match_cmp_result(
+            cmp_result,
+            scratch_track,
+            |cpu, _| {
+                if lt {
+                    cpu.inc_at(out);
+                }
+            },
+            |cpu, _| {
+                if eq {
+                    cpu.inc_at(out);
+                }
+            },
+            |cpu, _| {
+                if gt {
+                    cpu.inc_at(out);
+                }
+            },
+        );
+    }
+
+    /// Sets `out` (initially 0) to 1 if a == b
+    pub fn is_eq_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [false, true, false], out, scratch_track);
+    }
+
+    /// Sets `out` (initially 0) to 1 if a != b
+    pub fn is_ne_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [true, false, true], out, scratch_track);
+    }
+
+    /// Sets `out` (initially 0) to 1 if a < b, comparing unsigned
+    pub fn is_lt_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [true, false, false], out, scratch_track);
+    }
+
+    /// Sets `out` (initially 0) to 1 if a <= b, comparing unsigned
+    pub fn is_le_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [true, true, false], out, scratch_track);
+    }
+
+    /// Sets `out` (initially 0) to 1 if a > b, comparing unsigned
+    pub fn is_gt_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [false, false, true], out, scratch_track);
+    }
+
+    /// Sets `out` (initially 0) to 1 if a >= b, comparing unsigned
+    pub fn is_ge_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: Pos,
+        scratch_track: ScratchTrack,
+    ) {
+        self.cmp_2_uint_binregisters_to_bool(a, b, [false, true, true], out, scratch_track);
+    }
+
     /// We write -1 if a < b, 0 if a = b, and 1 if a > b to cmp_result.
     /// Initially cmp_result should be 0.
     pub fn cmp_2_u8s(&mut self, a: Pos, b: Pos, cmp_result: Pos, scratch_track: ScratchTrack) {
@@ -2225,6 +3008,59 @@ impl<'c> Cpu<'c> {
         }
     }
 
+    /// out = min(a, b), comparing as unsigned. out may alias a or b
+    pub fn min_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        self.select_binregister_by_cmp(a, b, out, false, scratch_track);
+    }
+
+    /// out = max(a, b), comparing as unsigned. out may alias a or b
+    pub fn max_binregister(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        self.select_binregister_by_cmp(a, b, out, true, scratch_track);
+    }
+
+    fn select_binregister_by_cmp(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
+        select_greater: bool,
+        scratch_track: ScratchTrack,
+    ) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(out.size, a.size);
+        let (cmp_result, scratch_track) = scratch_track.split_1();
+        let (result, scratch_track) = scratch_track.split_binregister(a.size);
+        let (if_lt, if_gt) = if select_greater { (b, a) } else { (a, b) };
+        self.cmp_2_uint_binregisters(a, b, cmp_result, scratch_track);
+        self.move_match_cmp_result(
+            cmp_result,
+            scratch_track,
+            |cpu, scratch_track| {
+                cpu.copy_binregister(if_lt, result, scratch_track, false);
+            },
+            |cpu, scratch_track| {
+                cpu.copy_binregister(a, result, scratch_track, false);
+            },
+            |cpu, scratch_track| {
+                cpu.copy_binregister(if_gt, result, scratch_track, false);
+            },
+        );
+        self.copy_binregister(result, out, scratch_track, true);
+        self.clr_binregister(result, scratch_track);
+    }
+
     /// Adds a*b to out
     pub fn mul_binregisters(
         &mut self,
@@ -2242,6 +3078,42 @@ impl<'c> Cpu<'c> {
             });
             cpu.shift_binregister_left(a_shifted, scratch_track);
         });
+        if b.size < a.size {
+            self.clr_binregister(a_shifted, scratch_track);
+        }
+    }
+
+    /// Sets out to base^exp, truncated to the size of out
+    pub fn pow_binregister(
+        &mut self,
+        base: BinRegister,
+        exp: BinRegister,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        self.clr_binregister(out, scratch_track);
+        self.inc_at(out.last_pos());
+        let (square, scratch_track) = scratch_track.split_binregister(out.size);
+        let (prod, scratch_track) = scratch_track.split_binregister(out.size);
+        // only the low bits of base can affect the truncated result
+        let n = base.size.min(out.size);
+        self.copy_binregister(
+            base.subview(base.size - n, n),
+            square.subview(out.size - n, n),
+            scratch_track,
+            false,
+        );
+        self.foreach_val_of_binregister_rev(exp, scratch_track, |cpu, val, scratch_track| {
+            cpu.if_nonzero(val, scratch_track, |cpu, scratch_track| {
+                cpu.mul_binregisters(out, square, prod, scratch_track);
+                cpu.copy_binregister(prod, out, scratch_track, true);
+                cpu.clr_binregister(prod, scratch_track);
+            });
+            cpu.mul_binregisters(square, square, prod, scratch_track);
+            cpu.copy_binregister(prod, square, scratch_track, true);
+            cpu.clr_binregister(prod, scratch_track);
+        });
+        self.clr_binregister(square, scratch_track);
     }
 
     /// Adds a/b to div and rem
@@ -2357,42 +3229,174 @@ impl<'c> Cpu<'c> {
         self.clr_binregister(b_shifted, scratch_track);
     }
 
+    /// Adds x/m to div and x%m to rem, building the constant m only once
+    pub fn divmod_binregister_by_const(
+        &mut self,
+        x: BinRegister,
+        m: u32,
+        div: BinRegister,
+        rem: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        assert_ne!(m, 0, "Division by zero");
+        let (m_reg, scratch_track) =
+            scratch_track.split_binregister((32 - m.leading_zeros()) as isize);
+        self.set_binregister(m_reg, m, scratch_track);
+        self.div_binregisters(x, m_reg, div, rem, scratch_track);
+        self.clr_binregister(m_reg, scratch_track);
+    }
+
+    /// Adds x/m to out
+    pub fn div_binregister_by_const(
+        &mut self,
+        x: BinRegister,
+        m: u32,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        let (rem, scratch_track) = scratch_track.split_binregister(x.size);
+        self.divmod_binregister_by_const(x, m, out, rem, scratch_track);
+        self.clr_binregister(rem, scratch_track);
+    }
+
+    /// Adds x%m to out
+    pub fn mod_binregister_by_const(
+        &mut self,
+        x: BinRegister,
+        m: u32,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        let (div, scratch_track) = scratch_track.split_binregister(x.size);
+        self.divmod_binregister_by_const(x, m, div, out, scratch_track);
+        self.clr_binregister(div, scratch_track);
+    }
+
+    /// Adds a/b to div and rem, treating a and b as two's complement signed integers.
+    /// The quotient is rounded towards zero and the remainder has the sign of a
+    pub fn div_binregisters_signed(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        div: BinRegister,
+        rem: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(div.size, a.size);
+        assert_eq!(rem.size, a.size);
+        let ([a_neg, div_neg], scratch_track) = scratch_track.split_2();
+        let (a_abs, scratch_track) = scratch_track.split_binregister(a.size);
+        let (b_abs, scratch_track) = scratch_track.split_binregister(b.size);
+        let (div_abs, scratch_track) = scratch_track.split_binregister(a.size);
+        let (rem_abs, scratch_track) = scratch_track.split_binregister(a.size);
+        self.copy_byte_autoscratch(a.at(0), a_neg, scratch_track);
+        self.copy_byte_autoscratch(a.at(0), div_neg, scratch_track);
+        self.if_nonzero(b.at(0), scratch_track, |cpu, scratch_track| {
+            cpu.not(div_neg, scratch_track);
+        });
+        self.copy_binregister(a, a_abs, scratch_track, false);
+        self.copy_binregister(b, b_abs, scratch_track, false);
+        self.if_nonzero(a_neg, scratch_track, |cpu, scratch_track| {
+            cpu.neg_binregister(a_abs, scratch_track);
+        });
+        self.if_nonzero(b.at(0), scratch_track, |cpu, scratch_track| {
+            cpu.neg_binregister(b_abs, scratch_track);
+        });
+        self.div_binregisters(a_abs, b_abs, div_abs, rem_abs, scratch_track);
+        self.if_nonzero(div_neg, scratch_track, |cpu, scratch_track| {
+            cpu.neg_binregister(div_abs, scratch_track);
+        });
+        self.if_nonzero(a_neg, scratch_track, |cpu, scratch_track| {
+            cpu.neg_binregister(rem_abs, scratch_track);
+        });
+        self.add_binregister_to_binregister(div_abs, div, scratch_track);
+        self.add_binregister_to_binregister(rem_abs, rem, scratch_track);
+        self.clr_at(a_neg);
+        self.clr_at(div_neg);
+        self.clr_binregister(a_abs, scratch_track);
+        self.clr_binregister(b_abs, scratch_track);
+        self.clr_binregister(div_abs, scratch_track);
+        self.clr_binregister(rem_abs, scratch_track);
+    }
+
+    /// Adds gcd(a, b) to out, using the Euclidean algorithm
+    pub fn gcd_binregisters(
+        &mut self,
+        a: BinRegister,
+        b: BinRegister,
+        out: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        assert_eq!(a.size, b.size);
+        assert_eq!(out.size, a.size);
+        let (keep_going, scratch_track) = scratch_track.split_1();
+        let (x, scratch_track) = scratch_track.split_binregister(a.size);
+        let (y, scratch_track) = scratch_track.split_binregister(a.size);
+        self.copy_binregister(a, x, scratch_track, false);
+        self.copy_binregister(b, y, scratch_track, false);
+        self.inc_at(keep_going);
+        self.loop_while(keep_going, |cpu| {
+            cpu.dec();
+            cpu.if_binregister_nonzero_else(
+                y,
+                scratch_track,
+                |cpu, scratch_track| {
+                    cpu.inc_at(keep_going);
+                    let (div, scratch_track) = scratch_track.split_binregister(a.size);
+                    let (rem, scratch_track) = scratch_track.split_binregister(a.size);
+                    cpu.div_binregisters(x, y, div, rem, scratch_track);
+                    cpu.clr_binregister(div, scratch_track);
+                    cpu.copy_binregister(y, x, scratch_track, true);
+                    cpu.copy_binregister(rem, y, scratch_track, true);
+                    cpu.clr_binregister(rem, scratch_track);
+                },
+                |_, _| {},
+            );
+        });
+        self.add_binregister_to_binregister(x, out, scratch_track);
+        self.clr_binregister(x, scratch_track);
+    }
+
     pub fn print_binregister_in_decimal(&mut self, x: BinRegister, scratch_track: ScratchTrack) {
+        self.print_binregister_in_base(x, 10, scratch_track);
+    }
+
+    /// Prints x without leading zeros in the given base, using the digits 0-9a-f
+    pub fn print_binregister_in_base(
+        &mut self,
+        x: BinRegister,
+        base: u8,
+        scratch_track: ScratchTrack,
+    ) {
+        assert!((2..=16).contains(&base), "Base must be between 2 and 16");
+        let rem_bits = (8 - (base - 1).leading_zeros()) as isize;
         let (continue_byte, scratch_track1) = scratch_track.split_1();
         self.inc_at(continue_byte);
         let (x_copy, scratch_track1) = scratch_track1.split_binregister(x.size);
         self.add_binregister_to_binregister(x, x_copy, scratch_track1);
-        let out_store_size = (x.size as f64 / std::f64::consts::LOG2_10).ceil() as isize + 1;
+        let out_store_size = (x.size as f64 / (base as f64).log2()).ceil() as isize + 1;
         let (out_store, scratch_track1) = scratch_track1.split_register(out_store_size);
         self.loop_while(continue_byte, |cpu| {
             cpu.shift_register_left(out_store, scratch_track1);
             let out = out_store.last_pos();
-            cpu.add_const_to_byte(out, b'0');
             let (rem, scratch_track2) = scratch_track1.split_binregister(x.size);
             let (div, scratch_track3) = scratch_track2.split_binregister(x.size);
-            let (ten, scratch_track4) = scratch_track3.split_binregister(4);
-            cpu.inc_at(ten.at(ten.size - 2));
-            cpu.inc_at(ten.at(ten.size - 4));
-            cpu.div_binregisters(x_copy, ten, div, rem, scratch_track4);
-            cpu.clr_binregister(ten, scratch_track4);
+            cpu.divmod_binregister_by_const(x_copy, base as u32, div, rem, scratch_track3);
             cpu.copy_binregister(div, x_copy, scratch_track3, true);
             cpu.clr_binregister(div, scratch_track3);
-            cpu.if_nonzero(rem.at(x.size - 1), scratch_track2, |cpu, _| {
-                cpu.clr_at(rem.at(x.size - 1));
-                cpu.add_const_to_byte(out, 1);
-            });
-            cpu.if_nonzero(rem.at(x.size - 2), scratch_track2, |cpu, _| {
-                cpu.clr_at(rem.at(x.size - 2));
-                cpu.add_const_to_byte(out, 2);
-            });
-            cpu.if_nonzero(rem.at(x.size - 3), scratch_track2, |cpu, _| {
-                cpu.clr_at(rem.at(x.size - 3));
-                cpu.add_const_to_byte(out, 4);
-            });
-            cpu.if_nonzero(rem.at(x.size - 4), scratch_track2, |cpu, _| {
-                cpu.clr_at(rem.at(x.size - 4));
-                cpu.add_const_to_byte(out, 8);
-            });
+            for i in 0..rem_bits.min(x.size) {
+                let bit = rem.at(x.size - 1 - i);
+                cpu.if_nonzero(bit, scratch_track2, |cpu, _| {
+                    cpu.clr_at(bit);
+                    cpu.add_const_to_byte(out, 1 << i);
+                });
+            }
+            if base > 10 {
+                cpu.add_digit_char_offset(out, b'a', scratch_track2);
+            } else {
+                cpu.add_const_to_byte(out, b'0');
+            }
             cpu.if_binregister_nonzero_else(
                 x_copy,
                 scratch_track1,
@@ -2417,6 +3421,71 @@ impl<'c> Cpu<'c> {
         );
     }
 
+    /// Prints x as a two's complement signed integer
+    pub fn print_binregister_in_signed_decimal(
+        &mut self,
+        x: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        let (x_abs, scratch_track) = scratch_track.split_binregister(x.size);
+        self.copy_binregister(x, x_abs, scratch_track, false);
+        self.if_nonzero(x.at(0), scratch_track, |cpu, scratch_track| {
+            cpu.print_text("-", scratch_track);
+            // the most negative value negates to itself, which is still correct when
+            // read back as unsigned
+            cpu.neg_binregister(x_abs, scratch_track);
+        });
+        self.print_binregister_in_decimal(x_abs, scratch_track);
+        self.clr_binregister(x_abs, scratch_track);
+    }
+
+    /// Reads an unsigned decimal number from stdin into register, which should start at zero:
+    /// every digit sets register to register * 10 + digit. Reading stops at the first byte
+    /// that isn't a digit (e.g. a newline or EOF), which is consumed.
+    pub fn read_binregister_in_decimal(
+        &mut self,
+        register: BinRegister,
+        scratch_track: ScratchTrack,
+    ) {
+        let ([keep_going, c, c_cpy, is_digit], scratch_track) = scratch_track.split_4();
+        let (ten, scratch_track) = scratch_track.split_binregister(4);
+        let (times_ten, scratch_track) = scratch_track.split_binregister(register.size);
+        self.inc_at(keep_going);
+        self.loop_while(keep_going, |cpu| {
+            cpu.goto(c);
+            cpu.read_stdin();
+            cpu.sub_const_from_byte(c, b'0');
+            cpu.copy_byte_autoscratch(c, c_cpy, scratch_track);
+            for _ in 0..10 {
+                cpu.if_zero(c_cpy, scratch_track, |cpu, _| {
+                    cpu.inc_at(is_digit);
+                });
+                cpu.dec_at(c_cpy);
+            }
+            cpu.clr_at(c_cpy);
+            cpu.if_nonzero_else(
+                is_digit,
+                scratch_track,
+                |cpu, scratch_track| {
+                    cpu.set_binregister(ten, 10u64, scratch_track);
+                    cpu.mul_binregisters(register, ten, times_ten, scratch_track);
+                    cpu.clr_binregister(ten, scratch_track);
+                    cpu.loop_while(c, |cpu| {
+                        cpu.dec();
+                        cpu.inc_binregister(times_ten, scratch_track);
+                    });
+                    cpu.copy_binregister(times_ten, register, scratch_track, true);
+                    cpu.clr_binregister(times_ten, scratch_track);
+                },
+                |cpu, _| {
+                    cpu.clr_at(c);
+                    cpu.dec_at(keep_going);
+                },
+            );
+            cpu.clr_at(is_digit);
+        });
+    }
+
     /*/// b -= a
     /// carry = 1 if b < a
     pub fn movesub_byte_with_carry(&mut self, a: Pos, b: Pos, carry: Pos, scratch: Pos) {
@@ -1,31 +1,39 @@
-use nom::character::complete::digit1;
+use crate::bf::TextPos;
+use nom::character::complete::{digit1, hex_digit1};
 #[allow(unused_imports)]
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag, take, take_while},
+    bytes::complete::{escaped, tag, take, take_while, take_while1},
     character::complete::{alphanumeric1 as alphanumeric, anychar, none_of, one_of},
-    combinator::{complete, map, opt},
+    combinator::{complete, cut, map, not, opt, recognize, verify},
     error::{context, convert_error, ErrorKind, ParseError, VerboseError},
-    multi::{fold_many1, many0, many1, separated_list},
+    multi::{fold_many0, many0, many1, separated_list},
     number::complete::double,
     sequence::{delimited, preceded, separated_pair, terminated},
     Err, IResult,
 };
 use num::BigUint;
 use num::Num;
+use num::ToPrimitive;
+use serde::{Serialize, Serializer};
 use std::collections::BTreeMap;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
 pub enum BinOpKind {
     Plus,
     Minus,
     Mul,
     Div,
     Mod,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
     Cmp(CmpKind),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
 pub enum CmpKind {
     GT,
     GE,
@@ -35,86 +43,158 @@ pub enum CmpKind {
     NE,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct BinOp {
     pub args: Box<(Expr, Expr)>,
     pub kind: BinOpKind,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum UnaryOpKind {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UnaryOp {
+    pub arg: Box<Expr>,
+    pub kind: UnaryOpKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct FnCall {
     pub fn_name: String,
     pub args: Vec<Expr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum Expr {
-    Literal(BigUint),
+    Literal(#[serde(serialize_with = "serialize_biguint")] BigUint),
     StringLiteral(String),
     VarRef(String),
     BinOp(BinOp),
+    UnaryOp(UnaryOp),
     FnCall(FnCall),
     Scope(Scope),
     IfElse(Box<IfElse>),
+    Match(Box<Match>),
     Deref(Box<Expr>),
     AddressOf(String),
+    Index(String, Box<Expr>),
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub enum VarType {
     Unit,
     U8,
+    Char,
     Bool,
+    U16,
     U32,
+    I32,
+    U64,
     StringLiteral,
     PtrTo(Box<VarType>),
+    Array(Box<VarType>, u32),
 }
 
-#[derive(Debug, Clone)]
+impl VarType {
+    /// Size in bytes, or None for an array that doesn't fit in a u32 (`parse_hir` rejects those)
+    pub fn size(&self) -> Option<u32> {
+        Some(match self {
+            VarType::U8 => 1,
+            VarType::Char => 1,
+            VarType::Bool => 1,
+            VarType::U16 => 2,
+            VarType::U32 => 4,
+            VarType::I32 => 4,
+            VarType::U64 => 8,
+            VarType::Unit => 0,
+            VarType::StringLiteral => 0,
+            VarType::PtrTo(_) => 4,
+            VarType::Array(elem_typ, len) => elem_typ.size()?.checked_mul(*len)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct VarDecl {
     pub var_name: String,
     pub typ: VarType,
-    pub init: Expr,
+    pub init: Option<Expr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct VarAssign {
     pub lhs: Expr,
     pub expr: Expr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Scope {
     pub stmts: Vec<Stmt>,
+    /// 1-based source line of each statement in `stmts`
+    #[serde(skip)]
+    pub stmt_lines: Vec<usize>,
+    /// Where each statement starts while parsing, `parse_hir` turns these into `stmt_lines`
+    #[serde(skip)]
+    stmt_starts: Vec<RestLen>,
     pub final_expr: Option<Box<Expr>>,
 }
 
-#[derive(Debug, Clone)]
+/// Length of the input left at some point during parsing, nom only gives us the rest
+#[derive(Debug, Copy, Clone)]
+struct RestLen(usize);
+
+#[derive(Debug, Clone, Serialize)]
 pub struct IfMaybeElse {
     pub cond: Expr,
     pub if_true: Expr,
     pub if_false: Option<Expr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct IfElse {
     pub cond: Expr,
     pub if_true: Expr,
     pub if_false: Expr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+pub struct MatchArm {
+    #[serde(serialize_with = "serialize_biguint")]
+    pub value: BigUint,
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Match {
+    pub scrutinee: Expr,
+    pub arms: Vec<MatchArm>,
+    pub default: Expr,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ReturnStmt {
     pub expr: Option<Expr>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct WhileLoop {
     pub cond: Expr,
     pub inner: Expr,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+pub struct ForLoop {
+    pub var_name: String,
+    pub typ: Option<VarType>,
+    pub start: Expr,
+    pub end: Expr,
+    pub inner: Expr,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub enum Stmt {
     Expr(Expr),
     VarDecl(VarDecl),
@@ -122,15 +202,16 @@ pub enum Stmt {
     IfMaybeElse(IfMaybeElse),
     Return(ReturnStmt),
     WhileLoop(WhileLoop),
+    ForLoop(ForLoop),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FnArgDecl {
     pub name: String,
     pub typ: VarType,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FnDecl {
     pub name: String,
     pub args: Vec<FnArgDecl>,
@@ -138,54 +219,252 @@ pub struct FnDecl {
     pub scope: Scope,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
+pub struct ConstDecl {
+    pub name: String,
+    pub typ: VarType,
+    #[serde(serialize_with = "serialize_biguint")]
+    pub value: BigUint,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Program {
     pub fns: BTreeMap<String, FnDecl>,
+    pub consts: BTreeMap<String, ConstDecl>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HirParseError {
+    pub pos: TextPos,
+    pub message: String,
+}
+
+impl std::fmt::Display for HirParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Parse error at {}: {}", self.pos, self.message)
+    }
+}
+
+impl HirParseError {
+    fn from_nom(src: &str, err: nom::Err<VerboseError<&str>>) -> HirParseError {
+        let e = match err {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => {
+                return HirParseError {
+                    pos: TextPos::from_offset(src, src.len()),
+                    message: "unexpected end of input".to_owned(),
+                }
+            }
+        };
+        // the first entry is where parsing stopped, contexts further down say what was expected
+        let rest = e.errors.first().map_or("", |(rest, _)| *rest);
+        let message = e
+            .errors
+            .iter()
+            .find_map(|(_, kind)| match kind {
+                nom::error::VerboseErrorKind::Context(ctx) => Some(ctx.to_string()),
+                _ => None,
+            })
+            .unwrap_or_else(|| match rest.chars().next() {
+                Some(c) => format!("unexpected '{}'", c),
+                None => "unexpected end of input".to_owned(),
+            });
+        HirParseError {
+            pos: TextPos::from_offset(src, src.len() - rest.len()),
+            message,
+        }
+    }
 }
 
-pub fn parse_hir(i: &str) -> Result<Program, nom::Err<VerboseError<&str>>> {
-    let (i, stmts) = program::<VerboseError<&str>>(i)?;
-    let (i, _) = ws::<VerboseError<&str>>(i)?;
+pub fn parse_hir(src: &str) -> Result<Program, HirParseError> {
+    let (i, stmts) =
+        program::<VerboseError<&str>>(src).map_err(|e| HirParseError::from_nom(src, e))?;
+    let (i, _) = ws::<VerboseError<&str>>(i).map_err(|e| HirParseError::from_nom(src, e))?;
     if i.len() > 0 {
-        Err(nom::Err::Failure(VerboseError::from_error_kind(
-            i,
-            nom::error::ErrorKind::Complete,
-        )))
+        Err(HirParseError {
+            pos: TextPos::from_offset(src, src.len() - i.len()),
+            message: "expected a function or constant declaration".to_owned(),
+        })
     } else {
-        Ok(stmts)
+        let mut program = stmts;
+        for f in program.fns.values_mut() {
+            resolve_lines_in_scope(src, &mut f.scope);
+        }
+        Ok(program)
+    }
+}
+
+fn resolve_lines_in_scope(src: &str, scope: &mut Scope) {
+    scope.stmt_lines = std::mem::take(&mut scope.stmt_starts)
+        .into_iter()
+        .map(|RestLen(len)| TextPos::from_offset(src, src.len() - len).line_num)
+        .collect();
+    for stmt in &mut scope.stmts {
+        match stmt {
+            Stmt::Expr(e) => resolve_lines_in_expr(src, e),
+            Stmt::VarDecl(d) => {
+                if let Some(init) = &mut d.init {
+                    resolve_lines_in_expr(src, init);
+                }
+            }
+            Stmt::VarAssign(a) => {
+                resolve_lines_in_expr(src, &mut a.lhs);
+                resolve_lines_in_expr(src, &mut a.expr);
+            }
+            Stmt::IfMaybeElse(i) => {
+                resolve_lines_in_expr(src, &mut i.cond);
+                resolve_lines_in_expr(src, &mut i.if_true);
+                if let Some(if_false) = &mut i.if_false {
+                    resolve_lines_in_expr(src, if_false);
+                }
+            }
+            Stmt::Return(r) => {
+                if let Some(e) = &mut r.expr {
+                    resolve_lines_in_expr(src, e);
+                }
+            }
+            Stmt::WhileLoop(w) => {
+                resolve_lines_in_expr(src, &mut w.cond);
+                resolve_lines_in_expr(src, &mut w.inner);
+            }
+            Stmt::ForLoop(f) => {
+                resolve_lines_in_expr(src, &mut f.start);
+                resolve_lines_in_expr(src, &mut f.end);
+                resolve_lines_in_expr(src, &mut f.inner);
+            }
+        }
+    }
+    if let Some(final_expr) = &mut scope.final_expr {
+        resolve_lines_in_expr(src, final_expr);
+    }
+}
+
+fn resolve_lines_in_expr(src: &str, expr: &mut Expr) {
+    match expr {
+        Expr::Literal(_) | Expr::StringLiteral(_) | Expr::VarRef(_) | Expr::AddressOf(_) => {}
+        Expr::BinOp(b) => {
+            resolve_lines_in_expr(src, &mut b.args.0);
+            resolve_lines_in_expr(src, &mut b.args.1);
+        }
+        Expr::UnaryOp(u) => resolve_lines_in_expr(src, &mut u.arg),
+        Expr::FnCall(f) => {
+            for arg in &mut f.args {
+                resolve_lines_in_expr(src, arg);
+            }
+        }
+        Expr::Scope(s) => resolve_lines_in_scope(src, s),
+        Expr::IfElse(i) => {
+            resolve_lines_in_expr(src, &mut i.cond);
+            resolve_lines_in_expr(src, &mut i.if_true);
+            resolve_lines_in_expr(src, &mut i.if_false);
+        }
+        Expr::Match(m) => {
+            resolve_lines_in_expr(src, &mut m.scrutinee);
+            for arm in &mut m.arms {
+                resolve_lines_in_expr(src, &mut arm.expr);
+            }
+            resolve_lines_in_expr(src, &mut m.default);
+        }
+        Expr::Deref(e) => resolve_lines_in_expr(src, e),
+        Expr::Index(_, e) => resolve_lines_in_expr(src, e),
     }
 }
 
+/// Parses a program and returns its AST as JSON
+pub fn parse_hir_to_json(src: &str) -> Result<String, HirParseError> {
+    let program = parse_hir(src)?;
+    Ok(serde_json::to_string(&program).expect("the AST always serializes"))
+}
+
+/// Writes a BigUint as its decimal digits, it can be too big for a JSON number
+fn serialize_biguint<S: Serializer>(n: &BigUint, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(n)
+}
+
+/// Skips whitespace and `//` line comments
 fn ws<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
     let chars = " \t\r\n";
-    take_while(move |c| chars.contains(c))(i)
+    recognize(fold_many0(
+        alt((
+            take_while1(move |c| chars.contains(c)),
+            recognize(preceded(tag("//"), take_while(|c| c != '\n'))),
+        )),
+        (),
+        |_, _| (),
+    ))(i)
 }
 
 fn str_literal<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
     let (i, _) = ws(i)?;
-    Ok(delimited(
+    delimited(
         tag("\""),
         escaped(none_of("\\\""), '\\', one_of(r#""n\"#)),
         tag("\""),
-    )(i)?)
+    )(i)
+}
+
+fn char_literal<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, u8, E> {
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("'")(i)?;
+    let (i, c) = alt((
+        map(tag("\\n"), |_| b'\n'),
+        map(tag("\\t"), |_| b'\t'),
+        map(tag("\\0"), |_| 0),
+        map(tag("\\'"), |_| b'\''),
+        map(tag("\\\\"), |_| b'\\'),
+        map(verify(none_of("\\'"), char::is_ascii), |c| c as u8),
+    ))(i)?;
+    let (i, _) = tag("'")(i)?;
+    Ok((i, c))
 }
 
 fn biguint<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BigUint, E> {
     let (i, _) = ws(i)?;
-    map(digit1, |s| {
-        Num::from_str_radix(s, 10).expect("nom::digit matched a non-int?")
-    })(i)
+    alt((
+        map(preceded(tag("0x"), hex_digit1), |s| {
+            Num::from_str_radix(s, 16).expect("nom::hex_digit matched a non-int?")
+        }),
+        map(
+            preceded(tag("0b"), take_while1(|c| c == '0' || c == '1')),
+            |s| Num::from_str_radix(s, 2).expect("matched a non-binary int?"),
+        ),
+        map(digit1, |s| {
+            Num::from_str_radix(s, 10).expect("nom::digit matched a non-int?")
+        }),
+    ))(i)
+}
+
+fn unary_op<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, UnaryOp, E> {
+    let (i, _) = ws(i)?;
+    let (i, kind) = alt((tag("-"), tag("!")))(i)?;
+    let (i, arg) = factor(i)?;
+    let kind = match kind {
+        "-" => UnaryOpKind::Neg,
+        "!" => UnaryOpKind::Not,
+        _ => unreachable!(),
+    };
+    Ok((
+        i,
+        UnaryOp {
+            arg: Box::new(arg),
+            kind,
+        },
+    ))
 }
 
 fn factor<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
     alt((
         map(bracketed_expr, |e| e),
         map(biguint, |u| Expr::Literal(u)),
+        map(unary_op, |u| Expr::UnaryOp(u)),
         map(str_literal, |s| Expr::StringLiteral(s.to_owned())),
+        map(char_literal, |c| Expr::Literal(BigUint::from(c))),
         map(if_else, |i| Expr::IfElse(Box::new(i))),
+        map(match_expr, |m| Expr::Match(Box::new(m))),
         map(fncall, |c| Expr::FnCall(c)),
         map(address_of, |s| Expr::AddressOf(s.to_owned())),
         map(deref, |e| e),
+        map(index, |(s, e)| Expr::Index(s.to_owned(), Box::new(e))),
         map(ident, |s| {
             if s == "true" {
                 Expr::Literal(BigUint::from(1u64))
@@ -229,10 +508,10 @@ fn cmp_term<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E>
     let (i, _) = ws(i)?;
     let (i, kind) = opt(alt((
         tag(">="),
-        tag(">"),
+        terminated(tag(">"), not(tag(">"))),
         tag("=="),
         tag("<="),
-        tag("<"),
+        terminated(tag("<"), not(tag("<"))),
         tag("!="),
     )))(i)?;
     let (i, _) = ws(i)?;
@@ -269,7 +548,7 @@ fn bracketed_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Ex
     Ok((i, e))
 }
 
-fn unbracketed_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+fn sum_term<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
     let (i, a) = cmp_term(i)?;
     let (i, _) = ws(i)?;
     let (i, kind) = opt(alt((tag("+"), tag("-"))))(i)?;
@@ -294,6 +573,49 @@ fn unbracketed_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str,
     }
 }
 
+/// Parses `sub`, optionally followed by one of the operators in `ops` and another `sub`
+fn binop_term<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+    sub: fn(&'a str) -> IResult<&'a str, Expr, E>,
+    ops: &[(&'static str, BinOpKind)],
+) -> IResult<&'a str, Expr, E> {
+    let (i, a) = sub(i)?;
+    let (i, _) = ws(i)?;
+    for (op, kind) in ops {
+        if let Ok((i, _)) = tag::<_, _, E>(*op)(i) {
+            let (i, b) = sub(i)?;
+            return Ok((
+                i,
+                Expr::BinOp(BinOp {
+                    args: Box::new((a, b)),
+                    kind: *kind,
+                }),
+            ));
+        }
+    }
+    Ok((i, a))
+}
+
+fn shift_term<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+    binop_term(
+        i,
+        sum_term,
+        &[("<<", BinOpKind::Shl), (">>", BinOpKind::Shr)],
+    )
+}
+
+fn bitand_term<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+    binop_term(i, shift_term, &[("&", BinOpKind::BitAnd)])
+}
+
+fn bitxor_term<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+    binop_term(i, bitand_term, &[("^", BinOpKind::BitXor)])
+}
+
+fn unbracketed_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+    binop_term(i, bitxor_term, &[("|", BinOpKind::BitOr)])
+}
+
 fn expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
     alt((map(unbracketed_expr, |e| e), map(bracketed_expr, |e| e)))(i)
 }
@@ -330,6 +652,19 @@ fn deref<'a, E: nom::error::ParseError<&'a str>>(i: &'a str) -> IResult<&'a str,
     ))(i)
 }
 
+fn index<'a, E: nom::error::ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (&'a str, Expr), E> {
+    let (i, _) = ws(i)?;
+    let (i, array_name) = ident(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("[")(i)?;
+    let (i, index) = expr(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("]")(i)?;
+    Ok((i, (array_name, index)))
+}
+
 fn fncall<'a, E: nom::error::ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FnCall, E> {
     let (i, fn_name) = ident(i)?;
     let (i, _) = tag("(")(i)?;
@@ -354,18 +689,54 @@ fn type_name<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, VarType
             let (i, inner_typ) = type_name(i)?;
             return Ok((i, VarType::PtrTo(Box::new(inner_typ))));
         }
+        if first_char == '[' {
+            let (i, elem_typ) = type_name(i)?;
+            let (i, _) = ws(i)?;
+            let (i, _) = tag(";")(i)?;
+            let (i, _) = ws(i)?;
+            let len_start = i;
+            let (i, len) = digit1(i)?;
+            let (i, _) = ws(i)?;
+            let (i, _) = tag("]")(i)?;
+            let typ = len
+                .parse()
+                .ok()
+                .map(|len| VarType::Array(Box::new(elem_typ), len))
+                .filter(|typ| typ.size().is_some())
+                .ok_or_else(|| {
+                    Err::Failure(E::add_context(
+                        len_start,
+                        "array is too large",
+                        E::from_error_kind(len_start, ErrorKind::Verify),
+                    ))
+                })?;
+            return Ok((i, typ));
+        }
     }
     // no pointer (would've returned already otherwise):
+    let typ_start = i;
     let (i, typ) = ident(i)?;
     let typ = {
         if typ == "bool" {
             VarType::Bool
         } else if typ == "u8" {
             VarType::U8
+        } else if typ == "char" {
+            VarType::Char
+        } else if typ == "u16" {
+            VarType::U16
         } else if typ == "u32" {
             VarType::U32
+        } else if typ == "i32" {
+            VarType::I32
+        } else if typ == "u64" {
+            VarType::U64
         } else {
-            panic!("Unknown variable type")
+            return Err(Err::Failure(E::add_context(
+                typ_start,
+                "unknown type",
+                E::from_error_kind(typ_start, ErrorKind::Verify),
+            )));
         }
     };
     Ok((i, typ))
@@ -374,15 +745,18 @@ fn type_name<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, VarType
 fn scope<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Scope, E> {
     let (i, _) = ws(i)?;
     let (i, _) = tag("{")(i)?;
-    let (i, mut stmts) = many0(stmt)(i)?;
+    let (i, stmts_with_pos) = many0(stmt_pos(stmt))(i)?;
+    let (mut stmt_starts, mut stmts): (Vec<_>, Vec<_>) = stmts_with_pos.into_iter().unzip();
     let (i, final_expr) = opt(expr)(i)?;
     let (i, _) = ws(i)?;
-    let (i, _) = tag("}")(i)?;
+    let (i, _) = cut(context("expected '}'", tag("}")))(i)?;
     if final_expr.is_some() {
         Ok((
             i,
             Scope {
                 stmts,
+                stmt_lines: Vec::new(),
+                stmt_starts,
                 final_expr: final_expr.map(|e| Box::new(e)),
             },
         ))
@@ -399,10 +773,13 @@ fn scope<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Scope, E> {
         }
         if let Some(final_expr) = final_expr {
             stmts.pop().unwrap();
+            stmt_starts.pop().unwrap();
             Ok((
                 i,
                 Scope {
                     stmts,
+                    stmt_lines: Vec::new(),
+                    stmt_starts,
                     final_expr: Some(Box::new(final_expr)),
                 },
             ))
@@ -411,6 +788,8 @@ fn scope<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Scope, E> {
                 i,
                 Scope {
                     stmts,
+                    stmt_lines: Vec::new(),
+                    stmt_starts,
                     final_expr: final_expr.map(|e| Box::new(e)),
                 },
             ))
@@ -423,13 +802,37 @@ fn if_maybe_else<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, IfM
     let (i, _) = tag("if")(i)?;
     let (i, cond) = expr(i)?;
     let (i, if_true) = scope(i)?;
-    let (i, if_false) = opt(preceded(ws, preceded(tag("else"), scope)))(i)?;
+    let (i, if_false) = opt(preceded(
+        ws,
+        preceded(
+            tag("else"),
+            alt((
+                map(scope, |s| Expr::Scope(s)),
+                // `else if` is sugar for an else block holding just the inner if
+                map(stmt_pos(if_maybe_else), |(pos, inner)| {
+                    match inner.if_false {
+                        Some(if_false) => Expr::IfElse(Box::new(IfElse {
+                            cond: inner.cond,
+                            if_true: inner.if_true,
+                            if_false,
+                        })),
+                        None => Expr::Scope(Scope {
+                            stmts: vec![Stmt::IfMaybeElse(inner)],
+                            stmt_lines: Vec::new(),
+                            stmt_starts: vec![pos],
+                            final_expr: None,
+                        }),
+                    }
+                }),
+            )),
+        ),
+    ))(i)?;
     Ok((
         i,
         IfMaybeElse {
             cond,
             if_true: Expr::Scope(if_true),
-            if_false: if_false.map(|s| Expr::Scope(s)),
+            if_false,
         },
     ))
 }
@@ -441,13 +844,55 @@ fn if_else<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, IfElse, E
     let (i, if_true) = scope(i)?;
     let (i, _) = ws(i)?;
     let (i, _) = tag("else")(i)?;
-    let (i, if_false) = scope(i)?;
+    let (i, if_false) = alt((
+        map(scope, |s| Expr::Scope(s)),
+        map(if_else, |inner| Expr::IfElse(Box::new(inner))),
+    ))(i)?;
     Ok((
         i,
         IfElse {
             cond,
             if_true: Expr::Scope(if_true),
-            if_false: Expr::Scope(if_false),
+            if_false,
+        },
+    ))
+}
+
+fn match_arm_body<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Expr, E> {
+    let (i, body) = alt((map(scope, |s| Expr::Scope(s)), expr))(i)?;
+    let (i, _) = opt(preceded(ws, tag(",")))(i)?;
+    Ok((i, body))
+}
+
+fn match_arm<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, MatchArm, E> {
+    let (i, value) = biguint(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("=>")(i)?;
+    let (i, expr) = match_arm_body(i)?;
+    Ok((i, MatchArm { value, expr }))
+}
+
+fn match_expr<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Match, E> {
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("match")(i)?;
+    let (i, _) = take_while1(|c: char| c.is_whitespace())(i)?;
+    let (i, scrutinee) = expr(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("{")(i)?;
+    let (i, arms) = many0(match_arm)(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("_")(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("=>")(i)?;
+    let (i, default) = match_arm_body(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("}")(i)?;
+    Ok((
+        i,
+        Match {
+            scrutinee,
+            arms,
+            default,
         },
     ))
 }
@@ -466,6 +911,31 @@ fn while_loop<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, WhileL
     ))
 }
 
+fn for_loop<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, ForLoop, E> {
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("for")(i)?;
+    let (i, var_name) = ident(i)?;
+    let (i, _) = ws(i)?;
+    let (i, typ) = opt(preceded(tag(":"), type_name))(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("in")(i)?;
+    let (i, start) = expr(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("..")(i)?;
+    let (i, end) = expr(i)?;
+    let (i, inner) = scope(i)?;
+    Ok((
+        i,
+        ForLoop {
+            var_name: var_name.to_owned(),
+            typ,
+            start,
+            end,
+            inner: Expr::Scope(inner),
+        },
+    ))
+}
+
 fn var_decl<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, VarDecl, E> {
     let (i, _) = ws(i)?;
     let (i, _) = tag("let ")(i)?;
@@ -474,8 +944,7 @@ fn var_decl<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, VarDecl,
     let (i, _) = tag(":")(i)?;
     let (i, typ) = type_name(i)?;
     let (i, _) = ws(i)?;
-    let (i, _) = tag("=")(i)?;
-    let (i, init) = expr(i)?;
+    let (i, init) = opt(preceded(tag("="), expr))(i)?;
 
     Ok((
         i,
@@ -509,6 +978,7 @@ fn return_stmt<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Retur
 fn stmt<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Stmt, E> {
     if let Ok((i, stmt)) = alt::<_, _, E, _>((
         map(while_loop, |w| Stmt::WhileLoop(w)),
+        map(for_loop, |f| Stmt::ForLoop(f)),
         map(if_maybe_else, |i| Stmt::IfMaybeElse(i)),
     ))(i)
     {
@@ -526,6 +996,18 @@ fn stmt<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Stmt, E> {
     }
 }
 
+/// Wraps a statement parser to also return where the statement starts
+fn stmt_pos<'a, O, E: ParseError<&'a str>>(
+    f: impl Fn(&'a str) -> IResult<&'a str, O, E>,
+) -> impl Fn(&'a str) -> IResult<&'a str, (RestLen, O), E> {
+    move |i: &'a str| {
+        let (i, _) = ws(i)?;
+        let pos = RestLen(i.len());
+        let (i, o) = f(i)?;
+        Ok((i, (pos, o)))
+    }
+}
+
 fn fn_arg_decl<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FnArgDecl, E> {
     let (i, arg_name) = ident(i)?;
     let (i, _) = ws(i)?;
@@ -545,10 +1027,10 @@ fn fn_decl<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FnDecl, E
     let (i, _) = tag("fn")(i)?;
     let (i, fn_name) = ident(i)?;
     let (i, _) = ws(i)?;
-    let (i, _) = tag("(")(i)?;
+    let (i, _) = cut(context("expected '('", tag("(")))(i)?;
     let (i, args) = separated_list(preceded(ws, tag(",")), fn_arg_decl)(i)?;
     let (i, _) = ws(i)?;
-    let (i, _) = tag(")")(i)?;
+    let (i, _) = cut(context("expected ')'", tag(")")))(i)?;
     let (i, ret) = opt(preceded(preceded(ws, tag("->")), type_name))(i)?;
     let ret = ret.unwrap_or(VarType::Unit);
     let (i, scope) = scope(i)?;
@@ -563,13 +1045,118 @@ fn fn_decl<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FnDecl, E
     ))
 }
 
-fn program<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Program, E> {
-    let (i, fns) = fold_many1(fn_decl, BTreeMap::new(), |mut fns, new_fn| {
-        if fns.contains_key(&new_fn.name) {
-            panic!("Double definition for function");
+fn const_decl<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (&'a str, VarType, Expr), E> {
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("const ")(i)?;
+    let (i, name) = ident(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag(":")(i)?;
+    let (i, typ) = type_name(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag("=")(i)?;
+    let (i, init) = expr(i)?;
+    let (i, _) = ws(i)?;
+    let (i, _) = tag(";")(i)?;
+    Ok((i, (name, typ, init)))
+}
+
+/// Evaluates a constant's initializer, which may refer to previously declared constants
+fn eval_const_expr(
+    expr: &Expr,
+    consts: &BTreeMap<String, ConstDecl>,
+) -> Result<BigUint, &'static str> {
+    Ok(match expr {
+        Expr::Literal(val) => val.clone(),
+        Expr::VarRef(name) => consts.get(name).ok_or("unknown constant")?.value.clone(),
+        Expr::BinOp(binop) => {
+            let a = eval_const_expr(&binop.args.0, consts)?;
+            let b = eval_const_expr(&binop.args.1, consts)?;
+            match binop.kind {
+                BinOpKind::Plus => a + b,
+                BinOpKind::Minus => {
+                    if a < b {
+                        return Err("constant would be negative");
+                    }
+                    a - b
+                }
+                BinOpKind::Mul => a * b,
+                BinOpKind::Div | BinOpKind::Mod if b == BigUint::from(0u8) => {
+                    return Err("division by zero in constant");
+                }
+                BinOpKind::Div => a / b,
+                BinOpKind::Mod => a % b,
+                BinOpKind::BitAnd => a & b,
+                BinOpKind::BitOr => a | b,
+                BinOpKind::BitXor => a ^ b,
+                BinOpKind::Shl => a << b.to_usize().ok_or("shifting by too much")?,
+                BinOpKind::Shr => a >> b.to_usize().ok_or("shifting by too much")?,
+                BinOpKind::Cmp(kind) => {
+                    let result = match kind {
+                        CmpKind::GT => a > b,
+                        CmpKind::GE => a >= b,
+                        CmpKind::EQ => a == b,
+                        CmpKind::LT => a < b,
+                        CmpKind::LE => a <= b,
+                        CmpKind::NE => a != b,
+                    };
+                    BigUint::from(result as u8)
+                }
+            }
         }
-        fns.insert(new_fn.name.clone(), new_fn);
-        fns
-    })(i)?;
-    Ok((i, Program { fns }))
+        _ => return Err("can't be evaluated at compile time"),
+    })
+}
+
+enum Item<'a> {
+    Fn(FnDecl),
+    Const((&'a str, VarType, Expr)),
+}
+
+fn program<'a, E: ParseError<&'a str>>(mut i: &'a str) -> IResult<&'a str, Program, E> {
+    let mut program = Program {
+        fns: BTreeMap::new(),
+        consts: BTreeMap::new(),
+    };
+    loop {
+        let (start, _) = ws(i)?;
+        let (rest, item) = match alt((map(fn_decl, Item::Fn), map(const_decl, Item::Const)))(start)
+        {
+            Ok(result) => result,
+            Err(Err::Error(_)) => return Ok((i, program)),
+            Err(e) => return Err(e),
+        };
+        // errors found after parsing a declaration are reported at its start
+        let failure = |msg| {
+            Err::Failure(E::add_context(
+                start,
+                msg,
+                E::from_error_kind(start, ErrorKind::Verify),
+            ))
+        };
+        match item {
+            Item::Fn(new_fn) => {
+                if program.fns.contains_key(&new_fn.name) {
+                    return Err(failure("function is already defined"));
+                }
+                program.fns.insert(new_fn.name.clone(), new_fn);
+            }
+            Item::Const((name, typ, init)) => {
+                if program.consts.contains_key(name) {
+                    return Err(failure("constant is already defined"));
+                }
+                let value = eval_const_expr(&init, &program.consts).map_err(failure)?;
+                program.consts.insert(
+                    name.to_owned(),
+                    ConstDecl {
+                        name: name.to_owned(),
+                        typ,
+                        value,
+                    },
+                );
+            }
+        }
+        i = rest;
+    }
 }
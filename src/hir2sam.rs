@@ -3,6 +3,8 @@ use crate::linker::*;
 use crate::sam::*;
 use num::BigUint;
 use num::Num;
+use num::ToPrimitive;
+use num::Zero;
 
 use std::collections::BTreeMap;
 
@@ -10,22 +12,24 @@ use std::collections::BTreeMap;
 // - return value value
 // - arguments
 // - CALL instruction writes instruction ptr + 5 here (CALL is 5 bytes wide)
+// a self-call in tail position overwrites the arguments in place and jumps
+// back to the function's entry block instead, reusing the stack frame
 
 pub fn hir2sam(program: &Program) -> BTreeMap<String, SamFn> {
     let mut sam_fns = BTreeMap::new();
     for (fn_name, function) in program.fns.iter() {
         let mut sam_block_arena = SamBlockArena { blocks: Vec::new() };
-        let mut cpu = SamCpu::new(&program.fns, fn_name, &mut sam_block_arena);
-        for stmt in &function.scope.stmts {
-            cpu.exec_stmt(stmt);
-        }
+        let mut cpu = SamCpu::new(&program.fns, &program.consts, fn_name, &mut sam_block_arena);
+        cpu.exec_stmts(&function.scope);
         cpu.ret(function.scope.final_expr.as_deref());
+        let frame_size = cpu.locals.max_stack_size;
         let prev = sam_fns.insert(
             function.name.clone(),
             SamFn {
                 name: function.name.clone(),
                 arg_sizes: function.args.iter().map(|x| type_size(&x.typ)).collect(),
                 ret_size: type_size(&function.ret),
+                frame_size,
                 blocks: sam_block_arena.blocks,
             },
         );
@@ -56,6 +60,9 @@ fn get_builtin_fn(name: &str) -> Option<BuiltInFn> {
         insert_builtin("print", VarType::Unit);
         insert_builtin("print_char", VarType::Unit);
         insert_builtin("read_char", VarType::U8);
+        insert_builtin("assert", VarType::Unit);
+        insert_builtin("alloc", VarType::U32);
+        insert_builtin("strlen", VarType::U32);
     }
     map.get(name).cloned()
 }
@@ -91,6 +98,8 @@ struct LocalVar<'a> {
 struct Locals<'a> {
     locals: BTreeMap<&'a str, LocalVar<'a>>,
     cur_stack_size: u32,
+    /// The most cur_stack_size has been in this scope and the scopes nested in it
+    max_stack_size: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -101,6 +110,17 @@ enum Dest<'a> {
     X,
 }
 
+/// What a branch tests once its condition has been evaluated
+#[derive(Clone, Copy, Debug)]
+enum Cond {
+    /// X is nonzero
+    X,
+    /// X holds the result of a Cmp op, and it's the given one
+    CmpIs(CmpResult),
+    /// X holds the result of a Cmp op, and it's not the given one
+    CmpIsNot(CmpResult),
+}
+
 #[derive(Debug)]
 pub struct SamBlock {
     pub ops: Vec<SamLOp>,
@@ -113,7 +133,7 @@ pub struct SamBlockArena {
 }
 
 impl SamBlockArena {
-    pub fn new_block_writer(&mut self) -> SamBlockWriter {
+    pub fn new_block_writer(&mut self) -> SamBlockWriter<'_> {
         let new_block_index = self.blocks.len();
         self.blocks.push(SamBlock {
             ops: Vec::new(),
@@ -141,19 +161,57 @@ impl<'o> SamBlockWriter<'o> {
         self.arena.blocks[self.block_index].next_block_index = next_block_index;
     }
 
-    pub fn reborrow_mut(&mut self) -> SamBlockWriter {
+    /// If the block ends in a comparison followed by the ops `process_cmp_result` adds,
+    /// drops those ops and returns what they would have tested, so a fused jump can be used
+    fn take_cmp_cond(&mut self) -> Cond {
+        let ops = &mut self.arena.blocks[self.block_index].ops;
+        for cmp_kind in [
+            CmpKind::LT,
+            CmpKind::LE,
+            CmpKind::EQ,
+            CmpKind::GE,
+            CmpKind::GT,
+        ] {
+            let result_ops = cmp_result_ops(cmp_kind);
+            let Some(cmp_index) = ops.len().checked_sub(result_ops.len() + 1) else {
+                continue;
+            };
+            let ends_with_result_ops = ops[cmp_index + 1..]
+                .iter()
+                .zip(&result_ops)
+                .all(|(op, result_op)| matches!(op, SamLOp::Simple(op) if op == result_op));
+            if is_cmp_op(&ops[cmp_index]) && ends_with_result_ops {
+                ops.truncate(cmp_index + 1);
+                return match cmp_kind {
+                    CmpKind::LT => Cond::CmpIs(CmpResult::Lt),
+                    CmpKind::LE => Cond::CmpIsNot(CmpResult::Gt),
+                    CmpKind::EQ => Cond::CmpIs(CmpResult::Eq),
+                    CmpKind::GE => Cond::CmpIsNot(CmpResult::Lt),
+                    CmpKind::GT => Cond::CmpIs(CmpResult::Gt),
+                    CmpKind::NE => unreachable!(),
+                };
+            }
+        }
+        Cond::X
+    }
+
+    pub fn reborrow_mut(&mut self) -> SamBlockWriter<'_> {
         SamBlockWriter {
-            arena: &mut self.arena,
+            arena: self.arena,
             block_index: self.block_index,
         }
     }
 }
 
 impl<'a> Locals<'a> {
+    fn contains(&self, name: &str) -> bool {
+        self.locals.contains_key(name)
+    }
+
     fn get(&self, name: &'a str) -> LocalVar<'a> {
         self.locals
             .get(name)
-            .expect(&format!("Accessing unknown local {:?}", name))
+            .unwrap_or_else(|| panic!("Accessing unknown local {:?}", name))
             .clone()
     }
 
@@ -166,7 +224,8 @@ impl<'a> Locals<'a> {
         if let Some(name) = name {
             self.locals.insert(name, result.clone());
         }
-        self.cur_stack_size += type_size(&typ);
+        self.cur_stack_size += type_size(typ);
+        self.max_stack_size = self.max_stack_size.max(self.cur_stack_size);
         result
     }
 
@@ -180,14 +239,8 @@ impl<'a> Locals<'a> {
 }
 
 fn type_size(typ: &VarType) -> u32 {
-    match typ {
-        VarType::U8 => 1,
-        VarType::Bool => 1,
-        VarType::U32 => 4,
-        VarType::Unit => 0,
-        VarType::StringLiteral => 0,
-        VarType::PtrTo(_) => 4,
-    }
+    typ.size()
+        .expect("parse_hir rejects arrays that are too large")
 }
 
 fn are_types_compatible(type1: &VarType, type2: &VarType) -> bool {
@@ -195,11 +248,69 @@ fn are_types_compatible(type1: &VarType, type2: &VarType) -> bool {
     type_size(type1) == type_size(type2)
 }
 
+fn fits_in_a(typ: &VarType) -> bool {
+    // u16s are zero-extended when loaded into A
+    matches!(type_size(typ), 2 | 4)
+}
+
+fn read_a_op(typ: &VarType) -> SamSOp {
+    if type_size(typ) == 2 {
+        SamSOp::ReadA16AtB
+    } else {
+        SamSOp::ReadAAtB
+    }
+}
+
+fn bitwise_op(kind: BinOpKind) -> SamSOp {
+    match kind {
+        BinOpKind::BitAnd => SamSOp::SetAToU32AtBAndA,
+        BinOpKind::BitOr => SamSOp::SetAToU32AtBOrA,
+        BinOpKind::BitXor => SamSOp::SetAToU32AtBXorA,
+        BinOpKind::Shl => SamSOp::SetAToU32AtBShlA,
+        BinOpKind::Shr => SamSOp::SetAToU32AtBShrA,
+        _ => panic!("{:?} is not a bitwise operator", kind),
+    }
+}
+
+/// Returns the type of an expression with two branches, if either is known
+fn unify_branch_types(type1: Option<VarType>, type2: Option<VarType>) -> Option<VarType> {
+    match type1 {
+        Some(type1) => match type2 {
+            Some(type2) => {
+                if type1 == type2 {
+                    Some(type1)
+                } else {
+                    panic!("Incompatible match arms: {:?} and {:?}", type1, type2);
+                }
+            }
+            None => Some(type1),
+        },
+        None => type2,
+    }
+}
+
+fn is_bitwise(kind: BinOpKind) -> bool {
+    matches!(
+        kind,
+        BinOpKind::BitAnd | BinOpKind::BitOr | BinOpKind::BitXor | BinOpKind::Shl | BinOpKind::Shr
+    )
+}
+
+fn write_a_op(typ: &VarType) -> SamSOp {
+    if type_size(typ) == 2 {
+        SamSOp::WriteA16AtB
+    } else {
+        SamSOp::WriteAAtB
+    }
+}
+
 struct SamCpu<'a, 'o> {
     locals: Locals<'a>,
     out: SamBlockWriter<'o>,
     cur_b_offset: u32,
     fn_decls: &'a BTreeMap<String, FnDecl>,
+    consts: &'a BTreeMap<String, ConstDecl>,
+    fn_name: &'a str,
     valret_local: LocalVar<'a>,
     iret_local: LocalVar<'a>,
 }
@@ -207,6 +318,7 @@ struct SamCpu<'a, 'o> {
 impl<'a, 'o> SamCpu<'a, 'o> {
     pub fn new(
         fn_decls: &'a BTreeMap<String, FnDecl>,
+        consts: &'a BTreeMap<String, ConstDecl>,
         fn_name: &'a str,
         arena: &'o mut SamBlockArena,
     ) -> SamCpu<'a, 'o> {
@@ -214,6 +326,7 @@ impl<'a, 'o> SamCpu<'a, 'o> {
         let mut locals = Locals {
             locals: BTreeMap::new(),
             cur_stack_size: 0,
+            max_stack_size: 0,
         };
         let valret_local = locals.new_temp(&decl.ret);
         for arg in &decl.args {
@@ -225,25 +338,35 @@ impl<'a, 'o> SamCpu<'a, 'o> {
             out: arena.new_block_writer(),
             cur_b_offset: iret_local.location,
             fn_decls,
+            consts,
+            fn_name,
             valret_local,
             iret_local,
         }
     }
 
     pub fn scope<R>(&mut self, f: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>) -> R) -> R {
-        let (rust_closure_return, cpu_b_offset, cpu_block_index) = {
+        let (rust_closure_return, cpu_b_offset, cpu_block_index, max_stack_size) = {
             let mut cpu = SamCpu {
                 locals: self.locals.clone(),
                 out: self.out.reborrow_mut(),
                 cur_b_offset: self.cur_b_offset,
                 fn_decls: self.fn_decls,
+                consts: self.consts,
+                fn_name: self.fn_name,
                 valret_local: self.valret_local.clone(),
                 iret_local: self.iret_local.clone(),
             };
-            (f(&mut cpu), cpu.cur_b_offset, cpu.out.block_index)
+            (
+                f(&mut cpu),
+                cpu.cur_b_offset,
+                cpu.out.block_index,
+                cpu.locals.max_stack_size,
+            )
         };
         self.out.block_index = cpu_block_index;
         self.cur_b_offset = cpu_b_offset;
+        self.locals.max_stack_size = max_stack_size;
         rust_closure_return
     }
 
@@ -257,12 +380,15 @@ impl<'a, 'o> SamCpu<'a, 'o> {
             out: child_out,
             cur_b_offset: self.cur_b_offset,
             fn_decls: self.fn_decls,
+            consts: self.consts,
+            fn_name: self.fn_name,
             valret_local: self.valret_local.clone(),
             iret_local: self.iret_local.clone(),
         };
         let entry_index = cpu.out.block_index;
         f(&mut cpu);
         self.cur_b_offset = cpu.cur_b_offset;
+        self.locals.max_stack_size = cpu.locals.max_stack_size;
         (entry_index, cpu.out.block_index)
     }
 
@@ -274,6 +400,113 @@ impl<'a, 'o> SamCpu<'a, 'o> {
         (old_block_index, new_block_index)
     }
 
+    /// Evaluates a condition for a branch, preferring a fused jump when it's a comparison
+    fn eval_cond(&mut self, cond: &'a Expr) -> Cond {
+        self.eval_expr(cond, &Dest::X);
+        self.out.take_cmp_cond()
+    }
+
+    /// `cond` should evaluate a condition that holds as long as `inner` should keep running
+    fn while_loop(
+        &mut self,
+        cond: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>) -> Cond,
+        inner: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>),
+    ) {
+        let start_b_offset = self.cur_b_offset;
+        let (inner_entry_index, inner_exit_index) = self.block(|cpu| {
+            inner(cpu);
+            cpu.goto_b_offset(start_b_offset);
+        });
+        let mut cond_kind = Cond::X;
+        let (cond_entry_index, cond_exit_index) = self.block(|cpu| {
+            cond_kind = cond(cpu);
+            cpu.goto_b_offset(start_b_offset);
+        });
+        let (old_index, new_index) = self.split_to_new_block();
+        let (jmp_op, fallthrough_index) = match cond_kind {
+            Cond::X => (SamLOp::JmpToBlockIfX(inner_entry_index), new_index),
+            Cond::CmpIs(r) => (SamLOp::JmpToBlockIfCmp(r, inner_entry_index), new_index),
+            Cond::CmpIsNot(r) => (SamLOp::JmpToBlockIfCmp(r, new_index), inner_entry_index),
+        };
+        self.out.arena.blocks[cond_exit_index].ops.push(jmp_op);
+        self.out.arena.blocks[old_index].next_block_index = Some(cond_entry_index);
+        self.out.arena.blocks[inner_exit_index].next_block_index = Some(cond_entry_index);
+        self.out.arena.blocks[cond_exit_index].next_block_index = Some(fallthrough_index);
+    }
+
+    /// Runs `if_true` if X is nonzero and `if_false` otherwise
+    pub fn if_else(
+        &mut self,
+        if_true: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>),
+        if_false: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>),
+    ) {
+        self.if_cond_else(Cond::X, if_true, if_false);
+    }
+
+    /// Runs `if_true` if `cond` holds and `if_false` otherwise
+    fn if_cond_else(
+        &mut self,
+        cond: Cond,
+        if_true: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>),
+        if_false: impl for<'b, 'o2> FnOnce(&'b mut SamCpu<'a, 'o2>),
+    ) {
+        let start_b_offset = self.cur_b_offset;
+        let (true_entry_index, true_exit_index) = self.block(if_true);
+        let end_b_offset = self.cur_b_offset;
+        self.cur_b_offset = start_b_offset;
+        let (false_entry_index, false_exit_index) = self.block(|cpu| {
+            if_false(cpu);
+            cpu.goto_b_offset(end_b_offset);
+        });
+        let (jmp_op, fallthrough_index) = match cond {
+            Cond::X => (SamLOp::JmpToBlockIfX(true_entry_index), false_entry_index),
+            Cond::CmpIs(r) => (
+                SamLOp::JmpToBlockIfCmp(r, true_entry_index),
+                false_entry_index,
+            ),
+            Cond::CmpIsNot(r) => (
+                SamLOp::JmpToBlockIfCmp(r, false_entry_index),
+                true_entry_index,
+            ),
+        };
+        self.out.add_op(jmp_op);
+        let (old_index, new_index) = self.split_to_new_block();
+        self.out.arena.blocks[old_index].next_block_index = Some(fallthrough_index);
+        self.out.arena.blocks[true_exit_index].next_block_index = Some(new_index);
+        self.out.arena.blocks[false_exit_index].next_block_index = Some(new_index);
+    }
+
+    /// Evaluates the first arm whose value equals the scrutinee, or `default` if none do
+    fn match_arms(
+        &mut self,
+        scrutinee_local: &LocalVar<'a>,
+        arms: &'a [MatchArm],
+        default: &'a Expr,
+        dest: &Dest<'a>,
+    ) {
+        let (arm, other_arms) = match arms.split_first() {
+            Some(split) => split,
+            None => {
+                self.eval_expr(default, dest);
+                return;
+            }
+        };
+        if type_size(&scrutinee_local.typ) == 1 {
+            self.set_x(&arm.value);
+            self.goto_b_offset(scrutinee_local.location);
+            self.out.add_op(SamLOp::Simple(SamSOp::CmpU8AtBWithX));
+        } else {
+            self.set_a(&arm.value);
+            self.goto_b_offset(scrutinee_local.location);
+            self.out.add_op(SamLOp::Simple(SamSOp::CmpU32AtBWithA));
+        }
+        self.if_cond_else(
+            Cond::CmpIs(CmpResult::Eq),
+            |cpu| cpu.eval_expr(&arm.expr, dest),
+            |cpu| cpu.match_arms(scrutinee_local, other_arms, default, dest),
+        );
+    }
+
     pub fn goto_b_offset(&mut self, offset: u32) {
         if self.cur_b_offset < offset {
             self.out.add_op(SamLOp::Simple(SamSOp::AddConstToB(
@@ -290,7 +523,10 @@ impl<'a, 'o> SamCpu<'a, 'o> {
     pub fn get_expr_type(&self, expr: &'a Expr) -> Option<VarType> {
         match expr {
             Expr::Literal(_lit) => None,
-            Expr::VarRef(varref) => Some(self.locals.get(varref).typ.clone()),
+            Expr::VarRef(varref) => match self.get_const(varref) {
+                Some(const_decl) => Some(const_decl.typ.clone()),
+                None => Some(self.locals.get(varref).typ.clone()),
+            },
             Expr::BinOp(binop) => {
                 if let BinOpKind::Cmp(_) = binop.kind {
                     Some(VarType::U8)
@@ -312,12 +548,16 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                     }
                 }
             }
+            Expr::UnaryOp(unop) => match unop.kind {
+                UnaryOpKind::Neg => self.get_expr_type(&unop.arg),
+                UnaryOpKind::Not => Some(VarType::U8),
+            },
             Expr::FnCall(f) => Some(if let Some(builtin_fn) = get_builtin_fn(&f.fn_name) {
                 builtin_fn.ret_type
             } else {
                 self.fn_decls
                     .get(&f.fn_name)
-                    .expect(&format!("Calling unknown function {:?}", f.fn_name))
+                    .unwrap_or_else(|| panic!("Calling unknown function {:?}", f.fn_name))
                     .ret
                     .clone()
             }),
@@ -325,23 +565,16 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                 Some(e) => self.get_expr_type(e),
                 None => Some(VarType::Unit),
             },
-            Expr::IfElse(s) => {
-                let true_type = self.get_expr_type(&s.if_true);
-                let false_type = self.get_expr_type(&s.if_false);
-                match true_type {
-                    Some(true_type) => match false_type {
-                        Some(false_type) => {
-                            if true_type == false_type {
-                                Some(true_type)
-                            } else {
-                                panic!("Incompatible match arms in if/else");
-                            }
-                        }
-                        None => Some(true_type),
-                    },
-                    None => false_type,
-                }
-            }
+            Expr::IfElse(s) => unify_branch_types(
+                self.get_expr_type(&s.if_true),
+                self.get_expr_type(&s.if_false),
+            ),
+            Expr::Match(m) => m
+                .arms
+                .iter()
+                .fold(self.get_expr_type(&m.default), |typ, arm| {
+                    unify_branch_types(typ, self.get_expr_type(&arm.expr))
+                }),
             Expr::StringLiteral(_) => Some(VarType::StringLiteral),
             Expr::Deref(e) => {
                 let ptr_type = self.get_expr_type(e);
@@ -358,9 +591,70 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                 }
             }
             Expr::AddressOf(ident) => {
-                let local = self.locals.get(&ident);
+                let local = self.locals.get(ident);
                 Some(VarType::PtrTo(Box::new(local.typ.clone())))
             }
+            Expr::Index(ident, _) => {
+                let local = self.locals.get(ident);
+                match local.typ {
+                    VarType::Array(elem_typ, _) => Some(*elem_typ),
+                    _ => panic!(
+                        "Indexing {} which is not an array but a {:?}",
+                        ident, local.typ
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Constants can be shadowed by locals
+    fn get_const(&self, name: &str) -> Option<&'a ConstDecl> {
+        if self.locals.contains(name) {
+            None
+        } else {
+            self.consts.get(name)
+        }
+    }
+
+    /// Evaluates arithmetic on literals and constants at compile time, wrapping at the width of `typ`
+    fn fold_const_expr(&self, expr: &Expr, typ: &VarType) -> Option<BigUint> {
+        match expr {
+            Expr::Literal(lit) => Some(lit.clone()),
+            Expr::VarRef(varref) => self.get_const(varref).map(|c| c.value.clone()),
+            Expr::BinOp(binop) => {
+                let bits = match typ {
+                    VarType::U8 | VarType::Char | VarType::Bool => 8,
+                    VarType::U16 => 16,
+                    VarType::U32 | VarType::I32 | VarType::PtrTo(_) => 32,
+                    VarType::U64 => 64,
+                    _ => return None,
+                };
+                let a = self.fold_const_expr(&binop.args.0, typ)?;
+                let b = self.fold_const_expr(&binop.args.1, typ)?;
+                let modulus = BigUint::from(1u8) << bits;
+                let signed = *typ == VarType::I32;
+                let result = match binop.kind {
+                    BinOpKind::Plus => a + b,
+                    BinOpKind::Minus => a + &modulus - b % &modulus,
+                    BinOpKind::Mul => a * b,
+                    BinOpKind::Div if !signed && !b.is_zero() => a / b,
+                    BinOpKind::Mod if !signed && !b.is_zero() => a % b,
+                    BinOpKind::BitAnd => a & b,
+                    BinOpKind::BitOr => a | b,
+                    BinOpKind::BitXor => a ^ b,
+                    BinOpKind::Shl => match b.to_usize() {
+                        Some(shift) if shift < bits => a << shift,
+                        _ => BigUint::zero(),
+                    },
+                    BinOpKind::Shr if !signed => match b.to_usize() {
+                        Some(shift) if shift < bits => (a % &modulus) >> shift,
+                        _ => BigUint::zero(),
+                    },
+                    _ => return None,
+                };
+                Some(result % modulus)
+            }
+            _ => None,
         }
     }
 
@@ -381,9 +675,9 @@ impl<'a, 'o> SamCpu<'a, 'o> {
     }
 
     pub fn write_a_at(&mut self, local: &LocalVar<'a>) {
-        assert!(are_types_compatible(&local.typ, &VarType::U32));
+        assert!(fits_in_a(&local.typ));
         self.goto_b_offset(local.location);
-        self.out.add_op(SamLOp::Simple(SamSOp::WriteAAtB));
+        self.out.add_op(SamLOp::Simple(write_a_op(&local.typ)));
     }
 
     pub fn read_x_at(&mut self, local: &LocalVar<'a>) {
@@ -393,9 +687,17 @@ impl<'a, 'o> SamCpu<'a, 'o> {
     }
 
     pub fn read_a_at(&mut self, local: &LocalVar<'a>) {
-        assert!(are_types_compatible(&local.typ, &VarType::U32));
+        assert!(fits_in_a(&local.typ));
         self.goto_b_offset(local.location);
-        self.out.add_op(SamLOp::Simple(SamSOp::ReadAAtB));
+        self.out.add_op(SamLOp::Simple(read_a_op(&local.typ)));
+    }
+
+    pub fn truncate_a_to_u16(&mut self) {
+        self.scope(|cpu| {
+            let tmp_local = cpu.locals.new_temp(&VarType::U16);
+            cpu.write_a_at(&tmp_local);
+            cpu.read_a_at(&tmp_local);
+        });
     }
 
     pub fn copy_local_to_local(&mut self, a: &LocalVar<'a>, b: &LocalVar<'a>) {
@@ -405,55 +707,199 @@ impl<'a, 'o> SamCpu<'a, 'o> {
         }
         match &a.typ {
             VarType::Unit => {}
-            VarType::U8 | VarType::Bool => {
+            VarType::U8 | VarType::Char | VarType::Bool => {
                 self.read_x_at(a);
                 self.write_x_at(b);
             }
-            VarType::U32 | VarType::PtrTo(_) => {
+            VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
                 self.read_a_at(a);
                 self.write_a_at(b);
             }
             VarType::StringLiteral => {}
+            VarType::U64 | VarType::Array(..) => {
+                for i in 0..type_size(&a.typ) {
+                    self.goto_b_offset(a.location + i);
+                    self.out.add_op(SamLOp::Simple(SamSOp::ReadXAtB));
+                    self.goto_b_offset(b.location + i);
+                    self.out.add_op(SamLOp::Simple(SamSOp::WriteXAtB));
+                }
+            }
+        }
+    }
+
+    pub fn zero_local(&mut self, local: &LocalVar<'a>) {
+        self.out.add_op(SamLOp::Simple(SamSOp::SetX(0)));
+        for i in 0..type_size(&local.typ) {
+            self.goto_b_offset(local.location + i);
+            self.out.add_op(SamLOp::Simple(SamSOp::WriteXAtB));
+        }
+    }
+
+    /// Puts the address of a dereferenced pointer or an indexed array element in A
+    pub fn eval_place_address(&mut self, place: &'a Expr) {
+        match place {
+            Expr::Deref(ptr_expr) => {
+                self.eval_expr(ptr_expr, &Dest::A);
+            }
+            Expr::Index(ident, index) => {
+                let array_local = self.locals.get(ident);
+                let elem_size = match &array_local.typ {
+                    VarType::Array(elem_typ, _) => type_size(elem_typ),
+                    _ => panic!(
+                        "Indexing {} which is not an array but a {:?}",
+                        ident, array_local.typ
+                    ),
+                };
+                self.scope(|cpu| {
+                    let offset_local = cpu.locals.new_temp(&VarType::U32);
+                    match cpu.get_expr_type(index) {
+                        Some(VarType::U8) | Some(VarType::Char) | Some(VarType::Bool) => {
+                            cpu.eval_expr(index, &Dest::X);
+                            cpu.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
+                        }
+                        _ => {
+                            cpu.eval_expr(index, &Dest::A);
+                        }
+                    }
+                    if elem_size != 1 {
+                        cpu.write_a_at(&offset_local);
+                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(elem_size)));
+                        cpu.out.add_op(SamLOp::Simple(SamSOp::MulU32AtBToA));
+                    }
+                    cpu.write_a_at(&offset_local);
+                    cpu.goto_b_offset(array_local.location);
+                    cpu.out.add_op(SamLOp::Simple(SamSOp::CopyBToA));
+                    cpu.goto_b_offset(offset_local.location);
+                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+                });
+            }
+            other => panic!("{:?} is not a place", other),
         }
     }
 
     pub fn ret(&mut self, val: Option<&'a Expr>) {
+        let start_b_offset = self.cur_b_offset;
         if let Some(val) = val {
-            self.eval_expr(val, &Dest::Local(self.valret_local.clone()));
+            self.eval_tail_expr(val);
         }
         self.goto_b_offset(self.iret_local.location);
         self.out.add_op(SamLOp::Simple(SamSOp::Ret));
+        // anything after an early return is unreachable, so it goes in a block nothing leads to,
+        // and code that merges with it doesn't need to line B up with the return
+        self.out.set_next_block_index(None);
+        let new_block_index = self.out.arena.new_block_writer().block_index;
+        self.out.block_index = new_block_index;
+        self.cur_b_offset = start_b_offset;
     }
 
-    pub fn eval_expr(&mut self, expr: &'a Expr, dest: &Dest<'a>) {
-        //let expr_type = self.get_expr_type(expr);
+    /// Evaluates the function's return value, turning self-calls in tail position into jumps
+    fn eval_tail_expr(&mut self, expr: &'a Expr) {
         match expr {
-            Expr::Literal(lit) => match dest {
-                Dest::None => {}
-                Dest::X => {
-                    self.set_x(lit);
-                }
-                Dest::A => {
-                    self.set_a(lit);
+            Expr::FnCall(fncall) if fncall.fn_name == self.fn_name => {
+                self.tail_call(fncall);
+            }
+            Expr::IfElse(i) => {
+                let cond = self.eval_cond(&i.cond);
+                self.if_cond_else(
+                    cond,
+                    |cpu| cpu.eval_tail_expr(&i.if_true),
+                    |cpu| cpu.eval_tail_expr(&i.if_false),
+                );
+            }
+            Expr::Scope(s) if s.final_expr.is_some() => {
+                self.scope(|cpu| {
+                    cpu.exec_stmts(s);
+                    cpu.eval_tail_expr(s.final_expr.as_ref().unwrap());
+                });
+            }
+            _ => {
+                self.eval_expr(expr, &Dest::Local(self.valret_local.clone()));
+            }
+        }
+    }
+
+    fn tail_call(&mut self, fncall: &'a FnCall) {
+        let fn_decl = &self.fn_decls[self.fn_name];
+        assert_eq!(fn_decl.args.len(), fncall.args.len());
+        self.scope(|cpu| {
+            // evaluate all new arguments before overwriting any, they may refer to the old ones
+            let mut arg_location = type_size(&fn_decl.ret);
+            let mut moves = Vec::new();
+            for (arg_expr, arg_decl) in fncall.args.iter().zip(fn_decl.args.iter()) {
+                let tmp_local = cpu.locals.new_temp(&arg_decl.typ);
+                cpu.scope(|cpu| {
+                    cpu.eval_expr(arg_expr, &Dest::Local(tmp_local.clone()));
+                });
+                let arg_local = LocalVar {
+                    name: &arg_decl.name,
+                    typ: arg_decl.typ.clone(),
+                    location: arg_location,
+                };
+                arg_location += type_size(&arg_decl.typ);
+                moves.push((tmp_local, arg_local));
+            }
+            for (tmp_local, arg_local) in &moves {
+                cpu.copy_local_to_local(tmp_local, arg_local);
+            }
+        });
+        self.goto_b_offset(self.iret_local.location);
+        // the entry block is always the first one in the arena
+        self.out.set_next_block_index(Some(0));
+        let new_block_index = self.out.arena.new_block_writer().block_index;
+        self.out.block_index = new_block_index;
+    }
+
+    pub fn eval_literal(&mut self, lit: &BigUint, typ: Option<VarType>, dest: &Dest<'a>) {
+        match dest {
+            Dest::None => {}
+            Dest::X => {
+                self.set_x(lit);
+            }
+            Dest::A => {
+                self.set_a(lit);
+            }
+            Dest::Local(local) => {
+                if let Some(typ) = typ {
+                    assert!(are_types_compatible(&local.typ, &typ));
                 }
-                Dest::Local(local) => {
-                    if let Some(expr_type) = self.get_expr_type(expr) {
-                        assert!(are_types_compatible(&local.typ, &expr_type));
+                match &local.typ {
+                    VarType::Unit => unreachable!(),
+                    VarType::U8 | VarType::Char | VarType::Bool => {
+                        self.set_x(lit);
+                        self.write_x_at(local);
                     }
-                    match &local.typ {
-                        VarType::Unit => unreachable!(),
-                        VarType::U8 | VarType::Bool => {
-                            self.set_x(lit);
-                            self.write_x_at(local);
-                        }
-                        VarType::U32 | VarType::PtrTo(_) => {
-                            self.set_a(lit);
-                            self.write_a_at(local);
+                    VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
+                        self.set_a(lit);
+                        self.write_a_at(local);
+                    }
+                    VarType::U64 => {
+                        // write it as two big-endian u32 halves
+                        let val = lit.to_u64().expect("Uint too large for u64");
+                        for (offset, half) in [(0, val >> 32), (4, val & 0xFFFF_FFFF)] {
+                            self.set_a(&BigUint::from(half));
+                            self.goto_b_offset(local.location + offset);
+                            self.out.add_op(SamLOp::Simple(SamSOp::WriteAAtB));
                         }
-                        VarType::StringLiteral => unreachable!(),
+                    }
+                    VarType::StringLiteral => unreachable!(),
+                    VarType::Array(..) => {
+                        panic!("Assigning a literal to array {}", local.name)
                     }
                 }
-            },
+            }
+        }
+    }
+
+    pub fn eval_expr(&mut self, expr: &'a Expr, dest: &Dest<'a>) {
+        //let expr_type = self.get_expr_type(expr);
+        match expr {
+            Expr::Literal(lit) => {
+                self.eval_literal(lit, self.get_expr_type(expr), dest);
+            }
+            Expr::VarRef(varref) if self.get_const(varref).is_some() => {
+                let const_decl = self.get_const(varref).unwrap();
+                self.eval_literal(&const_decl.value, Some(const_decl.typ.clone()), dest);
+            }
             Expr::VarRef(varref) => {
                 let varref_local = self.locals.get(varref);
                 match dest {
@@ -485,7 +931,7 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                     }
                     Dest::A => {
                         if let Some(typ) = maybe_typ {
-                            assert!(are_types_compatible(&typ, &VarType::U32));
+                            assert!(fits_in_a(&typ));
                             typ
                         } else {
                             VarType::U32
@@ -514,77 +960,178 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                             }
                             None => a_type,
                         },
-                        None => b_type.unwrap_or(result_typ.clone()),
+                        None => b_type.unwrap_or_else(|| {
+                            // two untyped operands, e.g. `-1 < 1`, compare as signed if either is negated
+                            let is_neg = |e: &Expr| {
+                                matches!(e, Expr::UnaryOp(u) if u.kind == UnaryOpKind::Neg)
+                            };
+                            if is_neg(&binop.args.0) || is_neg(&binop.args.1) {
+                                VarType::I32
+                            } else {
+                                result_typ.clone()
+                            }
+                        }),
                     }
                 } else {
                     result_typ.clone()
                 };
-                self.scope(|cpu| {
-                    let lhs_local = cpu.locals.new_temp(&arg_typ);
-                    cpu.eval_expr(&binop.args.0, &Dest::Local(lhs_local.clone()));
-                    match &arg_typ {
-                        VarType::U8 | VarType::Bool => {
-                            cpu.eval_expr(&binop.args.1, &Dest::X);
-                            cpu.goto_b_offset(lhs_local.location);
-                            match binop.kind {
-                                BinOpKind::Plus => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU8AtBToX));
-                                }
-                                BinOpKind::Minus => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::NegX));
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU8AtBToX));
-                                }
-                                BinOpKind::Mul => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::MulU8AtBToX));
-                                }
-                                BinOpKind::Div => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::SetXToU8AtBDivByX));
-                                }
-                                BinOpKind::Mod => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::SetXToU8AtBModX));
-                                }
-                                BinOpKind::Cmp(cmp_kind) => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU8AtBWithX));
-                                    process_cmp_result(cpu, cmp_kind);
+                // shifts by a literal don't need the amount in a register
+                let const_shift = match (binop.kind, &binop.args.1) {
+                    (BinOpKind::Shl | BinOpKind::Shr, Expr::Literal(n))
+                        if matches!(arg_typ, VarType::U16 | VarType::U32 | VarType::I32) =>
+                    {
+                        n.to_u8()
+                    }
+                    _ => None,
+                };
+                if let Some(val) = self.fold_const_expr(expr, &result_typ) {
+                    self.eval_literal(&val, Some(result_typ.clone()), dest);
+                } else if let Some(shift) = const_shift {
+                    self.eval_expr(&binop.args.0, &Dest::A);
+                    if binop.kind == BinOpKind::Shl {
+                        self.out.add_op(SamLOp::Simple(SamSOp::ShlAByConst(shift)));
+                    } else if arg_typ == VarType::I32 {
+                        panic!("Right shift of i32 values is not supported")
+                    } else {
+                        self.out.add_op(SamLOp::Simple(SamSOp::ShrAByConst(shift)));
+                    }
+                } else {
+                    self.scope(|cpu| {
+                        let lhs_local = if let VarType::U16 = &arg_typ {
+                            // u16 arithmetic is done on zero-extended u32s
+                            let lhs_local = cpu.locals.new_temp(&VarType::U32);
+                            cpu.eval_expr(&binop.args.0, &Dest::A);
+                            cpu.write_a_at(&lhs_local);
+                            lhs_local
+                        } else if matches!(arg_typ, VarType::U8 | VarType::Char | VarType::Bool)
+                            && is_bitwise(binop.kind)
+                        {
+                            // there are no u8 bitwise instructions, so use the u32 ones
+                            let lhs_local = cpu.locals.new_temp(&VarType::U32);
+                            cpu.eval_expr(&binop.args.0, &Dest::X);
+                            cpu.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
+                            cpu.write_a_at(&lhs_local);
+                            lhs_local
+                        } else {
+                            let lhs_local = cpu.locals.new_temp(&arg_typ);
+                            cpu.eval_expr(&binop.args.0, &Dest::Local(lhs_local.clone()));
+                            lhs_local
+                        };
+                        match &arg_typ {
+                            VarType::U8 | VarType::Char | VarType::Bool => {
+                                cpu.eval_expr(&binop.args.1, &Dest::X);
+                                cpu.goto_b_offset(lhs_local.location);
+                                match binop.kind {
+                                    BinOpKind::Plus => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::AddU8AtBToX));
+                                    }
+                                    BinOpKind::Minus => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::NegX));
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::AddU8AtBToX));
+                                    }
+                                    BinOpKind::Mul => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::MulU8AtBToX));
+                                    }
+                                    BinOpKind::Div => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetXToU8AtBDivByX));
+                                    }
+                                    BinOpKind::Mod => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetXToU8AtBModX));
+                                    }
+                                    BinOpKind::Cmp(cmp_kind) => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU8AtBWithX));
+                                        process_cmp_result(cpu, cmp_kind);
+                                    }
+                                    BinOpKind::BitAnd
+                                    | BinOpKind::BitOr
+                                    | BinOpKind::BitXor
+                                    | BinOpKind::Shl
+                                    | BinOpKind::Shr => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
+                                        cpu.out.add_op(SamLOp::Simple(bitwise_op(binop.kind)));
+                                        // the result is the lowest byte of a
+                                        cpu.write_a_at(&lhs_local);
+                                        cpu.goto_b_offset(lhs_local.location + 3);
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::ReadXAtB));
+                                    }
                                 }
                             }
-                        }
-                        VarType::U32 | VarType::PtrTo(_) => {
-                            cpu.eval_expr(&binop.args.1, &Dest::A);
-                            cpu.goto_b_offset(lhs_local.location);
-                            match binop.kind {
-                                BinOpKind::Plus => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
-                                }
-                                BinOpKind::Minus => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::NegA));
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
-                                }
-                                BinOpKind::Mul => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::MulU32AtBToA));
-                                }
-                                BinOpKind::Div => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::SetAToU32AtBDivByA));
+                            VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
+                                cpu.eval_expr(&binop.args.1, &Dest::A);
+                                cpu.goto_b_offset(lhs_local.location);
+                                match binop.kind {
+                                    BinOpKind::Plus => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+                                    }
+                                    BinOpKind::Minus => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::NegA));
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+                                    }
+                                    BinOpKind::Mul => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::MulU32AtBToA));
+                                    }
+                                    BinOpKind::Div | BinOpKind::Mod if arg_typ == VarType::I32 => {
+                                        panic!("Division of i32 values is not supported")
+                                    }
+                                    BinOpKind::Div => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetAToU32AtBDivByA));
+                                    }
+                                    BinOpKind::Mod => {
+                                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetAToU32AtBModA));
+                                    }
+                                    BinOpKind::Cmp(cmp_kind) => {
+                                        if arg_typ == VarType::I32 {
+                                            cpu.out.add_op(SamLOp::Simple(SamSOp::CmpI32AtBWithA));
+                                        } else {
+                                            cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU32AtBWithA));
+                                        }
+                                        process_cmp_result(cpu, cmp_kind);
+                                    }
+                                    BinOpKind::Shr if arg_typ == VarType::I32 => {
+                                        panic!("Right shift of i32 values is not supported")
+                                    }
+                                    BinOpKind::BitAnd
+                                    | BinOpKind::BitOr
+                                    | BinOpKind::BitXor
+                                    | BinOpKind::Shl
+                                    | BinOpKind::Shr => {
+                                        cpu.out.add_op(SamLOp::Simple(bitwise_op(binop.kind)));
+                                    }
                                 }
-                                BinOpKind::Mod => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::SetAToU32AtBModA));
-                                }
-                                BinOpKind::Cmp(cmp_kind) => {
-                                    cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU32AtBWithA));
-                                    process_cmp_result(cpu, cmp_kind);
+                            }
+                            VarType::U64 => {
+                                // the u64 ops take their operands next to each other in memory
+                                let rhs_local = cpu.locals.new_temp(&VarType::U64);
+                                assert_eq!(rhs_local.location, lhs_local.location + 8);
+                                cpu.eval_expr(&binop.args.1, &Dest::Local(rhs_local));
+                                cpu.goto_b_offset(lhs_local.location);
+                                let op = match binop.kind {
+                                    BinOpKind::Plus => SamSOp::AddU64AtB8ToU64AtB,
+                                    BinOpKind::Minus => SamSOp::SubU64AtB8FromU64AtB,
+                                    BinOpKind::Mul => SamSOp::MulU64AtBByU64AtB8,
+                                    other => panic!("{:?} of u64 values is not supported", other),
+                                };
+                                cpu.out.add_op(SamLOp::Simple(op));
+                                match dest {
+                                    Dest::None => {}
+                                    Dest::X | Dest::A => panic!("Writing U64 to a register?"),
+                                    Dest::Local(l) => cpu.copy_local_to_local(&lhs_local, l),
                                 }
                             }
+                            VarType::Unit => {
+                                panic!("Unit binop?")
+                            }
+                            VarType::StringLiteral => {
+                                panic!("Scope with type string literal?")
+                            }
+                            VarType::Array(..) => {
+                                panic!("Binop on arrays?")
+                            }
                         }
-                        VarType::Unit => {
-                            panic!("Unit binop?")
-                        }
-                        VarType::StringLiteral => {
-                            panic!("Scope with type string literal?")
-                        }
-                    }
-                });
+                    });
+                }
                 match &result_typ {
-                    VarType::U8 | VarType::Bool => {
+                    VarType::U8 | VarType::Char | VarType::Bool => {
                         match dest {
                             Dest::None => {}
                             Dest::X => {
@@ -598,7 +1145,19 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                             }
                         }
                     }
-                    VarType::U32 | VarType::PtrTo(_) => {
+                    VarType::U16 => match dest {
+                        Dest::None => {}
+                        Dest::X => {
+                            panic!("Writing U16 to X?")
+                        }
+                        Dest::A => {
+                            self.truncate_a_to_u16();
+                        }
+                        Dest::Local(l) => {
+                            self.write_a_at(l);
+                        }
+                    },
+                    VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
                         match dest {
                             Dest::None => {}
                             Dest::X => {
@@ -612,22 +1171,100 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                             }
                         }
                     }
+                    VarType::U64 => {
+                        // the result has already been copied to dest
+                    }
                     VarType::Unit => {
                         panic!("Unit binop?")
                     }
                     VarType::StringLiteral => {
                         panic!("Binop involving string literal not implemented")
                     }
+                    VarType::Array(..) => {
+                        panic!("Binop on arrays?")
+                    }
                 }
             }
+            Expr::UnaryOp(unop) => match unop.kind {
+                UnaryOpKind::Neg => {
+                    let typ = self.get_expr_type(&unop.arg).unwrap_or_else(|| match dest {
+                        Dest::X => VarType::U8,
+                        Dest::Local(local) => local.typ.clone(),
+                        _ => VarType::U32,
+                    });
+                    match &typ {
+                        VarType::U8 | VarType::Char | VarType::Bool => {
+                            self.eval_expr(&unop.arg, &Dest::X);
+                            self.out.add_op(SamLOp::Simple(SamSOp::NegX));
+                            match dest {
+                                Dest::None => {}
+                                Dest::X => {
+                                    // result is already in x
+                                }
+                                Dest::A => {
+                                    panic!("Writing U8 to A?")
+                                }
+                                Dest::Local(l) => {
+                                    self.write_x_at(l);
+                                }
+                            }
+                        }
+                        typ if fits_in_a(typ) => {
+                            self.eval_expr(&unop.arg, &Dest::A);
+                            self.out.add_op(SamLOp::Simple(SamSOp::NegA));
+                            match dest {
+                                Dest::None => {}
+                                Dest::X => {
+                                    panic!("Writing {:?} to X?", typ)
+                                }
+                                Dest::A => {
+                                    if let VarType::U16 = typ {
+                                        self.truncate_a_to_u16();
+                                    }
+                                }
+                                Dest::Local(l) => {
+                                    self.write_a_at(l);
+                                }
+                            }
+                        }
+                        typ => panic!("Negating {:?}", typ),
+                    }
+                }
+                UnaryOpKind::Not => {
+                    let typ = self.get_expr_type(&unop.arg).unwrap_or(VarType::U8);
+                    if fits_in_a(&typ) {
+                        // x = (arg != 0)
+                        self.scope(|cpu| {
+                            let arg_local = cpu.locals.new_temp(&VarType::U32);
+                            cpu.eval_expr(&unop.arg, &Dest::A);
+                            cpu.write_a_at(&arg_local);
+                            cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(0)));
+                            cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU32AtBWithA));
+                        });
+                    } else {
+                        self.eval_expr(&unop.arg, &Dest::X);
+                    }
+                    self.out.add_op(SamLOp::Simple(SamSOp::NotX));
+                    match dest {
+                        Dest::None => {}
+                        Dest::X => {
+                            // result is already in x
+                        }
+                        Dest::A => {
+                            self.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
+                        }
+                        Dest::Local(l) => {
+                            self.write_x_at(l);
+                        }
+                    }
+                }
+            },
             Expr::FnCall(fncall) => {
                 self.call(fncall, dest);
             }
             Expr::Scope(s) => {
                 self.scope(|cpu| {
-                    for stmt in &s.stmts {
-                        cpu.exec_stmt(stmt);
-                    }
+                    cpu.exec_stmts(s);
                     if let Some(final_expr) = &s.final_expr {
                         cpu.eval_expr(final_expr, dest);
                     } else {
@@ -647,26 +1284,47 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                 });
             }
             Expr::IfElse(i) => {
-                self.eval_expr(&i.cond, &Dest::X);
-                let start_b_offset = self.cur_b_offset;
-                let (true_entry_index, true_exit_index) = self.block(|cpu| {
-                    cpu.eval_expr(&i.if_true, dest);
-                });
-                let end_b_offset = self.cur_b_offset;
-                self.cur_b_offset = start_b_offset;
-                let (false_entry_index, false_exit_index) = self.block(|cpu| {
-                    cpu.eval_expr(&i.if_false, dest);
-                    cpu.goto_b_offset(end_b_offset);
+                let cond = self.eval_cond(&i.cond);
+                self.if_cond_else(
+                    cond,
+                    |cpu| cpu.eval_expr(&i.if_true, dest),
+                    |cpu| cpu.eval_expr(&i.if_false, dest),
+                );
+            }
+            Expr::Match(m) => {
+                let typ = self.get_expr_type(&m.scrutinee).unwrap_or(VarType::U32);
+                self.scope(|cpu| {
+                    let scrutinee_local = match &typ {
+                        VarType::U8 | VarType::Char | VarType::Bool => {
+                            let scrutinee_local = cpu.locals.new_temp(&typ);
+                            cpu.eval_expr(&m.scrutinee, &Dest::Local(scrutinee_local.clone()));
+                            scrutinee_local
+                        }
+                        typ if fits_in_a(typ) => {
+                            // widen so the arms can be compared with CmpU32AtBWithA
+                            let scrutinee_local = cpu.locals.new_temp(&VarType::U32);
+                            cpu.eval_expr(&m.scrutinee, &Dest::A);
+                            cpu.write_a_at(&scrutinee_local);
+                            scrutinee_local
+                        }
+                        typ => panic!("Can't match on {:?}", typ),
+                    };
+                    cpu.match_arms(&scrutinee_local, &m.arms, &m.default, dest);
                 });
-                self.out.add_op(SamLOp::JmpToBlockIfX(true_entry_index));
-                let (old_index, new_index) = self.split_to_new_block();
-                self.out.arena.blocks[old_index].next_block_index = Some(false_entry_index);
-                self.out.arena.blocks[true_exit_index].next_block_index = Some(new_index);
-                self.out.arena.blocks[false_exit_index].next_block_index = Some(new_index);
             }
-            Expr::StringLiteral(_) => {}
-            Expr::Deref(e) => {
-                self.eval_expr(e, &Dest::A);
+            Expr::StringLiteral(s) => match dest {
+                Dest::None => {}
+                Dest::X => panic!("Reading a string literal into X?"),
+                Dest::A => self.store_string_literal(s),
+                Dest::Local(local) => {
+                    if fits_in_a(&local.typ) {
+                        self.store_string_literal(s);
+                        self.write_a_at(local);
+                    }
+                }
+            },
+            Expr::Deref(_) | Expr::Index(..) => {
+                self.eval_place_address(expr);
                 self.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                 self.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
                 match dest {
@@ -676,22 +1334,29 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                         self.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                     }
                     Dest::A => {
-                        self.out.add_op(SamLOp::Simple(SamSOp::ReadAAtB));
+                        let typ = self.get_expr_type(expr).unwrap_or(VarType::U32);
+                        self.out.add_op(SamLOp::Simple(read_a_op(&typ)));
                         self.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                     }
                     Dest::Local(local) => match &local.typ {
                         VarType::Unit => {}
-                        VarType::U8 | VarType::Bool => {
+                        VarType::U8 | VarType::Char | VarType::Bool => {
                             self.out.add_op(SamLOp::Simple(SamSOp::ReadXAtB));
                             self.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                             self.write_x_at(local);
                         }
-                        VarType::U32 | VarType::PtrTo(_) => {
-                            self.out.add_op(SamLOp::Simple(SamSOp::ReadAAtB));
+                        VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
+                            self.out.add_op(SamLOp::Simple(read_a_op(&local.typ)));
                             self.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                             self.write_a_at(local);
                         }
                         VarType::StringLiteral => {}
+                        VarType::U64 => {
+                            panic!("Reading u64s through pointers is not supported")
+                        }
+                        VarType::Array(..) => {
+                            panic!("Reading arrays through pointers is not supported")
+                        }
                     },
                 }
             }
@@ -712,9 +1377,21 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                         VarType::U8 => {
                             panic!("Writing address of {} into U8 local {}", i, local.name)
                         }
+                        VarType::Char => {
+                            panic!("Writing address of {} into char local {}", i, local.name)
+                        }
                         VarType::Bool => {
                             panic!("Writing address of {} into bool local {}", i, local.name)
                         }
+                        VarType::U16 => {
+                            panic!("Writing address of {} into U16 local {}", i, local.name)
+                        }
+                        VarType::I32 => {
+                            panic!("Writing address of {} into I32 local {}", i, local.name)
+                        }
+                        VarType::U64 => {
+                            panic!("Writing address of {} into U64 local {}", i, local.name)
+                        }
                         VarType::U32 => {
                             self.write_a_at(local);
                         }
@@ -722,71 +1399,185 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                         VarType::PtrTo(_) => {
                             self.write_a_at(local);
                         }
+                        VarType::Array(..) => {
+                            panic!("Writing address of {} into array local {}", i, local.name)
+                        }
                     },
                 }
             }
         }
     }
 
-    pub fn call(&mut self, fncall: &'a FnCall, dest: &Dest<'a>) {
-        if fncall.fn_name == "print"
-            || fncall.fn_name == "println"
-            || fncall.fn_name == "print_char"
-        {
-            assert_eq!(fncall.args.len(), 1);
-            let arg = &fncall.args[0];
-            let typ = self.get_expr_type(arg).unwrap_or(VarType::U32);
-            match typ {
-                VarType::StringLiteral => {
-                    if let Expr::StringLiteral(s) = arg {
-                        for b in s.bytes() {
-                            self.out.add_op(SamLOp::Simple(SamSOp::SetX(b)));
-                            self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
-                        }
-                    }
-                    if fncall.fn_name == "println" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::SetX(10)));
+    /// Replaces the size in A by the address of that many freshly allocated heap bytes
+    fn alloc_a(&mut self) {
+        self.scope(|cpu| {
+            let size_local = cpu.locals.new_temp(&VarType::U32);
+            cpu.write_a_at(&size_local);
+            // the bump pointer is stored as an offset from the heap start,
+            // so the zeroed memory it starts out in means an empty heap
+            let offset_local = cpu.locals.new_temp(&VarType::U32);
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(HEAP_START)));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::ReadAAtB));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.write_a_at(&offset_local);
+            cpu.goto_b_offset(size_local.location);
+            cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+            cpu.write_a_at(&size_local);
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(HEAP_START)));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.read_a_at(&size_local);
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::WriteAAtB));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+            cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(HEAP_START + 4)));
+            cpu.goto_b_offset(offset_local.location);
+            cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+        });
+    }
+
+    /// Leaves the address of `s` and a null terminator in A, the linker places them in static data
+    fn store_string_literal(&mut self, s: &str) {
+        let bytes = s.bytes().chain(std::iter::once(0)).collect();
+        self.out.add_op(SamLOp::SetAToStaticData(bytes));
+    }
+
+    /// Prints a single argument of print, println or print_char, without a newline
+    fn print_arg(&mut self, arg: &'a Expr, as_char: bool) {
+        let typ = self.get_expr_type(arg).unwrap_or(VarType::U32);
+        match typ {
+            VarType::StringLiteral => {
+                if let Expr::StringLiteral(s) = arg {
+                    for b in s.bytes() {
+                        self.out.add_op(SamLOp::Simple(SamSOp::SetX(b)));
                         self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
                     }
                 }
-                VarType::U8 | VarType::Bool => {
-                    self.eval_expr(arg, &Dest::X);
-                    if let VarType::Bool = &typ {
-                        self.out.add_op(SamLOp::Simple(SamSOp::NotX));
-                        self.out.add_op(SamLOp::Simple(SamSOp::NotX));
-                    }
-                    if fncall.fn_name == "print" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintA));
-                    } else if fncall.fn_name == "println" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintA));
-                        self.out.add_op(SamLOp::Simple(SamSOp::SetX(10)));
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
-                    } else if fncall.fn_name == "print_char" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
-                    } else {
-                        unimplemented!()
-                    }
+            }
+            VarType::Char => {
+                self.eval_expr(arg, &Dest::X);
+                self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
+            }
+            VarType::U8 | VarType::Bool => {
+                self.eval_expr(arg, &Dest::X);
+                if let VarType::Bool = &typ {
+                    self.out.add_op(SamLOp::Simple(SamSOp::NotX));
+                    self.out.add_op(SamLOp::Simple(SamSOp::NotX));
                 }
-                VarType::U32 | VarType::PtrTo(_) => {
-                    self.eval_expr(arg, &Dest::A);
-                    if fncall.fn_name == "print" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintA));
-                    } else if fncall.fn_name == "println" {
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintA));
-                        self.out.add_op(SamLOp::Simple(SamSOp::SetX(10)));
-                        self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
-                    } else if fncall.fn_name == "print_char" {
-                        panic!("U32 is not a character")
-                    } else {
-                        unimplemented!()
+                if as_char {
+                    self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
+                } else {
+                    self.out.add_op(SamLOp::Simple(SamSOp::PrintXDecimal));
+                }
+            }
+            VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
+                if as_char {
+                    panic!("{:?} is not a character", typ)
+                }
+                self.eval_expr(arg, &Dest::A);
+                if typ == VarType::I32 {
+                    self.out.add_op(SamLOp::Simple(SamSOp::PrintSignedA));
+                } else {
+                    self.out.add_op(SamLOp::Simple(SamSOp::PrintA));
+                }
+            }
+            VarType::U64 => {
+                if as_char {
+                    panic!("{:?} is not a character", typ)
+                }
+                self.scope(|cpu| {
+                    let val_local = cpu.locals.new_temp(&VarType::U64);
+                    cpu.eval_expr(arg, &Dest::Local(val_local.clone()));
+                    cpu.goto_b_offset(val_local.location);
+                    cpu.out.add_op(SamLOp::Simple(SamSOp::PrintU64AtB));
+                });
+            }
+            VarType::Unit => {
+                panic!("Printing unit");
+            }
+            VarType::Array(..) => {
+                panic!("Printing arrays is not supported");
+            }
+        }
+    }
+
+    pub fn call(&mut self, fncall: &'a FnCall, dest: &Dest<'a>) {
+        if fncall.fn_name == "print_char" {
+            assert_eq!(fncall.args.len(), 1);
+            self.print_arg(&fncall.args[0], true);
+        } else if fncall.fn_name == "print" || fncall.fn_name == "println" {
+            // each argument is printed in turn, println adds a single newline at the end
+            for arg in &fncall.args {
+                self.print_arg(arg, false);
+            }
+            if fncall.fn_name == "println" {
+                self.out.add_op(SamLOp::Simple(SamSOp::SetX(10)));
+                self.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
+            }
+        } else if fncall.fn_name == "assert" {
+            assert_eq!(fncall.args.len(), 1);
+            // HIR carries no source spans, so the enclosing fn stands in for the location
+            let msg = format!("Assertion failed in fn {}\n", self.fn_name);
+            let cond = self.eval_cond(&fncall.args[0]);
+            self.if_cond_else(
+                cond,
+                |_| {},
+                |cpu| {
+                    for b in msg.bytes() {
+                        cpu.out.add_op(SamLOp::Simple(SamSOp::SetX(b)));
+                        cpu.out.add_op(SamLOp::Simple(SamSOp::PrintCharX));
                     }
+                    cpu.out.add_op(SamLOp::Simple(SamSOp::Crash));
+                },
+            );
+        } else if fncall.fn_name == "alloc" {
+            assert_eq!(fncall.args.len(), 1);
+            match self.get_expr_type(&fncall.args[0]) {
+                Some(VarType::U8) | Some(VarType::Char) | Some(VarType::Bool) => {
+                    self.eval_expr(&fncall.args[0], &Dest::X);
+                    self.out.add_op(SamLOp::Simple(SamSOp::MoveXToA));
                 }
-                VarType::Unit => {
-                    panic!("Printing unit");
+                _ => {
+                    self.eval_expr(&fncall.args[0], &Dest::A);
                 }
             }
+            self.alloc_a();
+            match dest {
+                Dest::None | Dest::A => {}
+                Dest::X => panic!("Reading an allocated pointer into X?"),
+                Dest::Local(local) => self.write_a_at(local),
+            }
+        } else if fncall.fn_name == "strlen" {
+            assert_eq!(fncall.args.len(), 1);
+            self.scope(|cpu| {
+                let start_local = cpu.locals.new_temp(&VarType::U32);
+                cpu.eval_expr(&fncall.args[0], &Dest::A);
+                cpu.write_a_at(&start_local);
+                // scan with the string pointer in B, then take the distance travelled
+                cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+                cpu.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
+                cpu.while_loop(
+                    |cpu| {
+                        cpu.out.add_op(SamLOp::Simple(SamSOp::ReadXAtB));
+                        Cond::X
+                    },
+                    |cpu| cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToB(1))),
+                );
+                cpu.out.add_op(SamLOp::Simple(SamSOp::CopyBToA));
+                cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
+                cpu.out.add_op(SamLOp::Simple(SamSOp::NegA));
+                cpu.goto_b_offset(start_local.location);
+                cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+                cpu.out.add_op(SamLOp::Simple(SamSOp::NegA));
+            });
+            match dest {
+                Dest::None | Dest::A => {}
+                Dest::X => panic!("Reading a string length into X?"),
+                Dest::Local(local) => self.write_a_at(local),
+            }
         } else if fncall.fn_name == "read_char" {
             self.out.add_op(SamLOp::Simple(SamSOp::StdinX));
             match dest {
@@ -799,21 +1590,27 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                 }
                 Dest::Local(local) => match &local.typ {
                     VarType::Unit => {}
-                    VarType::U8 | VarType::Bool => {
+                    VarType::U8 | VarType::Char | VarType::Bool => {
                         self.write_x_at(local);
                     }
-                    VarType::U32 | VarType::PtrTo(_) => {
+                    VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
                         self.out.add_op(SamLOp::Simple(SamSOp::StdinX));
                         self.write_a_at(local);
                     }
                     VarType::StringLiteral => {}
+                    VarType::U64 => {
+                        panic!("Reading a char into u64 {}", local.name)
+                    }
+                    VarType::Array(..) => {
+                        panic!("Reading a char into array {}", local.name)
+                    }
                 },
             }
         } else {
             let fn_decl = self
                 .fn_decls
                 .get(&fncall.fn_name)
-                .expect(&format!("Calling unknown function {}", fncall.fn_name));
+                .unwrap_or_else(|| panic!("Calling unknown function {}", fncall.fn_name));
             assert_eq!(fn_decl.args.len(), fncall.args.len());
             let valret_local = self.scope(|cpu| {
                 let valret_local = cpu.locals.new_temp(&fn_decl.ret);
@@ -839,19 +1636,30 @@ impl<'a, 'o> SamCpu<'a, 'o> {
         }
     }
 
+    /// Executes a scope's statements, marking where each source line starts
+    pub fn exec_stmts(&mut self, scope: &'a Scope) {
+        for (stmt, line) in scope.stmts.iter().zip(&scope.stmt_lines) {
+            self.out.add_op(SamLOp::SourceLine(*line));
+            self.exec_stmt(stmt);
+        }
+    }
+
     pub fn exec_stmt(&mut self, stmt: &'a Stmt) {
         match stmt {
             Stmt::VarDecl(decl) => {
                 let local = self.locals.new_named(&decl.var_name, &decl.typ);
-                self.eval_expr(&decl.init, &Dest::Local(local));
+                match &decl.init {
+                    Some(init) => self.eval_expr(init, &Dest::Local(local)),
+                    None => self.zero_local(&local),
+                }
             }
             Stmt::VarAssign(ass) => match &ass.lhs {
                 Expr::VarRef(s) => {
-                    let local = self.locals.get(&s);
+                    let local = self.locals.get(s);
                     self.eval_expr(&ass.expr, &Dest::Local(local));
                 }
-                Expr::Deref(ptr_expr) => {
-                    let lhs_typ = self.get_expr_type(&Expr::Deref(ptr_expr.clone()));
+                Expr::Deref(_) | Expr::Index(..) => {
+                    let lhs_typ = self.get_expr_type(&ass.lhs);
                     let rhs_typ = self.get_expr_type(&ass.expr);
                     let typ = if let Some(lhs_typ) = lhs_typ {
                         if let Some(rhs_typ) = rhs_typ {
@@ -876,27 +1684,33 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                         //let ptr_local = cpu.locals.new_temp(&VarType::PtrTo(Box::new(typ.clone())));
                         let val_local = cpu.locals.new_temp(&typ);
                         cpu.eval_expr(&ass.expr, &Dest::Local(val_local.clone()));
-                        cpu.eval_expr(ptr_expr, &Dest::A);
+                        cpu.eval_place_address(&ass.lhs);
                         match typ {
                             VarType::Unit => {}
-                            VarType::U8 | VarType::Bool => {
+                            VarType::U8 | VarType::Char | VarType::Bool => {
                                 cpu.read_x_at(&val_local);
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::WriteXAtB));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                             }
-                            VarType::U32 | VarType::PtrTo(_) => {
+                            VarType::U16 | VarType::U32 | VarType::I32 | VarType::PtrTo(_) => {
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::CopyAToB));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                                 // now B is restored, C contains the ptr
                                 cpu.read_a_at(&val_local);
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
-                                cpu.out.add_op(SamLOp::Simple(SamSOp::WriteAAtB));
+                                cpu.out.add_op(SamLOp::Simple(write_a_op(&typ)));
                                 cpu.out.add_op(SamLOp::Simple(SamSOp::SwapBAndC));
                             }
                             VarType::StringLiteral => {}
+                            VarType::U64 => {
+                                panic!("Writing u64s through pointers is not supported")
+                            }
+                            VarType::Array(..) => {
+                                panic!("Writing arrays through pointers is not supported")
+                            }
                         }
                     });
                 }
@@ -906,40 +1720,75 @@ impl<'a, 'o> SamCpu<'a, 'o> {
                 self.eval_expr(e, &Dest::None);
             }
             Stmt::IfMaybeElse(i) => {
-                self.eval_expr(&i.cond, &Dest::X);
-                let start_b_offset = self.cur_b_offset;
-                let (true_entry_index, true_exit_index) = self.block(|cpu| {
-                    cpu.eval_expr(&i.if_true, &Dest::None);
-                });
-                let end_b_offset = self.cur_b_offset;
-                self.cur_b_offset = start_b_offset;
-                let (false_entry_index, false_exit_index) = self.block(|cpu| {
-                    if let Some(if_false) = &i.if_false {
-                        cpu.eval_expr(if_false, &Dest::None);
-                    }
-                    cpu.goto_b_offset(end_b_offset);
-                });
-                self.out.add_op(SamLOp::JmpToBlockIfX(true_entry_index));
-                let (old_index, new_index) = self.split_to_new_block();
-                self.out.arena.blocks[old_index].next_block_index = Some(false_entry_index);
-                self.out.arena.blocks[true_exit_index].next_block_index = Some(new_index);
-                self.out.arena.blocks[false_exit_index].next_block_index = Some(new_index);
+                let cond = self.eval_cond(&i.cond);
+                self.if_cond_else(
+                    cond,
+                    |cpu| cpu.eval_expr(&i.if_true, &Dest::None),
+                    |cpu| {
+                        if let Some(if_false) = &i.if_false {
+                            cpu.eval_expr(if_false, &Dest::None);
+                        }
+                    },
+                );
             }
             Stmt::WhileLoop(w) => {
-                let start_b_offset = self.cur_b_offset;
-                let (inner_entry_index, inner_exit_index) = self.block(|cpu| {
-                    cpu.eval_expr(&w.inner, &Dest::None);
-                    cpu.goto_b_offset(start_b_offset);
-                });
-                let (cond_entry_index, cond_exit_index) = self.block(|cpu| {
-                    cpu.eval_expr(&w.cond, &Dest::X);
-                    cpu.goto_b_offset(start_b_offset);
-                    cpu.out.add_op(SamLOp::JmpToBlockIfX(inner_entry_index));
+                self.while_loop(
+                    |cpu| cpu.eval_cond(&w.cond),
+                    |cpu| cpu.eval_expr(&w.inner, &Dest::None),
+                );
+            }
+            Stmt::ForLoop(f) => {
+                // let i = start; let end = end; while i < end { inner; i = i + 1; }
+                let typ = f
+                    .typ
+                    .clone()
+                    .or_else(|| self.get_expr_type(&f.start))
+                    .or_else(|| self.get_expr_type(&f.end))
+                    .unwrap_or(VarType::U32);
+                assert!(
+                    matches!(typ, VarType::U8 | VarType::U32),
+                    "Can't use {:?} as a for loop variable",
+                    typ
+                );
+                self.scope(|cpu| {
+                    let var_local = cpu.locals.new_named(&f.var_name, &typ);
+                    let end_local = cpu.locals.new_temp(&typ);
+                    cpu.eval_expr(&f.start, &Dest::Local(var_local.clone()));
+                    cpu.eval_expr(&f.end, &Dest::Local(end_local.clone()));
+                    let cond_var_local = var_local.clone();
+                    cpu.while_loop(
+                        |cpu| match &typ {
+                            VarType::U8 => {
+                                cpu.read_x_at(&end_local);
+                                cpu.goto_b_offset(cond_var_local.location);
+                                cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU8AtBWithX));
+                                Cond::CmpIs(CmpResult::Lt)
+                            }
+                            _ => {
+                                cpu.read_a_at(&end_local);
+                                cpu.goto_b_offset(cond_var_local.location);
+                                cpu.out.add_op(SamLOp::Simple(SamSOp::CmpU32AtBWithA));
+                                Cond::CmpIs(CmpResult::Lt)
+                            }
+                        },
+                        |cpu| {
+                            cpu.eval_expr(&f.inner, &Dest::None);
+                            match &typ {
+                                VarType::U8 => {
+                                    cpu.read_x_at(&var_local);
+                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToX(1)));
+                                    cpu.write_x_at(&var_local);
+                                }
+                                _ => {
+                                    cpu.out.add_op(SamLOp::Simple(SamSOp::SetA(1)));
+                                    cpu.goto_b_offset(var_local.location);
+                                    cpu.out.add_op(SamLOp::Simple(SamSOp::AddU32AtBToA));
+                                    cpu.write_a_at(&var_local);
+                                }
+                            }
+                        },
+                    );
                 });
-                let (old_index, new_index) = self.split_to_new_block();
-                self.out.arena.blocks[old_index].next_block_index = Some(cond_entry_index);
-                self.out.arena.blocks[inner_exit_index].next_block_index = Some(cond_entry_index);
-                self.out.arena.blocks[cond_exit_index].next_block_index = Some(new_index);
             }
             Stmt::Return(s) => {
                 if let Some(ret_expr) = &s.expr {
@@ -956,25 +1805,27 @@ impl<'a, 'o> SamCpu<'a, 'o> {
     }
 }
 
-fn process_cmp_result(cpu: &mut SamCpu, cmp_kind: CmpKind) {
+/// The ops that turn the result of a Cmp op in X into a bool for `cmp_kind`
+fn cmp_result_ops(cmp_kind: CmpKind) -> Vec<SamSOp> {
     match cmp_kind {
-        CmpKind::GT => {
-            cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToX(255)));
-            cpu.out.add_op(SamLOp::Simple(SamSOp::NotX));
-        }
-        CmpKind::GE => {
-            cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToX(1)));
-        }
-        CmpKind::EQ => {
-            cpu.out.add_op(SamLOp::Simple(SamSOp::NotX));
-        }
-        CmpKind::LT => {
-            cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToX(1)));
-            cpu.out.add_op(SamLOp::Simple(SamSOp::NotX));
-        }
-        CmpKind::LE => {
-            cpu.out.add_op(SamLOp::Simple(SamSOp::AddConstToX(255)));
-        }
-        CmpKind::NE => {}
+        CmpKind::GT => vec![SamSOp::AddConstToX(255), SamSOp::NotX],
+        CmpKind::GE => vec![SamSOp::AddConstToX(1)],
+        CmpKind::EQ => vec![SamSOp::NotX],
+        CmpKind::LT => vec![SamSOp::AddConstToX(1), SamSOp::NotX],
+        CmpKind::LE => vec![SamSOp::AddConstToX(255)],
+        CmpKind::NE => vec![],
     }
 }
+
+fn process_cmp_result(cpu: &mut SamCpu, cmp_kind: CmpKind) {
+    for op in cmp_result_ops(cmp_kind) {
+        cpu.out.add_op(SamLOp::Simple(op));
+    }
+}
+
+fn is_cmp_op(op: &SamLOp) -> bool {
+    matches!(
+        op,
+        SamLOp::Simple(SamSOp::CmpU8AtBWithX | SamSOp::CmpU32AtBWithA | SamSOp::CmpI32AtBWithA)
+    )
+}
@@ -10,7 +10,7 @@
 mod bf;
 mod bf2wasm;
 mod cpu;
-mod hir;
+pub mod hir;
 mod hir2sam;
 mod linker;
 mod lir2bf;
@@ -23,7 +23,7 @@ extern crate num;
 extern crate num_format;
 extern crate wat;
 
-use bf2wasm::bf2wasm;
+use bf2wasm::{bf2wasm, DEFAULT_TAPE_PAGES};
 use nom::AsBytes;
 use std::fmt::Debug;
 use wasm_bindgen::prelude::*;
@@ -79,8 +79,7 @@ fn maina() {
             &prog,
             &mut std::io::stdin(),
             &mut std::io::stdout(),
-            None,
-            None,
+            RunOptions::default(),
         )
         .unwrap_or_else(print_err);
 }
@@ -131,8 +130,10 @@ fn mainc() {
             &ops,
             &mut std::io::stdin(),
             &mut std::io::stdout(),
-            Some(&cfg),
-            None,
+            RunOptions {
+                cpu_config: Some(&cfg),
+                ..Default::default()
+            },
         )
         .unwrap_or_else(print_err);
 }
@@ -163,8 +164,10 @@ fn maind() {
             &ops,
             &mut std::io::stdin(),
             &mut std::io::stdout(),
-            Some(&cfg),
-            None,
+            RunOptions {
+                cpu_config: Some(&cfg),
+                ..Default::default()
+            },
         )
         .unwrap_or_else(print_err);
 }
@@ -187,8 +190,10 @@ fn maine() {
             &ops,
             &mut std::io::stdin(),
             &mut std::io::stdout(),
-            Some(&cfg),
-            None,
+            RunOptions {
+                cpu_config: Some(&cfg),
+                ..Default::default()
+            },
         )
         .unwrap_or_else(print_err);
 
@@ -219,8 +224,11 @@ fn mainf() {
         &opt_ops,
         &mut std::io::stdin(),
         &mut std::io::stdout(),
-        Some(&cfg),
-        Some(&mut loop_count),
+        RunOptions {
+            cpu_config: Some(&cfg),
+            loop_count: Some(&mut loop_count),
+            ..Default::default()
+        },
     );
     println!();
     match result {
@@ -233,6 +241,7 @@ fn mainf() {
     }
     state.print_state(&cfg);
     println!("Instrs executed: {}", loop_count.get_instrs_executed());
+    println!("Max cell ptr: {}", state.max_cell_ptr());
 }
 
 #[allow(unused)]
@@ -284,8 +293,11 @@ fn main() {
         &opt_ops,
         &mut std::io::stdin(),
         &mut std::io::stdout(),
-        Some(&cfg),
-        Some(&mut loop_count),
+        RunOptions {
+            cpu_config: Some(&cfg),
+            loop_count: Some(&mut loop_count),
+            ..Default::default()
+        },
     );
     println!();
     match result {
@@ -302,6 +314,7 @@ fn main() {
         ops2str(&ops, BfFormatOptions::clean()).chars().count()
     );
     println!("Instrs executed: {}", loop_count.get_instrs_executed());
+    println!("Max cell ptr: {}", state.max_cell_ptr());
 }
 
 /// Returns the version of the program.
@@ -311,10 +324,58 @@ pub fn init_brainpluck() -> String {
     "0.0.3".to_owned()
 }
 
+#[derive(Debug)]
+pub enum CompileError {
+    Parse(HirParseError),
+    NoMainFunction,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Parse(e) => write!(f, "{}", e),
+            CompileError::NoMainFunction => write!(f, "No main function found"),
+        }
+    }
+}
+
+impl From<HirParseError> for CompileError {
+    fn from(e: HirParseError) -> CompileError {
+        CompileError::Parse(e)
+    }
+}
+
+/// Parses a program that can be run, i.e. one that has a main function
+fn parse_hir_with_main(src: &str) -> Result<Program, CompileError> {
+    let hir = parse_hir(src)?;
+    if !hir.fns.contains_key("main") {
+        return Err(CompileError::NoMainFunction);
+    }
+    Ok(hir)
+}
+
+/// Runs the whole pipeline, also returning the linked SAM listing
+fn compile_hir_with_sam(src: &str) -> Result<(String, Vec<BfOp>, CpuConfig), CompileError> {
+    let hir = parse_hir_with_main(src)?;
+    let sam = hir2sam(&hir);
+    let linked = link_sam_fns(sam);
+    let sam_str = linked.sam_str.clone();
+    let (ops, cfg) = sam2lir(linked);
+    let ops = get_optimized_bf_ops(&lir2bf(&ops));
+    Ok((sam_str, ops, cfg))
+}
+
+/// Compiles HIR source to optimized BF ops, along with the CPU config they were built for
+pub fn compile_hir_to_bf(src: &str) -> Result<(Vec<BfOp>, CpuConfig), CompileError> {
+    let (_, ops, cfg) = compile_hir_with_sam(src)?;
+    Ok((ops, cfg))
+}
+
 #[wasm_bindgen]
 pub struct CompilationResult {
     sam: String,
     bf: String,
+    error: Option<String>,
 }
 
 #[wasm_bindgen]
@@ -328,37 +389,46 @@ impl CompilationResult {
     pub fn bf(&self) -> String {
         self.bf.clone()
     }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
 }
 
 #[wasm_bindgen]
-pub fn compile(hir: &str) -> CompilationResult {
-    let hir = parse_hir(hir).unwrap();
-
-    let sam = hir2sam(&hir);
-
-    let linked = link_sam_fns(sam);
-    let sam_str = linked.sam_str.clone();
-
-    let (ops, _cfg) = sam2lir(linked);
-    let ops = lir2bf(&ops);
-
-    let ops = get_optimized_bf_ops(&ops);
-
-    let bf = ops2str(&ops, BfFormatOptions::clean());
+pub fn compile(src: &str) -> CompilationResult {
+    let (sam_str, ops, _cfg) = match compile_hir_with_sam(src) {
+        Ok(res) => res,
+        Err(e) => {
+            return CompilationResult {
+                sam: String::new(),
+                bf: String::new(),
+                error: Some(e.to_string()),
+            }
+        }
+    };
 
-    let bf = bf
-        .as_bytes()
-        .chunks(50)
-        .map(|buf| format!("{}\n", std::str::from_utf8(buf).unwrap()))
-        .collect::<String>();
+    let bf = ops2str(
+        &ops,
+        BfFormatOptions {
+            wrap_columns: Some(50),
+            ..BfFormatOptions::clean()
+        },
+    );
 
-    CompilationResult { sam: sam_str, bf }
+    CompilationResult {
+        sam: sam_str,
+        bf,
+        error: None,
+    }
 }
 
 #[wasm_bindgen]
 pub struct DebugResult {
     sam: String,
     output: String,
+    error: Option<String>,
 }
 
 #[wasm_bindgen]
@@ -372,11 +442,25 @@ impl DebugResult {
     pub fn output(&self) -> String {
         self.output.clone()
     }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
 }
 
 #[wasm_bindgen]
-pub fn debug_program(hir: &str, input: &str) -> DebugResult {
-    let hir = parse_hir(hir).unwrap();
+pub fn debug_program(src: &str, input: &str) -> DebugResult {
+    let hir = match parse_hir_with_main(src) {
+        Ok(hir) => hir,
+        Err(e) => {
+            return DebugResult {
+                sam: String::new(),
+                output: String::new(),
+                error: Some(e.to_string()),
+            }
+        }
+    };
 
     let sam = hir2sam(&hir);
 
@@ -390,62 +474,168 @@ pub fn debug_program(hir: &str, input: &str) -> DebugResult {
     let mut r = input.as_bytes();
 
     while !samstate.halted {
-        let op = samstate.decode_next_op();
+        let trace = samstate.trace_step(&mut r);
         output += &format!(
             "x: {:3} a: {:10} b: {:10} i: {:10}\n",
-            samstate.x, samstate.a, samstate.b, samstate.instr_ptr
+            trace.x, trace.a, trace.b, trace.instr_ptr
         );
-        output += &format!("Instruction: {:?}\n", op);
-        let mut w = Vec::new();
-        let _res = samstate
-            .run_op(&op, &mut r, &mut w)
-            .unwrap_or_else(|e| output += &format!("Error: {:?}", e));
-        if !w.is_empty() {
+        output += &format!("Instruction: {:?}\n", trace.op);
+        if let Err(e) = trace.result {
+            output += &format!("Error: {:?}", e);
+        }
+        if !trace.output.is_empty() {
             output += &format!(
                 "Received output: {:?}\n",
-                String::from_utf8_lossy(w.as_bytes()).to_string()
+                String::from_utf8_lossy(&trace.output).to_string()
             );
         }
     }
+    output += &format!("Instructions executed: {}\n", samstate.instrs_executed());
 
     DebugResult {
         sam: sam_str,
         output,
+        error: None,
+    }
+}
+
+#[wasm_bindgen]
+pub struct BfRunResult {
+    output: Vec<u8>,
+    error: Option<String>,
+}
+
+#[wasm_bindgen]
+impl BfRunResult {
+    /// The output decoded as UTF-8, with invalid sequences replaced
+    #[wasm_bindgen(getter)]
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).to_string()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn output_bytes(&self) -> Vec<u8> {
+        self.output.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
     }
 }
 
+/// With `max_steps`, gives up with an error instead of running forever on an infinite loop
 #[wasm_bindgen]
-pub fn parse_and_run_bf(bf: &str, input: &str) -> String {
-    let ops = parse_bf(bf).unwrap_or_else(|e| panic!("Unable to parse bf: {:?}", e));
+pub fn parse_and_run_bf(
+    bf: &str,
+    input: &str,
+    eof_behavior: EofBehavior,
+    max_steps: Option<u64>,
+) -> BfRunResult {
+    let ops = match parse_bf(bf) {
+        Ok(ops) => ops,
+        Err(e) => {
+            return BfRunResult {
+                output: Vec::new(),
+                error: Some(e.to_string()),
+            }
+        }
+    };
     let opt_ops = get_optimized_bf_ops(&ops);
     let mut bf_state = BfState::new();
+    bf_state.set_eof_behavior(eof_behavior);
     let mut r = input.as_bytes();
     let mut w = Vec::new();
-    bf_state
-        .run_ops(&opt_ops, &mut r, &mut w, None, None)
-        .expect("error running bf program");
-    String::from_utf8_lossy(w.as_bytes()).to_string()
+    let error = bf_state
+        .run_ops(
+            &opt_ops,
+            &mut r,
+            &mut w,
+            RunOptions {
+                max_steps,
+                ..Default::default()
+            },
+        )
+        .err()
+        .map(|e| format!("Error running bf program: {:?}", e));
+    BfRunResult { output: w, error }
 }
 
+/// `tape_pages` is the minimum size of the imported tape memory, in 64KiB pages.
+/// With `max_loop_iterations`, `run_bf` returns 2 once that many loop iterations have run.
+/// Invalid bf is returned as an error message, which JS gets as a thrown string.
 #[wasm_bindgen]
-pub fn compile_bf_to_wasm(bf: &str) -> Vec<u8> {
-    let ops = parse_bf(bf).unwrap_or_else(|e| panic!("Unable to parse bf: {:?}", e));
-    let wasm_bytes = bf2wasm(ops, true).unwrap_or_else(|e| panic!("Unable to parse wat: {:?}", e));
-    wasm_bytes
+pub fn compile_bf_to_wasm(
+    bf: &str,
+    tape_pages: u32,
+    eof_behavior: EofBehavior,
+    max_loop_iterations: Option<u64>,
+) -> Result<Vec<u8>, String> {
+    let ops = parse_bf(bf).map_err(|e| e.to_string())?;
+    let wasm_bytes = bf2wasm(ops, true, tape_pages, eof_behavior, max_loop_iterations)
+        .unwrap_or_else(|e| panic!("Unable to parse wat: {:?}", e));
+    Ok(wasm_bytes)
 }
 
-#[wasm_bindgen]
-pub fn perf_bf(bf: &str, input: &str) -> String {
-    let ops = parse_bf(bf).unwrap_or_else(|e| panic!("Unable to parse bf: {:?}", e));
+/// Also returns the error message when the program didn't run to the end,
+/// the loop counts up to that point are still useful
+fn run_bf_with_loop_count(
+    bf: &str,
+    input: &str,
+    max_steps: Option<u64>,
+) -> Result<(Vec<BfOp>, LoopCount, usize, Option<String>), ParseBfProgError> {
+    let ops = parse_bf(bf)?;
     let opt_ops = get_optimized_bf_ops(&ops);
     let mut bf_state = BfState::new();
     let mut r = input.as_bytes();
     let mut w = Vec::new();
     let mut loop_count = LoopCount::new();
-    bf_state
-        .run_ops(&opt_ops, &mut r, &mut w, None, Some(&mut loop_count))
-        .expect("error running bf program");
-    ops2str(&opt_ops, BfFormatOptions::perf_verbose(&loop_count))
+    let error = bf_state
+        .run_ops(
+            &opt_ops,
+            &mut r,
+            &mut w,
+            RunOptions {
+                loop_count: Some(&mut loop_count),
+                max_steps,
+                ..Default::default()
+            },
+        )
+        .err()
+        .map(|e| format!("Error running bf program: {:?}\n", e));
+    Ok((opt_ops, loop_count, bf_state.max_cell_ptr(), error))
+}
+
+#[wasm_bindgen]
+pub fn perf_bf(bf: &str, input: &str, max_steps: Option<u64>) -> String {
+    let (opt_ops, loop_count, _, error) = match run_bf_with_loop_count(bf, input, max_steps) {
+        Ok(res) => res,
+        Err(e) => return e.to_string(),
+    };
+    error.unwrap_or_default() + &ops2str(&opt_ops, BfFormatOptions::perf_verbose(&loop_count))
+}
+
+#[wasm_bindgen]
+pub fn perf_bf_summary(bf: &str, input: &str, max_steps: Option<u64>) -> String {
+    let (_, loop_count, max_cell_ptr, error) = match run_bf_with_loop_count(bf, input, max_steps) {
+        Ok(res) => res,
+        Err(e) => return e.to_string(),
+    };
+    let mut result = error.unwrap_or_default();
+    result += &format!(
+        "Instrs executed: {}\nMax cell ptr: {}\nTop 10 hottest loops:\n",
+        loop_count.get_instrs_executed(),
+        max_cell_ptr
+    );
+    for (loop_id, instrs_executed) in loop_count.hotspots(10) {
+        let loop_id = loop_id
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        result += &format!("loop {}: {}\n", loop_id, instrs_executed);
+    }
+    result
 }
 
 #[cfg(test)]
@@ -458,7 +648,15 @@ mod test {
         let mut r = i.as_bytes();
         let mut w = Vec::new();
         state
-            .run_ops(&prog, &mut r, &mut w, cfg, None)
+            .run_ops(
+                &prog,
+                &mut r,
+                &mut w,
+                RunOptions {
+                    cpu_config: cfg,
+                    ..Default::default()
+                },
+            )
             .unwrap_or_else(print_err);
         assert_eq!(w, o.as_bytes());
         if let Some(cfg) = cfg {
@@ -474,326 +672,2157 @@ mod test {
         test_parsed_bf_prog(&lir2bf(prog), i, o, Some(cfg));
     }
 
+    /// Runs a program through the interpreter, the wasm backend and, if a C compiler is
+    /// available, the C backend, returning the output of each in that order
+    fn run_all_backends(ops: &[BfOp], input: &[u8]) -> Vec<Vec<u8>> {
+        let mut outputs = vec![
+            run_and_capture(ops, input).unwrap_or_else(print_err),
+            run_wasm_backend(ops, input),
+        ];
+        if let Some(output) = run_c_backend(ops, input) {
+            outputs.push(output);
+        }
+        outputs
+    }
+
+    fn run_wasm_backend(ops: &[BfOp], input: &[u8]) -> Vec<u8> {
+        let (return_val, output) = run_wasm_backend_with_limit(ops, input, None);
+        // all the input is there from the start, so the program never has to wait for more
+        assert_eq!(return_val, 0);
+        output
+    }
+
+    /// Returns what `run_bf` returned along with the output
+    fn run_wasm_backend_with_limit(
+        ops: &[BfOp],
+        input: &[u8],
+        max_loop_iterations: Option<u64>,
+    ) -> (i32, Vec<u8>) {
+        use wasmi::{Caller, Engine, Linker, Memory, MemoryType, Module, Store};
+
+        struct Io {
+            input: Vec<u8>,
+            input_pos: usize,
+            output: Vec<u8>,
+        }
+
+        let wasm = bf2wasm(
+            ops.to_vec(),
+            true,
+            DEFAULT_TAPE_PAGES,
+            EofBehavior::Zero,
+            max_loop_iterations,
+        )
+        .unwrap_or_else(print_err);
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm[..]).unwrap_or_else(print_err);
+        let io = Io {
+            input: input.to_vec(),
+            input_pos: 0,
+            output: Vec::new(),
+        };
+        let mut store = Store::new(&engine, io);
+        let tape_type = MemoryType::new(DEFAULT_TAPE_PAGES, None).unwrap_or_else(print_err);
+        let tape = Memory::new(&mut store, tape_type).unwrap_or_else(print_err);
+        let mut linker = Linker::<Io>::new(&engine);
+        linker
+            .define("imports", "tape", tape)
+            .unwrap_or_else(print_err);
+        linker
+            .func_wrap("imports", "read_input_byte", |mut caller: Caller<Io>| {
+                let io = caller.data_mut();
+                match io.input.get(io.input_pos) {
+                    Some(&c) => {
+                        io.input_pos += 1;
+                        c as i32
+                    }
+                    None => -1,
+                }
+            })
+            .unwrap_or_else(print_err);
+        linker
+            .func_wrap(
+                "imports",
+                "write_output_byte",
+                |mut caller: Caller<Io>, byte: i32| caller.data_mut().output.push(byte as u8),
+            )
+            .unwrap_or_else(print_err);
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .unwrap_or_else(print_err);
+        let run_bf = instance
+            .get_typed_func::<(), i32>(&store, "run_bf")
+            .unwrap_or_else(print_err);
+        let return_val = run_bf.call(&mut store, ()).unwrap_or_else(print_err);
+        (return_val, store.into_data().output)
+    }
+
+    fn run_c_backend(ops: &[BfOp], input: &[u8]) -> Option<Vec<u8>> {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static NUM_BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "brainpluck_backend_{}_{}",
+            std::process::id(),
+            NUM_BUILDS.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = std::env::temp_dir();
+        let src_path = dir.join(format!("{}.c", name));
+        let exe_path = dir.join(name);
+        std::fs::write(&src_path, ops2c(ops)).unwrap_or_else(print_err);
+        let compiled = Command::new("cc")
+            .arg("-O1")
+            .arg("-o")
+            .arg(&exe_path)
+            .arg(&src_path)
+            .status();
+        let _ = std::fs::remove_file(&src_path);
+        // no C compiler around, so only the other backends get compared
+        if !matches!(compiled, Ok(status) if status.success()) {
+            return None;
+        }
+        let mut child = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap_or_else(print_err);
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input)
+            .unwrap_or_else(print_err);
+        let output = child.wait_with_output().unwrap_or_else(print_err);
+        let _ = std::fs::remove_file(&exe_path);
+        Some(output.stdout)
+    }
+
     #[test]
     fn test_hello_world_1() {
         test_raw_bf_prog("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", "", "Hello World!\n");
     }
 
+    #[test]
+    fn test_backends_agree_on_hello_world() {
+        let prog = parse_bf("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.").unwrap_or_else(print_err);
+        let outputs = run_all_backends(&prog, b"");
+        assert!(outputs.len() >= 2);
+        for output in outputs {
+            assert_eq!(output, b"Hello World!\n");
+        }
+    }
+
+    #[test]
+    fn test_bf_instruction_histogram() {
+        let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let prog = parse_bf(src).unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        assert!(opt_prog.len() < prog.len());
+        let histogram = bf_instruction_histogram(&opt_prog);
+        let expected = [
+            ('+', 40),
+            ('-', 21),
+            ('>', 18),
+            ('<', 8),
+            ('[', 3),
+            (']', 3),
+            ('.', 13),
+        ];
+        assert_eq!(histogram, expected.into_iter().collect());
+        assert_eq!(histogram, bf_instruction_histogram(&prog));
+    }
+
     #[test]
     fn test_hello_world_2() {
         test_raw_bf_prog(">++++++++[-<+++++++++>]<.>>+>-[+]++>++>+++[>[->+++<<+++>]<<]>-----.>->+++..+++.>-.<<+[>[+>+]>>]<--------------.>>.+++.------.--------.>+.>+.", "", "Hello World!\n");
     }
 
     #[test]
-    fn test_cell_size_check() {
-        test_raw_bf_prog(
-            "++++++++[>++++++++<-]>[<++++>-]+<[>-<[>++++<-]>[<++++++++>-]<
-            [>++++++++<-]+>[>++++++++++[>+++++<-]>+.-.[-]<<[-]<->] <[>>++
-            +++++[>+++++++<-]>.+++++.[-]<<<-]] >[>++++++++[>+++++++<-]>.[
-            -]<<-]<+++++++++++[>+++>+++++++++>+++++++++>+<<<<-]>-.>-.++++
-            +++.+++++++++++.<.>>.++.+++++++..<-.>>-[[-]<]",
-            "",
-            "8 bit cells",
+    fn test_bf_comment_offsets() {
+        let hir = parse_hir(
+            "fn main() {
+                println(3);
+            }",
+        )
+        .unwrap();
+        let (ops, _cfg) = sam2lir(link_sam_fns(hir2sam(&hir)));
+        let bf = lir2bf(&ops);
+        let clean = ops2str(&bf, BfFormatOptions::clean());
+
+        let offsets = bf_comment_offsets(&bf);
+        let (offset, _) = offsets
+            .iter()
+            .find(|(_, msg)| msg == "Main loop")
+            .expect("no main loop comment");
+        let index = bf
+            .iter()
+            .position(|op| matches!(op, BfOp::Comment(msg) if msg == "Main loop"))
+            .unwrap();
+        assert_eq!(
+            *offset,
+            ops2str(&bf[..index].to_vec(), BfFormatOptions::clean()).len()
+        );
+        assert!(
+            clean[*offset..].starts_with(&ops2str(&bf[index..].to_vec(), BfFormatOptions::clean()))
         );
+        assert!(bf_comment_listing(&bf).contains(&format!("{:8}: Main loop\n", offset)));
     }
 
     #[test]
-    fn test_add_const_to_register() {
-        let mut cfg = CpuConfig::new();
-        let register = cfg.add_register_track(TrackId::Register1, 4);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
-
-        cpu.add_const_to_register(register, 103050u64, scratch);
-        cpu.add_const_to_register(register, 20406u64, scratch);
+    fn test_source_line_comments() {
+        let hir = parse_hir(
+            "fn main() {
+                println(3);
+                print(4);
+            }",
+        )
+        .unwrap();
+        assert_eq!(hir.fns["main"].scope.stmt_lines, vec![2, 3]);
+        let nested =
+            parse_hir("fn main() {\n    while 0 {\n\n        println(1);\n    }\n}").unwrap();
+        match &nested.fns["main"].scope.stmts[0] {
+            Stmt::WhileLoop(w) => match &w.inner {
+                Expr::Scope(s) => assert_eq!(s.stmt_lines, vec![4]),
+                other => panic!("{:?}", other),
+            },
+            other => panic!("{:?}", other),
+        }
+        let linked = link_sam_fns(hir2sam(&hir));
+        assert_eq!(linked.source_lines.len(), 2);
+        assert_eq!(linked.source_lines[0].1, 2);
+        assert_eq!(linked.source_lines[1].1, 3);
+        assert!(linked.source_lines[0].0 < linked.source_lines[1].0);
+        let (ops, _cfg) = sam2lir(linked);
+        let bf = ops2str(&lir2bf(&ops), BfFormatOptions::clean_with_comments());
+        let line_2 = bf.find("line 2").expect("no comment for line 2");
+        let line_3 = bf.find("line 3").expect("no comment for line 3");
+        assert!(line_2 < line_3);
+    }
 
-        cpu.moveprint_byte(register.at(0), scratch);
-        cpu.print_text(", ", scratch);
-        cpu.moveprint_byte(register.at(1), scratch);
-        cpu.print_text(", ", scratch);
-        cpu.moveprint_byte(register.at(2), scratch);
-        cpu.print_text(", ", scratch);
-        cpu.moveprint_byte(register.at(3), scratch);
+    #[test]
+    fn test_move_mul() {
+        let prog = parse_bf("++++++++[->+++++++++<]>.<+++++[->>++++++++++++++++++++<<]>>+.")
+            .unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::MoveMul(1, 9))));
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::MoveMul(2, 20))));
+        for prog in [&prog, &opt_prog] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state
+                .run_ops(prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            assert_eq!(w, b"He");
+        }
+    }
 
-        test_lir_prog(&cpu.into_ops(), "", "0, 1, 226, 64", &cfg);
+    #[test]
+    fn test_move_sub() {
+        let prog = parse_bf("++++++++++[->++++++++++<]>>++<<+++[->-<]>.>[-<->]<.")
+            .unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::MoveSub(1))));
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::MoveSub(-1))));
+        let reparsed =
+            parse_bf(&ops2str(&opt_prog, BfFormatOptions::clean())).unwrap_or_else(print_err);
+        for prog in [&prog, &opt_prog, &reparsed] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state
+                .run_ops(prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            assert_eq!(w, b"a_");
+        }
     }
 
     #[test]
-    fn test_print_register_hex() {
-        let mut cfg = CpuConfig::new();
-        let register = cfg.add_register_track(TrackId::Register1, 4);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_strip_dead_loops() {
+        let count_loops = |src: &str| {
+            let prog = get_optimized_bf_ops(&parse_bf(src).unwrap_or_else(print_err));
+            let clrs = prog.iter().filter(|op| matches!(op, BfOp::Clr)).count();
+            let loops = prog.iter().filter(|op| matches!(op, BfOp::Loop(_))).count();
+            (clrs, loops)
+        };
+        assert_eq!(count_loops("+[-]"), (1, 0));
+        assert_eq!(count_loops("+[-][]"), (1, 0));
+        assert_eq!(count_loops("+[-][><]."), (1, 0));
+        assert_eq!(count_loops("[]+."), (0, 0));
+        // the cell may be nonzero here, so the loop has to stay
+        assert_eq!(count_loops(",[]"), (0, 1));
+    }
 
-        cpu.set_register(register, 123456u64);
-        cpu.moveprint_register_hex(register, scratch);
+    #[test]
+    fn test_skip_clearing_known_empty_cells() {
+        // cell 0 is moved away and later cleared again, cell 1 is moved back onto it
+        let prog = parse_bf(",>,<[->+<]>.<[-]>[-<+>]<.>[-]").unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        let clrs = opt_prog.iter().filter(|op| matches!(op, BfOp::Clr)).count();
+        let move_adds = opt_prog
+            .iter()
+            .filter(|op| matches!(op, BfOp::MoveAdd(_)))
+            .count();
+        assert_eq!((clrs, move_adds), (0, 2));
+        assert_eq!(opt_prog.len(), 11);
+        let mut outputs = Vec::new();
+        for prog in [&prog, &opt_prog] {
+            let mut w = Vec::new();
+            BfState::new()
+                .run_ops(prog, &mut "ab".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            outputs.push(w);
+        }
+        assert_eq!(outputs[0], b"\xc3\xc3".to_vec());
+        assert_eq!(outputs[0], outputs[1]);
+    }
 
-        test_lir_prog(&cpu.into_ops(), "", "0x0001E240", &cfg);
+    #[test]
+    fn test_scan_loops() {
+        let prog =
+            parse_bf(">+>+>+>+>+>+>+>+[<]>[>]++++++++[<++++++++>-]<+.").unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::ScanLeft)));
+        assert!(opt_prog.iter().any(|op| matches!(op, BfOp::ScanRight)));
+        let mut instrs_executed = Vec::new();
+        for prog in [&prog, &opt_prog] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            let mut loop_count = LoopCount::new();
+            state
+                .run_ops(
+                    prog,
+                    &mut "".as_bytes(),
+                    &mut w,
+                    RunOptions {
+                        loop_count: Some(&mut loop_count),
+                        ..Default::default()
+                    },
+                )
+                .unwrap_or_else(print_err);
+            assert_eq!(w, b"B");
+            instrs_executed.push(loop_count.get_instrs_executed());
+        }
+        assert!(instrs_executed[1] < instrs_executed[0]);
     }
 
     #[test]
-    fn test_unpack_and_print_register() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let register = register_builder.add_register(4);
-        let binregister = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_redundant_clr() {
+        let prog = parse_bf("[-][-]").unwrap_or_else(print_err);
+        assert!(get_optimized_bf_ops(&prog).len() < prog.len());
 
-        cpu.add_const_to_register(register, 0b111111111101010101010101u64, scratch);
-        cpu.unpack_register(register, binregister, scratch, false);
-        cpu.print_binregister_in_binary(binregister, scratch);
+        let count_clrs = |prog: &str| {
+            let prog = parse_bf(prog).unwrap_or_else(print_err);
+            get_optimized_bf_ops(&prog)
+                .iter()
+                .filter(|op| matches!(op, BfOp::Clr))
+                .count()
+        };
+        assert_eq!(count_clrs("+[-][-]"), 1);
+        assert_eq!(count_clrs("+[->+<][-]"), 0);
+        assert_eq!(count_clrs("+[->+<]>[-]"), 1);
+    }
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b00000000111111111101010101010101",
-            &cfg,
-        );
+    #[test]
+    fn test_clr_add_becomes_set() {
+        let prog = parse_bf(",[-]+++++.>,[-]--.").unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        assert!(matches!(
+            opt_prog.as_slice(),
+            [
+                BfOp::In,
+                BfOp::Set(5),
+                BfOp::Out,
+                BfOp::Right,
+                BfOp::In,
+                BfOp::Set(254),
+                BfOp::Out
+            ]
+        ));
+        for prog in [&prog, &opt_prog] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state
+                .run_ops(prog, &mut "ab".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            assert_eq!(w, [5, 254]);
+        }
     }
 
     #[test]
-    fn test_ifzero_binregister() {
+    fn test_dump_tracks() {
         let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let binregister = register_builder.add_binregister(32);
+        let data = cfg.add_data_track(TrackId::Stack);
         let scratch = cfg.add_scratch_track(TrackId::Scratch1);
         let mut cpu = Cpu::new(&cfg);
+        cpu.set_byte(data.at(0), 3);
+        cpu.set_byte(data.at(2), 7);
+        cpu.set_byte(scratch.track.at(1), 5);
 
-        cpu.set_binregister(binregister, 0b1000000000000000000000u64, scratch);
-        cpu.if_binregister_nonzero_else(
-            binregister,
-            scratch,
-            |cpu, scratch| {
-                cpu.breakpoint();
-                cpu.print_text("1", scratch);
-            },
-            |cpu, _| {
-                cpu.crash("oh no");
-            },
-        );
-        cpu.clr_binregister(binregister, scratch);
-        cpu.if_binregister_nonzero_else(
-            binregister,
-            scratch,
-            |cpu, _| {
-                cpu.crash("oh no");
-            },
-            |cpu, scratch| {
-                cpu.print_text("1", scratch);
-            },
-        );
+        let mut state = BfState::new();
+        state
+            .run_ops(
+                &lir2bf(&cpu.into_ops()),
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    cpu_config: Some(&cfg),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        // Cpu::new leaves 3 empty frames on the left before frame 0
+        let tracks = state.dump_tracks(&cfg);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].0, TrackId::Stack);
+        assert_eq!(tracks[0].1, [0, 0, 0, 3, 0, 7]);
+        assert_eq!(tracks[1].0, TrackId::Scratch1);
+        assert_eq!(tracks[1].1, [0, 0, 0, 0, 5]);
+    }
 
-        test_lir_prog(&cpu.into_ops(), "", "11", &cfg);
+    #[test]
+    fn test_breakpoint_callback() {
+        let prog = vec![
+            BfOp::Add(3),
+            BfOp::Loop(vec![BfOp::Breakpoint, BfOp::Dec, BfOp::Breakpoint]),
+        ];
+        let mut cells_seen = Vec::new();
+        BfState::new()
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    on_breakpoint: Some(&mut |state: &BfState| {
+                        cells_seen.push(state.snapshot().cells[0])
+                    }),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(cells_seen, [3, 2, 2, 1, 1, 0]);
     }
 
     #[test]
-    fn test_add_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_binregister(32);
-        let reg2 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_run_ops_streaming() {
+        let prog = parse_bf("++++++++[>++++++++<-]>+.+.+.+.+.").unwrap_or_else(print_err);
+        let mut state = BfState::new();
+        let mut output = Vec::new();
+        let mut num_calls = 0;
+        state
+            .run_ops_streaming(
+                &prog,
+                &mut "".as_bytes(),
+                &mut |byte| {
+                    output.push(byte);
+                    num_calls += 1;
+                },
+                RunOptions::default(),
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(num_calls, 5);
+        assert_eq!(output, b"ABCDE");
+    }
 
-        cpu.set_binregister(reg1, 789742058u64, scratch);
-        cpu.set_binregister(reg2, 391490498u64, scratch);
-        cpu.add_binregister_to_binregister(reg1, reg2, scratch);
-        cpu.print_binregister_in_binary(reg2, scratch);
+    #[test]
+    fn test_loop_count_hotspots() {
+        let prog = parse_bf("+++[->+<]>[->++++++++[->+<]<]>>[-]").unwrap_or_else(print_err);
+        let mut state = BfState::new();
+        let mut loop_count = LoopCount::new();
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    loop_count: Some(&mut loop_count),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b01000110011010000010110110101100",
-            &cfg,
-        );
+        let hotspots = loop_count.hotspots(3);
+        assert_eq!(hotspots.len(), 3);
+        // the outer loop of the multiplication, then the loop nested in it, then the final clear
+        assert_eq!(hotspots[0].0, vec![1]);
+        assert_eq!(hotspots[1].0, vec![1, 0]);
+        assert_eq!(hotspots[2].0, vec![2]);
+        assert!(hotspots[0].1 > hotspots[1].1 && hotspots[1].1 > hotspots[2].1);
+
+        let summary = perf_bf_summary("+++[->+<]>[->++++++++[->+<]<]>>[-]", "", None);
+        // perf_bf_summary optimizes first, which leaves only the outer multiplication loop
+        assert!(summary.contains("\nloop 0: "));
+        assert!(!summary.contains("\nloop 1"));
     }
 
     #[test]
-    fn test_add_registers() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_register(4);
-        let reg2 = register_builder.add_register(4);
-        let reg3 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_max_cell_ptr() {
+        let mut state = BfState::new();
+        let prog = parse_bf(">>>+<<[>]>>").unwrap_or_else(print_err);
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions::default(),
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(state.max_cell_ptr(), 3);
+        assert_eq!(state.tape_len(), 4);
 
-        cpu.set_register(reg1, 789742058u64);
-        cpu.set_register(reg2, 391490498u64);
-        cpu.add_register_to_register(reg1, reg2, scratch);
-        cpu.unpack_register(reg2, reg3, scratch, false);
-        cpu.print_binregister_in_binary(reg3, scratch);
+        let (ops, cfg) = compile_hir_to_bf(
+            "fn main() {
+                println(fib(5));
+            }
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b01000110011010000010110110101100",
-            &cfg,
-        );
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    1
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+        let ops = get_optimized_bf_ops(&ops);
+        let mut state = BfState::new();
+        let mut loop_count = LoopCount::new();
+        state
+            .run_ops(
+                &ops,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    cpu_config: Some(&cfg),
+                    loop_count: Some(&mut loop_count),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        // the SAM program and its stack take up at least a frame per byte
+        let num_tracks = cfg.get_tracks().len();
+        assert!(state.max_cell_ptr() > 100 * num_tracks);
+        assert!(state.max_cell_ptr() < state.tape_len());
     }
 
     #[test]
-    fn test_sub_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_binregister(32);
-        let reg2 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_run_and_capture_raw_bytes() {
+        let prog = get_optimized_bf_ops(&parse_bf("-.>,.").unwrap_or_else(print_err));
+        let output = run_and_capture(&prog, &[0x80]).unwrap_or_else(print_err);
+        assert_eq!(output, [0xFF, 0x80]);
 
-        cpu.set_binregister(reg1, 289742058u64, scratch);
-        cpu.set_binregister(reg2, 791490498u64, scratch);
-        cpu.sub_binregister_from_binregister(reg1, reg2, scratch);
-        cpu.print_binregister_in_binary(reg2, scratch);
+        let result = parse_and_run_bf("-.", "", EofBehavior::Zero, None);
+        assert_eq!(result.output_bytes(), [0xFF]);
+        assert_eq!(result.output(), "\u{FFFD}");
+    }
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b00011101111010000001001011011000",
-            &cfg,
+    #[test]
+    fn test_wasm_api_parse_errors() {
+        let compiled = compile("fn main() {\n    println(1);\n    let x: u8 = ;\n}");
+        let error = compiled.error().unwrap();
+        assert!(error.contains("at line "), "{}", error);
+        assert!(compiled.bf().is_empty());
+        assert!(debug_program("fn main( {}", "").error().is_some());
+
+        let result = parse_and_run_bf("+[.\n-]]", "", EofBehavior::Zero, None);
+        assert_eq!(result.error().unwrap(), "Unmatched ']' at line 2, column 3");
+        assert!(compile("fn main() { println(1); }").error().is_none());
+
+        let compiled = compile("fn main() {\n    let a: foo = 1;\n}");
+        assert_eq!(
+            compiled.error().unwrap(),
+            "Parse error at line 2, column 12: unknown type"
+        );
+        assert!(debug_program("fn main() { let a: foo = 1; }", "")
+            .error()
+            .is_some());
+        let bad_bf = "+[.\n-]]";
+        assert_eq!(
+            compile_bf_to_wasm(bad_bf, 1, EofBehavior::Zero, None).unwrap_err(),
+            "Unmatched ']' at line 2, column 3"
+        );
+        assert_eq!(
+            perf_bf(bad_bf, "", None),
+            "Unmatched ']' at line 2, column 3"
+        );
+        assert_eq!(
+            perf_bf_summary(bad_bf, "", None),
+            "Unmatched ']' at line 2, column 3"
         );
     }
 
     #[test]
-    fn test_shift_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
-
-        cpu.set_binregister(reg1, 0b01000110011010000010110110101100u64, scratch);
-        cpu.shift_binregister_left(reg1, scratch);
-        cpu.print_binregister_in_binary(reg1, scratch);
+    fn test_hir_parse_error_pos() {
+        let err = parse_hir("fn main() {\n    println(1);\n    if 1 {\n        println(2);\n}\n")
+            .unwrap_err();
+        assert_eq!(err.pos.line_num, 6, "{}", err);
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 6, column 1: expected '}'"
+        );
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b10001100110100000101101101011000",
-            &cfg,
+        let err = parse_hir("fn main() {}\n\nfn foo(a: u8 {}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 3, column 14: expected ')'"
         );
     }
 
     #[test]
-    fn test_shift_binregisters_right() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_hir_parse_error_instead_of_panic() {
+        assert!(parse_hir("fn main() { print_char('é'); }").is_err());
 
-        cpu.set_binregister(reg1, 0b01000110011010000010110110101101u64, scratch);
-        cpu.shift_binregister_right(reg1, scratch);
-        cpu.print_binregister_in_binary(reg1, scratch);
+        let err = parse_hir("const A: u8 = 1;\nconst B: u8 = A - 2;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 2, column 1: constant would be negative"
+        );
+        let err = parse_hir("const A: u8 = 1 / 0;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 1, column 1: division by zero in constant"
+        );
+        let err = parse_hir("fn main() {}\n  const A: u32 = B;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 2, column 3: unknown constant"
+        );
+        assert!(parse_hir("const A: u32 = 1 << 99999999999999999999;").is_err());
+        let err = parse_hir("fn main() {\n    let a: [u8; 99999999999];\n}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 2, column 17: array is too large"
+        );
+        assert!(parse_hir("fn main() { let a: [u32; 2000000000]; }").is_err());
+        let err = parse_hir("const A: u8 = 1;\nconst A: u8 = 2;\nfn main() {}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 2, column 1: constant is already defined"
+        );
+        let err = parse_hir("fn main() {}\nfn main() {}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Parse error at line 2, column 1: function is already defined"
+        );
+    }
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b00100011001101000001011011010110",
-            &cfg,
+    #[test]
+    fn test_hir_to_json() {
+        let json = parse_hir_to_json(
+            "fn double(x: u8) -> u8 {
+                let y: u8 = x * 2;
+                y
+            }",
+        )
+        .unwrap();
+        assert!(json.starts_with("{\"fns\":{\"double\":{\"name\":\"double\","));
+        assert!(json.contains(
+            "{\"VarDecl\":{\"var_name\":\"y\",\"typ\":\"U8\",\"init\":{\"BinOp\":\
+             {\"args\":[{\"VarRef\":\"x\"},{\"Literal\":\"2\"}],\"kind\":\"Mul\"}}}}"
+        ));
+        assert!(json.ends_with("\"consts\":{}}"));
+        assert!(!json.contains("stmt_lines"));
+        assert!(parse_hir_to_json("fn main() {").is_err());
+    }
+
+    #[test]
+    fn test_bf_ops_json() {
+        let prog = parse_bf(
+            "A \"quoted\" comment\n>>>>>>++++++++[->+++++++++<]>.<+++++[->>++++++++++++++++++++<<]>>+.\
+             [-]>>+[->+<]<<[->+>+<<]>[>]<[-]\t<<<++++[->+>+++>-<<<]>[-]",
+        )
+        .unwrap_or_else(print_err);
+        let opt_prog = get_optimized_bf_ops(&prog);
+        let json = bf_ops_to_json(&opt_prog);
+        let parsed_prog = bf_ops_from_json(&json).unwrap_or_else(print_err);
+        assert_eq!(bf_ops_to_json(&parsed_prog), json);
+        assert_eq!(
+            ops2str(&parsed_prog, BfFormatOptions::with_opts()),
+            ops2str(&opt_prog, BfFormatOptions::with_opts())
         );
+
+        let mut outputs = Vec::new();
+        for prog in [&opt_prog, &parsed_prog] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state
+                .run_ops(prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            outputs.push(w);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+
+        assert!(bf_ops_from_json("[\"Left\", {\"Shift\": 70000}]").is_err());
+        assert!(bf_ops_from_json("[\"Left\",").is_err());
     }
 
     #[test]
-    fn test_mul_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let reg1 = register_builder.add_binregister(32);
-        let reg2 = register_builder.add_binregister(32);
-        let reg3 = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_ops2c() {
+        let prog = parse_bf(",[.,]>+[->+<]>[-]").unwrap_or_else(print_err);
+        let c = ops2c(&get_optimized_bf_ops(&prog));
+        assert!(c.contains("unsigned char tape["));
+        assert!(c.contains("c=getchar();"));
+        assert!(c.contains("while(*p){\n        putchar(*p);\n"));
+        assert!(c.contains("p[1]+=*p;*p=0;"));
+        assert!(c.contains("\n    *p=0;\n"));
+    }
 
-        cpu.set_binregister(reg1, 103050u64, scratch);
-        cpu.set_binregister(reg2, 1561594u64, scratch);
-        cpu.mul_binregisters(reg1, reg2, reg3, scratch);
-        cpu.print_binregister_in_binary(reg3, scratch);
+    #[test]
+    fn test_ops2python() {
+        let prog = parse_bf(",[.,]>+[->+<]>[-]").unwrap_or_else(print_err);
+        let py = ops2python(&get_optimized_bf_ops(&prog));
+        assert!(py.contains("tape = bytearray("));
+        assert!(py.contains("while tape[p]:\n    sys.stdout.buffer.write(tape[p:p+1])\n"));
+        assert!(py.contains("\ntape[p]=0\n"));
+    }
 
-        test_lir_prog(
-            &cpu.into_ops(),
+    #[test]
+    fn test_bf2wasm_tape_pages() {
+        let far_right = ">".repeat(30000);
+        let prog = format!("{0}+.{0}+.{0}+.", far_right);
+        let ops = parse_bf(&prog).unwrap_or_else(print_err);
+        assert!(bf2wasm(ops.clone(), true, 2, EofBehavior::Zero, None).is_ok());
+        assert!(bf2wasm(ops, false, DEFAULT_TAPE_PAGES, EofBehavior::Zero, None).is_ok());
+    }
+
+    #[test]
+    fn test_bf2wasm_eof_behavior() {
+        let ops = parse_bf(",[.,]+>,+[<,.>,]").unwrap_or_else(print_err);
+        for eof_behavior in [
+            EofBehavior::Zero,
+            EofBehavior::NegativeOne,
+            EofBehavior::Unchanged,
+        ] {
+            assert!(bf2wasm(ops.clone(), true, DEFAULT_TAPE_PAGES, eof_behavior, None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_wrap_columns() {
+        let prog = parse_bf(">++++++++[-<+++++++++>]<.>>+>-[+]++>++>+++[>[->+++<<+++>]<<]>-----.>->+++..+++.>-.<<+[>[+>+]>>]<--------------.>>.+++.------.--------.>+.>+.")
+            .unwrap_or_else(print_err);
+        let mut loop_count = LoopCount::new();
+        BfState::new()
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    loop_count: Some(&mut loop_count),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        for format_opts in [
+            BfFormatOptions::clean(),
+            BfFormatOptions::perf_verbose(&loop_count),
+        ] {
+            let unwrapped = ops2str(&prog, format_opts);
+            let wrapped = ops2str(
+                &prog,
+                BfFormatOptions {
+                    wrap_columns: Some(40),
+                    ..format_opts
+                },
+            );
+            assert!(wrapped.lines().count() > unwrapped.lines().count());
+            assert!(wrapped.lines().all(|line| line.chars().count() <= 40));
+            assert_eq!(
+                wrapped.split_whitespace().collect::<String>(),
+                unwrapped.split_whitespace().collect::<String>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_comment_filter() {
+        let prog = vec![
+            BfOp::Comment("Main loop".to_owned()),
+            BfOp::Inc,
+            BfOp::Comment("shift_by_4".to_owned()),
+            BfOp::Right,
+        ];
+        assert_eq!(
+            ops2str(&prog, BfFormatOptions::clean_with_comments()),
+            "Main loop+shift_by_4>"
+        );
+        let filtered = ops2str(
+            &prog,
+            BfFormatOptions {
+                comment_filter: Some(|msg| !msg.starts_with("shift_by_")),
+                ..BfFormatOptions::clean_with_comments()
+            },
+        );
+        assert_eq!(filtered, "Main loop+>");
+    }
+
+    #[test]
+    fn test_cell_size_check() {
+        test_raw_bf_prog(
+            "++++++++[>++++++++<-]>[<++++>-]+<[>-<[>++++<-]>[<++++++++>-]<
+            [>++++++++<-]+>[>++++++++++[>+++++<-]>+.-.[-]<<[-]<->] <[>>++
+            +++++[>+++++++<-]>.+++++.[-]<<<-]] >[>++++++++[>+++++++<-]>.[
+            -]<<-]<+++++++++++[>+++>+++++++++>+++++++++>+<<<<-]>-.>-.++++
+            +++.+++++++++++.<.>>.++.+++++++..<-.>>-[[-]<]",
             "",
-            "0b01110111101101101101100011000100",
-            &cfg,
+            "8 bit cells",
         );
     }
 
     #[test]
-    fn test_div_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let a = register_builder.add_binregister(32);
-        let b = register_builder.add_binregister(32);
-        let div = register_builder.add_binregister(32);
-        let rem = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_16_bit_cells() {
+        // puts 256 in a cell, then prints 'A' only if that cell didn't wrap to zero
+        let prog = get_optimized_bf_ops(
+            &parse_bf("++++++++++++++++[>++++++++++++++++<-]>[[-]>++++++++[<++++++++>-]<+.[-]]")
+                .unwrap_or_else(print_err),
+        );
 
-        cpu.set_binregister(a, 1037250132u64, scratch);
-        cpu.set_binregister(b, 156347u64, scratch);
-        cpu.div_binregisters(a, b, div, rem, scratch);
-        cpu.print_binregister_in_binary(div, scratch);
-        cpu.print_newline(scratch);
-        cpu.print_binregister_in_binary(rem, scratch);
+        let mut w = Vec::new();
+        let mut state = BfState::with_cell_width(CellWidth::Bits16);
+        state
+            .run_ops(&prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+            .unwrap_or_else(print_err);
+        assert_eq!(w, b"A");
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b00000000000000000001100111101010\n0b00000000000000001010110001100110",
-            &cfg,
+        let mut w = Vec::new();
+        let mut state = BfState::new();
+        state
+            .run_ops(&prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+            .unwrap_or_else(print_err);
+        assert_eq!(w, b"");
+    }
+
+    #[test]
+    fn test_16_bit_cells_long_runs() {
+        // long runs of +/-, a clear followed by a long run, and a loop with a large factor
+        let prog = format!(
+            "{}>{}>[-]{}>+++[->{}<]",
+            "+".repeat(200),
+            "-".repeat(300),
+            "+".repeat(150),
+            "+".repeat(130),
         );
+        let raw = parse_bf(&prog).unwrap_or_else(print_err);
+        let optimized = get_optimized_bf_ops(&raw);
+        let run = |ops: &Vec<BfOp>| {
+            let mut state = BfState::with_cell_width(CellWidth::Bits16);
+            state
+                .run_ops(
+                    ops,
+                    &mut "".as_bytes(),
+                    &mut Vec::new(),
+                    RunOptions::default(),
+                )
+                .unwrap_or_else(print_err);
+            state.snapshot().cells
+        };
+        assert_eq!(run(&raw), vec![200, 65236, 150, 0, 390]);
+        assert_eq!(run(&optimized), run(&raw));
     }
 
     #[test]
-    fn test_div_binregisters_10() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let a = register_builder.add_binregister(32);
-        let b = register_builder.add_binregister(4);
-        let div = register_builder.add_binregister(32);
-        let rem = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_eof_behavior() {
+        let prog = parse_bf(",,.").unwrap_or_else(print_err);
+        for (eof_behavior, expected) in [
+            (EofBehavior::Zero, 0u8),
+            (EofBehavior::NegativeOne, 255),
+            (EofBehavior::Unchanged, b'A'),
+        ] {
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state.set_eof_behavior(eof_behavior);
+            state
+                .run_ops(&prog, &mut "A".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            assert_eq!(w, [expected]);
+        }
+    }
 
-        cpu.set_binregister(a, 1037250132u64, scratch);
-        cpu.set_binregister(b, 10u64, scratch);
-        cpu.div_binregisters(a, b, div, rem, scratch);
-        cpu.print_binregister_in_binary(div, scratch);
-        cpu.print_newline(scratch);
-        cpu.print_binregister_in_binary(rem, scratch);
+    #[test]
+    fn test_negative_tape() {
+        let prog = parse_bf("<++++++++[>++++++++<-]>+.").unwrap_or_else(print_err);
+        for optimize in [false, true] {
+            let prog = if optimize {
+                get_optimized_bf_ops(&prog)
+            } else {
+                prog.clone()
+            };
 
-        test_lir_prog(
-            &cpu.into_ops(),
-            "",
-            "0b00000110001011101011011111010101\n0b00000000000000000000000000000010",
-            &cfg,
+            let mut w = Vec::new();
+            let mut state = BfState::new();
+            state.set_allow_negative_tape(true);
+            state
+                .run_ops(&prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+                .unwrap_or_else(print_err);
+            assert_eq!(w, b"A");
+
+            let mut state = BfState::new();
+            let res = state.run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions::default(),
+            );
+            assert!(matches!(res, Err(RunOpError::PtrOutOfBounds)));
+        }
+    }
+
+    #[test]
+    fn test_max_steps() {
+        let prog = parse_bf("+[]").unwrap_or_else(print_err);
+        let mut state = BfState::new();
+        let res = state.run_ops(
+            &prog,
+            &mut "".as_bytes(),
+            &mut Vec::new(),
+            RunOptions {
+                max_steps: Some(1000),
+                ..Default::default()
+            },
         );
+        assert!(matches!(res, Err(RunOpError::StepLimitExceeded)));
+
+        let prog = parse_bf("+++.").unwrap_or_else(print_err);
+        let mut w = Vec::new();
+        let mut state = BfState::new();
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut w,
+                RunOptions {
+                    max_steps: Some(4),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(w, [3]);
+        // every run starts counting from 0 again
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut w,
+                RunOptions {
+                    max_steps: Some(4),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(state.steps_executed(), 4);
     }
 
     #[test]
-    fn test_print_binregister_decimal() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let a = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
+    fn test_max_steps_wasm_api() {
+        let result = parse_and_run_bf("+.[]", "", EofBehavior::Zero, Some(1000));
+        assert_eq!(result.output_bytes(), [1]);
+        assert!(result.error().unwrap().contains("StepLimitExceeded"));
+        assert!(perf_bf("+[]", "", Some(1000)).contains("StepLimitExceeded"));
+        let summary = perf_bf_summary("+[]", "", Some(1000));
+        assert!(summary.starts_with("Error running bf program: StepLimitExceeded\n"));
 
-        cpu.set_binregister(a, 1037250132u64, scratch);
-        cpu.print_binregister_in_decimal(a, scratch);
+        let ops = parse_bf("+.[],.").unwrap_or_else(print_err);
+        assert_eq!(
+            run_wasm_backend_with_limit(&ops, b"", Some(1000)),
+            (2, vec![1])
+        );
+        let ops = parse_bf("+++[-.]").unwrap_or_else(print_err);
+        assert_eq!(
+            run_wasm_backend_with_limit(&ops, b"", Some(3)),
+            (0, vec![2, 1, 0])
+        );
+        assert_eq!(
+            run_wasm_backend_with_limit(&ops, b"", Some(2)),
+            (2, vec![2, 1])
+        );
+    }
 
-        test_lir_prog(&cpu.into_ops(), "", "1037250132", &cfg);
+    #[test]
+    fn test_deadline() {
+        let prog = parse_bf("+[]").unwrap_or_else(print_err);
+        let mut state = BfState::new();
+        let start = std::time::Instant::now();
+        state.set_deadline(Some(start + std::time::Duration::from_millis(100)));
+        let res = state.run_ops(
+            &prog,
+            &mut "".as_bytes(),
+            &mut Vec::new(),
+            RunOptions::default(),
+        );
+        assert!(matches!(res, Err(RunOpError::TimedOut)));
+        assert!(start.elapsed() >= std::time::Duration::from_millis(100));
     }
 
     #[test]
-    fn test_cmp_2_binregisters() {
-        let mut cfg = CpuConfig::new();
-        let mut register_builder = cfg.build_register_track(TrackId::Register1);
-        let a = register_builder.add_binregister(32);
-        let b = register_builder.add_binregister(32);
-        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
-        let mut cpu = Cpu::new(&cfg);
-        let (cmp_result, scratch) = scratch.split_1();
-        cpu.add_const_to_byte(cmp_result, b'5');
+    fn test_snapshot_restore() {
+        let mut state = BfState::new();
+        let prog = parse_bf("+++>++").unwrap_or_else(print_err);
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions::default(),
+            )
+            .unwrap_or_else(print_err);
+        let snap = state.snapshot();
+        assert_eq!(snap.cells, [3, 2]);
+        assert_eq!(snap.cell_ptr, 1);
+
+        let prog = parse_bf(">>+++<<-").unwrap_or_else(print_err);
+        state
+            .run_ops(
+                &prog,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions::default(),
+            )
+            .unwrap_or_else(print_err);
+        assert_ne!(state.snapshot(), snap);
+
+        state.restore(&snap);
+        assert_eq!(state.snapshot(), snap);
+    }
+
+    #[test]
+    fn test_preloaded_tape() {
+        let prog = parse_bf(".>.>+.").unwrap_or_else(print_err);
+        let mut w = Vec::new();
+        let mut state = BfState::with_tape(vec![72, 73]);
+        state
+            .run_ops(&prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+            .unwrap_or_else(print_err);
+        assert_eq!(w, [72, 73, 1]);
+
+        let prog = parse_bf(".<.").unwrap_or_else(print_err);
+        let mut w = Vec::new();
+        let mut state = BfState::with_tape_and_ptr(vec![72, 73], 1);
+        state
+            .run_ops(&prog, &mut "".as_bytes(), &mut w, RunOptions::default())
+            .unwrap_or_else(print_err);
+        assert_eq!(w, b"IH");
+    }
+
+    #[test]
+    fn test_add_const_to_register() {
+        let mut cfg = CpuConfig::new();
+        let register = cfg.add_register_track(TrackId::Register1, 4);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.add_const_to_register(register, 103050u64, scratch);
+        cpu.add_const_to_register(register, 20406u64, scratch);
+
+        cpu.moveprint_byte(register.at(0), scratch);
+        cpu.print_text(", ", scratch);
+        cpu.moveprint_byte(register.at(1), scratch);
+        cpu.print_text(", ", scratch);
+        cpu.moveprint_byte(register.at(2), scratch);
+        cpu.print_text(", ", scratch);
+        cpu.moveprint_byte(register.at(3), scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "0, 1, 226, 64", &cfg);
+    }
+
+    #[test]
+    fn test_swap_registers() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_register(4);
+        let b = register_builder.add_register(4);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(a, 123456u64);
+        cpu.set_register(b, 0xDEADBEEFu64);
+        cpu.swap_registers(a, b, scratch);
+        cpu.moveprint_register_hex(a, scratch);
+        cpu.print_newline(scratch);
+        cpu.moveprint_register_hex(b, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "0xDEADBEEF\n0x0001E240", &cfg);
+    }
+
+    #[test]
+    fn test_read_named_register() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_register(4);
+        let b = register_builder.add_register(2);
+        cfg.name_register(TrackId::Register1, "a", a);
+        cfg.name_register(TrackId::Register1, "b", b);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(a, 123456u64);
+        cpu.set_register(b, 1000u64);
+        cpu.add_register_to_register(b.subview(0, 2), a.subview(2, 2), scratch);
+        // named registers are read relative to the frame the pointer is in
+        cpu.goto(a.at(0));
+
+        let mut state = BfState::new();
+        state
+            .run_ops(
+                &lir2bf(&cpu.into_ops()),
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    cpu_config: Some(&cfg),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        assert_eq!(
+            state.read_named_register(&cfg, TrackId::Register1, "a"),
+            Some(124456)
+        );
+        assert_eq!(
+            state.read_named_register(&cfg, TrackId::Register1, "b"),
+            Some(1000)
+        );
+        assert_eq!(
+            state.read_named_register(&cfg, TrackId::Register1, "c"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_print_register_hex() {
+        let mut cfg = CpuConfig::new();
+        let register = cfg.add_register_track(TrackId::Register1, 4);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(register, 123456u64);
+        cpu.moveprint_register_hex(register, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "0x0001E240", &cfg);
+    }
+
+    #[test]
+    fn test_print_register_in_binary() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_register(4);
+        let b = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(a, 0xDEADBEEFu64);
+        cpu.unpack_register(a, b, scratch, false);
+        cpu.moveprint_register_in_binary(a, scratch);
+        cpu.print_binregister_in_binary(b, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b11011110101011011011111011101111\
+             0b11011110101011011011111011101111",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let counter = register_builder.add_register(1);
+        let a = register_builder.add_register(2);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(a, 250u64);
+        cpu.add_const_to_byte(counter.at(0), 7);
+        cpu.repeat(counter.at(0), |cpu| {
+            cpu.inc_register(a, scratch);
+        });
+        cpu.moveprint_register_hex(a, scratch);
+        cpu.moveprint_register_hex(counter, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "0x01010x00", &cfg);
+    }
+
+    #[test]
+    fn test_set_register_signed() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_register(4);
+        let b = register_builder.add_register(2);
+        let c = register_builder.add_register(1);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        assert!(cpu.try_set_register(b, 70000u64).is_err());
+        assert!(cpu.try_set_register(b, 65535u64).is_ok());
+        cpu.set_register_signed(a, -1);
+        cpu.set_register_signed(b, -2);
+        cpu.set_register_signed(c, 200);
+        for reg in [a, b, c] {
+            cpu.moveprint_register_hex(reg, scratch);
+            cpu.print_newline(scratch);
+        }
+        cpu.set_register_signed(a, -70000);
+        cpu.moveprint_register_hex(a, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0xFFFFFFFF\n0xFFFE\n0xC8\n0xFFFEEE90",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_fill_slice() {
+        let mut cfg = CpuConfig::new();
+        let data = cfg.add_data_track(TrackId::Heap);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_byte(data.at(1), b'[');
+        cpu.set_byte(data.at(22), b']');
+        cpu.fill_slice(data.at(2), 20, b'A', scratch);
+        for i in 1..23 {
+            cpu.goto(data.at(i));
+            cpu.out();
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", "[AAAAAAAAAAAAAAAAAAAA]", &cfg);
+    }
+
+    #[test]
+    fn test_copy_region() {
+        let mut cfg = CpuConfig::new();
+        let data = cfg.add_data_track(TrackId::Heap);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for (i, c) in "0123456789abcdef".bytes().enumerate() {
+            cpu.set_byte(data.at(i as isize), c);
+        }
+        cpu.copy_region(data.at(0), data.at(20), 16, scratch);
+        // overlapping copy
+        cpu.copy_region(data.at(20), data.at(24), 16, scratch);
+        for i in 0..16 {
+            cpu.goto(data.at(i));
+            cpu.out();
+        }
+        cpu.print_newline(scratch);
+        for i in 20..40 {
+            cpu.goto(data.at(i));
+            cpu.out();
+        }
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0123456789abcdef\n01230123456789abcdef",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_unpack_and_print_register() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let register = register_builder.add_register(4);
+        let binregister = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.add_const_to_register(register, 0b111111111101010101010101u64, scratch);
+        cpu.unpack_register(register, binregister, scratch, false);
+        cpu.print_binregister_in_binary(binregister, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00000000111111111101010101010101",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_print_binregister_hex() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(10);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 123456u64, scratch);
+        cpu.set_binregister(reg2, 0b1011011110u64, scratch);
+        cpu.print_binregister_in_hex(reg1, scratch);
+        cpu.print_newline(scratch);
+        cpu.print_binregister_in_hex(reg2, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "0x1e240\n0x2de", &cfg);
+    }
+
+    #[test]
+    fn test_ifzero_binregister() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let binregister = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(binregister, 0b1000000000000000000000u64, scratch);
+        cpu.if_binregister_nonzero_else(
+            binregister,
+            scratch,
+            |cpu, scratch| {
+                cpu.breakpoint();
+                cpu.print_text("1", scratch);
+            },
+            |cpu, _| {
+                cpu.crash("oh no");
+            },
+        );
+        cpu.clr_binregister(binregister, scratch);
+        cpu.if_binregister_nonzero_else(
+            binregister,
+            scratch,
+            |cpu, _| {
+                cpu.crash("oh no");
+            },
+            |cpu, scratch| {
+                cpu.print_text("1", scratch);
+            },
+        );
+
+        test_lir_prog(&cpu.into_ops(), "", "11", &cfg);
+    }
+
+    #[test]
+    fn test_ifzero_small_binregister() {
+        fn run(size: isize, val: u64) -> (String, u64) {
+            let mut cfg = CpuConfig::new();
+            let mut register_builder = cfg.build_register_track(TrackId::Register1);
+            let binregister = register_builder.add_binregister(size);
+            let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+            let mut cpu = Cpu::new(&cfg);
+
+            cpu.set_binregister(binregister, val, scratch);
+            cpu.if_binregister_nonzero_else(
+                binregister,
+                scratch,
+                |cpu, scratch| cpu.print_text("1", scratch),
+                |cpu, scratch| cpu.print_text("0", scratch),
+            );
+            cpu.print_binregister_in_binary(binregister, scratch);
+
+            let ops = get_optimized_bf_ops(&lir2bf(&cpu.into_ops()));
+            let mut output = Vec::new();
+            let mut loop_count = LoopCount::new();
+            BfState::new()
+                .run_ops(
+                    &ops,
+                    &mut "".as_bytes(),
+                    &mut output,
+                    RunOptions {
+                        loop_count: Some(&mut loop_count),
+                        ..Default::default()
+                    },
+                )
+                .unwrap_or_else(print_err);
+            (
+                String::from_utf8(output).unwrap(),
+                loop_count.get_instrs_executed(),
+            )
+        }
+
+        for val in [0u64, 1, 0b10000000, 0b10110] {
+            // size 8 takes the unrolled path, size 9 still walks the register
+            let (small_out, small_instrs) = run(8, val);
+            let (big_out, big_instrs) = run(9, val);
+            assert_eq!(small_out[..1], big_out[..1]);
+            assert_eq!(small_out[1..], format!("0b{:08b}", val));
+            assert!(small_instrs < big_instrs);
+        }
+    }
+
+    #[test]
+    fn test_add_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 789742058u64, scratch);
+        cpu.set_binregister(reg2, 391490498u64, scratch);
+        cpu.add_binregister_to_binregister(reg1, reg2, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b01000110011010000010110110101100",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_add_const_to_binregister() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.add_const_to_binregister(reg1, 103050, scratch);
+        cpu.add_const_to_binregister(reg1, 20406, scratch);
+        cpu.add_const_to_binregister(reg1, 3, scratch);
+        cpu.print_binregister_in_decimal(reg1, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "123459", &cfg);
+    }
+
+    #[test]
+    fn test_add_registers() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_register(4);
+        let reg2 = register_builder.add_register(4);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_register(reg1, 789742058u64);
+        cpu.set_register(reg2, 391490498u64);
+        cpu.add_register_to_register(reg1, reg2, scratch);
+        cpu.unpack_register(reg2, reg3, scratch, false);
+        cpu.print_binregister_in_binary(reg3, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b01000110011010000010110110101100",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_print_text_control_chars() {
+        let mut cfg = CpuConfig::new();
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+        cpu.print_text("a\tb\nc", scratch);
+        test_lir_prog(&cpu.into_ops(), "", "a\tb\nc", &cfg);
+    }
+
+    #[test]
+    fn test_print_text_utf8() {
+        let mut cfg = CpuConfig::new();
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+        cpu.print_text("café ✓", scratch);
+        test_lir_prog(&cpu.into_ops(), "", "café ✓", &cfg);
+    }
+
+    #[test]
+    fn test_moveprint_byte_padded() {
+        let mut cfg = CpuConfig::new();
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let byte = register_builder.add_register(1).at(0);
+        let mut cpu = Cpu::new(&cfg);
+
+        for (x, width, pad) in [
+            (7, 4, ' '),
+            (123, 4, ' '),
+            (105, 4, '0'),
+            (40, 3, '0'),
+            (0, 2, ' '),
+            (255, 2, ' '),
+        ] {
+            cpu.set_byte(byte, x);
+            cpu.print_text("|", scratch);
+            cpu.moveprint_byte_padded(byte, width, pad, scratch);
+            cpu.print_text("|\n", scratch);
+        }
+        cpu.set_byte(byte, 105);
+        cpu.moveprint_byte(byte, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "|   7|\n| 123|\n|0105|\n|040|\n| 0|\n|255|\n105",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_add_registers_with_carries() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_register(4);
+        let reg2 = register_builder.add_register(4);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        let mut expected = String::new();
+        for (x, y) in [
+            (0xFFFFFFFFu32, 1u32),
+            (0xFFFFFFFF, 0xFFFFFFFF),
+            (0x00FF00FF, 0x00FFFF01),
+            (0x80808080, 0x80808080),
+            (0x12FFFFFE, 0x00000003),
+            (0, 0xFEFEFEFE),
+        ] {
+            cpu.set_register(reg1, x);
+            cpu.set_register(reg2, y);
+            cpu.add_register_to_register(reg1, reg2, scratch);
+            cpu.unpack_register(reg2, reg3, scratch, false);
+            cpu.print_binregister_in_binary(reg3, scratch);
+            cpu.clr_binregister(reg3, scratch);
+            cpu.clr_register(reg1, scratch);
+            cpu.clr_register(reg2, scratch);
+            expected += &format!("{:#034b}", x.wrapping_add(y));
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", &expected, &cfg);
+    }
+
+    #[test]
+    fn test_sub_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 289742058u64, scratch);
+        cpu.set_binregister(reg2, 791490498u64, scratch);
+        cpu.sub_binregister_from_binregister(reg1, reg2, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00011101111010000001001011011000",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_and_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 789742058u64, scratch);
+        cpu.set_binregister(reg2, 391490498u64, scratch);
+        cpu.and_binregisters(reg1, reg2, reg3, scratch);
+        cpu.print_binregister_in_binary(reg3, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00000111000100001000000111000010",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_or_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 789742058u64, scratch);
+        cpu.set_binregister(reg2, 391490498u64, scratch);
+        cpu.or_binregisters(reg1, reg2, reg2, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00111111010101111010101111101010",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_xor_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 789742058u64, scratch);
+        cpu.set_binregister(reg2, 391490498u64, scratch);
+        cpu.xor_binregisters(reg1, reg2, reg1, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00111000010001110010101000101000",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_not_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(8);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 789742058u64, scratch);
+        cpu.set_binregister(reg2, 0b10110001u64, scratch);
+        cpu.not_binregister(reg1, scratch);
+        cpu.not_binregister(reg2, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b11010000111011010111111000010101\
+             0b01001110",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_abs_binregister() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for x in [-1037250132i32, 1037250132, 0, i32::MIN] {
+            cpu.set_binregister(a, x as u32, scratch);
+            cpu.abs_binregister(a, scratch);
+            cpu.print_binregister_in_decimal(a, scratch);
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "1037250132\n1037250132\n0\n2147483648\n",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_shift_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 0b01000110011010000010110110101100u64, scratch);
+        cpu.shift_binregister_left(reg1, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b10001100110100000101101101011000",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_shift_binregisters_right() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 0b01000110011010000010110110101101u64, scratch);
+        cpu.shift_binregister_right(reg1, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00100011001101000001011011010110",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_arith_shift_binregisters_right() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 0b11000110011010000010110110101101u64, scratch);
+        cpu.set_binregister(reg2, 0b01000110011010000010110110101101u64, scratch);
+        cpu.arith_shift_binregister_right(reg1, scratch);
+        cpu.arith_shift_binregister_right(reg1, scratch);
+        cpu.arith_shift_binregister_right(reg2, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b11110001100110100000101101101011\
+             0b00100011001101000001011011010110",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_shift_binregisters_by() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 0b01000110011010000010110110101101u64, scratch);
+        cpu.set_binregister(reg2, 0b01000110011010000010110110101101u64, scratch);
+        cpu.set_binregister(reg3, 0b01000110011010000010110110101101u64, scratch);
+        cpu.shift_binregister_left_by(reg1, 5, scratch);
+        cpu.shift_binregister_right_by(reg2, 5, scratch);
+        cpu.shift_binregister_left_by(reg3, 40, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+        cpu.print_binregister_in_binary(reg3, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b11001101000001011011010110100000\
+             0b00000010001100110100000101101101\
+             0b00000000000000000000000000000000",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_rotate_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 0b11000110011010000010110110101100u64, scratch);
+        cpu.set_binregister(reg2, 0b11000110011010000010110110101100u64, scratch);
+        cpu.set_binregister(reg3, 0b11000110011010000010110110101101u64, scratch);
+        cpu.rotate_binregister_left(reg1, 1, scratch);
+        cpu.rotate_binregister_left(reg2, 33, scratch);
+        cpu.rotate_binregister_right(reg3, 4, scratch);
+        cpu.print_binregister_in_binary(reg1, scratch);
+        cpu.print_binregister_in_binary(reg2, scratch);
+        cpu.print_binregister_in_binary(reg3, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b10001100110100000101101101011001\
+             0b10001100110100000101101101011001\
+             0b11011100011001101000001011011010",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_mul_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let reg1 = register_builder.add_binregister(32);
+        let reg2 = register_builder.add_binregister(32);
+        let reg3 = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(reg1, 103050u64, scratch);
+        cpu.set_binregister(reg2, 1561594u64, scratch);
+        cpu.mul_binregisters(reg1, reg2, reg3, scratch);
+        cpu.print_binregister_in_binary(reg3, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b01110111101101101101100011000100",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_div_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let div = register_builder.add_binregister(32);
+        let rem = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(a, 1037250132u64, scratch);
+        cpu.set_binregister(b, 156347u64, scratch);
+        cpu.div_binregisters(a, b, div, rem, scratch);
+        cpu.print_binregister_in_binary(div, scratch);
+        cpu.print_newline(scratch);
+        cpu.print_binregister_in_binary(rem, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00000000000000000001100111101010\n0b00000000000000001010110001100110",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_div_binregisters_signed() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let div = register_builder.add_binregister(32);
+        let rem = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for (x, y) in [(-7i32, 2i32), (7, -2), (-7, -2)] {
+            cpu.set_binregister(a, x as u32, scratch);
+            cpu.set_binregister(b, y as u32, scratch);
+            cpu.clr_binregister(div, scratch);
+            cpu.clr_binregister(rem, scratch);
+            cpu.div_binregisters_signed(a, b, div, rem, scratch);
+            cpu.print_binregister_in_binary(div, scratch);
+            cpu.print_newline(scratch);
+            cpu.print_binregister_in_binary(rem, scratch);
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b11111111111111111111111111111101\n0b11111111111111111111111111111111\n\
+             0b11111111111111111111111111111101\n0b00000000000000000000000000000001\n\
+             0b00000000000000000000000000000011\n0b11111111111111111111111111111111\n",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_divmod_binregister_by_const() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let div = register_builder.add_binregister(32);
+        let rem = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(a, 1037250132u64, scratch);
+        cpu.div_binregister_by_const(a, 7, div, scratch);
+        cpu.mod_binregister_by_const(a, 7, rem, scratch);
+        cpu.print_binregister_in_decimal(div, scratch);
+        cpu.print_newline(scratch);
+        cpu.print_binregister_in_decimal(rem, scratch);
+        cpu.print_newline(scratch);
+        cpu.print_binregister_in_decimal(a, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            &format!("{}\n{}\n1037250132", 1037250132 / 7, 1037250132 % 7),
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_named_register_tracks() {
+        let mut cfg = CpuConfig::new();
+        let regs = ["Register1", "Register2", "Register3"].map(|name| {
+            cfg.build_register_track(TrackId::new(name))
+                .add_binregister(16)
+        });
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        assert!(cfg.get_tracks().contains_key(&TrackId::Register1));
+        assert!(cfg.get_tracks().contains_key(&TrackId::new("Register3")));
+        assert_eq!(format!("{:?}", TrackId::new("Register2")), "Register2");
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(regs[0], 1000u64, scratch);
+        cpu.set_binregister(regs[1], 200u64, scratch);
+        cpu.set_binregister(regs[2], 34u64, scratch);
+        cpu.add_binregister_to_binregister(regs[0], regs[2], scratch);
+        cpu.add_binregister_to_binregister(regs[1], regs[2], scratch);
+        for reg in regs {
+            cpu.print_binregister_in_decimal(reg, scratch);
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", "1000\n200\n1234\n", &cfg);
+    }
+
+    #[test]
+    fn test_anonymous_scratch_tracks() {
+        let mut cfg = CpuConfig::new();
+        let named = cfg.add_scratch_track(TrackId::Scratch1);
+        let tracks = (0..5)
+            .map(|_| cfg.add_anonymous_scratch_track())
+            .collect::<Vec<_>>();
+        assert_eq!(cfg.get_tracks().len(), 6);
+        let mut cpu = Cpu::new(&cfg);
+
+        let cells = tracks
+            .iter()
+            .map(|track| track.split_1().0)
+            .collect::<Vec<_>>();
+        for (i, &cell) in cells.iter().enumerate() {
+            cpu.add_const_to_byte(cell, b'a' + i as u8);
+        }
+        for &cell in cells.iter().rev() {
+            cpu.goto(cell);
+            cpu.out();
+            cpu.clr_at(cell);
+        }
+        cpu.print_newline(named);
+
+        test_lir_prog(&cpu.into_ops(), "", "edcba\n", &cfg);
+    }
+
+    #[test]
+    fn test_find_scratch_leaks() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let div = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+
+        let mut cpu = Cpu::new(&cfg);
+        cpu.set_binregister(a, 1037250132u64, scratch);
+        cpu.div_binregister_by_const(a, 7, div, scratch);
+        cpu.print_binregister_in_decimal(div, scratch);
+        assert_eq!(
+            find_scratch_leaks(&cpu.into_ops(), &cfg),
+            Vec::<usize>::new()
+        );
+
+        let mut cpu = Cpu::new(&cfg);
+        let ([tmp, flag], _) = scratch.split_2();
+        cpu.add_const_to_byte(tmp, 3);
+        cpu.inc_at(flag);
+        cpu.clr_at(flag);
+        let ops = cpu.into_ops();
+        let leaks = find_scratch_leaks(&ops, &cfg);
+        assert_eq!(leaks.len(), 1);
+        assert!(matches!(ops[leaks[0]], Lir::Inc));
+        // the same cell is found if it's still set when the ops are run
+        let bf = get_optimized_bf_ops(&lir2bf(&ops));
+        let mut state = BfState::new();
+        state
+            .run_ops(
+                &bf,
+                &mut "".as_bytes(),
+                &mut Vec::new(),
+                RunOptions {
+                    cpu_config: Some(&cfg),
+                    ..Default::default()
+                },
+            )
+            .unwrap_or_else(print_err);
+        let scratch_track = state
+            .dump_tracks(&cfg)
+            .into_iter()
+            .find(|(id, _)| *id == TrackId::Scratch1)
+            .unwrap()
+            .1;
+        assert_eq!(scratch_track.iter().filter(|&&c| c != 0).count(), 1);
+    }
+
+    #[test]
+    fn test_print_binregister_in_base() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        let mut expected = String::new();
+        for x in [3735928559u32, 1037250132, 0] {
+            cpu.set_binregister(a, x, scratch);
+            for base in [10, 16, 8, 2] {
+                cpu.print_binregister_in_base(a, base, scratch);
+                cpu.print_newline(scratch);
+            }
+            cpu.clr_binregister(a, scratch);
+            expected += &format!("{}\n{:x}\n{:o}\n{:b}\n", x, x, x, x);
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", &expected, &cfg);
+    }
+
+    #[test]
+    fn test_pow_binregister() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let base = register_builder.add_binregister(8);
+        let exp = register_builder.add_binregister(8);
+        let out = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        // results are truncated to the width of out, so 3^21 wraps around
+        for (b, e) in [(3u32, 10u32), (3, 21), (7, 0), (0, 5)] {
+            cpu.set_binregister(base, b, scratch);
+            cpu.set_binregister(exp, e, scratch);
+            cpu.pow_binregister(base, exp, out, scratch);
+            cpu.print_binregister_in_decimal(out, scratch);
+            cpu.print_newline(scratch);
+            cpu.clr_binregister(base, scratch);
+            cpu.clr_binregister(exp, scratch);
+        }
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            &format!("59049\n{}\n1\n0\n", 3u32.wrapping_pow(21)),
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_div_binregisters_10() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(4);
+        let div = register_builder.add_binregister(32);
+        let rem = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(a, 1037250132u64, scratch);
+        cpu.set_binregister(b, 10u64, scratch);
+        cpu.div_binregisters(a, b, div, rem, scratch);
+        cpu.print_binregister_in_binary(div, scratch);
+        cpu.print_newline(scratch);
+        cpu.print_binregister_in_binary(rem, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "0b00000110001011101011011111010101\n0b00000000000000000000000000000010",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_gcd_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let out = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for (x, y) in [(1037250132u64, 156347u64), (13221495, 5703390)] {
+            cpu.set_binregister(a, x, scratch);
+            cpu.set_binregister(b, y, scratch);
+            cpu.clr_binregister(out, scratch);
+            cpu.gcd_binregisters(a, b, out, scratch);
+            cpu.print_binregister_in_decimal(out, scratch);
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", "1\n259245\n", &cfg);
+    }
+
+    #[test]
+    fn test_print_binregister_decimal() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.set_binregister(a, 1037250132u64, scratch);
+        cpu.print_binregister_in_decimal(a, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "", "1037250132", &cfg);
+    }
+
+    #[test]
+    fn test_print_binregister_signed_decimal() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for x in [-1037250132i32, 1037250132, 0, i32::MIN] {
+            cpu.set_binregister(a, x as u32, scratch);
+            cpu.print_binregister_in_signed_decimal(a, scratch);
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "-1037250132\n1037250132\n0\n-2147483648\n",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_read_binregister_decimal() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        cpu.read_binregister_in_decimal(a, scratch);
+        cpu.inc_binregister(a, scratch);
+        cpu.print_binregister_in_decimal(a, scratch);
+
+        test_lir_prog(&cpu.into_ops(), "12345\n", "12346", &cfg);
+    }
+
+    #[test]
+    fn test_cmp_2_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+        let (cmp_result, scratch) = scratch.split_1();
+        cpu.add_const_to_byte(cmp_result, b'5');
 
         cpu.set_binregister(a, 136u64, scratch);
         cpu.set_binregister(b, 138u64, scratch);
@@ -818,7 +2847,1288 @@ mod test {
         cpu.out();
         cpu.clr();
 
-        test_lir_prog(&cpu.into_ops(), "", "43345", &cfg);
+        test_lir_prog(&cpu.into_ops(), "", "43345", &cfg);
+    }
+
+    #[test]
+    fn test_read_line_into() {
+        let mut cfg = CpuConfig::new();
+        let data = cfg.add_data_track(TrackId::Heap);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        // a whole line, one cut off at 3 bytes, then the rest of it up to EOF
+        for (start, max_len) in [(0, 8), (10, 3), (20, 8)] {
+            cpu.read_line_into(data.at(start), max_len, scratch);
+            for i in 0..=max_len {
+                let pos = data.at(start + i);
+                cpu.if_nonzero(pos, scratch, |cpu, _| {
+                    cpu.goto(pos);
+                    cpu.out();
+                });
+            }
+            cpu.print_text("|", scratch);
+        }
+
+        test_lir_prog(&cpu.into_ops(), "hello\nworld", "hello|wor|ld|", &cfg);
+    }
+
+    #[test]
+    fn test_binregister_predicates() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+        let (out, scratch) = scratch.split_1();
+
+        type Predicate = fn(&mut Cpu, BinRegister, BinRegister, Pos, ScratchTrack);
+        let predicates: [Predicate; 6] = [
+            |cpu, a, b, out, s| cpu.is_eq_binregister(a, b, out, s),
+            |cpu, a, b, out, s| cpu.is_ne_binregister(a, b, out, s),
+            |cpu, a, b, out, s| cpu.is_lt_binregister(a, b, out, s),
+            |cpu, a, b, out, s| cpu.is_le_binregister(a, b, out, s),
+            |cpu, a, b, out, s| cpu.is_gt_binregister(a, b, out, s),
+            |cpu, a, b, out, s| cpu.is_ge_binregister(a, b, out, s),
+        ];
+        cpu.set_binregister(b, 70000u64, scratch);
+        for predicate in predicates {
+            // equal, less and greater
+            for x in [70000u64, 69999, 70001] {
+                cpu.set_binregister(a, x, scratch);
+                predicate(&mut cpu, a, b, out, scratch);
+                cpu.add_const_to_byte(out, b'0');
+                cpu.goto(out);
+                cpu.out();
+                cpu.clr();
+            }
+            cpu.print_newline(scratch);
+        }
+
+        test_lir_prog(&cpu.into_ops(), "", "100\n011\n010\n110\n001\n101\n", &cfg);
+    }
+
+    #[test]
+    fn test_min_max_binregisters() {
+        let mut cfg = CpuConfig::new();
+        let mut register_builder = cfg.build_register_track(TrackId::Register1);
+        let a = register_builder.add_binregister(32);
+        let b = register_builder.add_binregister(32);
+        let out = register_builder.add_binregister(32);
+        let scratch = cfg.add_scratch_track(TrackId::Scratch1);
+        let mut cpu = Cpu::new(&cfg);
+
+        for (x, y) in [(103050u64, 1561594u64), (1561594, 103050), (20406, 20406)] {
+            cpu.set_binregister(a, x, scratch);
+            cpu.set_binregister(b, y, scratch);
+            cpu.min_binregister(a, b, out, scratch);
+            cpu.print_binregister_in_decimal(out, scratch);
+            cpu.print_text(" ", scratch);
+            cpu.max_binregister(a, b, out, scratch);
+            cpu.print_binregister_in_decimal(out, scratch);
+            cpu.print_newline(scratch);
+        }
+        // out aliasing one of the inputs
+        cpu.set_binregister(a, 7u64, scratch);
+        cpu.set_binregister(b, 5u64, scratch);
+        cpu.min_binregister(a, b, a, scratch);
+        cpu.print_binregister_in_decimal(a, scratch);
+        cpu.print_text(" ", scratch);
+        cpu.set_binregister(a, 7u64, scratch);
+        cpu.max_binregister(a, b, b, scratch);
+        cpu.print_binregister_in_decimal(b, scratch);
+
+        test_lir_prog(
+            &cpu.into_ops(),
+            "",
+            "103050 1561594\n103050 1561594\n20406 20406\n5 7",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_full_for_loop() {
+        let hir = parse_hir(
+            "fn main() {
+                let sum : u32 = 0;
+                for i in 0..10 {
+                    sum = sum + i;
+                }
+                println(sum);
+                for c : u8 in 65..68 {
+                    print_char(c);
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "45\nABC", &cfg);
+    }
+
+    #[test]
+    fn test_full_u16() {
+        let hir = parse_hir(
+            "fn main() {
+                let a : u16 = 65535;
+                let b : u16 = a + 2;
+                println(b);
+                let c : u16 = 300;
+                println(c * 7 - b);
+                println(c * 300);
+                if c < a {
+                    print(a / c);
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "1\n2099\n24464\n218", &cfg);
+    }
+
+    #[test]
+    fn test_full_i32() {
+        let hir = parse_hir(
+            "fn main() {
+                let a : i32 = 3 - 5;
+                println(a);
+                let b : i32 = -7;
+                println(a * b);
+                if b < a {
+                    println(b + 100);
+                }
+                if a > b {
+                    print(a - 1000);
+                }
+                if -1 < 1 {
+                    println(-1 >= 1);
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "-2\n14\n93\n-10020\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_array() {
+        let hir = parse_hir(
+            "fn main() {
+                let arr : [u8; 4];
+                for i : u8 in 0..4 {
+                    arr[i] = 10 * i + 3;
+                }
+                for i : u8 in 0..4 {
+                    println(arr[i]);
+                }
+                let big : [u32; 3];
+                big[1] = 70000;
+                big[2] = big[1] + 5;
+                print(big[0] + big[2]);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "3\n13\n23\n33\n70005", &cfg);
+    }
+
+    #[test]
+    fn test_full_bitwise() {
+        let hir = parse_hir(
+            "fn main() {
+                let x : u32 = 0xF0 & 0x3C;
+                println(x);
+                println(x | 1 << 8);
+                println(1 + 2 << 3);
+                println(6 & 3 | 8);
+                let a : u8 = 200;
+                let b : u8 = a ^ 7;
+                println(b);
+                println(a >> 3);
+                let p : u32 = 0xDEADBEEF;
+                let q : u32 = 0x0FF0FF00;
+                println(p & q);
+                println(p ^ q);
+                println(p | q);
+                print(70000 >> 2);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(
+            &ops,
+            "",
+            "48\n304\n24\n10\n207\n25\n245415424\n3512549871\n3757965295\n17500",
+            &cfg,
+        );
+    }
+
+    #[test]
+    fn test_full_const_shifts() {
+        let hir = parse_hir(
+            "fn main() {
+                let one : u32 = 1;
+                let x : u32 = one << 8;
+                println(x);
+                let y : u32 = 0x12345678;
+                println(y >> 12);
+                println(y << 40);
+                let z : u16 = 0xABCD;
+                println(z << 4);
+                let w : i32 = -3;
+                print(w << 2);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+        let disassembly = disassemble_sam(&linked);
+        assert!(
+            disassembly.contains("SHL_A_BY_CONST 8\n"),
+            "{}",
+            disassembly
+        );
+        assert!(
+            disassembly.contains("SHR_A_BY_CONST 12\n"),
+            "{}",
+            disassembly
+        );
+        assert!(!disassembly.contains("SET_A_TO_U32_AT_B_SHL_A"));
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "256\n74565\n0\n48336\n-12", &cfg);
+    }
+
+    #[test]
+    fn test_full_unary_ops() {
+        let hir = parse_hir(
+            "fn main() {
+                println(!0);
+                println(!5);
+                println(-3 + 10);
+                let a : u8 = 3;
+                println(-a);
+                let b : i32 = 4;
+                println(-b);
+                let c : u32 = 0;
+                println(!c);
+                print(!(a > 2));
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "1\n0\n7\n253\n-4\n1\n0", &cfg);
+    }
+
+    #[test]
+    fn test_parse_hex_and_binary_literals() {
+        fn parse_init(code: &str) -> num::BigUint {
+            let hir = parse_hir(code).unwrap();
+            match &hir.fns["main"].scope.stmts[0] {
+                Stmt::VarDecl(VarDecl {
+                    init: Some(Expr::Literal(val)),
+                    ..
+                }) => val.clone(),
+                other => panic!("Expected a literal declaration, got {:?}", other),
+            }
+        }
+
+        let dec = parse_init("fn main() { let x: u32 = 3735928559; }");
+        let hex = parse_init("fn main() { let x: u32 = 0xDEADBEEF; }");
+        let bin = parse_init("fn main() { let x: u32 = 0b11011110101011011011111011101111; }");
+        assert_eq!(dec, hex);
+        assert_eq!(dec, bin);
+    }
+
+    #[test]
+    fn test_parse_else_if() {
+        let hir = parse_hir(
+            "fn main() {
+                println(sign(0), sign(3), sign(200));
+                if 1 == 2 {
+                    print(1);
+                } else if 1 == 3 {
+                    print(2);
+                }
+            }
+
+            fn sign(x: u8) -> u8 {
+                if x == 0 {
+                    0
+                } else if x < 128 {
+                    1
+                } else {
+                    2
+                }
+            }",
+        )
+        .unwrap();
+
+        let if_else = match hir.fns["sign"].scope.final_expr.as_deref() {
+            Some(Expr::IfElse(if_else)) => if_else,
+            other => panic!("Expected an if/else, got {:?}", other),
+        };
+        match &if_else.if_false {
+            Expr::IfElse(inner) => assert!(matches!(inner.if_false, Expr::Scope(_))),
+            other => panic!("Expected a nested if/else, got {:?}", other),
+        }
+        // without a final else, the inner if is a statement in the else block
+        match hir.fns["main"].scope.final_expr.as_deref() {
+            Some(Expr::IfElse(if_else)) => match &if_else.if_false {
+                Expr::Scope(Scope { stmts, .. }) => assert!(matches!(
+                    stmts.as_slice(),
+                    [Stmt::IfMaybeElse(IfMaybeElse { if_false: None, .. })]
+                )),
+                other => panic!("Expected an else block, got {:?}", other),
+            },
+            other => panic!("Expected an if with an else if, got {:?}", other),
+        }
+
+        let mut samstate = SamState::new(link_sam_fns(hir2sam(&hir)));
+        let mut w = Vec::new();
+        samstate.run(&mut "".as_bytes(), &mut w).unwrap();
+        assert_eq!(w, b"012\n");
+    }
+
+    #[test]
+    fn test_full_match() {
+        let hir = parse_hir(
+            "fn main() {
+                println(fib(0));
+                println(fib(1));
+                println(fib(7));
+                let big : u32 = 70000;
+                print(match big {
+                    5 => 1,
+                    70000 => {
+                        big = big + 1;
+                        big
+                    }
+                    _ => 3,
+                });
+            }
+
+            fn fib(x: u8) -> u8 {
+                match x {
+                    0 => 10,
+                    1 => 20,
+                    _ => x * 2,
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "10\n20\n14\n70001", &cfg);
+    }
+
+    #[test]
+    fn test_full_consts() {
+        let hir = parse_hir(
+            "const ANSWER: u32 = 6 * 7;
+
+            fn main() {
+                println(ANSWER);
+                println(twice_answer());
+                println(SMALL + 1);
+            }
+
+            const SMALL: u8 = ANSWER / 10;
+
+            fn twice_answer() -> u32 {
+                ANSWER + ANSWER
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "42\n84\n5\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_char() {
+        let hir = parse_hir(
+            "fn main() {
+                let c: char = 'A';
+                print_char(c);
+                c = c + 2;
+                println(c);
+                let newline: char = '\\n';
+                let quote: char = '\\'';
+                print_char(quote);
+                print_char(newline);
+                if c == 'C' {
+                    let diff: u8 = c - 'A';
+                    println(diff);
+                }
+            }",
+        )
+        .unwrap();
+
+        match &hir.fns["main"].scope.stmts[0] {
+            Stmt::VarDecl(VarDecl {
+                typ: VarType::Char,
+                init: Some(Expr::Literal(val)),
+                ..
+            }) => assert_eq!(*val, num::BigUint::from(65u8)),
+            other => panic!("Expected a char declaration, got {:?}", other),
+        }
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "AC\n'\n2\n", &cfg);
+    }
+
+    #[test]
+    fn test_disassemble_sam() {
+        let hir = parse_hir(
+            "fn main() {
+                println(fib(5));
+            }
+
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    x
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let disassembly = disassemble_sam(&linked);
+        assert!(disassembly.contains("main:\n"));
+        assert!(disassembly.contains("fib:\n"));
+        assert!(disassembly.contains(": CALL "));
+        assert!(disassembly.contains(": RET\n"));
+        assert!(disassembly.ends_with(": HALT\n"));
+    }
+
+    #[test]
+    fn test_decode_sam_ops() {
+        let bytes = [OPCODE_SET_A, 0, 0, 1, 2, OPCODE_PRINT_A, OPCODE_HALT];
+        let ops = decode_sam_ops(&bytes);
+        assert_eq!(ops.len(), 3);
+        assert!(matches!(ops[0], (0, SamOp::Simple(SamSOp::SetA(258)))));
+        assert!(matches!(ops[1], (5, SamOp::Simple(SamSOp::PrintA))));
+        assert!(matches!(ops[2], (6, SamOp::Simple(SamSOp::Halt))));
+    }
+
+    #[test]
+    fn test_sam_trace_step() {
+        let hir = parse_hir(
+            "fn main() {
+                println(fib(5));
+            }
+
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    x
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+        let linked = link_sam_fns(hir2sam(&hir));
+        let main_pos = linked.fn_start_poss["main"];
+        let fib_pos = linked.fn_start_poss["fib"];
+        let mut samstate = SamState::new(linked);
+        let mut traces = Vec::new();
+        while !samstate.halted {
+            traces.push(samstate.trace_step(&mut "".as_bytes()));
+        }
+
+        // main pushes the argument 5 and calls fib
+        assert!(matches!(traces[0].op, SamOp::Simple(SamSOp::SetX(5))));
+        assert_eq!(traces[0].instr_ptr, main_pos);
+        assert_eq!(traces[1].x, 5);
+        let first_call = traces
+            .iter()
+            .find(|t| matches!(t.op, SamOp::Call(_)))
+            .unwrap();
+        assert!(matches!(first_call.op, SamOp::Call(pos) if pos == fib_pos));
+        assert!(traces.iter().all(|t| t.result.is_ok()));
+        let output: Vec<u8> = traces.iter().flat_map(|t| t.output.clone()).collect();
+        assert_eq!(output, b"5\n");
+        assert_eq!(traces.len() as u64, samstate.instrs_executed());
+    }
+
+    #[test]
+    fn test_sam_instrs_executed() {
+        fn run_fib() -> u64 {
+            let hir = parse_hir(
+                "fn main() {
+                    println(fib(5));
+                }
+
+                fn fib(x: u8) -> u8 {
+                    if x < 2 {
+                        x
+                    } else {
+                        fib(x - 1) + fib(x - 2)
+                    }
+                }",
+            )
+            .unwrap();
+
+            let sam = hir2sam(&hir);
+
+            let linked = link_sam_fns(sam);
+
+            let mut samstate = SamState::new(linked);
+            let mut output = Vec::new();
+            samstate.run(&mut "".as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "5\n");
+            samstate.instrs_executed()
+        }
+
+        let count = run_fib();
+        assert!(count > 0);
+        assert_eq!(count, run_fib());
+    }
+
+    #[test]
+    fn test_sam_program_serialization() {
+        let hir = parse_hir(
+            "fn main() {
+                println(fib(6));
+            }
+
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    x
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let serialized = linked.to_bytes();
+        let loaded = CompiledSamProgram::from_bytes(&serialized).unwrap();
+        assert_eq!(loaded.bytes, linked.bytes);
+        assert_eq!(loaded.fn_start_poss, linked.fn_start_poss);
+        assert_eq!(loaded.source_lines, linked.source_lines);
+        assert!(CompiledSamProgram::from_bytes(&serialized[1..]).is_err());
+        assert!(CompiledSamProgram::from_bytes(&serialized[..serialized.len() - 1]).is_err());
+        let mut bad_op = linked.clone();
+        bad_op.bytes[0] = NUM_OPCODES;
+        assert!(matches!(
+            CompiledSamProgram::from_bytes(&bad_op.to_bytes()),
+            Err(SamProgramDecodeError::InvalidOp(0))
+        ));
+        let mut truncated_op = linked.clone();
+        truncated_op.bytes = vec![OPCODE_SET_A, 0, 0];
+        assert!(matches!(
+            CompiledSamProgram::from_bytes(&truncated_op.to_bytes()),
+            Err(SamProgramDecodeError::InvalidOp(0))
+        ));
+        let mut no_main = linked.clone();
+        no_main.fn_start_poss.remove("main");
+        let err = CompiledSamProgram::from_bytes(&no_main.to_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "No main function found");
+
+        let mut samstate = SamState::new(loaded);
+        let mut output = Vec::new();
+        samstate.run(&mut "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "8\n");
+    }
+
+    #[test]
+    fn test_far_ptr_shifts() {
+        let hir = parse_hir(
+            "fn main() {
+                let x: u8 = 3;
+                let p: &u8 = &x;
+                let q: &u8 = p + 60000;
+                *q = 7;
+                *p = *p + *q;
+                *q = *q + *p;
+                println(*q);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let mut instrs_executed = Vec::new();
+        for far_ptr_shifts in [false, true] {
+            let (ops, cfg) = sam2lir_with_options(
+                linked.clone(),
+                Sam2LirOptions {
+                    far_ptr_shifts,
+                    ..Default::default()
+                },
+            );
+            let ops = get_optimized_bf_ops(&lir2bf(&ops));
+            let mut state = BfState::new();
+            let mut w = Vec::new();
+            let mut loop_count = LoopCount::new();
+            state
+                .run_ops(
+                    &ops,
+                    &mut "".as_bytes(),
+                    &mut w,
+                    RunOptions {
+                        cpu_config: Some(&cfg),
+                        loop_count: Some(&mut loop_count),
+                        ..Default::default()
+                    },
+                )
+                .unwrap_or_else(print_err);
+            assert_eq!(w, b"17\n");
+            state.check_scratch_is_empty(&cfg);
+            instrs_executed.push(loop_count.get_instrs_executed());
+        }
+        assert!(instrs_executed[1] < instrs_executed[0]);
+    }
+
+    #[test]
+    fn test_full_println_multiple_args() {
+        let hir = parse_hir(
+            "fn main() {
+                println(\"fib = \", fib(5));
+                let c : char = 'x';
+                let n : i32 = -4;
+                print(c, \" \", n, \" \");
+                println(1 < 2, \"!\");
+                println();
+            }
+
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    1
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "fib = 8\nx -4 1!\n\n", &cfg);
+    }
+
+    #[test]
+    fn test_compile_hir_to_bf() {
+        let (ops, cfg) = compile_hir_to_bf(
+            "fn main() {
+                println(fib(5));
+            }
+
+            fn fib(x: u8) -> u8 {
+                if x < 2 {
+                    1
+                } else {
+                    fib(x - 1) + fib(x - 2)
+                }
+            }",
+        )
+        .unwrap();
+        test_parsed_bf_prog(&ops, "", "8\n", Some(&cfg));
+
+        assert!(matches!(
+            compile_hir_to_bf("fn main() {"),
+            Err(CompileError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_compile_without_main() {
+        for src in ["", " \n\t\n", "// nothing here yet\n", "fn foo() {}"] {
+            assert!(parse_hir(src).is_ok(), "{:?}", src);
+            assert!(matches!(
+                compile_hir_to_bf(src),
+                Err(CompileError::NoMainFunction)
+            ));
+        }
+        assert_eq!(
+            compile("fn foo() {}").error().as_deref(),
+            Some("No main function found")
+        );
+        assert_eq!(
+            debug_program("", "").error().as_deref(),
+            Some("No main function found")
+        );
+
+        let (ops, cfg) = compile_hir_to_bf(
+            "// prints a number
+            fn main() { // comments can go anywhere whitespace can
+                println(1); // even here
+            }",
+        )
+        .unwrap();
+        test_parsed_bf_prog(&ops, "", "1\n", Some(&cfg));
+    }
+
+    #[test]
+    fn test_full_assert() {
+        let src = "fn main() {
+                let x: u8 = 3;
+                assert(x == 3);
+                println(\"ok\");
+                assert(x > 4);
+                println(\"unreachable\");
+            }";
+        let (ops, _) = compile_hir_to_bf(src).unwrap();
+        assert!(matches!(
+            run_and_capture(&ops, b""),
+            Err(RunOpError::Crashed)
+        ));
+        let mut w = Vec::new();
+        let _ = BfState::new().run_ops(&ops, &mut "".as_bytes(), &mut w, RunOptions::default());
+        assert_eq!(
+            String::from_utf8(w).unwrap(),
+            "ok\nAssertion failed in fn main\n"
+        );
+
+        let hir = parse_hir(src).unwrap();
+        let mut samstate = SamState::new(link_sam_fns(hir2sam(&hir)));
+        let mut w = Vec::new();
+        assert!(matches!(
+            samstate.run(&mut "".as_bytes(), &mut w),
+            Err(SamRunOpError::Crashed)
+        ));
+        assert!(samstate.halted);
+
+        let (ops, _) = compile_hir_to_bf("fn main() { assert(1); println(1); }").unwrap();
+        assert_eq!(run_and_capture(&ops, b"").unwrap(), b"1\n");
+    }
+
+    #[test]
+    fn test_full_alloc() {
+        let hir = parse_hir(
+            "fn main() {
+                let p: &u32 = alloc(4);
+                let q: &u8 = alloc(1);
+                *p = 70000;
+                *q = 7;
+                println(*p, \" \", *q);
+                let r: &u8 = alloc(1);
+                *r = 9;
+                println(*p, \" \", *q, \" \", *r);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "70000 7\n70000 7 9\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_string_in_memory() {
+        let hir = parse_hir(
+            "fn main() {
+                let s: &u8 = \"hi\";
+                let n: u32 = strlen(s);
+                let i: u32 = 0;
+                while i < n {
+                    print_char(*s);
+                    s = s + 1;
+                    i = i + 1;
+                }
+                println(\" \", n, \" \", strlen(\"hello\"));
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "hi 2 5\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_string_literal_in_loop() {
+        let hir = parse_hir(
+            "fn main() {
+                let i: u8 = 0;
+                while i < 3 {
+                    let s: &u8 = \"hi\";
+                    print_char(*(s + 1));
+                    i = i + 1;
+                }
+                println(strlen(\"hi\"));
+            }",
+        )
+        .unwrap();
+
+        let linked = link_sam_fns(hir2sam(&hir));
+        // both literals share the same static copy
+        assert_eq!(linked.data, b"hi\0");
+        let mut samstate = SamState::new(linked.clone());
+        let mut output = Vec::new();
+        samstate.run(&mut "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "iii2\n");
+        // nothing was allocated on the heap
+        assert_eq!(samstate.read_u32_at(HEAP_START), 0);
+
+        let (ops, cfg) = sam2lir(linked);
+        test_lir_prog(&ops, "", "iii2\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_tail_calls() {
+        let hir = parse_hir(
+            "fn main() {
+                println(sum_to(10, 0), \" \", swapper(1, 2, 3), \" \", countdown(20));
+            }
+
+            fn sum_to(n: u8, acc: u8) -> u8 {
+                if n == 0 {
+                    acc
+                } else {
+                    sum_to(n - 1, acc + n)
+                }
+            }
+
+            fn swapper(a: u8, b: u8, k: u8) -> u8 {
+                if k == 0 {
+                    a
+                } else {
+                    swapper(b, a, k - 1)
+                }
+            }
+
+            fn countdown(n: u32) -> u32 {
+                if n == 0 {
+                    7
+                } else {
+                    countdown(n - 1)
+                }
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "55 2 7\n", &cfg);
+
+        // the stack doesn't grow with the recursion depth
+        let hir = parse_hir(
+            "fn main() {
+                println(countdown(100000));
+            }
+
+            fn countdown(n: u32) -> u32 {
+                if n == 0 {
+                    7
+                } else {
+                    countdown(n - 1)
+                }
+            }",
+        )
+        .unwrap();
+        let linked = link_sam_fns(hir2sam(&hir));
+        let prog_len = linked.bytes.len();
+        let mut samstate = SamState::new(linked);
+        let mut w = Vec::new();
+        samstate.run(&mut "".as_bytes(), &mut w).unwrap();
+        assert_eq!(w, b"7\n");
+        assert!(samstate.cells.len() < prog_len + 64);
+    }
+
+    #[test]
+    fn test_full_early_return() {
+        let hir = parse_hir(
+            "fn main() {
+                println(check(3), \" \", check(20));
+                greet(0);
+                greet(1);
+                println(\"!\");
+            }
+
+            fn check(x: u8) -> u8 {
+                if x > 10 {
+                    return 1;
+                }
+                print(\"small \");
+                0
+            }
+
+            fn greet(x: u8) {
+                if x == 0 {
+                    return;
+                }
+                print(\"hi\");
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+        for f in sam.values() {
+            // nothing may follow a return
+            for block in &f.blocks {
+                if let Some(i) = block
+                    .ops
+                    .iter()
+                    .position(|op| matches!(op, SamLOp::Simple(SamSOp::Ret)))
+                {
+                    assert_eq!(i, block.ops.len() - 1);
+                    assert_eq!(block.next_block_index, None);
+                }
+            }
+        }
+
+        let linked = link_sam_fns(sam);
+
+        let (ops, cfg) = sam2lir(linked);
+
+        test_lir_prog(&ops, "", "small 0 1\nhi!\n", &cfg);
+    }
+
+    #[test]
+    fn test_fold_literal_arithmetic() {
+        let hir = parse_hir(
+            "fn main() {
+                let x: u32 = 2 + 3 * 4;
+                let y: u8 = (200 + 100) - 1;
+                println(x);
+                println(y);
+            }",
+        )
+        .unwrap();
+
+        let sam = hir2sam(&hir);
+        let ops = sam["main"]
+            .blocks
+            .iter()
+            .flat_map(|block| &block.ops)
+            .collect::<Vec<_>>();
+        let count = |pred: &dyn Fn(&SamLOp) -> bool| ops.iter().filter(|op| pred(op)).count();
+        assert_eq!(
+            count(&|op| matches!(op, SamLOp::Simple(SamSOp::SetA(14)))),
+            1
+        );
+        assert_eq!(
+            count(&|op| matches!(
+                op,
+                SamLOp::Simple(
+                    SamSOp::AddU32AtBToA
+                        | SamSOp::MulU32AtBToA
+                        | SamSOp::AddU8AtBToX
+                        | SamSOp::MulU8AtBToX
+                        | SamSOp::NegA
+                        | SamSOp::NegX
+                )
+            )),
+            0
+        );
+
+        let (ops, cfg) = sam2lir(link_sam_fns(sam));
+        test_lir_prog(&ops, "", "14\n43\n", &cfg);
+    }
+
+    #[test]
+    fn test_full_print_u8_decimal() {
+        let hir = parse_hir(
+            "fn main() {
+                let x: u8 = 7;
+                println(x, \" \", x * 6, \" \", x * 36);
+            }",
+        )
+        .unwrap();
+        let sam = hir2sam(&hir);
+        // the same program, printing through A like before PrintXDecimal existed
+        let mut sam_through_a = hir2sam(&hir);
+        for block in &mut sam_through_a.get_mut("main").unwrap().blocks {
+            block.ops = std::mem::take(&mut block.ops)
+                .into_iter()
+                .flat_map(|op| match op {
+                    SamLOp::Simple(SamSOp::PrintXDecimal) => vec![
+                        SamLOp::Simple(SamSOp::MoveXToA),
+                        SamLOp::Simple(SamSOp::PrintA),
+                    ],
+                    op => vec![op],
+                })
+                .collect();
+        }
+
+        let run = |sam| {
+            let linked = link_sam_fns(sam);
+            let mut samstate = SamState::new(linked.clone());
+            let mut output = Vec::new();
+            samstate.run(&mut "".as_bytes(), &mut output).unwrap();
+            assert_eq!(String::from_utf8(output).unwrap(), "7 42 252\n");
+
+            let (ops, cfg) = sam2lir(linked);
+            let mut state = BfState::new();
+            let mut output = Vec::new();
+            state
+                .run_ops(
+                    &get_optimized_bf_ops(&lir2bf(&ops)),
+                    &mut "".as_bytes(),
+                    &mut output,
+                    RunOptions {
+                        cpu_config: Some(&cfg),
+                        ..Default::default()
+                    },
+                )
+                .unwrap_or_else(print_err);
+            assert_eq!(String::from_utf8(output).unwrap(), "7 42 252\n");
+            (samstate.instrs_executed(), state.steps_executed())
+        };
+        let (sam_instrs, bf_steps) = run(sam);
+        let (sam_instrs_through_a, bf_steps_through_a) = run(sam_through_a);
+        assert_eq!(sam_instrs + 3, sam_instrs_through_a);
+        assert!(
+            bf_steps < bf_steps_through_a,
+            "{} >= {}",
+            bf_steps,
+            bf_steps_through_a
+        );
+    }
+
+    #[test]
+    fn test_full_u64() {
+        let hir = parse_hir(
+            "fn main() {
+                let a: u64 = 4000000000;
+                let b: u64 = square(55000);
+                println(a * b);
+                println((a * 3000000007 - a) + 1);
+            }
+
+            fn square(x: u64) -> u64 {
+                x * x
+            }",
+        )
+        .unwrap();
+
+        let expected = "12100000000000000000\n12000000024000000001\n";
+        let linked = link_sam_fns(hir2sam(&hir));
+        let mut samstate = SamState::new(linked.clone());
+        let mut output = Vec::new();
+        samstate.run(&mut "".as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        let (ops, cfg) = sam2lir(linked);
+        test_lir_prog(&ops, "", expected, &cfg);
+    }
+
+    #[test]
+    fn test_full_stack_limit() {
+        let hir = parse_hir(
+            "fn main() {
+                println(depth(0));
+            }
+
+            fn depth(x: u8) -> u8 {
+                print(\"*\");
+                depth(x + 1) + 1
+            }",
+        )
+        .unwrap();
+
+        let linked = link_sam_fns(hir2sam(&hir));
+        let options = Sam2LirOptions {
+            max_stack_size: Some(50),
+            ..Default::default()
+        };
+        let (ops, cfg) = sam2lir_with_options(linked, options);
+        let ops = get_optimized_bf_ops(&lir2bf(&ops));
+        let mut state = BfState::new();
+        let mut w = Vec::new();
+        let result = state.run_ops(
+            &ops,
+            &mut "".as_bytes(),
+            &mut w,
+            RunOptions {
+                cpu_config: Some(&cfg),
+                max_steps: Some(100_000_000),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(RunOpError::Crashed)));
+        assert!(!w.is_empty() && w.len() < 50);
+        assert!(w.iter().all(|&c| c == b'*'));
+    }
+
+    #[test]
+    fn test_full_stack_runs_into_heap() {
+        let hir = parse_hir(
+            "fn main() {
+                let p: &u8 = alloc(1);
+                *p = 7;
+                deep(p);
+            }
+
+            fn deep(p: &u8) {
+                let buf: [u8; 2000];
+                print_char(48 + *p);
+                deep(p);
+            }",
+        )
+        .unwrap();
+
+        let linked = link_sam_fns(hir2sam(&hir));
+        assert!(linked.max_frame_size > 2000);
+        // sam2lir does the same check as test_full_stack_limit, just with a different limit,
+        // and walking the bf tape all the way to the heap takes too long for a test
+        let mut samstate = SamState::new(linked);
+        let mut output = Vec::new();
+        let result = samstate.run(&mut "".as_bytes(), &mut output);
+        assert!(matches!(result, Err(SamRunOpError::Crashed)));
+        // every call got to see the heap intact, until one would have overwritten it
+        assert!(output.len() >= 10 && output.iter().all(|&c| c == b'7'));
+    }
+
+    #[test]
+    fn test_full_fused_cmp_jumps() {
+        let hir = parse_hir(
+            "fn main() {
+                let i: u32 = 0;
+                while i <= 12 {
+                    if i < 3 {
+                        print(\"a\");
+                    } else {
+                        if i >= 10 {
+                            print(\"b\");
+                        } else {
+                            if i == 5 {
+                                print(\"c\");
+                            } else {
+                                if i > 7 {
+                                    print(\"d\");
+                                } else {
+                                    print(\"e\");
+                                }
+                            }
+                        }
+                    }
+                    i = i + 1;
+                }
+                let n: u8 = 9;
+                while n > 4 {
+                    n = n - 2;
+                }
+                println(\" \", n);
+            }",
+        )
+        .unwrap();
+
+        let fused = hir2sam(&hir);
+        // the old lowering: normalize the comparison result in X, then jump if it's nonzero
+        let mut unfused = hir2sam(&hir);
+        for f in unfused.values_mut() {
+            for block in &mut f.blocks {
+                let mut ops = Vec::new();
+                for op in block.ops.drain(..) {
+                    match op {
+                        SamLOp::JmpToBlockIfCmp(cmp_result, b) => {
+                            match cmp_result {
+                                CmpResult::Lt => {
+                                    ops.push(SamLOp::Simple(SamSOp::AddConstToX(1)));
+                                }
+                                CmpResult::Eq => {}
+                                CmpResult::Gt => {
+                                    ops.push(SamLOp::Simple(SamSOp::AddConstToX(255)));
+                                }
+                            }
+                            ops.push(SamLOp::Simple(SamSOp::NotX));
+                            ops.push(SamLOp::JmpToBlockIfX(b));
+                        }
+                        op => ops.push(op),
+                    }
+                }
+                block.ops = ops;
+            }
+        }
+
+        let fused = link_sam_fns(fused);
+        let unfused = link_sam_fns(unfused);
+        assert!(fused.bytes.len() + 10 <= unfused.bytes.len());
+
+        let expected = "aaaeeceeddbbb 3\n";
+        for linked in [fused.clone(), unfused] {
+            let mut samstate = SamState::new(linked);
+            let mut w = Vec::new();
+            samstate.run(&mut "".as_bytes(), &mut w).unwrap();
+            assert_eq!(String::from_utf8(w).unwrap(), expected);
+        }
+        let (ops, cfg) = sam2lir(fused);
+        test_lir_prog(&ops, "", expected, &cfg);
     }
 
     #[test]
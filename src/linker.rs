@@ -7,6 +7,12 @@ pub enum SamLOp {
     Simple(SamSOp),
     Call(String),
     JmpToBlockIfX(usize),
+    JmpToBlockIfCmp(CmpResult, usize),
+    /// Marks the start of a source line, takes up no bytes
+    SourceLine(usize),
+    /// Sets A to the address of these bytes in the program's static data.
+    /// Equal bytes are only stored once, so they shouldn't be written to.
+    SetAToStaticData(Vec<u8>),
 }
 
 impl SamLOp {
@@ -15,6 +21,9 @@ impl SamLOp {
             SamLOp::Simple(op) => op.len(),
             SamLOp::Call(_) => 5,
             SamLOp::JmpToBlockIfX(_) => 5,
+            SamLOp::JmpToBlockIfCmp(..) => 5,
+            SamLOp::SourceLine(_) => 0,
+            SamLOp::SetAToStaticData(_) => 5,
         }
     }
 }
@@ -24,6 +33,8 @@ pub struct SamFn {
     pub name: String,
     pub arg_sizes: Vec<u32>,
     pub ret_size: u32,
+    /// Bytes of stack the function uses at most, counting from its return value
+    pub frame_size: u32,
     pub blocks: Vec<SamBlock>,
 }
 
@@ -42,6 +53,162 @@ pub struct CompiledSamProgram {
     pub bytes: Vec<u8>,
     pub fn_start_poss: BTreeMap<String, u32>,
     pub sam_str: String,
+    /// (byte offset, source line) for the first byte of each source line's code
+    pub source_lines: Vec<(u32, u32)>,
+    /// The largest `frame_size` of all functions
+    pub max_frame_size: u32,
+    /// Static data, loaded right after the code and the Halt that follows it
+    pub data: Vec<u8>,
+}
+
+impl CompiledSamProgram {
+    /// Calls crash when B is past this, as the called function could then write into the heap
+    pub fn stack_end(&self) -> SamVal {
+        HEAP_START.saturating_sub(self.max_frame_size)
+    }
+}
+
+const SAM_PROGRAM_MAGIC: &[u8; 4] = b"BFSM";
+const SAM_PROGRAM_VERSION: u8 = 4;
+
+#[derive(Debug)]
+pub enum SamProgramDecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    InvalidUtf8,
+    TrailingBytes,
+    /// The bytecode has an unknown or truncated op at this position
+    InvalidOp(usize),
+    NoMainFunction,
+}
+
+impl std::fmt::Display for SamProgramDecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SamProgramDecodeError::BadMagic => write!(f, "Not a compiled SAM program"),
+            SamProgramDecodeError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported SAM program version {}", v)
+            }
+            SamProgramDecodeError::UnexpectedEnd => write!(f, "Unexpected end of SAM program"),
+            SamProgramDecodeError::InvalidUtf8 => write!(f, "Invalid UTF-8 in SAM program"),
+            SamProgramDecodeError::TrailingBytes => write!(f, "Trailing bytes after SAM program"),
+            SamProgramDecodeError::InvalidOp(pos) => write!(f, "Invalid SAM op at {}", pos),
+            SamProgramDecodeError::NoMainFunction => write!(f, "No main function found"),
+        }
+    }
+}
+
+impl std::error::Error for SamProgramDecodeError {}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SamProgramDecodeError> {
+        if self.bytes.len() < n {
+            return Err(SamProgramDecodeError::UnexpectedEnd);
+        }
+        let (res, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(res)
+    }
+
+    fn u32(&mut self) -> Result<u32, SamProgramDecodeError> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn sized_bytes(&mut self) -> Result<&'a [u8], SamProgramDecodeError> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, SamProgramDecodeError> {
+        let b = self.sized_bytes()?;
+        String::from_utf8(b.to_vec()).map_err(|_| SamProgramDecodeError::InvalidUtf8)
+    }
+}
+
+impl CompiledSamProgram {
+    /// Serializes the program as a magic header followed by the function table,
+    /// the source line table, the largest frame size, the SAM string, the bytecode
+    /// and the static data. All integers are big-endian u32s.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn push_sized_bytes(vec: &mut Vec<u8>, bytes: &[u8]) {
+            push_u32_to_vec(vec, bytes.len() as u32);
+            vec.extend(bytes);
+        }
+
+        let mut res = Vec::with_capacity(self.bytes.len() + 100);
+        res.extend(SAM_PROGRAM_MAGIC);
+        res.push(SAM_PROGRAM_VERSION);
+        push_u32_to_vec(&mut res, self.fn_start_poss.len() as u32);
+        for (name, pos) in &self.fn_start_poss {
+            push_sized_bytes(&mut res, name.as_bytes());
+            push_u32_to_vec(&mut res, *pos);
+        }
+        push_u32_to_vec(&mut res, self.source_lines.len() as u32);
+        for (pos, line) in &self.source_lines {
+            push_u32_to_vec(&mut res, *pos);
+            push_u32_to_vec(&mut res, *line);
+        }
+        push_u32_to_vec(&mut res, self.max_frame_size);
+        push_sized_bytes(&mut res, self.sam_str.as_bytes());
+        push_sized_bytes(&mut res, &self.bytes);
+        push_sized_bytes(&mut res, &self.data);
+        res
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CompiledSamProgram, SamProgramDecodeError> {
+        let mut r = ByteReader { bytes };
+        if r.take(SAM_PROGRAM_MAGIC.len())? != SAM_PROGRAM_MAGIC {
+            return Err(SamProgramDecodeError::BadMagic);
+        }
+        let version = r.take(1)?[0];
+        if version != SAM_PROGRAM_VERSION {
+            return Err(SamProgramDecodeError::UnsupportedVersion(version));
+        }
+        let num_fns = r.u32()?;
+        let mut fn_start_poss = BTreeMap::new();
+        for _ in 0..num_fns {
+            let name = r.string()?;
+            let pos = r.u32()?;
+            fn_start_poss.insert(name, pos);
+        }
+        let num_source_lines = r.u32()?;
+        let mut source_lines = Vec::new();
+        for _ in 0..num_source_lines {
+            let pos = r.u32()?;
+            let line = r.u32()?;
+            source_lines.push((pos, line));
+        }
+        let max_frame_size = r.u32()?;
+        let sam_str = r.string()?;
+        let bytes = r.sized_bytes()?.to_vec();
+        let data = r.sized_bytes()?.to_vec();
+        if !r.bytes.is_empty() {
+            return Err(SamProgramDecodeError::TrailingBytes);
+        }
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let op =
+                try_decode_sam_op(&bytes[pos..]).ok_or(SamProgramDecodeError::InvalidOp(pos))?;
+            pos += op.len();
+        }
+        if !fn_start_poss.contains_key("main") {
+            return Err(SamProgramDecodeError::NoMainFunction);
+        }
+        Ok(CompiledSamProgram {
+            bytes,
+            fn_start_poss,
+            sam_str,
+            source_lines,
+            max_frame_size,
+            data,
+        })
+    }
 }
 
 pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
@@ -51,6 +218,9 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
         Call(String),
         JmpToByteOffset(SamIVal),
         JmpToByteOffsetIfX(SamIVal),
+        JmpToByteOffsetIfCmp(CmpResult, SamIVal),
+        SourceLine(usize),
+        SetAToStaticData(Vec<u8>),
     }
 
     impl SamFnOp {
@@ -60,6 +230,9 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
                 SamFnOp::Call(_) => 5,
                 SamFnOp::JmpToByteOffset(_) => 5,
                 SamFnOp::JmpToByteOffsetIfX(_) => 5,
+                SamFnOp::JmpToByteOffsetIfCmp(..) => 5,
+                SamFnOp::SourceLine(_) => 0,
+                SamFnOp::SetAToStaticData(_) => 5,
             }
         }
     }
@@ -127,6 +300,13 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
                             (block_start_poss[pre_to_post_num[*b]] as SamIVal)
                                 - (cur_num_bytes as SamIVal),
                         ),
+                        SamLOp::JmpToBlockIfCmp(cmp_result, b) => SamFnOp::JmpToByteOffsetIfCmp(
+                            *cmp_result,
+                            (block_start_poss[pre_to_post_num[*b]] as SamIVal)
+                                - (cur_num_bytes as SamIVal),
+                        ),
+                        SamLOp::SourceLine(line) => SamFnOp::SourceLine(*line),
+                        SamLOp::SetAToStaticData(data) => SamFnOp::SetAToStaticData(data.clone()),
                     };
                     cur_num_bytes += new_op.len();
                     ops.push(new_op);
@@ -164,37 +344,54 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
             sam_str += "    ";
             sam_str += &match op {
                 SamFnOp::Simple(op) => match op {
-                    SamSOp::Halt => format!("Halt"),
+                    SamSOp::Halt => "Halt".to_string(),
                     SamSOp::SetX(x) => format!("SetX({})", x),
                     SamSOp::SetA(x) => format!("SetA({})", x),
-                    SamSOp::ReadAAtB => format!("ReadAAtB"),
-                    SamSOp::ReadXAtB => format!("ReadXAtB"),
-                    SamSOp::WriteAAtB => format!("WriteAAtB"),
-                    SamSOp::WriteXAtB => format!("WriteXAtB"),
-                    SamSOp::PrintCharX => format!("PrintCharX"),
-                    SamSOp::StdinX => format!("StdinX"),
+                    SamSOp::ReadAAtB => "ReadAAtB".to_string(),
+                    SamSOp::ReadXAtB => "ReadXAtB".to_string(),
+                    SamSOp::WriteAAtB => "WriteAAtB".to_string(),
+                    SamSOp::WriteXAtB => "WriteXAtB".to_string(),
+                    SamSOp::PrintCharX => "PrintCharX".to_string(),
+                    SamSOp::StdinX => "StdinX".to_string(),
                     SamSOp::AddConstToB(c) => format!("AddConstToB({})", c),
                     SamSOp::SubConstFromB(c) => format!("SubConstFromB({})", c),
-                    SamSOp::PrintA => format!("PrintA"),
-                    SamSOp::Ret => format!("Ret"),
-                    SamSOp::AddU32AtBToA => format!("AddU32AtBToA"),
-                    SamSOp::AddU8AtBToX => format!("AddU8AtBToX"),
-                    SamSOp::MulU32AtBToA => format!("MulU32AtBToA"),
-                    SamSOp::MulU8AtBToX => format!("MulU8AtBToX"),
-                    SamSOp::NegX => format!("NegX"),
-                    SamSOp::NegA => format!("NegA"),
-                    SamSOp::MoveXToA => format!("MoveXToA"),
-                    SamSOp::NotX => format!("NotX"),
+                    SamSOp::PrintA => "PrintA".to_string(),
+                    SamSOp::Ret => "Ret".to_string(),
+                    SamSOp::AddU32AtBToA => "AddU32AtBToA".to_string(),
+                    SamSOp::AddU8AtBToX => "AddU8AtBToX".to_string(),
+                    SamSOp::MulU32AtBToA => "MulU32AtBToA".to_string(),
+                    SamSOp::MulU8AtBToX => "MulU8AtBToX".to_string(),
+                    SamSOp::NegX => "NegX".to_string(),
+                    SamSOp::NegA => "NegA".to_string(),
+                    SamSOp::MoveXToA => "MoveXToA".to_string(),
+                    SamSOp::NotX => "NotX".to_string(),
                     SamSOp::AddConstToX(c) => format!("AddConstToX({})", c),
-                    SamSOp::CmpU8AtBWithX => format!("CmpU8AtBWithX"),
-                    SamSOp::CmpU32AtBWithA => format!("CmpU32AtBWithA"),
-                    SamSOp::SetXToU8AtBDivByX => format!("SetXToU8AtBDivByX"),
-                    SamSOp::SetAToU32AtBDivByA => format!("SetAToU32AtBDivByA"),
-                    SamSOp::SetXToU8AtBModX => format!("SetXToU8AtBModX"),
-                    SamSOp::SetAToU32AtBModA => format!("SetAToU32AtBModA"),
-                    SamSOp::CopyAToB => format!("CopyAToB"),
-                    SamSOp::CopyBToA => format!("CopyBToA"),
-                    SamSOp::SwapBAndC => format!("SwapBAndC"),
+                    SamSOp::CmpU8AtBWithX => "CmpU8AtBWithX".to_string(),
+                    SamSOp::CmpU32AtBWithA => "CmpU32AtBWithA".to_string(),
+                    SamSOp::SetXToU8AtBDivByX => "SetXToU8AtBDivByX".to_string(),
+                    SamSOp::SetAToU32AtBDivByA => "SetAToU32AtBDivByA".to_string(),
+                    SamSOp::SetXToU8AtBModX => "SetXToU8AtBModX".to_string(),
+                    SamSOp::SetAToU32AtBModA => "SetAToU32AtBModA".to_string(),
+                    SamSOp::CopyAToB => "CopyAToB".to_string(),
+                    SamSOp::CopyBToA => "CopyBToA".to_string(),
+                    SamSOp::SwapBAndC => "SwapBAndC".to_string(),
+                    SamSOp::ReadA16AtB => "ReadA16AtB".to_string(),
+                    SamSOp::WriteA16AtB => "WriteA16AtB".to_string(),
+                    SamSOp::CmpI32AtBWithA => "CmpI32AtBWithA".to_string(),
+                    SamSOp::PrintSignedA => "PrintSignedA".to_string(),
+                    SamSOp::SetAToU32AtBAndA => "SetAToU32AtBAndA".to_string(),
+                    SamSOp::SetAToU32AtBOrA => "SetAToU32AtBOrA".to_string(),
+                    SamSOp::SetAToU32AtBXorA => "SetAToU32AtBXorA".to_string(),
+                    SamSOp::SetAToU32AtBShlA => "SetAToU32AtBShlA".to_string(),
+                    SamSOp::SetAToU32AtBShrA => "SetAToU32AtBShrA".to_string(),
+                    SamSOp::ShlAByConst(c) => format!("ShlAByConst({})", c),
+                    SamSOp::ShrAByConst(c) => format!("ShrAByConst({})", c),
+                    SamSOp::Crash => "Crash".to_string(),
+                    SamSOp::AddU64AtB8ToU64AtB => "AddU64AtB8ToU64AtB".to_string(),
+                    SamSOp::SubU64AtB8FromU64AtB => "SubU64AtB8FromU64AtB".to_string(),
+                    SamSOp::MulU64AtBByU64AtB8 => "MulU64AtBByU64AtB8".to_string(),
+                    SamSOp::PrintU64AtB => "PrintU64AtB".to_string(),
+                    SamSOp::PrintXDecimal => "PrintXDecimal".to_string(),
                 },
                 SamFnOp::Call(called_f_name) => {
                     format!("call \"{}\"", called_f_name)
@@ -205,6 +402,15 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
                 SamFnOp::JmpToByteOffsetIfX(offset) => {
                     format!("JumpIfX({})", offset)
                 }
+                SamFnOp::JmpToByteOffsetIfCmp(cmp_result, offset) => {
+                    format!("JumpIfX{:?}({})", cmp_result, offset)
+                }
+                SamFnOp::SourceLine(line) => {
+                    format!("// line {}", line)
+                }
+                SamFnOp::SetAToStaticData(data) => {
+                    format!("SetAToStaticData({:?})", String::from_utf8_lossy(data))
+                }
             };
             sam_str += "\n";
         }
@@ -212,20 +418,39 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
 
     // calculate all functions' first byte positions
     let mut fn_start_poss = BTreeMap::new();
-    {
-        let mut cur_num_bytes = 0;
-        for f_name in fns.keys() {
-            fn_start_poss.insert(f_name.clone(), cur_num_bytes as u32);
-            for op in &fn_ops[f_name] {
-                cur_num_bytes += op.len();
+    let mut code_len = 0;
+    for f_name in fns.keys() {
+        fn_start_poss.insert(f_name.clone(), code_len as u32);
+        for op in &fn_ops[f_name] {
+            code_len += op.len();
+        }
+    }
+
+    // the static data goes after the code and the Halt that SamState puts after it
+    let data_start = code_len as SamVal + 1;
+    let mut data = Vec::new();
+    let mut data_poss = BTreeMap::new();
+    for f_name in fns.keys() {
+        for op in &fn_ops[f_name] {
+            if let SamFnOp::SetAToStaticData(bytes) = op {
+                data_poss.entry(bytes).or_insert_with(|| {
+                    let pos = data_start + data.len() as SamVal;
+                    data.extend(bytes);
+                    pos
+                });
             }
         }
     }
 
     let mut bytes = Vec::with_capacity(1000);
+    let mut source_lines = Vec::new();
     for f_name in fns.keys() {
         for op in &fn_ops[f_name] {
             let sam_op = match op {
+                SamFnOp::SourceLine(line) => {
+                    source_lines.push((bytes.len() as u32, *line as u32));
+                    continue;
+                }
                 SamFnOp::Simple(op) => SamOp::Simple(*op),
                 SamFnOp::Call(called_f_name) => SamOp::Call(
                     *fn_start_poss
@@ -234,6 +459,10 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
                 ),
                 SamFnOp::JmpToByteOffset(offset) => SamOp::Jmp(*offset),
                 SamFnOp::JmpToByteOffsetIfX(offset) => SamOp::JmpIfX(*offset),
+                SamFnOp::JmpToByteOffsetIfCmp(cmp_result, offset) => {
+                    SamOp::JmpIfCmp(*cmp_result, *offset)
+                }
+                SamFnOp::SetAToStaticData(bytes) => SamOp::Simple(SamSOp::SetA(data_poss[bytes])),
             };
             let num_bytes = sam_op.encode();
             bytes.extend(num_bytes);
@@ -244,5 +473,8 @@ pub fn link_sam_fns(fns: BTreeMap<String, SamFn>) -> CompiledSamProgram {
         bytes,
         fn_start_poss,
         sam_str,
+        source_lines,
+        max_frame_size: fns.values().map(|f| f.frame_size).max().unwrap_or(0),
+        data,
     }
 }
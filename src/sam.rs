@@ -1,7 +1,12 @@
 use crate::linker::*;
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 
 pub type SamVal = u32;
+
+/// Address of the u32 that `alloc` bumps, the heap starts right after it.
+/// Calls crash rather than let the stack grow into it.
+pub const HEAP_START: SamVal = 0x10000;
 pub type SamIVal = i32;
 
 pub const OPCODE_HALT: u8 = 0;
@@ -38,10 +43,56 @@ pub const OPCODE_SET_A_TO_U32_AT_B_MOD_A: u8 = 30;
 pub const OPCODE_COPY_A_TO_B: u8 = 31;
 pub const OPCODE_COPY_B_TO_A: u8 = 32;
 pub const OPCODE_SWAP_B_AND_C: u8 = 33;
+pub const OPCODE_READ_A16_AT_B: u8 = 34;
+pub const OPCODE_WRITE_A16_AT_B: u8 = 35;
+pub const OPCODE_CMP_I32_AT_B_WITH_A: u8 = 36;
+pub const OPCODE_PRINT_SIGNED_A: u8 = 37;
+pub const OPCODE_SET_A_TO_U32_AT_B_AND_A: u8 = 38;
+pub const OPCODE_SET_A_TO_U32_AT_B_OR_A: u8 = 39;
+pub const OPCODE_SET_A_TO_U32_AT_B_XOR_A: u8 = 40;
+pub const OPCODE_SET_A_TO_U32_AT_B_SHL_A: u8 = 41;
+pub const OPCODE_SET_A_TO_U32_AT_B_SHR_A: u8 = 42;
+pub const OPCODE_SHL_A_BY_CONST: u8 = 43;
+pub const OPCODE_SHR_A_BY_CONST: u8 = 44;
+pub const OPCODE_CRASH: u8 = 45;
+pub const OPCODE_JUMP_IF_X_LT: u8 = 46;
+pub const OPCODE_JUMP_IF_X_EQ: u8 = 47;
+pub const OPCODE_JUMP_IF_X_GT: u8 = 48;
+pub const OPCODE_ADD_U64_AT_B8_TO_U64_AT_B: u8 = 49;
+pub const OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B: u8 = 50;
+pub const OPCODE_MUL_U64_AT_B_BY_U64_AT_B8: u8 = 51;
+pub const OPCODE_PRINT_U64_AT_B: u8 = 52;
+pub const OPCODE_PRINT_X_DECIMAL: u8 = 53;
+
+pub const NUM_OPCODES: u8 = 54;
 
-pub const NUM_OPCODES: u8 = 34;
+/// An outcome of the Cmp ops, which leave 255, 0 or 1 in X
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CmpResult {
+    Lt,
+    Eq,
+    Gt,
+}
+
+impl CmpResult {
+    pub fn x_value(&self) -> u8 {
+        match self {
+            CmpResult::Lt => 255,
+            CmpResult::Eq => 0,
+            CmpResult::Gt => 1,
+        }
+    }
+
+    pub fn jump_opcode(&self) -> u8 {
+        match self {
+            CmpResult::Lt => OPCODE_JUMP_IF_X_LT,
+            CmpResult::Eq => OPCODE_JUMP_IF_X_EQ,
+            CmpResult::Gt => OPCODE_JUMP_IF_X_GT,
+        }
+    }
+}
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SamSOp {
     Halt,
     SetX(u8),
@@ -74,6 +125,24 @@ pub enum SamSOp {
     CopyAToB,
     CopyBToA,
     SwapBAndC,
+    ReadA16AtB,
+    WriteA16AtB,
+    CmpI32AtBWithA,
+    PrintSignedA,
+    SetAToU32AtBAndA,
+    SetAToU32AtBOrA,
+    SetAToU32AtBXorA,
+    SetAToU32AtBShlA,
+    SetAToU32AtBShrA,
+    ShlAByConst(u8),
+    ShrAByConst(u8),
+    Crash,
+    // A only holds 32 bits, so the u64 ops work on memory. B8 is short for B + 8.
+    AddU64AtB8ToU64AtB,
+    SubU64AtB8FromU64AtB,
+    MulU64AtBByU64AtB8,
+    PrintU64AtB,
+    PrintXDecimal,
 }
 
 #[derive(Debug)]
@@ -82,6 +151,7 @@ pub enum SamOp {
     Call(SamVal),
     Jmp(SamIVal),
     JmpIfX(SamIVal),
+    JmpIfCmp(CmpResult, SamIVal),
 }
 
 impl SamSOp {
@@ -186,6 +256,57 @@ impl SamSOp {
             SamSOp::SwapBAndC => {
                 vec![OPCODE_SWAP_B_AND_C]
             }
+            SamSOp::ReadA16AtB => {
+                vec![OPCODE_READ_A16_AT_B]
+            }
+            SamSOp::WriteA16AtB => {
+                vec![OPCODE_WRITE_A16_AT_B]
+            }
+            SamSOp::CmpI32AtBWithA => {
+                vec![OPCODE_CMP_I32_AT_B_WITH_A]
+            }
+            SamSOp::PrintSignedA => {
+                vec![OPCODE_PRINT_SIGNED_A]
+            }
+            SamSOp::SetAToU32AtBAndA => {
+                vec![OPCODE_SET_A_TO_U32_AT_B_AND_A]
+            }
+            SamSOp::SetAToU32AtBOrA => {
+                vec![OPCODE_SET_A_TO_U32_AT_B_OR_A]
+            }
+            SamSOp::SetAToU32AtBXorA => {
+                vec![OPCODE_SET_A_TO_U32_AT_B_XOR_A]
+            }
+            SamSOp::SetAToU32AtBShlA => {
+                vec![OPCODE_SET_A_TO_U32_AT_B_SHL_A]
+            }
+            SamSOp::SetAToU32AtBShrA => {
+                vec![OPCODE_SET_A_TO_U32_AT_B_SHR_A]
+            }
+            SamSOp::ShlAByConst(val) => {
+                vec![OPCODE_SHL_A_BY_CONST, *val]
+            }
+            SamSOp::ShrAByConst(val) => {
+                vec![OPCODE_SHR_A_BY_CONST, *val]
+            }
+            SamSOp::Crash => {
+                vec![OPCODE_CRASH]
+            }
+            SamSOp::AddU64AtB8ToU64AtB => {
+                vec![OPCODE_ADD_U64_AT_B8_TO_U64_AT_B]
+            }
+            SamSOp::SubU64AtB8FromU64AtB => {
+                vec![OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B]
+            }
+            SamSOp::MulU64AtBByU64AtB8 => {
+                vec![OPCODE_MUL_U64_AT_B_BY_U64_AT_B8]
+            }
+            SamSOp::PrintU64AtB => {
+                vec![OPCODE_PRINT_U64_AT_B]
+            }
+            SamSOp::PrintXDecimal => {
+                vec![OPCODE_PRINT_X_DECIMAL]
+            }
         }
     }
 
@@ -213,6 +334,11 @@ impl SamOp {
                 push_samival_to_vec(&mut res, *offset);
                 res
             }
+            SamOp::JmpIfCmp(cmp_result, offset) => {
+                let mut res = vec![cmp_result.jump_opcode()];
+                push_samival_to_vec(&mut res, *offset);
+                res
+            }
         }
     }
 
@@ -289,10 +415,157 @@ fn decode_sam_op(slice: &[u8]) -> SamOp {
         OPCODE_COPY_A_TO_B => SamOp::Simple(SamSOp::CopyAToB),
         OPCODE_COPY_B_TO_A => SamOp::Simple(SamSOp::CopyBToA),
         OPCODE_SWAP_B_AND_C => SamOp::Simple(SamSOp::SwapBAndC),
+        OPCODE_READ_A16_AT_B => SamOp::Simple(SamSOp::ReadA16AtB),
+        OPCODE_WRITE_A16_AT_B => SamOp::Simple(SamSOp::WriteA16AtB),
+        OPCODE_CMP_I32_AT_B_WITH_A => SamOp::Simple(SamSOp::CmpI32AtBWithA),
+        OPCODE_PRINT_SIGNED_A => SamOp::Simple(SamSOp::PrintSignedA),
+        OPCODE_SET_A_TO_U32_AT_B_AND_A => SamOp::Simple(SamSOp::SetAToU32AtBAndA),
+        OPCODE_SET_A_TO_U32_AT_B_OR_A => SamOp::Simple(SamSOp::SetAToU32AtBOrA),
+        OPCODE_SET_A_TO_U32_AT_B_XOR_A => SamOp::Simple(SamSOp::SetAToU32AtBXorA),
+        OPCODE_SET_A_TO_U32_AT_B_SHL_A => SamOp::Simple(SamSOp::SetAToU32AtBShlA),
+        OPCODE_SET_A_TO_U32_AT_B_SHR_A => SamOp::Simple(SamSOp::SetAToU32AtBShrA),
+        OPCODE_SHL_A_BY_CONST => SamOp::Simple(SamSOp::ShlAByConst(slice[1])),
+        OPCODE_SHR_A_BY_CONST => SamOp::Simple(SamSOp::ShrAByConst(slice[1])),
+        OPCODE_CRASH => SamOp::Simple(SamSOp::Crash),
+        OPCODE_JUMP_IF_X_LT => SamOp::JmpIfCmp(CmpResult::Lt, decode_samival(&slice[1..5])),
+        OPCODE_JUMP_IF_X_EQ => SamOp::JmpIfCmp(CmpResult::Eq, decode_samival(&slice[1..5])),
+        OPCODE_JUMP_IF_X_GT => SamOp::JmpIfCmp(CmpResult::Gt, decode_samival(&slice[1..5])),
+        OPCODE_ADD_U64_AT_B8_TO_U64_AT_B => SamOp::Simple(SamSOp::AddU64AtB8ToU64AtB),
+        OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B => SamOp::Simple(SamSOp::SubU64AtB8FromU64AtB),
+        OPCODE_MUL_U64_AT_B_BY_U64_AT_B8 => SamOp::Simple(SamSOp::MulU64AtBByU64AtB8),
+        OPCODE_PRINT_U64_AT_B => SamOp::Simple(SamSOp::PrintU64AtB),
+        OPCODE_PRINT_X_DECIMAL => SamOp::Simple(SamSOp::PrintXDecimal),
         _ => panic!("decoding invalid sam op!"),
     }
 }
 
+/// Like `decode_sam_op`, but returns None for an unknown opcode or missing operand bytes
+pub fn try_decode_sam_op(slice: &[u8]) -> Option<SamOp> {
+    if *slice.first()? >= NUM_OPCODES {
+        return None;
+    }
+    // decode from a padded copy, then check the op fits in what we actually have
+    let mut padded = [0; 5];
+    let n = slice.len().min(padded.len());
+    padded[..n].copy_from_slice(&slice[..n]);
+    let op = decode_sam_op(&padded);
+    if op.len() > slice.len() {
+        None
+    } else {
+        Some(op)
+    }
+}
+
+/// Decodes a flat sequence of instructions, pairing each op with the address it starts at
+pub fn decode_sam_ops(bytes: &[u8]) -> Vec<(SamVal, SamOp)> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let op = decode_sam_op(&bytes[pos..]);
+        let len = op.len();
+        result.push((pos as SamVal, op));
+        pos += len;
+    }
+    result
+}
+
+fn opcode_mnemonic(opcode: u8) -> &'static str {
+    match opcode {
+        OPCODE_HALT => "HALT",
+        OPCODE_SET_X => "SET_X",
+        OPCODE_SET_A => "SET_A",
+        OPCODE_READ_A_AT_B => "READ_A_AT_B",
+        OPCODE_READ_X_AT_B => "READ_X_AT_B",
+        OPCODE_WRITE_A_AT_B => "WRITE_A_AT_B",
+        OPCODE_WRITE_X_AT_B => "WRITE_X_AT_B",
+        OPCODE_PRINT_CHAR_X => "PRINT_CHAR_X",
+        OPCODE_STDIN_X => "STDIN_X",
+        OPCODE_ADD_CONST_TO_B => "ADD_CONST_TO_B",
+        OPCODE_SUB_CONST_FROM_B => "SUB_CONST_FROM_B",
+        OPCODE_PRINT_A => "PRINT_A",
+        OPCODE_CALL => "CALL",
+        OPCODE_RET => "RET",
+        OPCODE_JUMP => "JUMP",
+        OPCODE_JUMP_IF_X => "JUMP_IF_X",
+        OPCODE_ADD_U8_AT_B_TO_X => "ADD_U8_AT_B_TO_X",
+        OPCODE_MUL_U8_AT_B_TO_X => "MUL_U8_AT_B_TO_X",
+        OPCODE_ADD_U32_AT_B_TO_A => "ADD_U32_AT_B_TO_A",
+        OPCODE_MUL_U32_AT_B_TO_A => "MUL_U32_AT_B_TO_A",
+        OPCODE_NEG_A => "NEG_A",
+        OPCODE_NEG_X => "NEG_X",
+        OPCODE_MOVE_X_TO_A => "MOVE_X_TO_A",
+        OPCODE_NOT_X => "NOT_X",
+        OPCODE_ADD_CONST_TO_X => "ADD_CONST_TO_X",
+        OPCODE_CMP_U8_AT_B_WITH_X => "CMP_U8_AT_B_WITH_X",
+        OPCODE_CMP_U32_AT_B_WITH_A => "CMP_U32_AT_B_WITH_A",
+        OPCODE_SET_X_TO_U8_AT_B_DIV_BY_X => "SET_X_TO_U8_AT_B_DIV_BY_X",
+        OPCODE_SET_A_TO_U32_AT_B_DIV_BY_A => "SET_A_TO_U32_AT_B_DIV_BY_A",
+        OPCODE_SET_X_TO_U8_AT_B_MOD_X => "SET_X_TO_U8_AT_B_MOD_X",
+        OPCODE_SET_A_TO_U32_AT_B_MOD_A => "SET_A_TO_U32_AT_B_MOD_A",
+        OPCODE_COPY_A_TO_B => "COPY_A_TO_B",
+        OPCODE_COPY_B_TO_A => "COPY_B_TO_A",
+        OPCODE_SWAP_B_AND_C => "SWAP_B_AND_C",
+        OPCODE_READ_A16_AT_B => "READ_A16_AT_B",
+        OPCODE_WRITE_A16_AT_B => "WRITE_A16_AT_B",
+        OPCODE_CMP_I32_AT_B_WITH_A => "CMP_I32_AT_B_WITH_A",
+        OPCODE_PRINT_SIGNED_A => "PRINT_SIGNED_A",
+        OPCODE_SET_A_TO_U32_AT_B_AND_A => "SET_A_TO_U32_AT_B_AND_A",
+        OPCODE_SET_A_TO_U32_AT_B_OR_A => "SET_A_TO_U32_AT_B_OR_A",
+        OPCODE_SET_A_TO_U32_AT_B_XOR_A => "SET_A_TO_U32_AT_B_XOR_A",
+        OPCODE_SET_A_TO_U32_AT_B_SHL_A => "SET_A_TO_U32_AT_B_SHL_A",
+        OPCODE_SET_A_TO_U32_AT_B_SHR_A => "SET_A_TO_U32_AT_B_SHR_A",
+        OPCODE_SHL_A_BY_CONST => "SHL_A_BY_CONST",
+        OPCODE_SHR_A_BY_CONST => "SHR_A_BY_CONST",
+        OPCODE_CRASH => "CRASH",
+        OPCODE_JUMP_IF_X_LT => "JUMP_IF_X_LT",
+        OPCODE_JUMP_IF_X_EQ => "JUMP_IF_X_EQ",
+        OPCODE_JUMP_IF_X_GT => "JUMP_IF_X_GT",
+        OPCODE_ADD_U64_AT_B8_TO_U64_AT_B => "ADD_U64_AT_B8_TO_U64_AT_B",
+        OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B => "SUB_U64_AT_B8_FROM_U64_AT_B",
+        OPCODE_MUL_U64_AT_B_BY_U64_AT_B8 => "MUL_U64_AT_B_BY_U64_AT_B8",
+        OPCODE_PRINT_U64_AT_B => "PRINT_U64_AT_B",
+        OPCODE_PRINT_X_DECIMAL => "PRINT_X_DECIMAL",
+        _ => panic!("no mnemonic for invalid sam opcode {}", opcode),
+    }
+}
+
+/// Renders the program's bytes as one `addr: MNEMONIC operands` line per instruction,
+/// including the `HALT` that `SamState::new` appends for `main` to return into.
+pub fn disassemble_sam(prog: &CompiledSamProgram) -> String {
+    let mut fn_names = BTreeMap::new();
+    for (name, pos) in &prog.fn_start_poss {
+        fn_names.insert(*pos, name.as_str());
+    }
+
+    let mut result = String::new();
+    for (pos, op) in decode_sam_ops(&prog.bytes) {
+        if let Some(name) = fn_names.get(&pos) {
+            result += &format!("{}:\n", name);
+        }
+        let mnemonic = opcode_mnemonic(prog.bytes[pos as usize]);
+        let operands = match op {
+            SamOp::Simple(SamSOp::SetX(x)) => format!(" {}", x),
+            SamOp::Simple(SamSOp::SetA(x)) => format!(" {}", x),
+            SamOp::Simple(SamSOp::AddConstToB(c)) => format!(" {}", c),
+            SamOp::Simple(SamSOp::SubConstFromB(c)) => format!(" {}", c),
+            SamOp::Simple(SamSOp::AddConstToX(c)) => format!(" {}", c),
+            SamOp::Simple(SamSOp::ShlAByConst(c)) => format!(" {}", c),
+            SamOp::Simple(SamSOp::ShrAByConst(c)) => format!(" {}", c),
+            SamOp::Simple(_) => String::new(),
+            SamOp::Call(addr) => match fn_names.get(&addr) {
+                Some(name) => format!(" {} ({})", addr, name),
+                None => format!(" {}", addr),
+            },
+            SamOp::Jmp(offset) | SamOp::JmpIfX(offset) | SamOp::JmpIfCmp(_, offset) => {
+                format!(" {:+} (-> {})", offset, pos as SamIVal + offset)
+            }
+        };
+        result += &format!("{:6}: {}{}\n", pos, mnemonic, operands);
+    }
+    result += &format!("{:6}: {}\n", prog.bytes.len(), opcode_mnemonic(OPCODE_HALT));
+    result
+}
+
 #[derive(Debug)]
 pub struct SamState {
     pub cells: Vec<u8>,
@@ -302,24 +575,42 @@ pub struct SamState {
     pub b: SamVal,
     pub c: SamVal,
     pub x: u8,
+    instrs_executed: u64,
+    /// See `CompiledSamProgram::stack_end`
+    stack_end: SamVal,
 }
 
 #[derive(Debug)]
 pub enum SamRunOpError {
     Halted,
+    Crashed,
     ReaderErr(std::io::Error),
     WriterErr(std::io::Error),
 }
 
+/// One step of a traced run: the op that was executed, the registers before it, and its output
+#[derive(Debug)]
+pub struct SamTrace {
+    pub op: SamOp,
+    pub x: u8,
+    pub a: SamVal,
+    pub b: SamVal,
+    pub instr_ptr: SamVal,
+    pub output: Vec<u8>,
+    pub result: Result<(), SamRunOpError>,
+}
+
 impl SamState {
     pub fn new(prog: CompiledSamProgram) -> SamState {
         let instr_ptr = *prog
             .fn_start_poss
             .get("main")
             .expect("no main function found");
+        let stack_end = prog.stack_end();
         let mut cells = prog.bytes;
         let hlt = cells.len() as SamVal;
         cells.extend(&[OPCODE_HALT]);
+        cells.extend(prog.data);
         let b = cells.len() as SamVal;
         push_u32_to_vec(&mut cells, hlt);
         SamState {
@@ -330,6 +621,8 @@ impl SamState {
             b,
             c: 0,
             x: 0,
+            instrs_executed: 0,
+            stack_end,
         }
     }
 
@@ -344,6 +637,17 @@ impl SamState {
         decode_u32(&self.cells[at as usize..])
     }
 
+    pub fn read_u64_at(&mut self, at: SamVal) -> u64 {
+        let hi = self.read_u32_at(at) as u64;
+        let lo = self.read_u32_at(at + 4) as u64;
+        (hi << 32) | lo
+    }
+
+    pub fn read_u16_at(&mut self, at: SamVal) -> u16 {
+        self.reserve_cells(at + 2);
+        u16::from_be_bytes([self.cells[at as usize], self.cells[at as usize + 1]])
+    }
+
     pub fn read_u8_at(&mut self, at: SamVal) -> u8 {
         self.reserve_cells(at + 1);
         self.cells[at as usize]
@@ -354,11 +658,28 @@ impl SamState {
         write_u32(&mut self.cells[at as usize..], val);
     }
 
+    pub fn write_u64_at(&mut self, val: u64, at: SamVal) {
+        self.write_u32_at((val >> 32) as u32, at);
+        self.write_u32_at(val as u32, at + 4);
+    }
+
+    pub fn write_u16_at(&mut self, val: u16, at: SamVal) {
+        self.reserve_cells(at + 2);
+        let [val0, val1] = val.to_be_bytes();
+        self.cells[at as usize] = val0;
+        self.cells[at as usize + 1] = val1;
+    }
+
     pub fn write_u8_at(&mut self, val: u8, at: SamVal) {
         self.reserve_cells(at + 1);
         self.cells[at as usize] = val;
     }
 
+    /// Number of instructions run so far.
+    pub fn instrs_executed(&self) -> u64 {
+        self.instrs_executed
+    }
+
     pub fn decode_next_op(&mut self) -> SamOp {
         self.reserve_cells(self.instr_ptr + 5);
         decode_sam_op(&self.cells[self.instr_ptr as usize..])
@@ -370,8 +691,24 @@ impl SamState {
         writer: &mut impl Write,
     ) -> Result<(), SamRunOpError> {
         let op = self.decode_next_op();
-        let res = self.run_op(&op, reader, writer)?;
-        Ok(res)
+        self.run_op(&op, reader, writer)?;
+        Ok(())
+    }
+
+    pub fn trace_step(&mut self, reader: &mut impl Read) -> SamTrace {
+        let op = self.decode_next_op();
+        let (x, a, b, instr_ptr) = (self.x, self.a, self.b, self.instr_ptr);
+        let mut output = Vec::new();
+        let result = self.run_op(&op, reader, &mut output);
+        SamTrace {
+            op,
+            x,
+            a,
+            b,
+            instr_ptr,
+            output,
+            result,
+        }
     }
 
     pub fn run(
@@ -394,6 +731,7 @@ impl SamState {
         if self.halted {
             return Err(SamRunOpError::Halted);
         }
+        self.instrs_executed += 1;
         match op {
             SamOp::Simple(op) => {
                 let mut jumped = false;
@@ -508,7 +846,7 @@ impl SamState {
                         }
                     }
                     SamSOp::AddConstToX(val) => {
-                        self.x += *val;
+                        self.x = self.x.wrapping_add(*val);
                     }
                     SamSOp::CmpU8AtBWithX => {
                         let atb = self.read_u8_at(self.b);
@@ -551,12 +889,121 @@ impl SamState {
                     SamSOp::SwapBAndC => {
                         std::mem::swap(&mut self.b, &mut self.c);
                     }
+                    SamSOp::ReadA16AtB => {
+                        self.a = self.read_u16_at(self.b) as u32;
+                    }
+                    SamSOp::WriteA16AtB => {
+                        self.write_u16_at(self.a as u16, self.b);
+                    }
+                    SamSOp::CmpI32AtBWithA => {
+                        let atb = self.read_u32_at(self.b) as i32;
+                        self.x = match atb.cmp(&(self.a as i32)) {
+                            std::cmp::Ordering::Greater => 1,
+                            std::cmp::Ordering::Equal => 0,
+                            std::cmp::Ordering::Less => 255,
+                        }
+                    }
+                    SamSOp::PrintSignedA => {
+                        match write!(writer, "{}", self.a as i32) {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                        match writer.flush() {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                    }
+                    SamSOp::SetAToU32AtBAndA => {
+                        let atb = self.read_u32_at(self.b);
+                        self.a &= atb;
+                    }
+                    SamSOp::SetAToU32AtBOrA => {
+                        let atb = self.read_u32_at(self.b);
+                        self.a |= atb;
+                    }
+                    SamSOp::SetAToU32AtBXorA => {
+                        let atb = self.read_u32_at(self.b);
+                        self.a ^= atb;
+                    }
+                    SamSOp::SetAToU32AtBShlA => {
+                        let atb = self.read_u32_at(self.b);
+                        self.a = atb.checked_shl(self.a).unwrap_or(0);
+                    }
+                    SamSOp::SetAToU32AtBShrA => {
+                        let atb = self.read_u32_at(self.b);
+                        self.a = atb.checked_shr(self.a).unwrap_or(0);
+                    }
+                    SamSOp::ShlAByConst(val) => {
+                        self.a = self.a.checked_shl(*val as u32).unwrap_or(0);
+                    }
+                    SamSOp::ShrAByConst(val) => {
+                        self.a = self.a.checked_shr(*val as u32).unwrap_or(0);
+                    }
+                    SamSOp::Crash => {
+                        self.halted = true;
+                        return Err(SamRunOpError::Crashed);
+                    }
+                    SamSOp::AddU64AtB8ToU64AtB => {
+                        let res = self
+                            .read_u64_at(self.b)
+                            .wrapping_add(self.read_u64_at(self.b + 8));
+                        self.write_u64_at(res, self.b);
+                    }
+                    SamSOp::SubU64AtB8FromU64AtB => {
+                        let res = self
+                            .read_u64_at(self.b)
+                            .wrapping_sub(self.read_u64_at(self.b + 8));
+                        self.write_u64_at(res, self.b);
+                    }
+                    SamSOp::MulU64AtBByU64AtB8 => {
+                        let res = self
+                            .read_u64_at(self.b)
+                            .wrapping_mul(self.read_u64_at(self.b + 8));
+                        self.write_u64_at(res, self.b);
+                    }
+                    SamSOp::PrintU64AtB => {
+                        let val = self.read_u64_at(self.b);
+                        match write!(writer, "{}", val) {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                        match writer.flush() {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                    }
+                    SamSOp::PrintXDecimal => {
+                        match write!(writer, "{}", self.x) {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                        match writer.flush() {
+                            Ok(()) => {}
+                            Err(e) => {
+                                return Err(SamRunOpError::WriterErr(e));
+                            }
+                        }
+                    }
                 }
                 if !jumped {
                     self.instr_ptr += op.len() as SamVal;
                 }
             }
             SamOp::Call(f) => {
+                if self.b > self.stack_end {
+                    self.halted = true;
+                    return Err(SamRunOpError::Crashed);
+                }
                 self.write_u32_at(self.instr_ptr + 5, self.b);
                 self.instr_ptr = *f;
             }
@@ -578,6 +1025,17 @@ impl SamState {
                     self.instr_ptr += op.len() as SamVal;
                 }
             }
+            SamOp::JmpIfCmp(cmp_result, offset) => {
+                if self.x == cmp_result.x_value() {
+                    let new_instr_ptr = self.instr_ptr as SamIVal + *offset;
+                    if new_instr_ptr < 0 {
+                        panic!("Jumped left of tape!");
+                    }
+                    self.instr_ptr = new_instr_ptr as SamVal;
+                } else {
+                    self.instr_ptr += op.len() as SamVal;
+                }
+            }
         }
         Ok(())
     }
@@ -5,154 +5,172 @@ use num::BigUint;
 use std::collections::{HashMap, HashSet};
 use std::result;
 
+/// log2 of the largest step taken by `far_ptr_shifts`
+const FAR_PTR_SHIFT_LOG2: isize = 12;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sam2LirOptions {
+    /// Move the pointer in steps of 4096, 2048, 1024 and 512 frames before the steps of 256.
+    /// This makes far jumps, like those into the heap, much faster, but makes the generated
+    /// code bigger. On by default.
+    pub far_ptr_shifts: bool,
+    /// Crash when a call is made while the stack already takes up more than this many bytes.
+    /// Calls that could make the stack run into the heap always crash.
+    pub max_stack_size: Option<u32>,
+}
+
+impl Default for Sam2LirOptions {
+    fn default() -> Sam2LirOptions {
+        Sam2LirOptions {
+            far_ptr_shifts: true,
+            max_stack_size: None,
+        }
+    }
+}
+
 pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
+    sam2lir_with_options(prog, Sam2LirOptions::default())
+}
+
+pub fn sam2lir_with_options(
+    prog: CompiledSamProgram,
+    options: Sam2LirOptions,
+) -> (Vec<Lir>, CpuConfig) {
+    /// Moves the frame (together with the whole scratch track) by shift_by frames.
+    fn shift_frame_with_scratch(cpu: &mut Cpu, scratch_track: ScratchTrack, shift_by: isize) {
+        let scratch_track_size = scratch_track.offset + scratch_track.dont_go_left_of.unwrap_or(0);
+        let dir = shift_by.signum();
+        if shift_by.abs() >= 2 {
+            let counter = Pos {
+                track: scratch_track.track.track_num,
+                frame: if dir < 0 { -1 } else { scratch_track_size },
+            };
+            cpu.add_const_to_byte(counter, scratch_track_size as u8);
+            cpu.loop_while(counter, |cpu| {
+                cpu.dec();
+                cpu.moveadd_byte(
+                    counter.get_shifted(-dir),
+                    counter.get_shifted(-dir + shift_by),
+                );
+                cpu.moveadd_byte(counter, counter.get_shifted(-dir));
+                cpu.goto(counter.get_shifted(-dir));
+                cpu.now_were_actually_at(counter);
+            });
+            cpu.shift_frame_untracked(dir * scratch_track_size, false);
+        } else {
+            let mut frames = (0..scratch_track_size).collect::<Vec<_>>();
+            if dir > 0 {
+                frames.reverse();
+            }
+            for i in frames {
+                cpu.moveadd_byte(
+                    Pos {
+                        track: scratch_track.track.track_num,
+                        frame: i,
+                    },
+                    Pos {
+                        track: scratch_track.track.track_num,
+                        frame: i + shift_by,
+                    },
+                );
+            }
+        }
+        cpu.shift_frame_untracked(shift_by, false);
+    }
+
+    /// Moves the frame until cur_ptr equals ptr. Steps of 256 frames bring the bytes above
+    /// the lowest in line, then `goto_ptr_binregister` takes care of the lowest byte.
+    /// With `far_ptr_shifts`, the steps of 256 are preceded by steps of 4096, 2048, 1024 and 512.
     fn goto_ptr_register(
         cpu: &mut Cpu,
         scratch_track: ScratchTrack,
         ptr: Register,
         cur_ptr: Register,
+        options: Sam2LirOptions,
     ) {
         let ([keep_going, cmp_result], scratch_track) = scratch_track.split_2();
-
-        let mut ptr = ptr;
-        let mut cur_ptr = cur_ptr;
-
-        // elements are (log256, log2)
-        //let shift_logs = [(1, 0), (0, 4), (0, 3), (0, 2), (0, 0)];
-        let shift_logs = [(1, 0)];
-
-        for (shift_by_log256, shift_by_log2) in shift_logs {
-            let shift_by = 1 << (shift_by_log256 * 8 + shift_by_log2);
+        let high_size = ptr.size - 1;
+        let high_ptr = ptr.subview(0, high_size);
+        let high_cur_ptr = cur_ptr.subview(0, high_size);
+        let max_shift_by_log2 = if options.far_ptr_shifts {
+            FAR_PTR_SHIFT_LOG2 - 8
+        } else {
+            0
+        };
+
+        // each step moves by 256 << shift_by_log2 frames. Only the first one can take more
+        // than one step, after it the shifted pointers differ by at most 1.
+        for shift_by_log2 in (0..=max_shift_by_log2).rev() {
+            let shift_by = 256 << shift_by_log2;
+            let steps = BigUint::from(1u32 << shift_by_log2);
             cpu.comment(format!("shift_by_{}", shift_by));
             cpu.inc_at(keep_going);
             cpu.loop_while(keep_going, |cpu| {
-                if shift_by_log2 != 0 {
-                    assert!(ptr.size == 1);
-                    assert!(cur_ptr.size == 1);
-                    let ([a, b, rem], scratch_track) = scratch_track.split_3();
-                    cpu.div_u8_by_const(ptr.at(0), 1 << shift_by_log2, a, rem, scratch_track);
-                    cpu.div_u8_by_const(cur_ptr.at(0), 1 << shift_by_log2, b, rem, scratch_track);
-                    cpu.cmp_2_u8s(a, b, cmp_result, scratch_track);
-                    cpu.clr_at(a);
-                    cpu.clr_at(b);
-                    cpu.clr_at(rem);
+                if shift_by_log2 == 0 {
+                    cpu.cmp_2_uint_registers(high_ptr, high_cur_ptr, cmp_result, scratch_track);
                 } else {
+                    // dividing just the lowest of the high bytes by 2^shift_by_log2 compares
+                    // the same as shifting the whole pointers right by 8 + shift_by_log2
+                    let (ptr_shifted, scratch_track) = scratch_track.split_register(high_size);
+                    let (cur_ptr_shifted, scratch_track) = scratch_track.split_register(high_size);
+                    let (rem, scratch_track) = scratch_track.split_1();
+                    for (from, to) in [(high_ptr, ptr_shifted), (high_cur_ptr, cur_ptr_shifted)] {
+                        cpu.copy_register(
+                            from.subview(0, high_size - 1),
+                            to.subview(0, high_size - 1),
+                            scratch_track,
+                            false,
+                        );
+                        cpu.div_u8_by_const(
+                            from.last_pos(),
+                            1 << shift_by_log2,
+                            to.last_pos(),
+                            rem,
+                            scratch_track,
+                        );
+                        cpu.clr_at(rem);
+                    }
                     cpu.cmp_2_uint_registers(
-                        ptr.subview(0, ptr.size - shift_by_log256),
-                        cur_ptr.subview(0, cur_ptr.size - shift_by_log256),
+                        ptr_shifted,
+                        cur_ptr_shifted,
                         cmp_result,
                         scratch_track,
                     );
+                    cpu.clr_register(ptr_shifted, scratch_track);
+                    cpu.clr_register(cur_ptr_shifted, scratch_track);
                 }
 
                 cpu.move_match_cmp_result(
                     cmp_result,
                     scratch_track,
                     |cpu, scratch_track| {
-                        if shift_by_log2 != 0 {
-                            assert!(cur_ptr.size == 1);
-                            cpu.sub_const_from_byte(cur_ptr.at(0), 1 << shift_by_log2);
+                        if shift_by_log2 == 0 {
+                            cpu.dec_register(high_cur_ptr, scratch_track);
                         } else {
-                            cpu.dec_register(
-                                cur_ptr.subview(0, cur_ptr.size - shift_by_log256),
-                                scratch_track,
-                            );
+                            // subtracting wraps around like adding 256^high_size - steps
+                            let wrap = BigUint::from(256u32).pow(high_size as u32);
+                            cpu.add_const_to_register(high_cur_ptr, wrap - &steps, scratch_track);
                         }
-                        let scratch_track_size =
-                            scratch_track.offset + scratch_track.dont_go_left_of.unwrap_or(0);
-                        if shift_by >= 2 {
-                            let counter = Pos {
-                                track: scratch_track.track.track_num,
-                                frame: -1,
-                            };
-                            cpu.add_const_to_byte(counter, scratch_track_size as u8);
-                            cpu.loop_while(counter, |cpu| {
-                                cpu.dec();
-                                cpu.moveadd_byte(
-                                    counter.get_shifted(1),
-                                    counter.get_shifted(1 - shift_by),
-                                );
-                                cpu.moveadd_byte(counter, counter.get_shifted(1));
-                                cpu.goto(counter.get_shifted(1));
-                                cpu.now_were_actually_at(counter);
-                            });
-                            cpu.shift_frame_untracked(-scratch_track_size, false);
-                        } else {
-                            for i in 0..scratch_track_size {
-                                cpu.moveadd_byte(
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i,
-                                    },
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i - shift_by,
-                                    },
-                                );
-                            }
-                        }
-                        cpu.shift_frame_untracked(-shift_by, false);
+                        shift_frame_with_scratch(cpu, scratch_track, -shift_by);
                     },
                     |cpu, _| {
                         cpu.dec_at(keep_going);
                     },
                     |cpu, scratch_track| {
-                        if shift_by_log2 != 0 {
-                            assert!(cur_ptr.size == 1);
-                            cpu.add_const_to_byte(cur_ptr.at(0), 1 << shift_by_log2);
+                        if shift_by_log2 == 0 {
+                            cpu.inc_register(high_cur_ptr, scratch_track);
                         } else {
-                            cpu.inc_register(
-                                cur_ptr.subview(0, cur_ptr.size - shift_by_log256),
-                                scratch_track,
-                            );
+                            cpu.add_const_to_register(high_cur_ptr, steps.clone(), scratch_track);
                         }
-                        let scratch_track_size =
-                            scratch_track.offset + scratch_track.dont_go_left_of.unwrap_or(0);
-                        if shift_by >= 2 {
-                            let counter = Pos {
-                                track: scratch_track.track.track_num,
-                                frame: scratch_track_size,
-                            };
-                            cpu.add_const_to_byte(counter, scratch_track_size as u8);
-                            cpu.loop_while(counter, |cpu| {
-                                cpu.dec();
-                                cpu.moveadd_byte(
-                                    counter.get_shifted(-1),
-                                    counter.get_shifted(-1 + shift_by),
-                                );
-                                cpu.moveadd_byte(counter, counter.get_shifted(-1));
-                                cpu.goto(counter.get_shifted(-1));
-                                cpu.now_were_actually_at(counter);
-                            });
-                            cpu.shift_frame_untracked(scratch_track_size, false);
-                        } else {
-                            for i in (0..scratch_track_size).rev() {
-                                cpu.moveadd_byte(
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i,
-                                    },
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i + shift_by,
-                                    },
-                                );
-                            }
-                        }
-                        cpu.shift_frame_untracked(shift_by, false);
+                        shift_frame_with_scratch(cpu, scratch_track, shift_by);
                     },
                 );
             });
             cpu.clr_at(cmp_result);
-
-            if shift_by_log256 > 0 {
-                ptr = ptr.subview(ptr.size - shift_by_log256, shift_by_log256);
-                cur_ptr = cur_ptr.subview(cur_ptr.size - shift_by_log256, shift_by_log256);
-            }
         }
 
-        assert_eq!(ptr.size, 1);
-        assert_eq!(cur_ptr.size, 1);
+        let ptr = ptr.subview(high_size, 1);
+        let cur_ptr = cur_ptr.subview(high_size, 1);
         let (ptr_unpacked, scratch_track) = scratch_track.split_binregister(8);
         cpu.unpack_register(ptr, ptr_unpacked, scratch_track, false);
         let (cur_ptr_unpacked, scratch_track) = scratch_track.split_binregister(8);
@@ -196,40 +214,7 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
                             cur_ptr.subview(0, cur_ptr.size - shift_by_log2),
                             scratch_track,
                         );
-                        let scratch_track_size =
-                            scratch_track.offset + scratch_track.dont_go_left_of.unwrap_or(0);
-                        if shift_by >= 2 {
-                            let counter = Pos {
-                                track: scratch_track.track.track_num,
-                                frame: -1,
-                            };
-                            cpu.add_const_to_byte(counter, scratch_track_size as u8);
-                            cpu.loop_while(counter, |cpu| {
-                                cpu.dec();
-                                cpu.moveadd_byte(
-                                    counter.get_shifted(1),
-                                    counter.get_shifted(1 - shift_by),
-                                );
-                                cpu.moveadd_byte(counter, counter.get_shifted(1));
-                                cpu.goto(counter.get_shifted(1));
-                                cpu.now_were_actually_at(counter);
-                            });
-                            cpu.shift_frame_untracked(-scratch_track_size, false);
-                        } else {
-                            for i in 0..scratch_track_size {
-                                cpu.moveadd_byte(
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i,
-                                    },
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i - shift_by,
-                                    },
-                                );
-                            }
-                        }
-                        cpu.shift_frame_untracked(-shift_by, false);
+                        shift_frame_with_scratch(cpu, scratch_track, -shift_by);
                     },
                     |cpu, _| {
                         cpu.dec_at(keep_going);
@@ -239,40 +224,7 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
                             cur_ptr.subview(0, cur_ptr.size - shift_by_log2),
                             scratch_track,
                         );
-                        let scratch_track_size =
-                            scratch_track.offset + scratch_track.dont_go_left_of.unwrap_or(0);
-                        if shift_by >= 2 {
-                            let counter = Pos {
-                                track: scratch_track.track.track_num,
-                                frame: scratch_track_size,
-                            };
-                            cpu.add_const_to_byte(counter, scratch_track_size as u8);
-                            cpu.loop_while(counter, |cpu| {
-                                cpu.dec();
-                                cpu.moveadd_byte(
-                                    counter.get_shifted(-1),
-                                    counter.get_shifted(-1 + shift_by),
-                                );
-                                cpu.moveadd_byte(counter, counter.get_shifted(-1));
-                                cpu.goto(counter.get_shifted(-1));
-                                cpu.now_were_actually_at(counter);
-                            });
-                            cpu.shift_frame_untracked(scratch_track_size, false);
-                        } else {
-                            for i in (0..scratch_track_size).rev() {
-                                cpu.moveadd_byte(
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i,
-                                    },
-                                    Pos {
-                                        track: scratch_track.track.track_num,
-                                        frame: i + shift_by,
-                                    },
-                                );
-                            }
-                        }
-                        cpu.shift_frame_untracked(shift_by, false);
+                        shift_frame_with_scratch(cpu, scratch_track, shift_by);
                     },
                 );
             });
@@ -289,9 +241,11 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
         .fn_start_poss
         .get("main")
         .expect("no main function found");
+    let stack_end = prog.stack_end();
     let mut cells = prog.bytes;
     let hlt = cells.len() as SamVal;
     cells.extend(&[OPCODE_HALT]);
+    cells.extend(prog.data);
     let initial_b = cells.len() as SamVal;
     push_u32_to_vec(&mut cells, hlt);
 
@@ -312,17 +266,12 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
     let (cur_ptr, scratch_track) = scratch_track.split_register(4);
     let data_track = cfg.add_data_track(TrackId::Stack);
 
-    /*match cfg.tracks.get_mut(&TrackId::Scratch1).unwrap() {
-        TrackKind::MultipleRegisters(_, ref mut register_map, ref mut _binregister_map) => {
-            register_map.insert("a".to_owned(), a);
-            register_map.insert("b".to_owned(), b);
-            register_map.insert("c".to_owned(), c);
-            register_map.insert("x".to_owned(), x);
-            register_map.insert("iptr".to_owned(), iptr);
-            register_map.insert("cur_ptr".to_owned(), cur_ptr);
-        }
-        _ => unreachable!(),
-    }*/
+    cfg.name_register(TrackId::Scratch1, "a", a);
+    cfg.name_register(TrackId::Scratch1, "b", b);
+    cfg.name_register(TrackId::Scratch1, "c", c);
+    cfg.name_register(TrackId::Scratch1, "x", x);
+    cfg.name_register(TrackId::Scratch1, "iptr", iptr);
+    cfg.name_register(TrackId::Scratch1, "cur_ptr", cur_ptr);
 
     let print_debug_messages = false;
     let print_comments = true;
@@ -337,13 +286,25 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
 
     cpu.shift_frame_untracked(5, false);
 
+    let mut source_lines = prog.source_lines.iter().peekable();
     for (i, val) in cells.into_iter().enumerate() {
+        while let Some((_, line)) = source_lines.next_if(|(pos, _)| *pos as usize == i) {
+            cpu.comment(format!("line {}", line));
+        }
         cpu.add_const_to_byte(data_track.at(i as isize), val);
     }
 
     cpu.set_register(iptr, initial_instr_ptr);
     cpu.set_register(b, initial_b);
 
+    let max_b_at_call = match options.max_stack_size {
+        Some(max_stack_size) => stack_end.min(initial_b.saturating_add(max_stack_size)),
+        None => stack_end,
+    };
+    if initial_b > stack_end {
+        cpu.crash("Program too big, its stack would overlap the heap");
+    }
+
     let (not_halted, scratch_track) = scratch_track.split_1();
     cpu.inc_at(not_halted);
 
@@ -366,11 +327,23 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
     should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_DIV_BY_A);
     should_goto_b_instr_set.insert(OPCODE_SET_X_TO_U8_AT_B_MOD_X);
     should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_MOD_A);
+    should_goto_b_instr_set.insert(OPCODE_READ_A16_AT_B);
+    should_goto_b_instr_set.insert(OPCODE_WRITE_A16_AT_B);
+    should_goto_b_instr_set.insert(OPCODE_CMP_I32_AT_B_WITH_A);
+    should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_AND_A);
+    should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_OR_A);
+    should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_XOR_A);
+    should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_SHL_A);
+    should_goto_b_instr_set.insert(OPCODE_SET_A_TO_U32_AT_B_SHR_A);
+    should_goto_b_instr_set.insert(OPCODE_ADD_U64_AT_B8_TO_U64_AT_B);
+    should_goto_b_instr_set.insert(OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B);
+    should_goto_b_instr_set.insert(OPCODE_MUL_U64_AT_B_BY_U64_AT_B8);
+    should_goto_b_instr_set.insert(OPCODE_PRINT_U64_AT_B);
 
     cpu.comment("Main loop");
 
     cpu.loop_while(not_halted, |cpu| {
-        goto_ptr_register(cpu, scratch_track, iptr, cur_ptr);
+        goto_ptr_register(cpu, scratch_track, iptr, cur_ptr, options);
         let (should_goto_b, scratch_track) = scratch_track.split_1();
         {
             let (deccing_instr_cpy, scratch_track) = scratch_track.split_1();
@@ -429,11 +402,14 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
         cpu.comment("Go to b (if needed)");
         cpu.if_nonzero(should_goto_b, scratch_track, |cpu, scratch_track| {
             cpu.dec_at(should_goto_b);
-            goto_ptr_register(cpu, scratch_track, b, cur_ptr);
+            goto_ptr_register(cpu, scratch_track, b, cur_ptr, options);
         });
 
         let atb_1 = data_track.view_register_at(0, 1);
+        let atb_2 = data_track.view_register_at(0, 2);
         let atb_4 = data_track.view_register_at(0, 4);
+        let atb_8 = data_track.view_register_at(0, 8);
+        let atb8_8 = data_track.view_register_at(8, 8);
 
         let (inc_iptr_by, scratch_track) = scratch_track.split_1();
 
@@ -647,12 +623,24 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
                 cpu.comment("Call");
             }
 
+            {
+                let (cmp_result, scratch_track) = scratch_track.split_1();
+                let (limit, scratch_track) = scratch_track.split_register(4);
+                cpu.set_register(limit, max_b_at_call);
+                cpu.cmp_2_uint_registers(b, limit, cmp_result, scratch_track);
+                cpu.clr_register(limit, scratch_track);
+                cpu.dec_at(cmp_result);
+                cpu.if_zero(cmp_result, scratch_track, |cpu, _| {
+                    cpu.crash("Stack overflow");
+                });
+                cpu.clr_at(cmp_result);
+            }
+
             // inc instr_ptr by 5
             {
                 let (counter, scratch_track) = scratch_track.split_1();
                 cpu.add_const_to_byte(counter, 5);
-                cpu.loop_while(counter, |cpu| {
-                    cpu.dec();
+                cpu.repeat(counter, |cpu| {
                     cpu.inc_register(iptr, scratch_track);
                 });
             }
@@ -1088,11 +1076,430 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
             }
             cpu.add_const_to_byte(inc_iptr_by, 1);
 
-            let (c_cpy, scratch_track) = scratch_track.split_register(c.size);
-            cpu.copy_register(c, c_cpy, scratch_track, false);
-            cpu.copy_register(b, c, scratch_track, true);
-            cpu.copy_register(c_cpy, b, scratch_track, true);
-            cpu.clr_register(c_cpy, scratch_track);
+            cpu.swap_registers(b, c, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_READ_A16_AT_B);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: ReadA16AtB");
+            }
+            if print_comments {
+                cpu.comment("ReadA16AtB");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            cpu.clr_register(a.subview(0, 2), scratch_track);
+            cpu.copy_register(atb_2, a.subview_tail(2), scratch_track, true);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_WRITE_A16_AT_B);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: WriteA16AtB");
+            }
+            if print_comments {
+                cpu.comment("WriteA16AtB");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            cpu.copy_register(a.subview_tail(2), atb_2, scratch_track, true);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_CMP_I32_AT_B_WITH_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: CmpI32AtBWithA");
+            }
+            if print_comments {
+                cpu.comment("CmpI32AtBWithA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            let (cmp_result, scratch_track) = scratch_track.split_1();
+            cpu.cmp_2_int_binregisters(atb_unpacked, a_unpacked, cmp_result, scratch_track);
+            cpu.clr_at(x.at(0));
+            cpu.moveadd_byte(cmp_result, x.at(0));
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_PRINT_SIGNED_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: PrintSignedA");
+            }
+            if print_comments {
+                cpu.comment("PrintSignedA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+
+            cpu.print_binregister_in_signed_decimal(a_unpacked, scratch_track);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SET_A_TO_U32_AT_B_AND_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SetAToU32AtBAndA");
+            }
+            if print_comments {
+                cpu.comment("SetAToU32AtBAndA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+            cpu.clr_register(a, scratch_track);
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            cpu.and_binregisters(atb_unpacked, a_unpacked, a_unpacked, scratch_track);
+            cpu.pack_binregister(a_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SET_A_TO_U32_AT_B_OR_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SetAToU32AtBOrA");
+            }
+            if print_comments {
+                cpu.comment("SetAToU32AtBOrA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+            cpu.clr_register(a, scratch_track);
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            cpu.or_binregisters(atb_unpacked, a_unpacked, a_unpacked, scratch_track);
+            cpu.pack_binregister(a_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SET_A_TO_U32_AT_B_XOR_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SetAToU32AtBXorA");
+            }
+            if print_comments {
+                cpu.comment("SetAToU32AtBXorA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+            cpu.clr_register(a, scratch_track);
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            cpu.xor_binregisters(atb_unpacked, a_unpacked, a_unpacked, scratch_track);
+            cpu.pack_binregister(a_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SET_A_TO_U32_AT_B_SHL_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SetAToU32AtBShlA");
+            }
+            if print_comments {
+                cpu.comment("SetAToU32AtBShlA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            // shift one bit at a time, by the lowest byte of a
+            let (counter, scratch_track) = scratch_track.split_1();
+            cpu.copy_byte_autoscratch(a.at(3), counter, scratch_track);
+            cpu.loop_while(counter, |cpu| {
+                cpu.dec();
+                cpu.shift_binregister_left(atb_unpacked, scratch_track);
+            });
+            // shifting by 256 or more clears everything
+            for i in 0..3 {
+                cpu.if_nonzero(a.at(i), scratch_track, |cpu, scratch_track| {
+                    cpu.clr_binregister(atb_unpacked, scratch_track);
+                });
+            }
+            cpu.pack_binregister(atb_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SET_A_TO_U32_AT_B_SHR_A);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SetAToU32AtBShrA");
+            }
+            if print_comments {
+                cpu.comment("SetAToU32AtBShrA");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(atb_4, atb_unpacked, scratch_track, false);
+
+            // shift one bit at a time, by the lowest byte of a
+            let (counter, scratch_track) = scratch_track.split_1();
+            cpu.copy_byte_autoscratch(a.at(3), counter, scratch_track);
+            cpu.loop_while(counter, |cpu| {
+                cpu.dec();
+                cpu.shift_binregister_right(atb_unpacked, scratch_track);
+            });
+            // shifting by 256 or more clears everything
+            for i in 0..3 {
+                cpu.if_nonzero(a.at(i), scratch_track, |cpu, scratch_track| {
+                    cpu.clr_binregister(atb_unpacked, scratch_track);
+                });
+            }
+            cpu.pack_binregister(atb_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SHL_A_BY_CONST);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: ShlAByConst");
+            }
+            if print_comments {
+                cpu.comment("ShlAByConst");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 2);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+
+            let (counter, scratch_track) = scratch_track.split_1();
+            cpu.copy_byte_autoscratch(instr_data.at(0), counter, scratch_track);
+            cpu.loop_while(counter, |cpu| {
+                cpu.dec();
+                cpu.shift_binregister_left(a_unpacked, scratch_track);
+            });
+            cpu.pack_binregister(a_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SHR_A_BY_CONST);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: ShrAByConst");
+            }
+            if print_comments {
+                cpu.comment("ShrAByConst");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 2);
+
+            let (a_unpacked, scratch_track) = scratch_track.split_binregister(32);
+            cpu.unpack_register(a, a_unpacked, scratch_track, false);
+
+            let (counter, scratch_track) = scratch_track.split_1();
+            cpu.copy_byte_autoscratch(instr_data.at(0), counter, scratch_track);
+            cpu.loop_while(counter, |cpu| {
+                cpu.dec();
+                cpu.shift_binregister_right(a_unpacked, scratch_track);
+            });
+            cpu.pack_binregister(a_unpacked, a, scratch_track, true);
+
+            cpu.clr_binregister(a_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, _scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_CRASH);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: Crash");
+            }
+            if print_comments {
+                cpu.comment("Crash");
+            }
+            cpu.crash("Assertion failed");
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        for cmp_result in [CmpResult::Lt, CmpResult::Eq, CmpResult::Gt] {
+            cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+                assert_eq!(cur_instr_num, cmp_result.jump_opcode());
+                if print_debug_messages {
+                    cpu.debug_message(format!("Instruction: JumpIfX{:?}", cmp_result));
+                }
+                if print_comments {
+                    cpu.comment(format!("JumpIfX{:?}", cmp_result));
+                }
+
+                // shift X so that it's zero exactly when it holds cmp_result, then shift it back
+                let shift = cmp_result.x_value();
+                cpu.add_const_to_byte(x.at(0), shift.wrapping_neg());
+                cpu.if_nonzero_else(
+                    x.at(0),
+                    scratch_track,
+                    |cpu, _| {
+                        cpu.add_const_to_byte(inc_iptr_by, 5);
+                    },
+                    |cpu, scratch_track| {
+                        cpu.add_register_to_register(instr_data, iptr, scratch_track);
+                    },
+                );
+                cpu.add_const_to_byte(x.at(0), shift);
+            });
+            cur_instr_num += 1;
+            cpu.dec_at(instr_cpy);
+        }
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_ADD_U64_AT_B8_TO_U64_AT_B);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: AddU64AtB8ToU64AtB");
+            }
+            if print_comments {
+                cpu.comment("AddU64AtB8ToU64AtB");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb_8, atb_unpacked, scratch_track, false);
+            let (atb8_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb8_8, atb8_unpacked, scratch_track, false);
+
+            cpu.add_binregister_to_binregister(atb8_unpacked, atb_unpacked, scratch_track);
+            cpu.pack_binregister(atb_unpacked, atb_8, scratch_track, true);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+            cpu.clr_binregister(atb8_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_SUB_U64_AT_B8_FROM_U64_AT_B);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: SubU64AtB8FromU64AtB");
+            }
+            if print_comments {
+                cpu.comment("SubU64AtB8FromU64AtB");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb_8, atb_unpacked, scratch_track, false);
+            let (atb8_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb8_8, atb8_unpacked, scratch_track, false);
+
+            cpu.sub_binregister_from_binregister(atb8_unpacked, atb_unpacked, scratch_track);
+            cpu.pack_binregister(atb_unpacked, atb_8, scratch_track, true);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+            cpu.clr_binregister(atb8_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_MUL_U64_AT_B_BY_U64_AT_B8);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: MulU64AtBByU64AtB8");
+            }
+            if print_comments {
+                cpu.comment("MulU64AtBByU64AtB8");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb_8, atb_unpacked, scratch_track, false);
+            let (atb8_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb8_8, atb8_unpacked, scratch_track, false);
+            let (result_unpacked, scratch_track) = scratch_track.split_binregister(64);
+
+            cpu.mul_binregisters(atb_unpacked, atb8_unpacked, result_unpacked, scratch_track);
+            cpu.pack_binregister(result_unpacked, atb_8, scratch_track, true);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+            cpu.clr_binregister(atb8_unpacked, scratch_track);
+            cpu.clr_binregister(result_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_PRINT_U64_AT_B);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: PrintU64AtB");
+            }
+            if print_comments {
+                cpu.comment("PrintU64AtB");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (atb_unpacked, scratch_track) = scratch_track.split_binregister(64);
+            cpu.unpack_register(atb_8, atb_unpacked, scratch_track, false);
+
+            cpu.print_binregister_in_decimal(atb_unpacked, scratch_track);
+
+            cpu.clr_binregister(atb_unpacked, scratch_track);
+        });
+        cur_instr_num += 1;
+        cpu.dec_at(instr_cpy);
+
+        cpu.if_zero(instr_cpy, scratch_track, |cpu, scratch_track| {
+            assert_eq!(cur_instr_num, OPCODE_PRINT_X_DECIMAL);
+            if print_debug_messages {
+                cpu.debug_message("Instruction: PrintXDecimal");
+            }
+            if print_comments {
+                cpu.comment("PrintXDecimal");
+            }
+            cpu.add_const_to_byte(inc_iptr_by, 1);
+
+            let (x_cpy, scratch_track) = scratch_track.split_1();
+            cpu.copy_byte_autoscratch(x.at(0), x_cpy, scratch_track);
+            cpu.moveprint_byte(x_cpy, scratch_track);
         });
         cur_instr_num += 1;
         cpu.dec_at(instr_cpy);
@@ -1107,8 +1514,7 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
             cpu.print_registers(all_registers.track);
         }
 
-        cpu.loop_while(inc_iptr_by, |cpu| {
-            cpu.dec();
+        cpu.repeat(inc_iptr_by, |cpu| {
             cpu.inc_register(iptr, scratch_track);
         });
 
@@ -1119,6 +1525,7 @@ pub fn sam2lir(prog: CompiledSamProgram) -> (Vec<Lir>, CpuConfig) {
     });
 
     cpu.clr_at(not_halted);
+    cpu.clr_register(all_registers, scratch_track);
 
     (cpu.into_ops(), cfg)
 }
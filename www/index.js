@@ -151,6 +151,10 @@ document.getElementById("compile_button").onclick = function() {
 
     try {
         let compiled = wasm.compile(hir);
+        if (compiled.error !== undefined) {
+            alert(compiled.error);
+            return;
+        }
         document.getElementById("compiled_sam").value = compiled.sam;
         document.getElementById("compiled_bf").value = compiled.bf;
     } catch (err) {
@@ -165,6 +169,10 @@ document.getElementById("debug_button").onclick = function() {
     let input = document.getElementById("bf_input").value;
 
     let result = wasm.debug_program(hir, input);
+    if (result.error !== undefined) {
+        alert(result.error);
+        return;
+    }
 
     document.getElementById("compiled_sam").value = result.sam;
     document.getElementById("compiled_bf").value = "Debugging runs the assembly code directly";
@@ -174,6 +182,10 @@ document.getElementById("debug_button").onclick = function() {
 // JIT:
 
 let myWorker = null;
+// size of the bf tape when running compiled wasm, in 64KiB pages
+const TAPE_PAGES = 100;
+// the worker reports an error once the compiled program has run this many loop iterations
+const MAX_LOOP_ITERATIONS = 1_000_000_000_000n;
 document.getElementById("jit_run_button").onclick = function() {
     if (myWorker != null) {
         myWorker.terminate();
@@ -201,10 +213,11 @@ document.getElementById("jit_run_button").onclick = function() {
     let bf = document.getElementById("compiled_bf").value;
 
     try {
-        let result = wasm.compile_bf_to_wasm(bf);
-        myWorker.postMessage(["start", result]);
+        let result = wasm.compile_bf_to_wasm(bf, TAPE_PAGES, wasm.EofBehavior.Zero, MAX_LOOP_ITERATIONS);
+        myWorker.postMessage(["start", result, TAPE_PAGES]);
     } catch (err) {
-        alert("Error compiling brainfuck code. See the console for more info.");
+        alert("Error compiling brainfuck code: " + err);
+        return;
     }
 
     document.getElementById("bf_output").value = "";
@@ -240,6 +253,9 @@ document.getElementById("send_input_button").onclick = sendInput;
 
 // Running:
 
+// Give up instead of freezing the page forever on an infinite loop
+const MAX_STEPS = 10_000_000_000n;
+
 document.getElementById("run_button").onclick = function() {
     let bf = document.getElementById("compiled_bf").value;
     let input = document.getElementById("bf_input").value;
@@ -247,9 +263,12 @@ document.getElementById("run_button").onclick = function() {
     document.getElementById("bf_output").value = "Running...\n\nThis page will freeze until the entire program finishes.";
 
     setTimeout(() => {
-        let result = wasm.parse_and_run_bf(bf, input);
+        let result = wasm.parse_and_run_bf(bf, input, wasm.EofBehavior.Zero, MAX_STEPS);
 
-        document.getElementById("bf_output").value = result;
+        document.getElementById("bf_output").value = result.output;
+        if (result.error !== undefined) {
+            document.getElementById("bf_output").value += "\n\n" + result.error;
+        }
     });
 }
 
@@ -262,7 +281,7 @@ document.getElementById("perf_button").onclick = function() {
     document.getElementById("bf_output").value = "Running...\n\nThis page will freeze until the entire program finishes.";
 
     setTimeout(() => {
-        let result = wasm.perf_bf(bf, input);
+        let result = wasm.perf_bf(bf, input, MAX_STEPS);
 
         document.getElementById("bf_output").value = result;
         document.getElementById("bf_output").disabled = false;
@@ -46,7 +46,7 @@ onmessage = function(e) {
                 write_output_byte(byte) {
                     postMessage(["output", byte]);
                 },
-                tape: new WebAssembly.Memory({ initial: 100 })
+                tape: new WebAssembly.Memory({ initial: e.data[2] })
             },
         };
 
@@ -61,6 +61,9 @@ onmessage = function(e) {
                     wasmState.module = null;
                 } else if (return_val == 1) {
                     postMessage(["need_more_input"]);
+                } else if (return_val == 2) {
+                    postMessage(["error", "step limit exceeded"]);
+                    wasmState.module = null;
                 }
             } catch (err) {
                 postMessage(["error", err]);
@@ -84,6 +87,9 @@ onmessage = function(e) {
                     wasmState.module = null;
                 } else if (return_val == 1) {
                     postMessage(["need_more_input"]);
+                } else if (return_val == 2) {
+                    postMessage(["error", "step limit exceeded"]);
+                    wasmState.module = null;
                 }
             } catch (err) {
                 postMessage(["error", err]);